@@ -0,0 +1,54 @@
+//! Requests per second through the full SessionLayer for each backend.
+//!
+//! Postgres and Redis are only included when `AXUM_SESSION_BENCH_POSTGRES_URL` or
+//! `AXUM_SESSION_BENCH_REDIS_URL` is set and the service is reachable.
+
+mod common;
+
+use axum_session::SessionConfig;
+use common::{backends, cookies, request, Backend, Comparison};
+use criterion::{BatchSize, Criterion};
+use tokio::runtime::Runtime;
+
+fn layer_requests(c: &mut Criterion, rt: &Runtime, backends: &[Backend]) -> Comparison {
+    let mut group = c.benchmark_group("backends");
+    let mut comparison = Comparison::new("Returning session requests per backend");
+
+    for backend in backends {
+        group.bench_function(format!("{}/new_session", backend.name), |b| {
+            b.to_async(rt).iter_batched(
+                || backend.app.clone(),
+                |app| request(app, None),
+                BatchSize::SmallInput,
+            )
+        });
+
+        // Most requests belong to a session that already exists.
+        let cookie = rt.block_on(async { cookies(&request(backend.app.clone(), None).await) });
+        group.bench_function(format!("{}/returning_session", backend.name), |b| {
+            b.to_async(rt).iter_batched(
+                || backend.app.clone(),
+                |app| async { request(app, Some(&cookie)).await },
+                BatchSize::SmallInput,
+            )
+        });
+
+        rt.block_on(
+            comparison.measure_async(backend.name, || request(backend.app.clone(), Some(&cookie))),
+        );
+    }
+
+    group.finish();
+    comparison
+}
+
+fn main() {
+    let mut criterion = Criterion::default().configure_from_args();
+    let rt = Runtime::new().unwrap();
+    let backends = rt.block_on(backends(SessionConfig::default()));
+
+    let comparison = layer_requests(&mut criterion, &rt, &backends);
+
+    criterion.final_summary();
+    comparison.print();
+}
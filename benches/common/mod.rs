@@ -0,0 +1,319 @@
+//! Shared harness for the criterion benches.
+//!
+//! Every bench includes this with `mod common;` so pools, apps and the comparison table
+//! are built the same way everywhere. Not every bench uses every item.
+#![allow(dead_code)]
+
+use async_trait::async_trait;
+use axum::{body::Body, http::Request, response::Response, routing::get, Router};
+use axum_session::{
+    bench::SessionTestPool, DatabaseError, DatabasePool, Session, SessionAnyPool, SessionConfig,
+    SessionLayer, SessionNullPool, SessionStore,
+};
+use axum_session_redispool::SessionRedisPool;
+use axum_session_sqlx::{SessionPgPool, SessionSqlitePool};
+use http::header;
+use std::{
+    future::Future,
+    hint::black_box,
+    time::{Duration, Instant},
+};
+use tower::ServiceExt;
+
+/// Postgres url used by the database benches. They are skipped when unset.
+pub const POSTGRES_URL_ENV: &str = "AXUM_SESSION_BENCH_POSTGRES_URL";
+/// Redis url used by the database benches. They are skipped when unset.
+pub const REDIS_URL_ENV: &str = "AXUM_SESSION_BENCH_REDIS_URL";
+/// How long an optional service gets to accept a connection before its benches are skipped.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long each row of a [`Comparison`] is measured for.
+const COMPARISON_TIME: Duration = Duration::from_millis(500);
+
+/// Persistent pool which accepts every write without storing it,
+/// so benches measure the serialization and encryption done before the database.
+#[derive(Debug, Clone)]
+pub struct DiscardPool;
+
+#[async_trait]
+impl DatabasePool for DiscardPool {
+    async fn initiate(&self, _table_name: &str) -> Result<(), DatabaseError> {
+        Ok(())
+    }
+
+    async fn count(&self, _table_name: &str) -> Result<i64, DatabaseError> {
+        Ok(0)
+    }
+
+    async fn store(
+        &self,
+        _id: &str,
+        session: &str,
+        _expires: i64,
+        _table_name: &str,
+    ) -> Result<(), DatabaseError> {
+        black_box(session);
+        Ok(())
+    }
+
+    async fn load(&self, _id: &str, _table_name: &str) -> Result<Option<String>, DatabaseError> {
+        Ok(None)
+    }
+
+    async fn delete_one_by_id(&self, _id: &str, _table_name: &str) -> Result<(), DatabaseError> {
+        Ok(())
+    }
+
+    async fn exists(&self, _id: &str, _table_name: &str) -> Result<bool, DatabaseError> {
+        Ok(false)
+    }
+
+    async fn delete_by_expiry(&self, _table_name: &str) -> Result<Vec<String>, DatabaseError> {
+        Ok(Vec::new())
+    }
+
+    async fn delete_all(&self, _table_name: &str) -> Result<(), DatabaseError> {
+        Ok(())
+    }
+
+    async fn get_ids(&self, _table_name: &str) -> Result<Vec<String>, DatabaseError> {
+        Ok(Vec::new())
+    }
+
+    fn auto_handles_expiry(&self) -> bool {
+        true
+    }
+}
+
+/// Handler reading and writing the session on every request.
+pub async fn counter<T>(session: Session<T>) -> String
+where
+    T: DatabasePool + Clone + std::fmt::Debug + Sync + Send + 'static,
+{
+    let count: usize = session.get("count").unwrap_or(0) + 1;
+    session.set("count", count);
+    count.to_string()
+}
+
+/// Router with the [`counter`] handler behind the SessionLayer.
+pub fn app<T>(store: SessionStore<T>) -> Router
+where
+    T: DatabasePool + Clone + std::fmt::Debug + Sync + Send + 'static,
+{
+    Router::new()
+        .route("/", get(counter::<T>))
+        .layer(SessionLayer::new(store))
+}
+
+/// Sends one request, with the cookies of an earlier response if given.
+pub async fn request(app: Router, cookie: Option<&str>) -> Response {
+    let mut request = Request::get("/");
+
+    if let Some(cookie) = cookie {
+        request = request.header(header::COOKIE, cookie);
+    }
+
+    app.oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .unwrap()
+}
+
+/// Returns the cookies set by the response as a `Cookie` header value.
+pub fn cookies(res: &Response) -> String {
+    res.headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok()?.split(';').next())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// A SessionLayer app over one backend.
+pub struct Backend {
+    pub name: &'static str,
+    pub app: Router,
+}
+
+/// Builds an app for every backend available.
+/// The in process backends are always included, Postgres and Redis only when their url
+/// environment variable is set and the service accepts a connection within a few seconds.
+pub async fn backends(config: SessionConfig) -> Vec<Backend> {
+    let mut backends = vec![
+        Backend {
+            name: "null_pool",
+            app: app(SessionStore::<SessionNullPool>::new(None, config.clone())
+                .await
+                .unwrap()),
+        },
+        Backend {
+            name: "test_pool",
+            app: app(
+                SessionStore::new(Some(SessionTestPool::default()), config.clone())
+                    .await
+                    .unwrap(),
+            ),
+        },
+    ];
+
+    match sqlite_memory().await {
+        Ok(pool) => backends.push(Backend {
+            name: "sqlite_memory",
+            app: app(store(pool, &config).await),
+        }),
+        Err(err) => eprintln!("skipping sqlite_memory: {err}"),
+    }
+
+    if let Some(pool) = optional_service(POSTGRES_URL_ENV, postgres).await {
+        backends.push(Backend {
+            name: "postgres",
+            app: app(store(pool, &config).await),
+        });
+    }
+
+    if let Some(pool) = optional_service(REDIS_URL_ENV, redis).await {
+        backends.push(Backend {
+            name: "redis",
+            app: app(store(pool, &config).await),
+        });
+    }
+
+    backends
+}
+
+/// Stores use a dedicated table so benches never touch real session data.
+async fn store<T>(pool: T, config: &SessionConfig) -> SessionStore<SessionAnyPool>
+where
+    T: DatabasePool + Clone + std::fmt::Debug + Sync + Send + 'static,
+{
+    let config = config.clone().with_table_name("axum_session_bench");
+    SessionStore::new(Some(SessionAnyPool::new(pool)), config)
+        .await
+        .unwrap()
+}
+
+async fn sqlite_memory() -> Result<SessionSqlitePool, sqlx::Error> {
+    // A single connection keeps every request on the same in memory database.
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await?;
+    Ok(pool.into())
+}
+
+async fn postgres(url: String) -> Result<SessionPgPool, String> {
+    let pool = sqlx::postgres::PgPoolOptions::new()
+        .max_connections(5)
+        .connect(&url)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(pool.into())
+}
+
+async fn redis(url: String) -> Result<SessionRedisPool, String> {
+    let client = redis::Client::open(url).map_err(|e| e.to_string())?;
+    // The pool connects lazily so check the service answers before benching it.
+    client
+        .get_multiplexed_async_connection()
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(redis_pool::RedisPool::from(client).into())
+}
+
+/// Connects to an optional service, printing why it was skipped if it is not available.
+async fn optional_service<T, F, Fut>(env: &str, connect: F) -> Option<T>
+where
+    F: FnOnce(String) -> Fut,
+    Fut: Future<Output = Result<T, String>>,
+{
+    let Ok(url) = std::env::var(env) else {
+        eprintln!("skipping {env}: not set");
+        return None;
+    };
+
+    match tokio::time::timeout(CONNECT_TIMEOUT, connect(url)).await {
+        Ok(Ok(pool)) => Some(pool),
+        Ok(Err(err)) => {
+            eprintln!("skipping {env}: {err}");
+            None
+        }
+        Err(_) => {
+            eprintln!("skipping {env}: connection timed out");
+            None
+        }
+    }
+}
+
+/// Small throughput table printed after the criterion output, so results can be
+/// compared at a glance. Criterion keeps the detailed and statistically sound numbers.
+pub struct Comparison {
+    title: String,
+    rows: Vec<(String, f64)>,
+}
+
+impl Comparison {
+    pub fn new(title: impl Into<String>) -> Self {
+        Self {
+            title: title.into(),
+            rows: Vec::new(),
+        }
+    }
+
+    /// Runs the operation repeatedly for a short fixed time and records operations per second.
+    pub fn measure(&mut self, name: impl Into<String>, mut op: impl FnMut()) {
+        let start = Instant::now();
+        let mut count = 0u64;
+
+        while start.elapsed() < COMPARISON_TIME {
+            op();
+            count += 1;
+        }
+
+        self.record(name, count, start.elapsed());
+    }
+
+    /// Async version of [`Comparison::measure`].
+    pub async fn measure_async<F, Fut>(&mut self, name: impl Into<String>, mut op: F)
+    where
+        F: FnMut() -> Fut,
+        Fut: Future,
+    {
+        let start = Instant::now();
+        let mut count = 0u64;
+
+        while start.elapsed() < COMPARISON_TIME {
+            black_box(op().await);
+            count += 1;
+        }
+
+        self.record(name, count, start.elapsed());
+    }
+
+    fn record(&mut self, name: impl Into<String>, count: u64, elapsed: Duration) {
+        self.rows
+            .push((name.into(), count as f64 / elapsed.as_secs_f64()));
+    }
+
+    /// Prints every row with its rate relative to the fastest.
+    pub fn print(&self) {
+        let fastest = self.rows.iter().map(|(_, rate)| *rate).fold(0.0, f64::max);
+        let width = self
+            .rows
+            .iter()
+            .map(|(name, _)| name.len())
+            .max()
+            .unwrap_or(0)
+            .max(4);
+
+        println!("\n{}", self.title);
+        println!("{:<width$} | {:>12} | {:>8}", "name", "ops/sec", "relative");
+        println!("{:-<width$}-+-{:-<12}-+-{:-<8}", "", "", "");
+
+        for (name, rate) in &self.rows {
+            println!(
+                "{:<width$} | {:>12.0} | {:>7.2}x",
+                name,
+                rate,
+                rate / fastest
+            );
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! Micro benches of the work done for every request: signing cookies,
+//! serializing and encrypting the session and generating new ids.
+
+mod common;
+
+use axum_session::{
+    bench::{self, SessionTestPool},
+    Key, SessionConfig, SessionStore,
+};
+use common::Comparison;
+use criterion::{BenchmarkId, Criterion};
+use std::{collections::HashMap, hint::black_box};
+use tokio::runtime::Runtime;
+
+/// Approximate serialized session sizes in bytes.
+const PAYLOAD_SIZES: [usize; 4] = [64, 1_024, 16_384, 262_144];
+/// What the signature is bound to, standing in for the ip and user agent string.
+const MESSAGE: &str = "127.0.0.1Mozilla/5.0";
+
+fn payload(size: usize) -> HashMap<String, String> {
+    // Values are split over several keys like a real session.
+    let value_len = (size / 8).max(1);
+    (0..8)
+        .map(|index| (format!("key-{index}"), "x".repeat(value_len)))
+        .collect()
+}
+
+fn cookie_signing(c: &mut Criterion) {
+    let key = Key::generate();
+    let id = uuid::Uuid::new_v4().to_string();
+    let signed = bench::sign_header(&id, &key, MESSAGE).unwrap();
+
+    let mut group = c.benchmark_group("cookie");
+    group.bench_function("sign", |b| {
+        b.iter(|| bench::sign_header(black_box(&id), &key, MESSAGE).unwrap())
+    });
+    group.bench_function("verify", |b| {
+        b.iter(|| bench::verify_header(black_box(&signed), &key, MESSAGE).unwrap())
+    });
+    group.finish();
+}
+
+fn session_payloads(c: &mut Criterion, rt: &Runtime) -> Comparison {
+    let store = rt.block_on(async {
+        SessionStore::<SessionTestPool>::new(None, SessionConfig::default())
+            .await
+            .unwrap()
+    });
+    let key = Key::generate();
+    let id = uuid::Uuid::new_v4().to_string();
+    let mut comparison = Comparison::new("Session serialize and encrypt by payload size");
+
+    let mut group = c.benchmark_group("session_data");
+    for size in PAYLOAD_SIZES {
+        let data = payload(size);
+        let serialized = bench::serialize_session(&store, &id, data.clone()).unwrap();
+
+        group.bench_with_input(BenchmarkId::new("serialize", size), &data, |b, data| {
+            b.iter(|| bench::serialize_session(&store, &id, data.clone()).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("encrypt", size), &serialized, |b, s| {
+            b.iter(|| bench::encrypt(&id, black_box(s), &key).unwrap())
+        });
+
+        comparison.measure(format!("serialize+encrypt/{size}"), || {
+            let serialized = bench::serialize_session(&store, &id, data.clone()).unwrap();
+            black_box(bench::encrypt(&id, &serialized, &key).unwrap());
+        });
+    }
+    group.finish();
+
+    comparison
+}
+
+fn id_generation(c: &mut Criterion, rt: &Runtime) {
+    #[cfg_attr(not(feature = "key-store"), allow(unused_mut))]
+    let mut configs = vec![("without_bloom_filter", SessionConfig::default())];
+
+    #[cfg(feature = "key-store")]
+    configs.push((
+        "with_bloom_filter",
+        SessionConfig::default().with_bloom_filter(true),
+    ));
+    #[cfg(not(feature = "key-store"))]
+    eprintln!("skipping generate_id/with_bloom_filter: needs the key-store feature");
+
+    let mut group = c.benchmark_group("generate_id");
+    for (name, config) in configs {
+        let store = rt.block_on(async {
+            SessionStore::new(Some(SessionTestPool::default()), config)
+                .await
+                .unwrap()
+        });
+        group.bench_function(name, |b| {
+            b.to_async(rt)
+                .iter(|| async { bench::generate_id(&store).await.unwrap() })
+        });
+    }
+    group.finish();
+}
+
+fn main() {
+    let mut criterion = Criterion::default().configure_from_args();
+    let rt = Runtime::new().unwrap();
+
+    cookie_signing(&mut criterion);
+    let comparison = session_payloads(&mut criterion, &rt);
+    id_generation(&mut criterion, &rt);
+
+    criterion.final_summary();
+    comparison.print();
+}
@@ -0,0 +1,187 @@
+mod common;
+
+use axum::{body::Body, http::Request, routing::get, Router};
+use axum_session::{Key, Session, SessionConfig, SessionLayer, SessionNullPool, SessionStore};
+use common::{app, DiscardPool};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use std::{
+    hint::black_box,
+    sync::{Arc, Mutex},
+};
+use tokio::runtime::{Builder, Runtime};
+use tower::ServiceExt;
+use tracing::{
+    field::{Field, Visit},
+    span, Dispatch, Event, Level, Metadata, Subscriber,
+};
+
+/// Subscriber enabling events up to a level which formats their fields like a log line would.
+struct LevelSubscriber(Level);
+
+/// Formats every field of an event into a string which is then discarded.
+struct FieldFormatter(String);
+
+impl Visit for FieldFormatter {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        use std::fmt::Write;
+        let _ = write!(self.0, "{}={:?} ", field.name(), value);
+    }
+}
+
+impl Subscriber for LevelSubscriber {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        *metadata.level() <= self.0
+    }
+
+    fn new_span(&self, _span: &span::Attributes<'_>) -> span::Id {
+        span::Id::from_u64(1)
+    }
+
+    fn record(&self, _span: &span::Id, _values: &span::Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut formatter = FieldFormatter(String::new());
+        event.record(&mut formatter);
+        black_box(formatter.0);
+    }
+
+    fn enter(&self, _span: &span::Id) {}
+
+    fn exit(&self, _span: &span::Id) {}
+}
+
+/// Runs one request through the layer and keeps the Session so it can be used directly.
+fn loaded_session(rt: &Runtime) -> Session<SessionNullPool> {
+    rt.block_on(async {
+        let store = SessionStore::<SessionNullPool>::new(None, SessionConfig::default())
+            .await
+            .unwrap();
+        let slot = Arc::new(Mutex::new(None));
+        let captured = slot.clone();
+        let app = Router::new()
+            .route(
+                "/",
+                get(move |session: Session<SessionNullPool>| async move {
+                    *captured.lock().unwrap() = Some(session);
+                }),
+            )
+            .layer(SessionLayer::new(store));
+
+        app.oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        let session = slot.lock().unwrap().take().unwrap();
+        session
+    })
+}
+
+fn session_access(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let session = loaded_session(&rt);
+    session.set("user", 42u64);
+
+    let mut group = c.benchmark_group("session");
+    group.bench_function("get", |b| {
+        b.iter(|| black_box(session.get::<u64>(black_box("user"))))
+    });
+    group.bench_function("set", |b| {
+        b.iter(|| session.set(black_box("user"), black_box(42u64)))
+    });
+    group.bench_function("get_remove", |b| {
+        b.iter(|| {
+            session.set("removable", 7u64);
+            black_box(session.get_remove::<u64>(black_box("removable")))
+        })
+    });
+    group.finish();
+}
+
+fn service_call(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("service");
+
+    let null_app = rt.block_on(async {
+        app(
+            SessionStore::<SessionNullPool>::new(None, SessionConfig::default())
+                .await
+                .unwrap(),
+        )
+    });
+    group.bench_function("null_pool", |b| {
+        b.to_async(&rt).iter_batched(
+            || null_app.clone(),
+            |app| async move {
+                app.oneshot(Request::get("/").body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    // Persistent stores serialize the session on every request before storing it.
+    for (name, config) in [
+        ("store_session", SessionConfig::default()),
+        (
+            "store_session_encrypted",
+            SessionConfig::default().with_database_key(Key::generate()),
+        ),
+    ] {
+        let store_app =
+            rt.block_on(async { app(SessionStore::new(Some(DiscardPool), config).await.unwrap()) });
+        group.bench_function(name, |b| {
+            b.to_async(&rt).iter_batched(
+                || store_app.clone(),
+                |app| async move {
+                    app.oneshot(Request::get("/").body(Body::empty()).unwrap())
+                        .await
+                        .unwrap()
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+/// Compares a storing request with its info level events disabled and enabled, so the cost
+/// of the session.id fields is only paid when the events are logged.
+fn tracing_levels(c: &mut Criterion) {
+    // The subscriber is set for this thread only, so the requests must run on it.
+    let rt = Builder::new_current_thread().enable_all().build().unwrap();
+    let store_app = rt.block_on(async {
+        app(
+            SessionStore::new(Some(DiscardPool), SessionConfig::default())
+                .await
+                .unwrap(),
+        )
+    });
+    let mut group = c.benchmark_group("tracing");
+
+    for (name, level) in [
+        ("info_disabled", Level::WARN),
+        ("info_enabled", Level::INFO),
+    ] {
+        let _guard = tracing::dispatcher::set_default(&Dispatch::new(LevelSubscriber(level)));
+        group.bench_function(name, |b| {
+            b.to_async(&rt).iter_batched(
+                || store_app.clone(),
+                |app| async move {
+                    app.oneshot(Request::get("/").body(Body::empty()).unwrap())
+                        .await
+                        .unwrap()
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, session_access, service_call, tracing_levels);
+criterion_main!(benches);
@@ -0,0 +1,320 @@
+#![doc = include_str!("../README.md")]
+#![allow(dead_code)]
+#![warn(clippy::all, nonstandard_style, future_incompatible)]
+#![forbid(unsafe_code)]
+
+use async_trait::async_trait;
+use axum_session::{DatabaseError, DatabasePool, Session, SessionStore};
+use azure_data_cosmos::{clients::ContainerClient, CosmosError, FeedScope, Query};
+use chrono::Utc;
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Debug, Formatter};
+
+pub type SessionCosmosSession = Session<SessionCosmosPool>;
+pub type SessionCosmosSessionStore = SessionStore<SessionCosmosPool>;
+
+#[derive(Default, Debug, Serialize, Deserialize)]
+struct CosmosSessionData {
+    /// Also the partition key of the document.
+    id: String,
+    expires: i64,
+    session: String,
+    /// Unix timestamp of the last time the session was stored.
+    #[serde(default)]
+    last_active: i64,
+    /// Seconds until Cosmos DB removes the document. Only set when the container uses TTL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ttl: Option<i64>,
+}
+
+///Cosmos DB's Pool type for the DatabasePool. Needs a Cosmos DB ContainerClient.
+///
+/// The container must use `/id` as its partition key. The table name is not used
+/// as the container client already points at the container to store the sessions in.
+#[derive(Clone)]
+pub struct SessionCosmosPool {
+    client: ContainerClient,
+    ttl: bool,
+}
+
+impl From<ContainerClient> for SessionCosmosPool {
+    fn from(client: ContainerClient) -> Self {
+        SessionCosmosPool { client, ttl: false }
+    }
+}
+
+impl Debug for SessionCosmosPool {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionCosmosPool")
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+impl SessionCosmosPool {
+    /// Set this when TTL is enabled on the container so Cosmos DB removes expired sessions.
+    /// Every document then gets a `ttl` matching its expiry and the session store no longer
+    /// sweeps the container for expired sessions.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let pool = SessionCosmosPool::from(container).with_ttl(true);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_ttl(mut self, ttl: bool) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Gets the id's returned by a `SELECT VALUE c.id` query across every partition.
+    async fn query_ids(&self, query: Query) -> Result<Vec<String>, DatabaseError> {
+        self.client
+            .query_items::<String>(query, FeedScope::full_container(), None)
+            .await
+            .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?
+            .try_collect()
+            .await
+            .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))
+    }
+
+    /// Deletes the document, treating a missing document as already deleted.
+    async fn delete_item(&self, id: &str) -> Result<(), DatabaseError> {
+        match self.client.delete_item(id.to_owned(), id, None).await {
+            Ok(_) => Ok(()),
+            Err(err) if is_not_found(&err) => Ok(()),
+            Err(err) => Err(DatabaseError::GenericDeleteError(err.to_string())),
+        }
+    }
+
+    /// Reads the document, returning None when it does not exist.
+    async fn read_item(&self, id: &str) -> Result<Option<CosmosSessionData>, DatabaseError> {
+        match self.client.read_item(id.to_owned(), id, None).await {
+            Ok(response) => response
+                .into_model::<CosmosSessionData>()
+                .map(Some)
+                .map_err(|err| DatabaseError::GenericSelectError(err.to_string())),
+            Err(err) if is_not_found(&err) => Ok(None),
+            Err(err) => Err(DatabaseError::GenericSelectError(err.to_string())),
+        }
+    }
+}
+
+fn is_not_found(err: &CosmosError) -> bool {
+    err.status().is_not_found()
+}
+
+fn ids_query(query: &str, params: &[(&str, i64)]) -> Result<Query, DatabaseError> {
+    params
+        .iter()
+        .try_fold(Query::from(query), |query, (name, value)| {
+            query.with_parameter(*name, value)
+        })
+        .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))
+}
+
+#[async_trait]
+impl DatabasePool for SessionCosmosPool {
+    // Cosmos DB containers are created along with their partition key and TTL settings,
+    // so only check that the container can be reached.
+    async fn initiate(&self, _table_name: &str) -> Result<(), DatabaseError> {
+        self.client
+            .read(None)
+            .await
+            .map_err(|err| DatabaseError::GenericCreateError(err.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn delete_by_expiry(&self, _table_name: &str) -> Result<Vec<String>, DatabaseError> {
+        // Cosmos DB removes the expired documents itself.
+        if self.ttl {
+            return Ok(Vec::new());
+        }
+
+        let query = ids_query(
+            "SELECT VALUE c.id FROM c WHERE c.expires <= @now",
+            &[("@now", Utc::now().timestamp())],
+        )?;
+        let ids = self.query_ids(query).await?;
+
+        for id in &ids {
+            self.delete_item(id).await?;
+        }
+
+        Ok(ids)
+    }
+
+    // Aggregates are not supported across partitions so the unexpired id's are counted instead.
+    async fn count(&self, table_name: &str) -> Result<i64, DatabaseError> {
+        Ok(self.get_ids(table_name).await?.len() as i64)
+    }
+
+    async fn store(
+        &self,
+        id: &str,
+        session: &str,
+        expires: i64,
+        _table_name: &str,
+    ) -> Result<(), DatabaseError> {
+        let now = Utc::now().timestamp();
+        let data = CosmosSessionData {
+            id: id.to_string(),
+            expires,
+            session: session.to_string(),
+            last_active: now,
+            // Cosmos DB needs a ttl of at least one second.
+            ttl: self.ttl.then(|| (expires - now).max(1)),
+        };
+
+        self.client
+            .upsert_item(id.to_owned(), id, data, None)
+            .await
+            .map_err(|err| DatabaseError::GenericInsertError(err.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn load(&self, id: &str, _table_name: &str) -> Result<Option<String>, DatabaseError> {
+        Ok(self.read_item(id).await?.and_then(|result| {
+            if result.session.is_empty() || result.expires < Utc::now().timestamp() {
+                None
+            } else {
+                Some(result.session)
+            }
+        }))
+    }
+
+    async fn delete_one_by_id(&self, id: &str, _table_name: &str) -> Result<(), DatabaseError> {
+        self.delete_item(id).await
+    }
+
+    // Expired documents are left in place until swept or removed by TTL, so they do not count.
+    async fn exists(&self, id: &str, _table_name: &str) -> Result<bool, DatabaseError> {
+        Ok(self
+            .read_item(id)
+            .await?
+            .is_some_and(|result| result.expires >= Utc::now().timestamp()))
+    }
+
+    async fn delete_all(&self, _table_name: &str) -> Result<(), DatabaseError> {
+        let ids = self
+            .query_ids(Query::from("SELECT VALUE c.id FROM c"))
+            .await?;
+
+        for id in &ids {
+            self.delete_item(id).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn get_ids(&self, _table_name: &str) -> Result<Vec<String>, DatabaseError> {
+        let query = ids_query(
+            "SELECT VALUE c.id FROM c WHERE c.expires >= @now",
+            &[("@now", Utc::now().timestamp())],
+        )?;
+
+        self.query_ids(query).await
+    }
+
+    async fn count_active(
+        &self,
+        since: i64,
+        now: i64,
+        _table_name: &str,
+    ) -> Result<Option<i64>, DatabaseError> {
+        let query = ids_query(
+            "SELECT VALUE c.id FROM c WHERE c.last_active >= @since AND c.expires > @now",
+            &[("@since", since), ("@now", now)],
+        )?;
+
+        Ok(Some(self.query_ids(query).await?.len() as i64))
+    }
+
+    fn auto_handles_expiry(&self) -> bool {
+        self.ttl
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SessionCosmosPool;
+    use axum_session::{DatabasePool, SessionConfig, SessionStore};
+    use azure_data_cosmos::{
+        models::ContainerProperties, options::Region, AccountEndpoint, AccountReference,
+        CosmosClientBuilder, RoutingStrategy,
+    };
+
+    /// The well known key of the Cosmos DB emulator.
+    const EMULATOR_KEY: &str =
+        "C2y6yDjf5/R+ob0N8A7Cgv30VRDJIWEHLM+4QDU5DE2nQ9nDuVTqobD4b8mGGyPMbIZnqyMsEcaGQy67XIw/Jw==";
+
+    /// Connects to the emulator and creates the container partitioned by `/id` if needed.
+    async fn pool(container: &str) -> SessionCosmosPool {
+        let endpoint: AccountEndpoint = "http://localhost:8081/".parse().unwrap();
+        let account = AccountReference::with_authentication_key(endpoint, EMULATOR_KEY);
+        let client = CosmosClientBuilder::new()
+            .build(account, RoutingStrategy::ProximityTo(Region::WEST_US))
+            .await
+            .unwrap();
+        // Creating fails once the database and container exist from an earlier run.
+        let _ = client.create_database("axum_session_test", None).await;
+        let database = client.database_client("axum_session_test");
+        let _ = database
+            .create_container(
+                ContainerProperties::new(container.to_owned(), "/id".into()),
+                None,
+            )
+            .await;
+
+        let pool =
+            SessionCosmosPool::from(database.container_client(container, None).await.unwrap());
+        pool.delete_all("").await.unwrap();
+        pool
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the Cosmos DB emulator at http://localhost:8081"]
+    async fn expired_sessions_do_not_exist() {
+        let pool = pool("exists_test").await;
+        SessionStore::new(Some(pool.clone()), SessionConfig::default())
+            .await
+            .unwrap();
+
+        let now = chrono::Utc::now().timestamp();
+        pool.store("session", "{}", now + 3_600, "").await.unwrap();
+        pool.store("old", "{}", now - 60, "").await.unwrap();
+
+        assert!(pool.exists("session", "").await.unwrap());
+        assert!(!pool.exists("old", "").await.unwrap());
+        assert!(!pool.exists("missing", "").await.unwrap());
+        assert_eq!(
+            pool.load("session", "").await.unwrap().as_deref(),
+            Some("{}")
+        );
+        assert_eq!(pool.load("old", "").await.unwrap(), None);
+        assert_eq!(pool.get_ids("").await.unwrap(), ["session"]);
+        assert_eq!(pool.count("").await.unwrap(), 1);
+        assert_eq!(pool.count_active(now - 60, now, "").await.unwrap(), Some(1));
+
+        assert_eq!(pool.delete_by_expiry("").await.unwrap(), ["old"]);
+        pool.delete_one_by_id("session", "").await.unwrap();
+        assert!(!pool.exists("session", "").await.unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the Cosmos DB emulator at http://localhost:8081"]
+    async fn ttl_leaves_sweeping_to_cosmos() {
+        let pool = pool("ttl_test").await.with_ttl(true);
+        assert!(pool.auto_handles_expiry());
+
+        let now = chrono::Utc::now().timestamp();
+        pool.store("old", "{}", now - 60, "").await.unwrap();
+        // The document waits for Cosmos DB's TTL sweep yet already counts as gone.
+        assert!(pool.delete_by_expiry("").await.unwrap().is_empty());
+        assert!(!pool.exists("old", "").await.unwrap());
+        assert_eq!(pool.load("old", "").await.unwrap(), None);
+    }
+}
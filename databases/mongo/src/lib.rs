@@ -4,11 +4,13 @@
 #![forbid(unsafe_code)]
 
 use async_trait::async_trait;
-use axum_session::{DatabaseError, DatabasePool, Session, SessionStore};
+use axum_session::{DatabaseError, DatabasePool, IdsPage, Session, SessionStore};
 use chrono::Utc;
 use mongodb::{
-    bson::{doc, Document},
-    Client,
+    bson::doc,
+    error::{Error, ErrorKind},
+    options::IndexOptions,
+    Client, IndexModel,
 };
 use serde::{Deserialize, Serialize};
 
@@ -20,15 +22,17 @@ struct MongoSessionData {
     id: String,
     expires: i64,
     session: String,
+    /// Unix timestamp of the last time the session was stored.
+    /// Defaulted so documents stored by older versions still load.
+    #[serde(default)]
+    last_active: i64,
 }
-impl MongoSessionData {
-    fn to_document(&self) -> Document {
-        doc! {
-            "id": &self.id,
-            "expires": self.expires,
-            "session": &self.session
-        }
-    }
+
+/// Error code MongoDB returns when creating a collection which already exists.
+const NAMESPACE_EXISTS: i32 = 48;
+
+fn is_namespace_exists(err: &Error) -> bool {
+    matches!(err.kind.as_ref(), ErrorKind::Command(err) if err.code == NAMESPACE_EXISTS)
 }
 
 ///Mongodb's Pool type for the DatabasePool. Needs a mongodb Client.
@@ -45,22 +49,34 @@ impl From<Client> for SessionMongoPool {
 
 #[async_trait]
 impl DatabasePool for SessionMongoPool {
-    // Make sure the collection exists in the database
-    // by inserting a record then deleting it
+    // Make sure the collection and its indexes exist in the database.
+    // Both steps succeed when they already exist so replicas can start at the same time.
     async fn initiate(&self, table_name: &str) -> Result<(), DatabaseError> {
-        let tmp = MongoSessionData::default();
-
         if let Some(db) = &self.client.default_database() {
+            if let Err(err) = db.create_collection(table_name).await {
+                if !is_namespace_exists(&err) {
+                    return Err(DatabaseError::GenericCreateError(err.to_string()));
+                }
+            }
+
             let col = db.collection::<MongoSessionData>(table_name);
 
-            let _ = &col
-                .insert_one(&tmp)
-                .await
-                .map_err(|err| DatabaseError::GenericInsertError(err.to_string()))?;
-            let _ = col
-                .find_one_and_delete(tmp.to_document())
+            // Older versions probed the collection with a default document which was left
+            // behind if the process stopped in between. Duplicates would fail the unique index.
+            col.delete_many(doc! {"id": ""})
                 .await
                 .map_err(|err| DatabaseError::GenericDeleteError(err.to_string()))?;
+
+            col.create_indexes([
+                IndexModel::builder()
+                    .keys(doc! {"id": 1})
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+                IndexModel::builder().keys(doc! {"expires": 1}).build(),
+                IndexModel::builder().keys(doc! {"last_active": 1}).build(),
+            ])
+            .await
+            .map_err(|err| DatabaseError::GenericCreateError(err.to_string()))?;
         }
 
         Ok(())
@@ -120,7 +136,8 @@ impl DatabasePool for SessionMongoPool {
             let update_data = doc! {"$set": {
                 "id": id.to_string(),
                 "expires": expires,
-                "session": session.to_string()
+                "session": session.to_string(),
+                "last_active": Utc::now().timestamp()
             }};
 
             db.collection::<MongoSessionData>(table_name)
@@ -145,7 +162,7 @@ impl DatabasePool for SessionMongoPool {
                     .collection::<MongoSessionData>(table_name)
                     .find_one(filter)
                     .await
-                    .unwrap_or_default()
+                    .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?
                 {
                     Some(result) => {
                         if result.session.is_empty() {
@@ -175,12 +192,14 @@ impl DatabasePool for SessionMongoPool {
 
     async fn exists(&self, id: &str, table_name: &str) -> Result<bool, DatabaseError> {
         Ok(match &self.client.default_database() {
-            Some(db) => db
-                .collection::<MongoSessionData>(table_name)
-                .find_one(doc! {"id": id})
-                .await
-                .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?
-                .is_some(),
+            Some(db) => {
+                db.collection::<MongoSessionData>(table_name)
+                    .count_documents(doc! {"id": id})
+                    .limit(1)
+                    .await
+                    .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?
+                    > 0
+            }
             None => false,
         })
     }
@@ -219,7 +238,202 @@ impl DatabasePool for SessionMongoPool {
         Ok(ids)
     }
 
+    async fn get_ids_page(
+        &self,
+        table_name: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<IdsPage, DatabaseError> {
+        let mut ids: Vec<String> = Vec::new();
+
+        // A limit of 0 would return every document.
+        if limit == 0 {
+            return Ok(IdsPage::default());
+        }
+
+        if let Some(db) = &self.client.default_database() {
+            // Pages by the unique id index so each page starts after the last id of the one before.
+            let mut filter = doc! {"expires":
+                {"$gte": Utc::now().timestamp()}
+            };
+
+            if let Some(cursor) = cursor {
+                filter.insert("id", doc! {"$gt": cursor});
+            }
+
+            let mut result = db
+                .collection::<MongoSessionData>(table_name)
+                .find(filter)
+                .sort(doc! {"id": 1})
+                .limit(limit as i64)
+                .await
+                .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?;
+
+            while result
+                .advance()
+                .await
+                .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?
+            {
+                let item = result
+                    .deserialize_current()
+                    .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?;
+                ids.push(item.id);
+            }
+        }
+
+        Ok(IdsPage {
+            cursor: ids
+                .last()
+                .filter(|_| limit > 0 && ids.len() >= limit)
+                .cloned(),
+            ids,
+        })
+    }
+
+    async fn count_active(
+        &self,
+        since: i64,
+        now: i64,
+        table_name: &str,
+    ) -> Result<Option<i64>, DatabaseError> {
+        Ok(match &self.client.default_database() {
+            Some(db) => {
+                let filter = doc! {
+                    "last_active": {"$gte": since},
+                    "expires": {"$gt": now}
+                };
+
+                Some(
+                    db.collection::<MongoSessionData>(table_name)
+                        .count_documents(filter)
+                        .await
+                        .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?
+                        as i64,
+                )
+            }
+            None => None,
+        })
+    }
+
+    async fn touch_active(
+        &self,
+        id: &str,
+        now: i64,
+        table_name: &str,
+    ) -> Result<(), DatabaseError> {
+        if let Some(db) = &self.client.default_database() {
+            db.collection::<MongoSessionData>(table_name)
+                .update_one(doc! {"id": id}, doc! {"$set": {"last_active": now}})
+                .await
+                .map_err(|err| DatabaseError::GenericInsertError(err.to_string()))?;
+        }
+
+        Ok(())
+    }
+
     fn auto_handles_expiry(&self) -> bool {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::SessionMongoPool;
+    use axum_session::{DatabasePool, SessionConfig, SessionStore};
+    use mongodb::{bson::doc, Client};
+
+    #[tokio::test]
+    #[ignore = "needs a MongoDB server at mongodb://127.0.0.1:27017"]
+    async fn concurrent_initiate_creates_indexes() {
+        let client = Client::with_uri_str("mongodb://127.0.0.1:27017/axum_session_test")
+            .await
+            .unwrap();
+        let db = client.default_database().unwrap();
+        let table_name = "initiate_test";
+        db.collection::<mongodb::bson::Document>(table_name)
+            .drop()
+            .await
+            .unwrap();
+
+        let pool = SessionMongoPool::from(client.clone());
+        let config = SessionConfig::default().with_table_name(table_name);
+        let (a, b) = tokio::join!(
+            SessionStore::new(Some(pool.clone()), config.clone()),
+            SessionStore::new(Some(pool.clone()), config.clone())
+        );
+        a.unwrap();
+        b.unwrap();
+        // Starting again with everything in place still succeeds.
+        pool.initiate(table_name).await.unwrap();
+
+        let col = db.collection::<mongodb::bson::Document>(table_name);
+        let indexes = col.list_index_names().await.unwrap();
+        for index in ["id_1", "expires_1", "last_active_1"] {
+            assert!(indexes.iter().any(|name| name == index), "{index} missing");
+        }
+        assert_eq!(col.count_documents(doc! {}).await.unwrap(), 0);
+
+        let expires = chrono::Utc::now().timestamp() + 3_600;
+        pool.store("session", "{}", expires, table_name)
+            .await
+            .unwrap();
+        assert!(pool.exists("session", table_name).await.unwrap());
+        assert!(!pool.exists("missing", table_name).await.unwrap());
+        assert_eq!(
+            pool.load("session", table_name).await.unwrap().as_deref(),
+            Some("{}")
+        );
+
+        // load's filter is answered by the id index instead of a collection scan.
+        let explain = db
+            .run_command(doc! {
+                "explain": {
+                    "find": table_name,
+                    "filter": {"id": "session", "expires": {"$gte": 0}}
+                },
+                "verbosity": "queryPlanner"
+            })
+            .await
+            .unwrap();
+        let plan = explain.get_document("queryPlanner").unwrap().to_string();
+        assert!(plan.contains("IXSCAN") && !plan.contains("COLLSCAN"));
+
+        col.drop().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MongoDB server at mongodb://127.0.0.1:27017"]
+    async fn ids_page() {
+        let client = Client::with_uri_str("mongodb://127.0.0.1:27017/axum_session_test")
+            .await
+            .unwrap();
+        let table_name = "ids_page_test";
+        let pool = SessionMongoPool::from(client);
+        pool.delete_all(table_name).await.unwrap();
+        pool.initiate(table_name).await.unwrap();
+
+        let expires = chrono::Utc::now().timestamp() + 3_600;
+        for id in ["e", "b", "d", "a", "c"] {
+            pool.store(id, "{}", expires, table_name).await.unwrap();
+        }
+        pool.store("old", "{}", 0, table_name).await.unwrap();
+
+        let mut ids = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = pool
+                .get_ids_page(table_name, cursor.as_deref(), 2)
+                .await
+                .unwrap();
+            assert!(page.ids.len() <= 2);
+            ids.extend(page.ids);
+            cursor = page.cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(ids, ["a", "b", "c", "d", "e"]);
+
+        pool.delete_all(table_name).await.unwrap();
+    }
+}
@@ -6,3 +6,151 @@
 mod redis_bb8_pool;
 pub use self::redis_bb8_pool::*;
 pub(crate) mod redis_bb8_tools;
+
+#[cfg(test)]
+mod tests {
+    use super::SessionRedisPool;
+    use axum_session::{DatabasePool, SessionConfig, SessionStore};
+    use bb8_redis::{bb8::Pool, RedisConnectionManager};
+
+    #[tokio::test]
+    async fn colliding_namespaces_are_rejected() {
+        // No connection is made so the namespace is checked without a server.
+        let manager = RedisConnectionManager::new("redis://127.0.0.1:6379/0").unwrap();
+        let pool: SessionRedisPool = Pool::builder().build_unchecked(manager).into();
+
+        for namespace in ["app:sub", "app__counters", "app__rate"] {
+            let config = SessionConfig::default().with_key_namespace(namespace);
+            let err = SessionStore::new(Some(pool.clone()), config)
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                err,
+                axum_session::SessionError::InvalidConfiguration(_)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at redis://127.0.0.1:6379"]
+    async fn key_namespaces_are_isolated() {
+        let manager = RedisConnectionManager::new("redis://127.0.0.1:6379/0").unwrap();
+        let pool: SessionRedisPool = Pool::builder().build(manager).await.unwrap().into();
+        let expires = chrono::Utc::now().timestamp() + 3_600;
+        let mut stores = Vec::new();
+
+        for namespace in ["axum_session_bb8_test_a", "axum_session_bb8_test_b"] {
+            let config = SessionConfig::default().with_key_namespace(namespace);
+            let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+            store.clear_store().await.unwrap();
+
+            for index in 0..3 {
+                pool.store(&format!("{namespace}-{index}"), "{}", expires, namespace)
+                    .await
+                    .unwrap();
+            }
+
+            stores.push(store);
+        }
+
+        let (a, b) = (&stores[0], &stores[1]);
+        assert_eq!(a.count().await.unwrap(), 3);
+        assert_eq!(b.count().await.unwrap(), 3);
+        let ids = pool.get_ids("axum_session_bb8_test_a").await.unwrap();
+        assert_eq!(ids.len(), 3);
+        assert!(ids
+            .iter()
+            .all(|id| id.starts_with("axum_session_bb8_test_a-")));
+
+        a.clear_store().await.unwrap();
+        assert_eq!(a.count().await.unwrap(), 0);
+        assert_eq!(b.count().await.unwrap(), 3);
+        b.clear_store().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at redis://127.0.0.1:6379"]
+    async fn counters_follow_the_session() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/0").unwrap();
+        let mut con = client.get_multiplexed_async_connection().await.unwrap();
+        let manager = RedisConnectionManager::new("redis://127.0.0.1:6379/0").unwrap();
+        let pool: SessionRedisPool = Pool::builder().build(manager).await.unwrap().into();
+        let table = "axum_session_bb8_test_counters";
+        pool.delete_all(table).await.unwrap();
+
+        let expires = chrono::Utc::now().timestamp() + 3_600;
+        pool.store("a", "{}", expires, table).await.unwrap();
+        assert_eq!(
+            pool.counter_incr("a", "calls", 2, expires, table)
+                .await
+                .unwrap(),
+            Some(2)
+        );
+        assert_eq!(
+            pool.counter_incr("a", "calls", 3, expires, table)
+                .await
+                .unwrap(),
+            Some(5)
+        );
+        assert_eq!(
+            pool.counter_get("a", "calls", table).await.unwrap(),
+            Some(5)
+        );
+        assert_eq!(
+            pool.counter_get("a", "other", table).await.unwrap(),
+            Some(0)
+        );
+        // Counters are never seen as sessions.
+        assert_eq!(pool.count(table).await.unwrap(), 1);
+        assert_eq!(pool.get_ids(table).await.unwrap(), vec!["a"]);
+
+        // The counters expire with the session, also once it is stored with a later expiry.
+        let key = format!("{table}__counters:a");
+        let ttl: i64 = redis::cmd("TTL")
+            .arg(&key)
+            .query_async(&mut con)
+            .await
+            .unwrap();
+        assert!((3_590..=3_600).contains(&ttl));
+        pool.store("a", "{}", expires + 600, table).await.unwrap();
+        let ttl: i64 = redis::cmd("TTL")
+            .arg(&key)
+            .query_async(&mut con)
+            .await
+            .unwrap();
+        assert!((4_190..=4_200).contains(&ttl));
+
+        pool.counter_rename("a", "b", expires, table).await.unwrap();
+        assert_eq!(
+            pool.counter_get("a", "calls", table).await.unwrap(),
+            Some(0)
+        );
+        assert_eq!(
+            pool.counter_get("b", "calls", table).await.unwrap(),
+            Some(5)
+        );
+
+        pool.delete_one_by_id("b", table).await.unwrap();
+        assert_eq!(
+            pool.counter_get("b", "calls", table).await.unwrap(),
+            Some(0)
+        );
+
+        pool.counter_incr("c", "calls", 1, expires, table)
+            .await
+            .unwrap();
+        pool.delete_all(table).await.unwrap();
+        assert_eq!(
+            pool.counter_get("c", "calls", table).await.unwrap(),
+            Some(0)
+        );
+
+        // Without a table name the Session keeps its counters within its data.
+        assert_eq!(
+            pool.counter_incr("a", "calls", 1, expires, "")
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
@@ -12,3 +12,264 @@ mod redis_cluster_pool;
 pub use self::redis_cluster_pool::*;
 
 pub(crate) mod redis_tools;
+
+#[cfg(test)]
+mod tests {
+    use super::SessionRedisPool;
+    use axum::{
+        body::Body,
+        extract::ConnectInfo,
+        http::{Request, StatusCode},
+        routing::get,
+        Router,
+    };
+    use axum_session::{DatabasePool, SessionConfig, SessionLayer, SessionStore};
+    use redis_pool::RedisPool;
+    use std::net::SocketAddr;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn colliding_namespaces_are_rejected() {
+        // The client connects lazily so the namespace is checked without a server.
+        let client = redis::Client::open("redis://127.0.0.1:6379/0").unwrap();
+        let pool: SessionRedisPool = RedisPool::from(client).into();
+
+        for namespace in ["app:sub", "app__counters", "app__rate"] {
+            let config = SessionConfig::default().with_key_namespace(namespace);
+            let err = SessionStore::new(Some(pool.clone()), config)
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                err,
+                axum_session::SessionError::InvalidConfiguration(_)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at redis://127.0.0.1:6379"]
+    async fn ids_page_scans_the_namespace() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/0").unwrap();
+        let pool: SessionRedisPool = RedisPool::from(client).into();
+        let table = "axum_session_test_page";
+        let expires = chrono::Utc::now().timestamp() + 3_600;
+        pool.delete_all(table).await.unwrap();
+
+        for index in 0..5 {
+            pool.store(&format!("page-{index}"), "{}", expires, table)
+                .await
+                .unwrap();
+        }
+
+        // SCAN may return a key twice, so the pages are compared as a set.
+        let mut ids = std::collections::BTreeSet::new();
+        let mut cursor = None;
+        loop {
+            let page = pool
+                .get_ids_page(table, cursor.as_deref(), 2)
+                .await
+                .unwrap();
+            ids.extend(page.ids);
+            cursor = page.cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(
+            ids.into_iter().collect::<Vec<_>>(),
+            (0..5)
+                .map(|index| format!("page-{index}"))
+                .collect::<Vec<_>>()
+        );
+        assert!(pool.get_ids_page(table, Some("x"), 2).await.is_err());
+
+        pool.delete_all(table).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at redis://127.0.0.1:6379"]
+    async fn key_namespaces_are_isolated() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/0").unwrap();
+        let pool: SessionRedisPool = RedisPool::from(client).into();
+        let expires = chrono::Utc::now().timestamp() + 3_600;
+        let mut stores = Vec::new();
+
+        for namespace in ["axum_session_test_a", "axum_session_test_b"] {
+            let config = SessionConfig::default().with_key_namespace(namespace);
+            let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+            store.clear_store().await.unwrap();
+
+            for index in 0..3 {
+                pool.store(&format!("{namespace}-{index}"), "{}", expires, namespace)
+                    .await
+                    .unwrap();
+            }
+
+            stores.push(store);
+        }
+
+        let (a, b) = (&stores[0], &stores[1]);
+        assert_eq!(a.count().await.unwrap(), 3);
+        assert_eq!(b.count().await.unwrap(), 3);
+        let ids = pool.get_ids("axum_session_test_a").await.unwrap();
+        assert_eq!(ids.len(), 3);
+        assert!(ids.iter().all(|id| id.starts_with("axum_session_test_a-")));
+
+        a.clear_store().await.unwrap();
+        assert_eq!(a.count().await.unwrap(), 0);
+        assert_eq!(b.count().await.unwrap(), 3);
+        b.clear_store().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at redis://127.0.0.1:6379"]
+    async fn counters_follow_the_session() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/0").unwrap();
+        let mut con = client.get_multiplexed_async_connection().await.unwrap();
+        let pool: SessionRedisPool = RedisPool::from(client.clone()).into();
+        let table = "axum_session_test_counters";
+        pool.delete_all(table).await.unwrap();
+
+        let expires = chrono::Utc::now().timestamp() + 3_600;
+        pool.store("a", "{}", expires, table).await.unwrap();
+        assert_eq!(
+            pool.counter_incr("a", "calls", 2, expires, table)
+                .await
+                .unwrap(),
+            Some(2)
+        );
+        assert_eq!(
+            pool.counter_incr("a", "calls", 3, expires, table)
+                .await
+                .unwrap(),
+            Some(5)
+        );
+        assert_eq!(
+            pool.counter_get("a", "calls", table).await.unwrap(),
+            Some(5)
+        );
+        assert_eq!(
+            pool.counter_get("a", "other", table).await.unwrap(),
+            Some(0)
+        );
+        // Counters are never seen as sessions.
+        assert_eq!(pool.count(table).await.unwrap(), 1);
+        assert_eq!(pool.get_ids(table).await.unwrap(), vec!["a"]);
+
+        // The counters expire with the session, also once it is stored with a later expiry.
+        let key = format!("{table}__counters:a");
+        let ttl: i64 = redis::cmd("TTL")
+            .arg(&key)
+            .query_async(&mut con)
+            .await
+            .unwrap();
+        assert!((3_590..=3_600).contains(&ttl));
+        pool.store("a", "{}", expires + 600, table).await.unwrap();
+        let ttl: i64 = redis::cmd("TTL")
+            .arg(&key)
+            .query_async(&mut con)
+            .await
+            .unwrap();
+        assert!((4_190..=4_200).contains(&ttl));
+
+        pool.counter_rename("a", "b", expires, table).await.unwrap();
+        assert_eq!(
+            pool.counter_get("a", "calls", table).await.unwrap(),
+            Some(0)
+        );
+        assert_eq!(
+            pool.counter_get("b", "calls", table).await.unwrap(),
+            Some(5)
+        );
+
+        pool.delete_one_by_id("b", table).await.unwrap();
+        assert_eq!(
+            pool.counter_get("b", "calls", table).await.unwrap(),
+            Some(0)
+        );
+
+        pool.counter_incr("c", "calls", 1, expires, table)
+            .await
+            .unwrap();
+        pool.delete_all(table).await.unwrap();
+        assert_eq!(
+            pool.counter_get("c", "calls", table).await.unwrap(),
+            Some(0)
+        );
+
+        // Without a table name the Session keeps its counters within its data.
+        assert_eq!(
+            pool.counter_incr("a", "calls", 1, expires, "")
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at redis://127.0.0.1:6379"]
+    async fn creation_limit_is_shared() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/0").unwrap();
+        let mut con = client.get_multiplexed_async_connection().await.unwrap();
+        let pool: SessionRedisPool = RedisPool::from(client).into();
+        let table = "axum_session_test_creation";
+        // The counter of the current hour, which may be left from an earlier run.
+        let bucket = chrono::Utc::now().timestamp() / 3_600;
+        let _: () = redis::cmd("DEL")
+            .arg(format!("{table}__rate:creation:10.0.0.1:{bucket}"))
+            .query_async(&mut con)
+            .await
+            .unwrap();
+
+        // Two instances sharing the pool count against the same limit.
+        let mut apps = Vec::new();
+
+        for _ in 0..2 {
+            let config = SessionConfig::default()
+                .with_table_name(table)
+                .with_distributed_creation_limit(2, chrono::Duration::try_hours(1).unwrap());
+            let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+            apps.push(
+                Router::new()
+                    .route("/", get(|| async {}))
+                    .layer(SessionLayer::new(store)),
+            );
+        }
+
+        let request = || {
+            let mut req = Request::get("/").body(Body::empty()).unwrap();
+            req.extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))));
+            req
+        };
+
+        let statuses = [0, 1, 0, 1].map(|app| apps[app].clone().oneshot(request()));
+        let mut codes = Vec::new();
+
+        for status in statuses {
+            codes.push(status.await.unwrap().status());
+        }
+
+        assert_eq!(
+            codes,
+            [
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS,
+                StatusCode::TOO_MANY_REQUESTS
+            ]
+        );
+
+        pool.delete_all(table).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn empty_namespace_needs_opt_in() {
+        // The pool connects lazily so no server is needed to build the store.
+        let client = redis::Client::open("redis://127.0.0.1:6379/0").unwrap();
+        let pool: SessionRedisPool = RedisPool::from(client).into();
+        let config = SessionConfig::default().with_table_name("");
+
+        assert!(SessionStore::new(Some(pool), config).await.is_err());
+    }
+}
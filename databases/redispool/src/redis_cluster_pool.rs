@@ -1,5 +1,6 @@
 use async_trait::async_trait;
-use axum_session::{DatabaseError, DatabasePool, Session, SessionStore};
+use axum_session::{DatabaseError, DatabasePool, IdsPage, Session, SessionStore};
+use chrono::Utc;
 use redis_pool::ClusterRedisPool;
 
 ///Redis's Session Helper type for the DatabasePool.
@@ -45,6 +46,7 @@ impl DatabasePool for SessionRedisClusterPool {
             .map_err(|err| DatabaseError::GenericAcquire(err.to_string()))?;
 
         let count: i64 = if table_name.is_empty() {
+            // Only reached when the SessionStore was allowed the global namespace.
             redis::cmd("DBSIZE")
                 .query_async(&mut con)
                 .await
@@ -52,10 +54,16 @@ impl DatabasePool for SessionRedisClusterPool {
         } else {
             // Assuming we have a table name, we need to count all the keys that match the table name.
             // We can't use DBSIZE because that would count all the keys in the database.
-            let keys = super::redis_tools::scan_keys(&mut con, &format!("{}:*", table_name))
-                .await
-                .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?;
-            keys.len() as i64
+            let keys = super::redis_tools::scan_keys(
+                &mut con,
+                &super::redis_tools::namespace_pattern(table_name),
+            )
+            .await
+            .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?;
+            let active_key = super::redis_tools::active_key(table_name);
+            keys.iter()
+                .filter(|key| Some(*key) != active_key.as_ref())
+                .count() as i64
         };
 
         Ok(count)
@@ -68,6 +76,7 @@ impl DatabasePool for SessionRedisClusterPool {
         expires: i64,
         table_name: &str,
     ) -> Result<(), DatabaseError> {
+        let member = id;
         let id = if table_name.is_empty() {
             id.to_string()
         } else {
@@ -75,46 +84,72 @@ impl DatabasePool for SessionRedisClusterPool {
         };
         let mut con = self
             .pool
-            .aquire()
+            .acquire()
             .await
-            .map_err(|err| DatabaseError::GenericAquire(err.to_string()))?;
+            .map_err(|err| DatabaseError::GenericAcquire(err.to_string()))?;
         redis::pipe()
             .atomic() //makes this a transation.
             .set(&id, session)
             .ignore()
             .expire_at(&id, expires)
             .ignore()
-            .query_async(&mut con)
+            .query_async::<()>(&mut con)
             .await
             .map_err(|err| DatabaseError::GenericInsertError(err.to_string()))?;
+
+        // The counters and index live in other slots so they can not be part of the transaction above.
+        if let Some(counters_key) = super::redis_tools::counters_key(table_name, member) {
+            // Counters live exactly as long as the session they belong to.
+            redis::cmd("EXPIREAT")
+                .arg(counters_key)
+                .arg(expires)
+                .query_async::<()>(&mut con)
+                .await
+                .map_err(|err| DatabaseError::GenericInsertError(err.to_string()))?;
+        }
+
+        if let Some(active_key) = super::redis_tools::active_key(table_name) {
+            let now = Utc::now().timestamp();
+            redis::pipe()
+                .zadd(&active_key, member, now)
+                .ignore()
+                // Sessions idle for longer than a lifespan have expired so drop them from the index.
+                .zrembyscore(&active_key, "-inf", format!("({}", now - (expires - now)))
+                .ignore()
+                .query_async::<()>(&mut con)
+                .await
+                .map_err(|err| DatabaseError::GenericInsertError(err.to_string()))?;
+        }
+
         Ok(())
     }
 
     async fn load(&self, id: &str, table_name: &str) -> Result<Option<String>, DatabaseError> {
         let mut con = self
             .pool
-            .aquire()
+            .acquire()
             .await
-            .map_err(|err| DatabaseError::GenericAquire(err.to_string()))?;
+            .map_err(|err| DatabaseError::GenericAcquire(err.to_string()))?;
         let id = if table_name.is_empty() {
             id.to_string()
         } else {
             format!("{}:{}", table_name, id)
         };
-        let result: String = redis::cmd("GET")
+        let result: Option<String> = redis::cmd("GET")
             .arg(id)
             .query_async(&mut con)
             .await
             .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?;
-        Ok(Some(result))
+        Ok(result)
     }
 
     async fn delete_one_by_id(&self, id: &str, table_name: &str) -> Result<(), DatabaseError> {
         let mut con = self
             .pool
-            .aquire()
+            .acquire()
             .await
-            .map_err(|err| DatabaseError::GenericAquire(err.to_string()))?;
+            .map_err(|err| DatabaseError::GenericAcquire(err.to_string()))?;
+        let member = id;
         let id = if table_name.is_empty() {
             id.to_string()
         } else {
@@ -122,18 +157,36 @@ impl DatabasePool for SessionRedisClusterPool {
         };
         redis::cmd("DEL")
             .arg(id)
-            .query_async(&mut con)
+            .query_async::<()>(&mut con)
             .await
             .map_err(|err| DatabaseError::GenericDeleteError(err.to_string()))?;
+
+        if let Some(active_key) = super::redis_tools::active_key(table_name) {
+            redis::cmd("ZREM")
+                .arg(active_key)
+                .arg(member)
+                .query_async::<()>(&mut con)
+                .await
+                .map_err(|err| DatabaseError::GenericDeleteError(err.to_string()))?;
+        }
+
+        if let Some(counters_key) = super::redis_tools::counters_key(table_name, member) {
+            redis::cmd("DEL")
+                .arg(counters_key)
+                .query_async::<()>(&mut con)
+                .await
+                .map_err(|err| DatabaseError::GenericDeleteError(err.to_string()))?;
+        }
+
         Ok(())
     }
 
     async fn exists(&self, id: &str, table_name: &str) -> Result<bool, DatabaseError> {
         let mut con = self
             .pool
-            .aquire()
+            .acquire()
             .await
-            .map_err(|err| DatabaseError::GenericAquire(err.to_string()))?;
+            .map_err(|err| DatabaseError::GenericAcquire(err.to_string()))?;
         let id = if table_name.is_empty() {
             id.to_string()
         } else {
@@ -151,24 +204,36 @@ impl DatabasePool for SessionRedisClusterPool {
     async fn delete_all(&self, table_name: &str) -> Result<(), DatabaseError> {
         let mut con = self
             .pool
-            .aquire()
+            .acquire()
             .await
-            .map_err(|err| DatabaseError::GenericAquire(err.to_string()))?;
+            .map_err(|err| DatabaseError::GenericAcquire(err.to_string()))?;
         if table_name.is_empty() {
+            // Only reached when the SessionStore was allowed the global namespace.
             redis::cmd("FLUSHDB")
-                .query_async(&mut con)
+                .query_async::<()>(&mut con)
                 .await
                 .map_err(|err| DatabaseError::GenericDeleteError(err.to_string()))?;
         } else {
             // Assuming we have a table name, we need to delete all the keys that match the table name.
             // We can't use FLUSHDB because that would delete all the keys in the database.
-            let keys = super::redis_tools::scan_keys(&mut con, &format!("{}:*", table_name))
+            let mut keys = super::redis_tools::scan_keys(
+                &mut con,
+                &super::redis_tools::namespace_pattern(table_name),
+            )
+            .await
+            .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?;
+            keys.extend(
+                super::redis_tools::scan_keys(
+                    &mut con,
+                    &super::redis_tools::counters_pattern(table_name),
+                )
                 .await
-                .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?;
+                .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?,
+            );
             for key in keys {
                 redis::cmd("DEL")
                     .arg(key)
-                    .query_async(&mut con)
+                    .query_async::<()>(&mut con)
                     .await
                     .map_err(|err| DatabaseError::GenericDeleteError(err.to_string()))?;
             }
@@ -179,23 +244,238 @@ impl DatabasePool for SessionRedisClusterPool {
     async fn get_ids(&self, table_name: &str) -> Result<Vec<String>, DatabaseError> {
         let mut con = self
             .pool
-            .aquire()
+            .acquire()
             .await
-            .map_err(|err| DatabaseError::GenericAquire(err.to_string()))?;
-        let table_name = if table_name.is_empty() {
-            "*".to_string()
-        } else {
-            format!("{}:0", table_name)
+            .map_err(|err| DatabaseError::GenericAcquire(err.to_string()))?;
+        let keys = super::redis_tools::scan_keys(
+            &mut con,
+            &super::redis_tools::namespace_pattern(table_name),
+        )
+        .await
+        .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?;
+        Ok(super::redis_tools::ids_from_keys(table_name, keys))
+    }
+
+    async fn get_ids_page(
+        &self,
+        table_name: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<IdsPage, DatabaseError> {
+        let mut con = self
+            .pool
+            .acquire()
+            .await
+            .map_err(|err| DatabaseError::GenericAcquire(err.to_string()))?;
+        let (keys, cursor) = super::redis_tools::scan_page(
+            &mut con,
+            &super::redis_tools::namespace_pattern(table_name),
+            cursor,
+            limit,
+        )
+        .await?;
+
+        Ok(IdsPage {
+            ids: super::redis_tools::ids_from_keys(table_name, keys),
+            cursor,
+        })
+    }
+
+    async fn count_active(
+        &self,
+        since: i64,
+        _now: i64,
+        table_name: &str,
+    ) -> Result<Option<i64>, DatabaseError> {
+        let Some(active_key) = super::redis_tools::active_key(table_name) else {
+            return Ok(None);
         };
 
-        let result: Vec<String> =
-            super::redis_tools::scan_keys(&mut con, &format!("{}:*", table_name))
-                .await
-                .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?;
-        Ok(result)
+        let mut con = self
+            .pool
+            .acquire()
+            .await
+            .map_err(|err| DatabaseError::GenericAcquire(err.to_string()))?;
+        let count: i64 = redis::cmd("ZCOUNT")
+            .arg(active_key)
+            .arg(since)
+            .arg("+inf")
+            .query_async(&mut con)
+            .await
+            .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?;
+
+        Ok(Some(count))
+    }
+
+    async fn touch_active(
+        &self,
+        id: &str,
+        now: i64,
+        table_name: &str,
+    ) -> Result<(), DatabaseError> {
+        let Some(active_key) = super::redis_tools::active_key(table_name) else {
+            return Ok(());
+        };
+
+        let mut con = self
+            .pool
+            .acquire()
+            .await
+            .map_err(|err| DatabaseError::GenericAcquire(err.to_string()))?;
+        // XX only updates sessions already indexed so a removed session is not added back.
+        redis::cmd("ZADD")
+            .arg(active_key)
+            .arg("XX")
+            .arg(now)
+            .arg(id)
+            .query_async::<()>(&mut con)
+            .await
+            .map_err(|err| DatabaseError::GenericInsertError(err.to_string()))?;
+        Ok(())
+    }
+
+    async fn rate_increment(
+        &self,
+        bucket_key: &str,
+        window: i64,
+        table_name: &str,
+    ) -> Result<u64, DatabaseError> {
+        // Each window gets its own key which expires with the window so counters reset on their own.
+        let bucket = Utc::now().timestamp() / window;
+        let Some(rate_key) = super::redis_tools::rate_key(table_name, bucket_key, bucket) else {
+            return Err(DatabaseError::GenericNotSupportedError(
+                "rate_increment requires a table name.".into(),
+            ));
+        };
+
+        let mut con = self
+            .pool
+            .acquire()
+            .await
+            .map_err(|err| DatabaseError::GenericAcquire(err.to_string()))?;
+        let (count,): (u64,) = redis::pipe()
+            .atomic() //makes this a transation.
+            .incr(&rate_key, 1)
+            .expire_at(&rate_key, (bucket + 1) * window)
+            .ignore()
+            .query_async(&mut con)
+            .await
+            .map_err(|err| DatabaseError::GenericInsertError(err.to_string()))?;
+
+        Ok(count)
+    }
+
+    async fn counter_incr(
+        &self,
+        id: &str,
+        name: &str,
+        delta: i64,
+        expires: i64,
+        table_name: &str,
+    ) -> Result<Option<i64>, DatabaseError> {
+        let Some(counters_key) = super::redis_tools::counters_key(table_name, id) else {
+            return Ok(None);
+        };
+
+        let mut con = self
+            .pool
+            .acquire()
+            .await
+            .map_err(|err| DatabaseError::GenericAcquire(err.to_string()))?;
+        let (value,): (i64,) = redis::pipe()
+            .atomic() //makes this a transation.
+            .hincr(&counters_key, name, delta)
+            .expire_at(&counters_key, expires)
+            .ignore()
+            .query_async(&mut con)
+            .await
+            .map_err(|err| DatabaseError::GenericInsertError(err.to_string()))?;
+
+        Ok(Some(value))
+    }
+
+    async fn counter_get(
+        &self,
+        id: &str,
+        name: &str,
+        table_name: &str,
+    ) -> Result<Option<i64>, DatabaseError> {
+        let Some(counters_key) = super::redis_tools::counters_key(table_name, id) else {
+            return Ok(None);
+        };
+
+        let mut con = self
+            .pool
+            .acquire()
+            .await
+            .map_err(|err| DatabaseError::GenericAcquire(err.to_string()))?;
+        let value: Option<i64> = redis::cmd("HGET")
+            .arg(counters_key)
+            .arg(name)
+            .query_async(&mut con)
+            .await
+            .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?;
+
+        Ok(Some(value.unwrap_or_default()))
+    }
+
+    async fn counter_rename(
+        &self,
+        id: &str,
+        new_id: &str,
+        expires: i64,
+        table_name: &str,
+    ) -> Result<(), DatabaseError> {
+        let (Some(old_key), Some(new_key)) = (
+            super::redis_tools::counters_key(table_name, id),
+            super::redis_tools::counters_key(table_name, new_id),
+        ) else {
+            return Ok(());
+        };
+
+        let mut con = self
+            .pool
+            .acquire()
+            .await
+            .map_err(|err| DatabaseError::GenericAcquire(err.to_string()))?;
+        let counters: Vec<(String, i64)> = redis::cmd("HGETALL")
+            .arg(&old_key)
+            .query_async(&mut con)
+            .await
+            .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?;
+
+        if counters.is_empty() {
+            return Ok(());
+        }
+
+        // The keys live in different slots so the copy and the delete are separate commands.
+        redis::pipe()
+            .atomic() //makes this a transation.
+            .hset_multiple(&new_key, &counters)
+            .ignore()
+            .expire_at(&new_key, expires)
+            .ignore()
+            .query_async::<()>(&mut con)
+            .await
+            .map_err(|err| DatabaseError::GenericInsertError(err.to_string()))?;
+        redis::cmd("DEL")
+            .arg(old_key)
+            .query_async::<()>(&mut con)
+            .await
+            .map_err(|err| DatabaseError::GenericDeleteError(err.to_string()))?;
+
+        Ok(())
     }
 
     fn auto_handles_expiry(&self) -> bool {
         true
     }
+
+    fn uses_key_namespace(&self) -> bool {
+        true
+    }
+
+    fn validate_table_name(&self, table_name: &str) -> Result<(), DatabaseError> {
+        super::redis_tools::validate_namespace(table_name)
+    }
 }
@@ -36,3 +36,192 @@ where
 
     Ok(keys)
 }
+
+/// Runs one SCAN step from the cursor, or from the start without one, asking for about
+/// count keys. The returned cursor is None once the scan went through every key.
+pub async fn scan_page<C>(
+    con: &mut RedisPoolConnection<C>,
+    pattern: &str,
+    cursor: Option<&str>,
+    count: usize,
+) -> Result<(Vec<String>, Option<String>), DatabaseError>
+where
+    C: ConnectionLike + Send + Sync + 'static,
+{
+    let cursor = match cursor {
+        Some(cursor) => cursor.parse::<u64>().map_err(|_| {
+            DatabaseError::GenericSelectError(format!("invalid SCAN cursor {cursor}"))
+        })?,
+        None => 0,
+    };
+    let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+        .arg(cursor)
+        .arg("MATCH")
+        .arg(pattern)
+        .arg("COUNT")
+        .arg(count.max(1))
+        .query_async(con)
+        .await
+        .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?;
+
+    Ok((keys, (next != 0).then(|| next.to_string())))
+}
+
+/// Key of the sorted set indexing when each session within the table was last stored.
+/// Members are the session ids scored by the unix timestamp they were stored at.
+/// Returns None without a table name as the index would mix with the sessions.
+pub fn active_key(table_name: &str) -> Option<String> {
+    if table_name.is_empty() {
+        None
+    } else {
+        Some(format!("{}:__active", table_name))
+    }
+}
+
+/// Key of the rate counter for bucket_key within the window bucket.
+/// Kept outside of the `{table}:` prefix so the counters are never seen as sessions.
+/// Returns None without a table name as the counters would mix with the sessions.
+pub fn rate_key(table_name: &str, bucket_key: &str, bucket: i64) -> Option<String> {
+    if table_name.is_empty() {
+        None
+    } else {
+        Some(format!("{}__rate:{}:{}", table_name, bucket_key, bucket))
+    }
+}
+
+/// Key of the hash holding the counters of the session id, one field per counter name.
+/// Kept outside of the `{table}:` prefix so the counters are never seen as sessions.
+/// Returns None without a table name as the counters would mix with the sessions.
+pub fn counters_key(table_name: &str, id: &str) -> Option<String> {
+    if table_name.is_empty() {
+        None
+    } else {
+        Some(format!("{}__counters:{}", table_name, id))
+    }
+}
+
+/// Rejects namespaces whose keys would be matched by another namespace's patterns.
+/// A `:` lets `{ns}:*` match the sessions of a namespace such as `{ns}:sub`, and the
+/// `__counters` or `__rate` suffixes would share their keys with another namespace's counters.
+pub fn validate_namespace(table_name: &str) -> Result<(), DatabaseError> {
+    if table_name.contains(':') {
+        return Err(DatabaseError::GenericNotSupportedError(format!(
+            "Key namespace {table_name:?} must not contain ':'"
+        )));
+    }
+
+    if ["__counters", "__rate"]
+        .iter()
+        .any(|suffix| table_name.ends_with(suffix))
+    {
+        return Err(DatabaseError::GenericNotSupportedError(format!(
+            "Key namespace {table_name:?} must not end with __counters or __rate"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Escapes the glob characters within the table name so they only match themselves.
+fn escape_pattern(table_name: &str) -> String {
+    let mut pattern = String::with_capacity(table_name.len() + 2);
+
+    for c in table_name.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            pattern.push('\\');
+        }
+
+        pattern.push(c);
+    }
+
+    pattern
+}
+
+/// SCAN pattern matching every key within the table name's namespace.
+/// Glob characters within the table name are escaped so they only match themselves.
+/// Without a table name every key in the database matches.
+pub fn namespace_pattern(table_name: &str) -> String {
+    if table_name.is_empty() {
+        return "*".to_string();
+    }
+
+    let mut pattern = escape_pattern(table_name);
+    pattern.push_str(":*");
+    pattern
+}
+
+/// SCAN pattern matching the counters of every session within the table name's namespace.
+pub fn counters_pattern(table_name: &str) -> String {
+    format!("{}__counters:*", escape_pattern(table_name))
+}
+
+/// Turns the keys found with [`namespace_pattern`] back into session ids,
+/// skipping the index of active sessions.
+pub fn ids_from_keys(table_name: &str, keys: Vec<String>) -> Vec<String> {
+    if table_name.is_empty() {
+        return keys;
+    }
+
+    let prefix = format!("{}:", table_name);
+    let active_key = active_key(table_name);
+
+    keys.into_iter()
+        .filter(|key| Some(key) != active_key.as_ref())
+        .filter_map(|key| key.strip_prefix(&prefix).map(str::to_owned))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        counters_key, counters_pattern, ids_from_keys, namespace_pattern, rate_key,
+        validate_namespace,
+    };
+
+    #[test]
+    fn namespace_keys() {
+        assert_eq!(namespace_pattern("app_a"), "app_a:*");
+        assert_eq!(namespace_pattern("app*[1]"), "app\\*\\[1\\]:*");
+        assert_eq!(namespace_pattern(""), "*");
+        assert_eq!(counters_pattern("app*"), "app\\*__counters:*");
+
+        let keys = vec![
+            "app_a:one".to_owned(),
+            "app_a:__active".to_owned(),
+            "app_a:two".to_owned(),
+        ];
+        assert_eq!(ids_from_keys("app_a", keys), vec!["one", "two"]);
+    }
+
+    /// Glob match for the patterns built here, where only `*` is used unescaped.
+    fn matches(pattern: &str, key: &str) -> bool {
+        let pattern = pattern.replace('\\', "");
+
+        match pattern.split_once('*') {
+            Some((prefix, "")) => key.starts_with(prefix),
+            _ => pattern == key,
+        }
+    }
+
+    #[test]
+    fn nested_namespaces_are_rejected() {
+        // Without the check the sessions of `app:sub` are seen as sessions of `app`.
+        assert!(matches(&namespace_pattern("app"), "app:sub:id"));
+        assert!(validate_namespace("app:sub").is_err());
+        assert!(validate_namespace("app").is_ok());
+        assert!(validate_namespace("app_sub").is_ok());
+    }
+
+    #[test]
+    fn counter_namespaces_are_rejected() {
+        // Without the check the counters of `app` are seen as sessions of `app__counters`.
+        let counters = counters_key("app", "id").unwrap();
+        assert!(matches(&namespace_pattern("app__counters"), &counters));
+        assert!(validate_namespace("app__counters").is_err());
+
+        let rate = rate_key("app", "ip", 1).unwrap();
+        assert!(matches(&namespace_pattern("app__rate"), &rate));
+        assert!(validate_namespace("app__rate").is_err());
+        assert!(validate_namespace("app__counters_v2").is_ok());
+    }
+}
@@ -1,5 +1,5 @@
 use async_trait::async_trait;
-use axum_session::{DatabaseError, DatabasePool, Session, SessionStore};
+use axum_session::{DatabaseError, DatabasePool, IdsPage, Session, SessionStore};
 use chrono::Utc;
 use sqlx::{pool::Pool, MySql, MySqlPool};
 
@@ -20,6 +20,11 @@ impl From<Pool<MySql>> for SessionMySqlPool {
     }
 }
 
+/// Quotes the table name.
+fn table(table_name: &str) -> String {
+    crate::quote_table_name(table_name, '`')
+}
+
 #[async_trait]
 impl DatabasePool for SessionMySqlPool {
     async fn initiate(&self, table_name: &str) -> Result<(), DatabaseError> {
@@ -28,23 +33,26 @@ impl DatabasePool for SessionMySqlPool {
             CREATE TABLE IF NOT EXISTS %%TABLE_NAME%% (
                 id VARCHAR(128) NOT NULL PRIMARY KEY,
                 expires BIGINT NULL,
-                session TEXT NOT NULL
+                session TEXT NOT NULL,
+                last_active BIGINT NULL
             )
         "#
-            .replace("%%TABLE_NAME%%", table_name),
+            .replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .execute(&self.pool)
         .await
         .map_err(|err| DatabaseError::GenericCreateError(err.to_string()))?;
 
+        let (schema, unqualified) = crate::split_table_name(table_name);
         let (t,): (bool,) = sqlx::query_as(
-            &r#"
+            r#"
             select DATA_TYPE = 'integer' 
             from INFORMATION_SCHEMA.COLUMNS 
-            WHERE TABLE_NAME = '%%TABLE_NAME%%' and COLUMN_NAME = 'expires';
-            "#
-            .replace("%%TABLE_NAME%%", table_name),
+            WHERE TABLE_SCHEMA = COALESCE(?, DATABASE()) AND TABLE_NAME = ? and COLUMN_NAME = 'expires';
+            "#,
         )
+        .bind(schema)
+        .bind(unqualified)
         .fetch_one(&self.pool)
         .await
         .map_err(|err| DatabaseError::GenericCreateError(err.to_string()))?;
@@ -54,7 +62,56 @@ impl DatabasePool for SessionMySqlPool {
                 &r#"
                     ALTER TABLE %%TABLE_NAME%% MODIFY expires BIGINT;
                 "#
-                .replace("%%TABLE_NAME%%", table_name),
+                .replace("%%TABLE_NAME%%", &table(table_name)),
+            )
+            .execute(&self.pool)
+            .await
+            .map_err(|err| DatabaseError::GenericCreateError(err.to_string()))?;
+        }
+
+        // Tables created by older versions are missing the last_active column.
+        let (has_last_active,): (bool,) = sqlx::query_as(
+            r#"
+            SELECT COUNT(*) > 0 FROM INFORMATION_SCHEMA.COLUMNS
+            WHERE TABLE_SCHEMA = COALESCE(?, DATABASE()) AND TABLE_NAME = ? AND COLUMN_NAME = 'last_active';
+            "#,
+        )
+        .bind(schema)
+        .bind(unqualified)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericCreateError(err.to_string()))?;
+
+        if !has_last_active {
+            sqlx::query(
+                &r#"ALTER TABLE %%TABLE_NAME%% ADD COLUMN last_active BIGINT NULL"#
+                    .replace("%%TABLE_NAME%%", &table(table_name)),
+            )
+            .execute(&self.pool)
+            .await
+            .map_err(|err| DatabaseError::GenericCreateError(err.to_string()))?;
+        }
+
+        // MySQL has no CREATE INDEX IF NOT EXISTS so check for it first.
+        let index_name = crate::index_name(table_name, "expires");
+        let (has_expires_index,): (bool,) = sqlx::query_as(
+            r#"
+            SELECT COUNT(*) > 0 FROM INFORMATION_SCHEMA.STATISTICS
+            WHERE TABLE_SCHEMA = COALESCE(?, DATABASE()) AND TABLE_NAME = ? AND INDEX_NAME = ?;
+            "#,
+        )
+        .bind(schema)
+        .bind(unqualified)
+        .bind(&index_name)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericCreateError(err.to_string()))?;
+
+        if !has_expires_index {
+            sqlx::query(
+                &r#"CREATE INDEX %%INDEX_NAME%% ON %%TABLE_NAME%% (expires)"#
+                    .replace("%%INDEX_NAME%%", &index_name)
+                    .replace("%%TABLE_NAME%%", &table(table_name)),
             )
             .execute(&self.pool)
             .await
@@ -70,7 +127,7 @@ impl DatabasePool for SessionMySqlPool {
             SELECT id FROM %%TABLE_NAME%%
             WHERE (expires IS NULL OR expires < ?)
         "#
-            .replace("%%TABLE_NAME%%", table_name),
+            .replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .bind(Utc::now().timestamp())
         .fetch_all(&self.pool)
@@ -81,7 +138,7 @@ impl DatabasePool for SessionMySqlPool {
 
         sqlx::query(
             &r#"DELETE FROM %%TABLE_NAME%% WHERE expires < ?"#
-                .replace("%%TABLE_NAME%%", table_name),
+                .replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .bind(Utc::now().timestamp())
         .execute(&self.pool)
@@ -93,7 +150,7 @@ impl DatabasePool for SessionMySqlPool {
 
     async fn count(&self, table_name: &str) -> Result<i64, DatabaseError> {
         let (count,) = sqlx::query_as(
-            &r#"SELECT COUNT(*) FROM %%TABLE_NAME%%"#.replace("%%TABLE_NAME%%", table_name),
+            &r#"SELECT COUNT(*) FROM %%TABLE_NAME%%"#.replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .fetch_one(&self.pool)
         .await
@@ -112,16 +169,18 @@ impl DatabasePool for SessionMySqlPool {
         sqlx::query(
             &r#"
         INSERT INTO %%TABLE_NAME%%
-            (id, session, expires) SELECT ?, ?, ?
+            (id, session, expires, last_active) SELECT ?, ?, ?, ?
         ON DUPLICATE KEY UPDATE
             expires = VALUES(expires),
-            session = VALUES(session)
+            session = VALUES(session),
+            last_active = VALUES(last_active)
     "#
-            .replace("%%TABLE_NAME%%", table_name),
+            .replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .bind(id)
         .bind(session)
         .bind(expires)
+        .bind(Utc::now().timestamp())
         .execute(&self.pool)
         .await
         .map_err(|err| DatabaseError::GenericInsertError(err.to_string()))?;
@@ -134,7 +193,7 @@ impl DatabasePool for SessionMySqlPool {
             SELECT session FROM %%TABLE_NAME%%
             WHERE id = ? AND (expires IS NULL OR expires > ?)
         "#
-            .replace("%%TABLE_NAME%%", table_name),
+            .replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .bind(id)
         .bind(Utc::now().timestamp())
@@ -147,7 +206,8 @@ impl DatabasePool for SessionMySqlPool {
 
     async fn delete_one_by_id(&self, id: &str, table_name: &str) -> Result<(), DatabaseError> {
         sqlx::query(
-            &r#"DELETE FROM %%TABLE_NAME%% WHERE id = ?"#.replace("%%TABLE_NAME%%", table_name),
+            &r#"DELETE FROM %%TABLE_NAME%% WHERE id = ?"#
+                .replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .bind(id)
         .execute(&self.pool)
@@ -162,7 +222,7 @@ impl DatabasePool for SessionMySqlPool {
             SELECT COUNT(*) FROM %%TABLE_NAME%%
             WHERE id = ? AND (expires IS NULL OR expires > ?)
         "#
-            .replace("%%TABLE_NAME%%", table_name),
+            .replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .bind(id)
         .bind(Utc::now().timestamp())
@@ -174,7 +234,7 @@ impl DatabasePool for SessionMySqlPool {
     }
 
     async fn delete_all(&self, table_name: &str) -> Result<(), DatabaseError> {
-        sqlx::query(&r#"TRUNCATE %%TABLE_NAME%%"#.replace("%%TABLE_NAME%%", table_name))
+        sqlx::query(&r#"TRUNCATE %%TABLE_NAME%%"#.replace("%%TABLE_NAME%%", &table(table_name)))
             .execute(&self.pool)
             .await
             .map_err(|err| DatabaseError::GenericDeleteError(err.to_string()))?;
@@ -187,7 +247,7 @@ impl DatabasePool for SessionMySqlPool {
             SELECT id FROM %%TABLE_NAME%%
             WHERE (expires IS NULL OR expires > ?)
         "#
-            .replace("%%TABLE_NAME%%", table_name),
+            .replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .bind(Utc::now().timestamp())
         .fetch_all(&self.pool)
@@ -199,7 +259,81 @@ impl DatabasePool for SessionMySqlPool {
         Ok(result)
     }
 
+    async fn get_ids_page(
+        &self,
+        table_name: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<IdsPage, DatabaseError> {
+        let result: Vec<(String,)> = sqlx::query_as(
+            &r#"
+            SELECT id FROM %%TABLE_NAME%%
+            WHERE (expires IS NULL OR expires > ?)
+            AND (? IS NULL OR id > ?)
+            ORDER BY id
+            LIMIT ?
+        "#
+            .replace("%%TABLE_NAME%%", &table(table_name)),
+        )
+        .bind(Utc::now().timestamp())
+        .bind(cursor)
+        .bind(cursor)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?;
+
+        Ok(crate::ids_page(
+            result.into_iter().map(|(s,)| s).collect(),
+            limit,
+        ))
+    }
+
+    async fn count_active(
+        &self,
+        since: i64,
+        now: i64,
+        table_name: &str,
+    ) -> Result<Option<i64>, DatabaseError> {
+        let (count,) = sqlx::query_as(
+            &r#"
+            SELECT COUNT(*) FROM %%TABLE_NAME%%
+            WHERE last_active >= ? AND (expires IS NULL OR expires > ?)
+        "#
+            .replace("%%TABLE_NAME%%", &table(table_name)),
+        )
+        .bind(since)
+        .bind(now)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?;
+
+        Ok(Some(count))
+    }
+
+    async fn touch_active(
+        &self,
+        id: &str,
+        now: i64,
+        table_name: &str,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query(
+            &r#"UPDATE %%TABLE_NAME%% SET last_active = ? WHERE id = ?"#
+                .replace("%%TABLE_NAME%%", &table(table_name)),
+        )
+        .bind(now)
+        .bind(id)
+        .execute(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericInsertError(err.to_string()))?;
+        Ok(())
+    }
+
     fn auto_handles_expiry(&self) -> bool {
         false
     }
+
+    fn validate_table_name(&self, table_name: &str) -> Result<(), DatabaseError> {
+        crate::validate_table_name(table_name)
+    }
 }
@@ -1,5 +1,5 @@
 use async_trait::async_trait;
-use axum_session::{DatabaseError, DatabasePool, Session, SessionStore};
+use axum_session::{DatabaseError, DatabasePool, IdsPage, Session, SessionStore};
 use chrono::Utc;
 use sqlx::{pool::Pool, PgPool, Postgres};
 
@@ -20,6 +20,21 @@ impl From<Pool<Postgres>> for SessionPgPool {
     }
 }
 
+/// Quotes the table name, lower cased as Postgres folded the unquoted names earlier versions used.
+fn table(table_name: &str) -> String {
+    crate::quote_table_name(&table_name.to_lowercase(), '"')
+}
+
+/// Quotes the name of the table holding the creation rate limit counters.
+fn rate_limits_table(table_name: &str) -> String {
+    table(&format!("{table_name}_rate_limits"))
+}
+
+/// Quotes the name of the table holding the counters of the sessions.
+fn counters_table(table_name: &str) -> String {
+    table(&format!("{table_name}_counters"))
+}
+
 #[async_trait]
 impl DatabasePool for SessionPgPool {
     async fn initiate(&self, table_name: &str) -> Result<(), DatabaseError> {
@@ -28,23 +43,28 @@ impl DatabasePool for SessionPgPool {
             CREATE TABLE IF NOT EXISTS %%TABLE_NAME%% (
                 "id" VARCHAR(128) NOT NULL PRIMARY KEY,
                 "expires" BIGINT NULL,
-                "session" TEXT NOT NULL
+                "session" TEXT NOT NULL,
+                "last_active" BIGINT NULL
             )
         "#
-            .replace("%%TABLE_NAME%%", table_name),
+            .replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .execute(&self.pool)
         .await
         .map_err(|err| DatabaseError::GenericCreateError(err.to_string()))?;
 
+        let lower = table_name.to_lowercase();
+        let (schema, unqualified) = crate::split_table_name(&lower);
         let (t,): (bool,) = sqlx::query_as(
-            &r#"
+            r#"
             SELECT data_type = 'integer'
             FROM information_schema.columns
-            WHERE table_name = '%%TABLE_NAME%%' and column_name = 'expires';
-            "#
-            .replace("%%TABLE_NAME%%", table_name),
+            WHERE table_schema = COALESCE($1, current_schema())
+            AND table_name = $2 and column_name = 'expires';
+            "#,
         )
+        .bind(schema)
+        .bind(unqualified)
         .fetch_one(&self.pool)
         .await
         .map_err(|err| DatabaseError::GenericCreateError(err.to_string()))?;
@@ -54,13 +74,63 @@ impl DatabasePool for SessionPgPool {
                 &r#"
                 ALTER TABLE %%TABLE_NAME%% ALTER COLUMN expires TYPE BIGINT;
                 "#
-                .replace("%%TABLE_NAME%%", table_name),
+                .replace("%%TABLE_NAME%%", &table(table_name)),
             )
             .execute(&self.pool)
             .await
             .map_err(|err| DatabaseError::GenericCreateError(err.to_string()))?;
         }
 
+        // Tables created by older versions are missing the last_active column.
+        sqlx::query(
+            &r#"ALTER TABLE %%TABLE_NAME%% ADD COLUMN IF NOT EXISTS "last_active" BIGINT NULL"#
+                .replace("%%TABLE_NAME%%", &table(table_name)),
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericCreateError(err.to_string()))?;
+
+        sqlx::query(
+            &r#"CREATE INDEX IF NOT EXISTS %%INDEX_NAME%% ON %%TABLE_NAME%% ("expires")"#
+                .replace("%%INDEX_NAME%%", &crate::index_name(&lower, "expires"))
+                .replace("%%TABLE_NAME%%", &table(table_name)),
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericCreateError(err.to_string()))?;
+
+        sqlx::query(
+            &r#"
+            CREATE TABLE IF NOT EXISTS %%RATE_LIMITS_TABLE%% (
+                "key" VARCHAR(256) NOT NULL,
+                "bucket" BIGINT NOT NULL,
+                "count" BIGINT NOT NULL,
+                "expires" BIGINT NOT NULL,
+                PRIMARY KEY ("key", "bucket")
+            )
+        "#
+            .replace("%%RATE_LIMITS_TABLE%%", &rate_limits_table(table_name)),
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericCreateError(err.to_string()))?;
+
+        sqlx::query(
+            &r#"
+            CREATE TABLE IF NOT EXISTS %%COUNTERS_TABLE%% (
+                "id" VARCHAR(128) NOT NULL,
+                "name" VARCHAR(256) NOT NULL,
+                "value" BIGINT NOT NULL,
+                "expires" BIGINT NOT NULL,
+                PRIMARY KEY ("id", "name")
+            )
+        "#
+            .replace("%%COUNTERS_TABLE%%", &counters_table(table_name)),
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericCreateError(err.to_string()))?;
+
         Ok(())
     }
 
@@ -70,7 +140,7 @@ impl DatabasePool for SessionPgPool {
             SELECT id FROM %%TABLE_NAME%%
             WHERE (expires IS NULL OR expires < $1)
         "#
-            .replace("%%TABLE_NAME%%", table_name),
+            .replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .bind(Utc::now().timestamp())
         .fetch_all(&self.pool)
@@ -81,7 +151,32 @@ impl DatabasePool for SessionPgPool {
 
         sqlx::query(
             &r#"DELETE FROM %%TABLE_NAME%% WHERE expires < $1"#
-                .replace("%%TABLE_NAME%%", table_name),
+                .replace("%%TABLE_NAME%%", &table(table_name)),
+        )
+        .bind(Utc::now().timestamp())
+        .execute(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericDeleteError(err.to_string()))?;
+
+        sqlx::query(
+            &r#"DELETE FROM %%RATE_LIMITS_TABLE%% WHERE expires < $1"#
+                .replace("%%RATE_LIMITS_TABLE%%", &rate_limits_table(table_name)),
+        )
+        .bind(Utc::now().timestamp())
+        .execute(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericDeleteError(err.to_string()))?;
+
+        // Counters outlive their expiry while their session row is still there, as the row's
+        // expiry moves on every store while the counter's only moves when it changes.
+        sqlx::query(
+            &r#"
+            DELETE FROM %%COUNTERS_TABLE%% AS counters
+            WHERE counters.expires < $1
+            AND NOT EXISTS (SELECT 1 FROM %%TABLE_NAME%% AS sessions WHERE sessions.id = counters.id)
+        "#
+            .replace("%%COUNTERS_TABLE%%", &counters_table(table_name))
+            .replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .bind(Utc::now().timestamp())
         .execute(&self.pool)
@@ -92,7 +187,7 @@ impl DatabasePool for SessionPgPool {
 
     async fn count(&self, table_name: &str) -> Result<i64, DatabaseError> {
         let (count,) = sqlx::query_as(
-            &r#"SELECT COUNT(*) FROM %%TABLE_NAME%%"#.replace("%%TABLE_NAME%%", table_name),
+            &r#"SELECT COUNT(*) FROM %%TABLE_NAME%%"#.replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .fetch_one(&self.pool)
         .await
@@ -111,16 +206,18 @@ impl DatabasePool for SessionPgPool {
         sqlx::query(
             &r#"
         INSERT INTO %%TABLE_NAME%%
-            (id, session, expires) SELECT $1, $2, $3
+            (id, session, expires, last_active) SELECT $1, $2, $3, $4
         ON CONFLICT(id) DO UPDATE SET
             expires = EXCLUDED.expires,
-            session = EXCLUDED.session
+            session = EXCLUDED.session,
+            last_active = EXCLUDED.last_active
     "#
-            .replace("%%TABLE_NAME%%", table_name),
+            .replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .bind(id)
         .bind(session)
         .bind(expires)
+        .bind(Utc::now().timestamp())
         .execute(&self.pool)
         .await
         .map_err(|err| DatabaseError::GenericInsertError(err.to_string()))?;
@@ -133,7 +230,7 @@ impl DatabasePool for SessionPgPool {
             SELECT session FROM %%TABLE_NAME%%
             WHERE id = $1 AND (expires IS NULL OR expires > $2)
         "#
-            .replace("%%TABLE_NAME%%", table_name),
+            .replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .bind(id)
         .bind(Utc::now().timestamp())
@@ -146,7 +243,17 @@ impl DatabasePool for SessionPgPool {
 
     async fn delete_one_by_id(&self, id: &str, table_name: &str) -> Result<(), DatabaseError> {
         sqlx::query(
-            &r#"DELETE FROM %%TABLE_NAME%% WHERE id = $1"#.replace("%%TABLE_NAME%%", table_name),
+            &r#"DELETE FROM %%TABLE_NAME%% WHERE id = $1"#
+                .replace("%%TABLE_NAME%%", &table(table_name)),
+        )
+        .bind(id)
+        .execute(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericDeleteError(err.to_string()))?;
+
+        sqlx::query(
+            &r#"DELETE FROM %%COUNTERS_TABLE%% WHERE id = $1"#
+                .replace("%%COUNTERS_TABLE%%", &counters_table(table_name)),
         )
         .bind(id)
         .execute(&self.pool)
@@ -161,7 +268,7 @@ impl DatabasePool for SessionPgPool {
             SELECT COUNT(*) FROM %%TABLE_NAME%%
             WHERE id = $1 AND (expires IS NULL OR expires > $2)
         "#
-            .replace("%%TABLE_NAME%%", table_name),
+            .replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .bind(id)
         .bind(Utc::now().timestamp())
@@ -173,10 +280,14 @@ impl DatabasePool for SessionPgPool {
     }
 
     async fn delete_all(&self, table_name: &str) -> Result<(), DatabaseError> {
-        sqlx::query(&r#"TRUNCATE %%TABLE_NAME%%"#.replace("%%TABLE_NAME%%", table_name))
-            .execute(&self.pool)
-            .await
-            .map_err(|err| DatabaseError::GenericDeleteError(err.to_string()))?;
+        sqlx::query(
+            &r#"TRUNCATE %%TABLE_NAME%%, %%COUNTERS_TABLE%%"#
+                .replace("%%TABLE_NAME%%", &table(table_name))
+                .replace("%%COUNTERS_TABLE%%", &counters_table(table_name)),
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericDeleteError(err.to_string()))?;
         Ok(())
     }
 
@@ -186,7 +297,7 @@ impl DatabasePool for SessionPgPool {
             SELECT id FROM %%TABLE_NAME%%
             WHERE (expires IS NULL OR expires > $1)
         "#
-            .replace("%%TABLE_NAME%%", table_name),
+            .replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .bind(Utc::now().timestamp())
         .fetch_all(&self.pool)
@@ -198,7 +309,179 @@ impl DatabasePool for SessionPgPool {
         Ok(result)
     }
 
+    async fn get_ids_page(
+        &self,
+        table_name: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<IdsPage, DatabaseError> {
+        // Compare bytewise so the pages keep the same order whatever the database collation is.
+        let result: Vec<(String,)> = sqlx::query_as(
+            &r#"
+            SELECT id FROM %%TABLE_NAME%%
+            WHERE (expires IS NULL OR expires > $1)
+            AND ($2 IS NULL OR id COLLATE "C" > $2)
+            ORDER BY id COLLATE "C"
+            LIMIT $3
+        "#
+            .replace("%%TABLE_NAME%%", &table(table_name)),
+        )
+        .bind(Utc::now().timestamp())
+        .bind(cursor)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?;
+
+        Ok(crate::ids_page(
+            result.into_iter().map(|(s,)| s).collect(),
+            limit,
+        ))
+    }
+
+    async fn count_active(
+        &self,
+        since: i64,
+        now: i64,
+        table_name: &str,
+    ) -> Result<Option<i64>, DatabaseError> {
+        let (count,) = sqlx::query_as(
+            &r#"
+            SELECT COUNT(*) FROM %%TABLE_NAME%%
+            WHERE last_active >= $1 AND (expires IS NULL OR expires > $2)
+        "#
+            .replace("%%TABLE_NAME%%", &table(table_name)),
+        )
+        .bind(since)
+        .bind(now)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?;
+
+        Ok(Some(count))
+    }
+
+    async fn touch_active(
+        &self,
+        id: &str,
+        now: i64,
+        table_name: &str,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query(
+            &r#"UPDATE %%TABLE_NAME%% SET last_active = $1 WHERE id = $2"#
+                .replace("%%TABLE_NAME%%", &table(table_name)),
+        )
+        .bind(now)
+        .bind(id)
+        .execute(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericInsertError(err.to_string()))?;
+        Ok(())
+    }
+
+    async fn rate_increment(
+        &self,
+        bucket_key: &str,
+        window: i64,
+        table_name: &str,
+    ) -> Result<u64, DatabaseError> {
+        // Each window gets its own row so counters reset without needing a reset query.
+        let bucket = Utc::now().timestamp() / window;
+        let (count,): (i64,) = sqlx::query_as(
+            &r#"
+        INSERT INTO %%RATE_LIMITS_TABLE%% AS rates
+            (key, bucket, count, expires) SELECT $1, $2, 1, $3
+        ON CONFLICT(key, bucket) DO UPDATE SET
+            count = rates.count + 1
+        RETURNING count
+    "#
+            .replace("%%RATE_LIMITS_TABLE%%", &rate_limits_table(table_name)),
+        )
+        .bind(bucket_key)
+        .bind(bucket)
+        .bind((bucket + 1) * window)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericInsertError(err.to_string()))?;
+
+        Ok(count as u64)
+    }
+
+    async fn counter_incr(
+        &self,
+        id: &str,
+        name: &str,
+        delta: i64,
+        expires: i64,
+        table_name: &str,
+    ) -> Result<Option<i64>, DatabaseError> {
+        let (value,): (i64,) = sqlx::query_as(
+            &r#"
+        INSERT INTO %%COUNTERS_TABLE%% AS counters
+            (id, name, value, expires) SELECT $1, $2, $3, $4
+        ON CONFLICT(id, name) DO UPDATE SET
+            value = counters.value + EXCLUDED.value,
+            expires = GREATEST(counters.expires, EXCLUDED.expires)
+        RETURNING value
+    "#
+            .replace("%%COUNTERS_TABLE%%", &counters_table(table_name)),
+        )
+        .bind(id)
+        .bind(name)
+        .bind(delta)
+        .bind(expires)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericInsertError(err.to_string()))?;
+
+        Ok(Some(value))
+    }
+
+    async fn counter_get(
+        &self,
+        id: &str,
+        name: &str,
+        table_name: &str,
+    ) -> Result<Option<i64>, DatabaseError> {
+        let result: Option<(i64,)> = sqlx::query_as(
+            &r#"SELECT value FROM %%COUNTERS_TABLE%% WHERE id = $1 AND name = $2"#
+                .replace("%%COUNTERS_TABLE%%", &counters_table(table_name)),
+        )
+        .bind(id)
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?;
+
+        Ok(Some(result.map_or(0, |(value,)| value)))
+    }
+
+    async fn counter_rename(
+        &self,
+        id: &str,
+        new_id: &str,
+        expires: i64,
+        table_name: &str,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query(
+            &r#"UPDATE %%COUNTERS_TABLE%% SET id = $2, expires = $3 WHERE id = $1"#
+                .replace("%%COUNTERS_TABLE%%", &counters_table(table_name)),
+        )
+        .bind(id)
+        .bind(new_id)
+        .bind(expires)
+        .execute(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericInsertError(err.to_string()))?;
+
+        Ok(())
+    }
+
     fn auto_handles_expiry(&self) -> bool {
         false
     }
+
+    fn validate_table_name(&self, table_name: &str) -> Result<(), DatabaseError> {
+        crate::validate_table_name(table_name)
+    }
 }
@@ -1,5 +1,5 @@
 use async_trait::async_trait;
-use axum_session::{DatabaseError, DatabasePool, Session, SessionStore};
+use axum_session::{DatabaseError, DatabasePool, IdsPage, Session, SessionStore};
 use chrono::Utc;
 use sqlx::{pool::Pool, Sqlite};
 
@@ -20,6 +20,11 @@ impl From<Pool<Sqlite>> for SessionSqlitePool {
     }
 }
 
+/// Quotes the table name.
+fn table(table_name: &str) -> String {
+    crate::quote_table_name(table_name, '"')
+}
+
 #[async_trait]
 impl DatabasePool for SessionSqlitePool {
     async fn initiate(&self, table_name: &str) -> Result<(), DatabaseError> {
@@ -28,10 +33,52 @@ impl DatabasePool for SessionSqlitePool {
             CREATE TABLE IF NOT EXISTS %%TABLE_NAME%% (
                 "id" VARCHAR(128) NOT NULL PRIMARY KEY,
                 "expires" BIGINT NULL,
-                "session" TEXT NOT NULL
+                "session" TEXT NOT NULL,
+                "last_active" BIGINT NULL
             )
         "#
-            .replace("%%TABLE_NAME%%", table_name),
+            .replace("%%TABLE_NAME%%", &table(table_name)),
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericCreateError(err.to_string()))?;
+
+        // Tables created by older versions are missing the last_active column.
+        let (schema, unqualified) = crate::split_table_name(table_name);
+        let (has_last_active,): (bool,) = sqlx::query_as(
+            r#"
+            SELECT COUNT(*) > 0 FROM pragma_table_info($1, COALESCE($2, 'main'))
+            WHERE name = 'last_active'
+            "#,
+        )
+        .bind(unqualified)
+        .bind(schema)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericCreateError(err.to_string()))?;
+
+        if !has_last_active {
+            sqlx::query(
+                &r#"ALTER TABLE %%TABLE_NAME%% ADD COLUMN "last_active" BIGINT NULL"#
+                    .replace("%%TABLE_NAME%%", &table(table_name)),
+            )
+            .execute(&self.pool)
+            .await
+            .map_err(|err| DatabaseError::GenericCreateError(err.to_string()))?;
+        }
+
+        // Sqlite takes the schema from the index name and needs the table unqualified.
+        let index_name = crate::quote_table_name(
+            &match schema {
+                Some(schema) => format!("{schema}.{}", crate::index_name(table_name, "expires")),
+                None => crate::index_name(table_name, "expires"),
+            },
+            '"',
+        );
+        sqlx::query(
+            &r#"CREATE INDEX IF NOT EXISTS %%INDEX_NAME%% ON %%TABLE_NAME%% ("expires")"#
+                .replace("%%INDEX_NAME%%", &index_name)
+                .replace("%%TABLE_NAME%%", &table(unqualified)),
         )
         .execute(&self.pool)
         .await
@@ -46,7 +93,7 @@ impl DatabasePool for SessionSqlitePool {
             SELECT id FROM %%TABLE_NAME%%
             WHERE (expires IS NULL OR expires < $1)
         "#
-            .replace("%%TABLE_NAME%%", table_name),
+            .replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .bind(Utc::now().timestamp())
         .fetch_all(&self.pool)
@@ -57,7 +104,7 @@ impl DatabasePool for SessionSqlitePool {
 
         sqlx::query(
             &r#"DELETE FROM %%TABLE_NAME%% WHERE expires < $1"#
-                .replace("%%TABLE_NAME%%", table_name),
+                .replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .bind(Utc::now().timestamp())
         .execute(&self.pool)
@@ -68,7 +115,7 @@ impl DatabasePool for SessionSqlitePool {
 
     async fn count(&self, table_name: &str) -> Result<i64, DatabaseError> {
         let (count,) = sqlx::query_as(
-            &r#"SELECT COUNT(*) FROM %%TABLE_NAME%%"#.replace("%%TABLE_NAME%%", table_name),
+            &r#"SELECT COUNT(*) FROM %%TABLE_NAME%%"#.replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .fetch_one(&self.pool)
         .await
@@ -87,16 +134,18 @@ impl DatabasePool for SessionSqlitePool {
         sqlx::query(
             &r#"
         INSERT INTO %%TABLE_NAME%%
-            (id, session, expires) SELECT $1, $2, $3
+            (id, session, expires, last_active) SELECT $1, $2, $3, $4
         ON CONFLICT(id) DO UPDATE SET
             expires = EXCLUDED.expires,
-            session = EXCLUDED.session
+            session = EXCLUDED.session,
+            last_active = EXCLUDED.last_active
     "#
-            .replace("%%TABLE_NAME%%", table_name),
+            .replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .bind(id)
         .bind(session)
         .bind(expires)
+        .bind(Utc::now().timestamp())
         .execute(&self.pool)
         .await
         .map_err(|err| DatabaseError::GenericInsertError(err.to_string()))?;
@@ -109,7 +158,7 @@ impl DatabasePool for SessionSqlitePool {
             SELECT session FROM %%TABLE_NAME%%
             WHERE id = $1 AND (expires IS NULL OR expires > $2)
         "#
-            .replace("%%TABLE_NAME%%", table_name),
+            .replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .bind(id)
         .bind(Utc::now().timestamp())
@@ -122,7 +171,8 @@ impl DatabasePool for SessionSqlitePool {
 
     async fn delete_one_by_id(&self, id: &str, table_name: &str) -> Result<(), DatabaseError> {
         sqlx::query(
-            &r#"DELETE FROM %%TABLE_NAME%% WHERE id = $1"#.replace("%%TABLE_NAME%%", table_name),
+            &r#"DELETE FROM %%TABLE_NAME%% WHERE id = $1"#
+                .replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .bind(id)
         .execute(&self.pool)
@@ -137,7 +187,7 @@ impl DatabasePool for SessionSqlitePool {
             SELECT COUNT(*) FROM %%TABLE_NAME%%
             WHERE id = $1 AND (expires IS NULL OR expires > $2)
         "#
-            .replace("%%TABLE_NAME%%", table_name),
+            .replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .bind(id)
         .bind(Utc::now().timestamp())
@@ -149,7 +199,7 @@ impl DatabasePool for SessionSqlitePool {
     }
 
     async fn delete_all(&self, table_name: &str) -> Result<(), DatabaseError> {
-        sqlx::query(&r#"DELETE FROM %%TABLE_NAME%%"#.replace("%%TABLE_NAME%%", table_name))
+        sqlx::query(&r#"DELETE FROM %%TABLE_NAME%%"#.replace("%%TABLE_NAME%%", &table(table_name)))
             .execute(&self.pool)
             .await
             .map_err(|err| DatabaseError::GenericDeleteError(err.to_string()))?;
@@ -162,7 +212,7 @@ impl DatabasePool for SessionSqlitePool {
             SELECT id FROM %%TABLE_NAME%%
             WHERE (expires IS NULL OR expires > $1)
         "#
-            .replace("%%TABLE_NAME%%", table_name),
+            .replace("%%TABLE_NAME%%", &table(table_name)),
         )
         .bind(Utc::now().timestamp())
         .fetch_all(&self.pool)
@@ -174,7 +224,80 @@ impl DatabasePool for SessionSqlitePool {
         Ok(result)
     }
 
+    async fn get_ids_page(
+        &self,
+        table_name: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<IdsPage, DatabaseError> {
+        let result: Vec<(String,)> = sqlx::query_as(
+            &r#"
+            SELECT id FROM %%TABLE_NAME%%
+            WHERE (expires IS NULL OR expires > $1)
+            AND ($2 IS NULL OR id > $2)
+            ORDER BY id
+            LIMIT $3
+        "#
+            .replace("%%TABLE_NAME%%", &table(table_name)),
+        )
+        .bind(Utc::now().timestamp())
+        .bind(cursor)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?;
+
+        Ok(crate::ids_page(
+            result.into_iter().map(|(s,)| s).collect(),
+            limit,
+        ))
+    }
+
+    async fn count_active(
+        &self,
+        since: i64,
+        now: i64,
+        table_name: &str,
+    ) -> Result<Option<i64>, DatabaseError> {
+        let (count,) = sqlx::query_as(
+            &r#"
+            SELECT COUNT(*) FROM %%TABLE_NAME%%
+            WHERE last_active >= $1 AND (expires IS NULL OR expires > $2)
+        "#
+            .replace("%%TABLE_NAME%%", &table(table_name)),
+        )
+        .bind(since)
+        .bind(now)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericSelectError(err.to_string()))?;
+
+        Ok(Some(count))
+    }
+
+    async fn touch_active(
+        &self,
+        id: &str,
+        now: i64,
+        table_name: &str,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query(
+            &r#"UPDATE %%TABLE_NAME%% SET last_active = $1 WHERE id = $2"#
+                .replace("%%TABLE_NAME%%", &table(table_name)),
+        )
+        .bind(now)
+        .bind(id)
+        .execute(&self.pool)
+        .await
+        .map_err(|err| DatabaseError::GenericInsertError(err.to_string()))?;
+        Ok(())
+    }
+
     fn auto_handles_expiry(&self) -> bool {
         false
     }
+
+    fn validate_table_name(&self, table_name: &str) -> Result<(), DatabaseError> {
+        crate::validate_table_name(table_name)
+    }
 }
@@ -0,0 +1,69 @@
+#![doc = include_str!("../README.md")]
+#![warn(clippy::all, nonstandard_style, future_incompatible)]
+#![forbid(unsafe_code)]
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, DeriveInput, LitInt, LitStr, Path};
+
+/// Derives `axum_session::SessionEntry`.
+///
+/// Accepts `#[session_entry(key = "..", version = .., migrate = path)]`.
+/// `key` defaults to the type name, `version` defaults to 1 and `migrate`
+/// defaults to dropping data stored under older versions.
+#[proc_macro_derive(SessionEntry, attributes(session_entry))]
+pub fn derive_session_entry(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn expand(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let ident = &input.ident;
+    let mut key = LitStr::new(&ident.to_string(), ident.span());
+    let mut version = LitInt::new("1", ident.span());
+    let mut migrate: Option<Path> = None;
+
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|a| a.path().is_ident("session_entry"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("key") {
+                key = meta.value()?.parse()?;
+            } else if meta.path.is_ident("version") {
+                version = meta.value()?.parse()?;
+                version.base10_parse::<u32>()?;
+            } else if meta.path.is_ident("migrate") {
+                migrate = Some(meta.value()?.parse()?);
+            } else {
+                return Err(meta.error("expected `key`, `version` or `migrate`"));
+            }
+
+            Ok(())
+        })?;
+    }
+
+    let migrate = migrate.map(|path| {
+        quote! {
+            fn migrate(from_version: u32, raw: ::axum_session::__private::serde_json::Value) -> ::std::option::Option<Self> {
+                #path(from_version, raw)
+            }
+        }
+    });
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::axum_session::SessionEntry for #ident #ty_generics #where_clause {
+            const KEY: &'static str = #key;
+            const VERSION: u32 = #version;
+
+            #migrate
+        }
+    })
+}
@@ -0,0 +1,591 @@
+use crate::SessionData;
+use chrono::{DateTime, Utc};
+use dashmap::{mapref::entry::Entry, DashMap};
+use std::time::Instant;
+
+/// The map of loaded sessions the request accounting works on.
+///
+/// Each method must run as a single step under the entry's lock, which is what lets the
+/// accounting below be model checked using a plain mutex map instead of the DashMap.
+pub(crate) trait SessionMap {
+    /// Runs f on the session if it is loaded.
+    fn update<R>(&self, id: &str, f: impl FnOnce(&mut SessionData) -> R) -> Option<R>;
+
+    /// Removes the session if it is loaded and f returns true.
+    fn remove_if(&self, id: &str, f: impl FnOnce(&SessionData) -> bool) -> Option<SessionData>;
+
+    /// Inserts the session if it is not loaded and returns None, otherwise runs f on the
+    /// loaded one and the session which was not inserted.
+    fn insert_or_update<R>(
+        &self,
+        id: &str,
+        data: SessionData,
+        f: impl FnOnce(&mut SessionData, SessionData) -> R,
+    ) -> Option<R>;
+
+    /// Inserts the session, replacing any loaded under the same id.
+    fn insert(&self, id: &str, data: SessionData);
+
+    /// Keeps only the sessions f returns true for.
+    fn retain(&self, f: impl FnMut(&SessionData) -> bool);
+}
+
+impl SessionMap for DashMap<String, SessionData> {
+    fn update<R>(&self, id: &str, f: impl FnOnce(&mut SessionData) -> R) -> Option<R> {
+        self.get_mut(id).map(|mut data| f(&mut data))
+    }
+
+    fn remove_if(&self, id: &str, f: impl FnOnce(&SessionData) -> bool) -> Option<SessionData> {
+        DashMap::remove_if(self, id, |_, data| f(data)).map(|(_, data)| data)
+    }
+
+    fn insert_or_update<R>(
+        &self,
+        id: &str,
+        data: SessionData,
+        f: impl FnOnce(&mut SessionData, SessionData) -> R,
+    ) -> Option<R> {
+        match self.entry(id.to_owned()) {
+            Entry::Occupied(mut entry) => Some(f(entry.get_mut(), data)),
+            Entry::Vacant(entry) => {
+                entry.insert(data);
+                None
+            }
+        }
+    }
+
+    fn insert(&self, id: &str, data: SessionData) {
+        DashMap::insert(self, id.to_owned(), data);
+    }
+
+    fn retain(&self, mut f: impl FnMut(&SessionData) -> bool) {
+        DashMap::retain(self, |_, data| f(data));
+    }
+}
+
+/// How starting a request on a session went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Join {
+    /// The request was added to the session.
+    Joined,
+    /// The session already has as many requests as allowed.
+    Limited,
+    /// The session's ID is being renewed. The request has to wait for the renewal to finish
+    /// and start again, as the ID is revoked once it did.
+    Renewing,
+    /// The session is not loaded.
+    Missing,
+}
+
+/// Adds a request to the loaded session unless it would pass max, then runs f on it.
+pub(crate) fn join<M: SessionMap>(
+    map: &M,
+    id: &str,
+    max: Option<usize>,
+    now: DateTime<Utc>,
+    f: impl FnOnce(&mut SessionData),
+) -> Join {
+    map.update(id, |data| {
+        if data.renewing {
+            Join::Renewing
+        } else if data.try_set_request(max, now) {
+            f(data);
+            Join::Joined
+        } else {
+            Join::Limited
+        }
+    })
+    .unwrap_or(Join::Missing)
+}
+
+/// Inserts a session loaded from the database, which holds the request already.
+/// If another request loaded it meanwhile that one is joined instead unless it would pass max,
+/// so neither loses the other's changes or request count.
+///
+/// An ephemeral session, standing in for one the database failed to load, takes the data
+/// of a session which loaded instead, as nothing written to the ephemeral one is saved.
+pub(crate) fn insert_loaded<M: SessionMap>(
+    map: &M,
+    id: &str,
+    data: SessionData,
+    max: Option<usize>,
+    now: DateTime<Utc>,
+) -> Join {
+    map.insert_or_update(id, data, |loaded, data| {
+        if loaded.renewing {
+            Join::Renewing
+        } else if loaded.try_set_request(max, now) {
+            if loaded.ephemeral && !data.ephemeral {
+                let requests = loaded.requests;
+                *loaded = data;
+                loaded.requests = requests;
+            }
+
+            Join::Joined
+        } else {
+            Join::Limited
+        }
+    })
+    .unwrap_or(Join::Joined)
+}
+
+/// Removes the request from the session. Returns false if the session is not loaded.
+pub(crate) fn end_request<M: SessionMap>(map: &M, id: &str) -> bool {
+    map.update(id, SessionData::remove_request).is_some()
+}
+
+/// Unloads the session if no request holds it and f returns true.
+/// Checked under the same lock as the removal, so a request starting in between keeps it.
+pub(crate) fn unload_if_idle<M: SessionMap>(
+    map: &M,
+    id: &str,
+    f: impl FnOnce(&SessionData) -> bool,
+) -> Option<SessionData> {
+    map.remove_if(id, |data| !data.is_parallel() && !data.renewing && f(data))
+}
+
+/// Reserves the session's renewal when the request is the only one holding the session.
+/// Otherwise it is left for the last request to finish, so requests holding the current ID
+/// never lose the session they are changing.
+///
+/// Once reserved, requests starting on the current ID wait until [`finish_renew`] or
+/// [`cancel_renew`] is called.
+pub(crate) fn take_renew(data: &mut SessionData) -> bool {
+    if data.requests > 1 || data.renewing || !std::mem::take(&mut data.renew) {
+        return false;
+    }
+
+    data.renewing = true;
+    true
+}
+
+/// Copies the session reserved using [`take_renew`] to the new ID, where the renewing request
+/// continues. The current ID is kept until the renewal finishes so requests sending it wait
+/// instead of loading the row while it is moved.
+pub(crate) fn move_renewed<M: SessionMap>(map: &M, id: &str, new_id: &str) {
+    if let Some(mut moved) = map.update(id, |data| data.clone()) {
+        moved.id = new_id.to_owned();
+        moved.renewing = false;
+        map.insert(new_id, moved);
+    }
+}
+
+/// Releases the ID the session was renewed from, once the database moved to the new ID.
+pub(crate) fn finish_renew<M: SessionMap>(map: &M, id: &str) {
+    map.remove_if(id, |data| data.renewing);
+}
+
+/// Undoes a renewal which failed, leaving it for the next request to finish.
+pub(crate) fn cancel_renew<M: SessionMap>(map: &M, id: &str, new_id: Option<&str>) {
+    if let Some(new_id) = new_id {
+        map.remove_if(new_id, |_| true);
+    }
+
+    map.update(id, |data| {
+        data.renewing = false;
+        data.renew = true;
+    });
+}
+
+/// Unloads the sessions whose memory lifespan passed, keeping pinned sessions and
+/// those a request still holds.
+pub(crate) fn sweep_memory<M: SessionMap>(map: &M, now: Instant) {
+    map.retain(|data| data.kept_in_memory(now));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        end_request, finish_renew, insert_loaded, join, move_renewed, sweep_memory, take_renew,
+        unload_if_idle, Join, SessionMap,
+    };
+    use crate::{RuntimeConfig, SessionConfig, SessionData, SystemClock};
+    use chrono::Utc;
+    use loom::{
+        model::Builder,
+        sync::{Arc, Mutex},
+        thread,
+    };
+    use std::{collections::HashMap, time::Instant};
+
+    /// SessionMap whose steps are loom operations so every interleaving of them is explored.
+    #[derive(Default)]
+    struct ModelMap(Mutex<HashMap<String, SessionData>>);
+
+    impl SessionMap for ModelMap {
+        fn update<R>(&self, id: &str, f: impl FnOnce(&mut SessionData) -> R) -> Option<R> {
+            self.0.lock().unwrap().get_mut(id).map(f)
+        }
+
+        fn remove_if(&self, id: &str, f: impl FnOnce(&SessionData) -> bool) -> Option<SessionData> {
+            let mut map = self.0.lock().unwrap();
+
+            if map.get(id).is_some_and(f) {
+                map.remove(id)
+            } else {
+                None
+            }
+        }
+
+        fn insert_or_update<R>(
+            &self,
+            id: &str,
+            data: SessionData,
+            f: impl FnOnce(&mut SessionData, SessionData) -> R,
+        ) -> Option<R> {
+            let mut map = self.0.lock().unwrap();
+
+            match map.get_mut(id) {
+                Some(loaded) => Some(f(loaded, data)),
+                None => {
+                    map.insert(id.to_owned(), data);
+                    None
+                }
+            }
+        }
+
+        fn insert(&self, id: &str, data: SessionData) {
+            self.0.lock().unwrap().insert(id.to_owned(), data);
+        }
+
+        fn retain(&self, mut f: impl FnMut(&SessionData) -> bool) {
+            self.0.lock().unwrap().retain(|_, data| f(data));
+        }
+    }
+
+    /// Database rows and native counters, each changed in a single step like a query.
+    #[derive(Default)]
+    struct ModelDb {
+        rows: Mutex<HashMap<String, SessionData>>,
+        counters: Mutex<HashMap<String, i64>>,
+    }
+
+    impl ModelDb {
+        fn incr(&self, id: &str) {
+            *self
+                .counters
+                .lock()
+                .unwrap()
+                .entry(id.to_owned())
+                .or_default() += 1;
+        }
+
+        /// Replaces the counters of new_id with those of id, like HGETALL and a replace.
+        fn rename_counters(&self, id: &str, new_id: &str) {
+            let mut counters = self.counters.lock().unwrap();
+            let moved = counters.remove(id).unwrap_or_default();
+            counters.insert(new_id.to_owned(), moved);
+        }
+    }
+
+    /// Explores the interleavings up to the preemption bound, which `LOOM_MAX_PREEMPTIONS` can raise.
+    fn model(f: impl Fn() + Sync + Send + 'static) {
+        let mut builder = Builder::new();
+        builder.preemption_bound.get_or_insert(2);
+        builder.check(f);
+    }
+
+    fn session(id: &str, requests: usize) -> SessionData {
+        let runtime = RuntimeConfig::from(&SessionConfig::default());
+        let mut data = SessionData::new(id.to_owned(), true, &runtime, &SystemClock);
+        data.requests = requests;
+        data
+    }
+
+    /// Starts a request the way the service does, loading the session if it is missing and
+    /// waiting while its ID is renewed.
+    fn start_request(map: &ModelMap, db: &ModelDb, id: &str) {
+        loop {
+            match join(map, id, None, Utc::now(), |_| {}) {
+                Join::Joined => return,
+                Join::Renewing => thread::yield_now(),
+                Join::Limited => unreachable!("no limit is set"),
+                Join::Missing => {
+                    let mut loaded = db
+                        .rows
+                        .lock()
+                        .unwrap()
+                        .get(id)
+                        .cloned()
+                        .unwrap_or_else(|| session(id, 1));
+                    loaded.requests = 1;
+
+                    match insert_loaded(map, id, loaded, None, Utc::now()) {
+                        Join::Renewing => thread::yield_now(),
+                        _ => return,
+                    }
+                }
+            }
+        }
+    }
+
+    /// Writes to the session, counts the write and saves it the way a request does.
+    fn write_and_finish(map: &ModelMap, db: &ModelDb, id: &str, key: &str) {
+        map.update(id, |data| {
+            data.data.insert(key.to_owned(), "1".to_owned());
+        })
+        .expect("the session was removed while a request held it");
+        db.incr(id);
+        let saved = map.update(id, |data| data.clone()).unwrap();
+        db.rows.lock().unwrap().insert(id.to_owned(), saved);
+        assert!(end_request(map, id), "the request lost its session");
+    }
+
+    /// Renews the session the way the service does once the response is ready.
+    /// Returns the ID the request finished on.
+    fn renew(map: &ModelMap, db: &ModelDb, id: &'static str) -> &'static str {
+        if !map.update(id, take_renew).unwrap() {
+            return id;
+        }
+
+        move_renewed(map, id, "new");
+        db.rename_counters(id, "new");
+        db.rows.lock().unwrap().remove(id);
+        finish_renew(map, id);
+        "new"
+    }
+
+    /// Loads the session the way the first request did, holding requests for it.
+    fn loaded(map: &ModelMap, db: &ModelDb, requests: usize) {
+        let mut data = session("old", requests);
+        data.data.insert("kept".to_owned(), "1".to_owned());
+        db.rows
+            .lock()
+            .unwrap()
+            .insert("old".to_owned(), data.clone());
+        db.incr("old");
+        map.insert("old", data);
+    }
+
+    /// Checks no request is counted anymore and the renewed session's row exists once,
+    /// under the ID it was left at, which is returned.
+    fn check_finished(map: &ModelMap, db: &ModelDb) -> String {
+        let memory = map.0.lock().unwrap();
+        assert!(memory
+            .values()
+            .all(|data| data.requests == 0 && !data.renewing));
+
+        let rows = db.rows.lock().unwrap();
+        let kept: Vec<&String> = rows
+            .iter()
+            .filter(|(_, data)| data.data.contains_key("kept"))
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(
+            kept.len(),
+            1,
+            "the renewed session was lost or left under both IDs"
+        );
+
+        let id = kept[0].clone();
+        assert!(memory.contains_key(&id));
+        id
+    }
+
+    #[test]
+    fn renew_with_parallel_request() {
+        model(|| {
+            let map = Arc::new(ModelMap::default());
+            let db = Arc::new(ModelDb::default());
+            loaded(&map, &db, 2);
+            map.update("old", |data| data.renew = true).unwrap();
+
+            let renewing = {
+                let (map, db) = (map.clone(), db.clone());
+                thread::spawn(move || {
+                    let id = renew(&map, &db, "old");
+                    write_and_finish(&map, &db, id, "renewer");
+                })
+            };
+
+            // The request holding the session from before keeps it.
+            write_and_finish(&map, &db, "old", "parallel");
+            renewing.join().unwrap();
+
+            // Only the last request renews, otherwise the renewal waits for the next one.
+            let id = check_finished(&map, &db);
+            assert_eq!(
+                id == "old",
+                map.update("old", |data| data.renew).unwrap_or_default()
+            );
+            assert_eq!(
+                map.update(&id, |data| data.data.len()).unwrap(),
+                3,
+                "renew lost data"
+            );
+            assert_eq!(db.counters.lock().unwrap()[&id], 3, "renew lost a counter");
+        });
+    }
+
+    #[test]
+    fn renew_while_request_starts() {
+        model(|| {
+            let map = Arc::new(ModelMap::default());
+            let db = Arc::new(ModelDb::default());
+            loaded(&map, &db, 1);
+            map.update("old", |data| data.renew = true).unwrap();
+
+            let renewing = {
+                let (map, db) = (map.clone(), db.clone());
+                thread::spawn(move || {
+                    let id = renew(&map, &db, "old");
+                    write_and_finish(&map, &db, id, "renewer");
+                })
+            };
+
+            // A request sending the old ID either joins before the renewal is reserved or
+            // waits for it and starts a new session, as the old ID is revoked.
+            start_request(&map, &db, "old");
+            write_and_finish(&map, &db, "old", "late");
+            renewing.join().unwrap();
+
+            let id = check_finished(&map, &db);
+            let data = map.update(&id, |data| data.data.clone()).unwrap();
+            let counters = db.counters.lock().unwrap();
+            assert!(data.contains_key("renewer"));
+
+            if data.contains_key("late") {
+                // Joined before the renewal was reserved, which then waits for the last request.
+                assert_eq!(
+                    id == "old",
+                    map.update("old", |data| data.renew).unwrap_or_default()
+                );
+                assert_eq!(counters[&id], 3, "renew lost a counter");
+            } else {
+                assert_eq!(id, "new");
+                assert_eq!(counters["new"], 2, "renew lost a counter");
+                assert_eq!(counters["old"], 1);
+                assert_eq!(db.rows.lock().unwrap()["old"].data.len(), 1);
+            }
+        });
+    }
+
+    #[test]
+    fn loads_respect_the_limit() {
+        model(|| {
+            let map = ModelMap::default();
+            assert_eq!(
+                insert_loaded(&map, "id", session("id", 1), Some(1), Utc::now()),
+                Join::Joined
+            );
+            // A second load joining the first one is limited like a request joining in memory.
+            assert_eq!(
+                insert_loaded(&map, "id", session("id", 1), Some(1), Utc::now()),
+                Join::Limited
+            );
+            assert_eq!(join(&map, "id", Some(1), Utc::now(), |_| {}), Join::Limited);
+            assert_eq!(
+                insert_loaded(&map, "id", session("id", 1), Some(2), Utc::now()),
+                Join::Joined
+            );
+            assert_eq!(map.update("id", |data| data.requests), Some(2));
+        });
+    }
+
+    #[test]
+    fn loads_replace_ephemeral_sessions() {
+        model(|| {
+            let map = ModelMap::default();
+            let mut ephemeral = session("id", 1);
+            ephemeral.ephemeral = true;
+            ephemeral.data.insert("dropped".to_owned(), "1".to_owned());
+            map.insert("id", ephemeral);
+
+            let mut stored = session("id", 1);
+            stored.data.insert("user".to_owned(), "1".to_owned());
+            assert_eq!(
+                insert_loaded(&map, "id", stored, None, Utc::now()),
+                Join::Joined
+            );
+
+            let (data, ephemeral, requests) = map
+                .update("id", |data| {
+                    (data.data.clone(), data.ephemeral, data.requests)
+                })
+                .unwrap();
+            assert!(!ephemeral);
+            assert_eq!(requests, 2);
+            assert!(data.contains_key("user") && !data.contains_key("dropped"));
+
+            // A failed load joins the loaded session instead of standing in for it.
+            let mut failed = session("id", 1);
+            failed.ephemeral = true;
+            assert_eq!(
+                insert_loaded(&map, "id", failed, None, Utc::now()),
+                Join::Joined
+            );
+            assert_eq!(map.update("id", |data| data.ephemeral), Some(false));
+        });
+    }
+
+    #[test]
+    fn parallel_loads_and_unloads() {
+        model(|| {
+            let map = Arc::new(ModelMap::default());
+            let db = Arc::new(ModelDb::default());
+
+            let requests: Vec<_> = (0..2)
+                .map(|n| {
+                    let (map, db) = (map.clone(), db.clone());
+                    thread::spawn(move || {
+                        start_request(&map, &db, "id");
+                        map.update("id", |data| {
+                            data.data.insert(n.to_string(), "1".to_owned());
+                        })
+                        .expect("the session was removed while a request held it");
+                        assert!(end_request(&*map, "id"));
+                        // A zero memory lifespan unloads the session after each request.
+                        unload_if_idle(&*map, "id", |_| true);
+                    })
+                })
+                .collect();
+
+            for request in requests {
+                request.join().unwrap();
+            }
+
+            assert!(
+                map.0.lock().unwrap().is_empty(),
+                "an idle session stayed loaded"
+            );
+        });
+    }
+
+    #[test]
+    fn sweep_with_parallel_requests() {
+        model(|| {
+            let map = Arc::new(ModelMap::default());
+            let db = Arc::new(ModelDb::default());
+            let mut loaded = session("id", 0);
+            loaded.autoremove = Instant::now();
+            map.insert("id", loaded);
+
+            let mut threads: Vec<_> = (0..2)
+                .map(|n| {
+                    let (map, db) = (map.clone(), db.clone());
+                    thread::spawn(move || {
+                        start_request(&map, &db, "id");
+                        map.update("id", |data| {
+                            data.data.insert(n.to_string(), "1".to_owned());
+                        })
+                        .expect("the session was removed while a request held it");
+                        assert!(end_request(&*map, "id"));
+                    })
+                })
+                .collect();
+
+            let sweeping = map.clone();
+            threads.push(thread::spawn(move || {
+                sweep_memory(&*sweeping, Instant::now())
+            }));
+
+            for thread in threads {
+                thread.join().unwrap();
+            }
+
+            let memory = map.0.lock().unwrap();
+            assert!(memory.len() <= 1, "the session was duplicated");
+            assert!(memory.values().all(|data| data.requests == 0));
+        });
+    }
+}
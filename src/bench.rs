@@ -0,0 +1,54 @@
+//! Internals exposed to the crates criterion benches behind the `bench` feature.
+//! Nothing here is part of the public API and it may change in any release.
+
+use crate::{sec::encrypt, DatabasePool, Key, Session, SessionData, SessionError, SessionStore};
+use std::{collections::HashMap, fmt::Debug};
+
+pub use crate::databases::test_pool::SessionTestPool;
+
+/// Signs a header value the same way the session header and cookie values are signed.
+pub fn sign_header(value: &str, key: &Key, message: &str) -> Result<String, &'static str> {
+    crate::sec::sign_header(value, key, message)
+}
+
+/// Verifies a value signed with [`sign_header`].
+pub fn verify_header(value: &str, key: &Key, message: &str) -> Result<String, &'static str> {
+    crate::sec::verify_header(value, key, message)
+}
+
+/// Encrypts a session payload the same way the database key does.
+pub fn encrypt(name: &str, value: &str, key: &Key) -> Result<String, &'static str> {
+    encrypt::encrypt(name, value, key)
+}
+
+/// Decrypts a payload encrypted with [`encrypt`].
+pub fn decrypt(name: &str, value: &str, key: &Key) -> Result<String, SessionError> {
+    encrypt::decrypt(name, value, key)
+}
+
+/// Serializes a new session holding the data the way it is written to the database.
+pub fn serialize_session<T>(
+    store: &SessionStore<T>,
+    id: &str,
+    data: HashMap<String, String>,
+) -> Result<String, SessionError>
+where
+    T: DatabasePool + Clone + Debug + Sync + Send + 'static,
+{
+    let mut session = SessionData::new(
+        id.to_owned(),
+        true,
+        &store.runtime_config(),
+        store.config.clock.as_ref(),
+    );
+    session.data = data;
+    Ok(serde_json::to_string(&session)?)
+}
+
+/// Generates a new unused session id the same way the layer does for new sessions.
+pub async fn generate_id<T>(store: &SessionStore<T>) -> Result<String, SessionError>
+where
+    T: DatabasePool + Clone + Debug + Sync + Send + 'static,
+{
+    Session::generate_id(store).await
+}
@@ -0,0 +1,83 @@
+use crate::SessionConfig;
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Separates the cache key MAC from the other MACs made with the same key.
+const CACHE_KEY_CONTEXT: &[u8] = b"axum_session cache key:";
+
+/// Bumped when the layout of the Session values changes so older cache keys stop matching.
+const CACHE_KEY_VERSION: u32 = 1;
+
+/// Adds a length prefix so different splits of the same bytes never produce the same MAC.
+fn update_part(mac: &mut Hmac<Sha256>, part: &[u8]) {
+    mac.update(&(part.len() as u64).to_be_bytes());
+    mac.update(part);
+}
+
+/// Creates the cache key of a Session ID and the serialized values of the listed keys.
+///
+/// Keyed by the cache key salt, else the signing key, else an empty key which still
+/// does not reveal the randomly generated ID.
+pub(crate) fn cache_key(
+    config: &SessionConfig,
+    id: &str,
+    parts: &[(&str, Option<&str>)],
+) -> String {
+    let secret = match (&config.cache_key_salt, &config.cookie_and_header.key) {
+        (Some(salt), _) => salt.as_slice(),
+        (None, Some(key)) => key.signing(),
+        (None, None) => &[],
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(CACHE_KEY_CONTEXT);
+    mac.update(&CACHE_KEY_VERSION.to_be_bytes());
+    update_part(&mut mac, id.as_bytes());
+
+    for (name, value) in parts {
+        update_part(&mut mac, name.as_bytes());
+
+        // Missing values hash differently from values holding an empty string.
+        match value {
+            Some(value) => {
+                mac.update(&[1]);
+                update_part(&mut mac, value.as_bytes());
+            }
+            None => mac.update(&[0]),
+        }
+    }
+
+    BASE64_URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cache_key;
+    use crate::{Key, SessionConfig};
+
+    #[test]
+    fn keyed_and_unambiguous() {
+        let config = SessionConfig::default();
+        let id = "4a4e5d3b-6f1c-4d8e-9a2b-7c3d5e6f7a8b";
+        let key = cache_key(&config, id, &[("user", Some("42"))]);
+        assert_eq!(key, cache_key(&config, id, &[("user", Some("42"))]));
+
+        // A missing value differs from an empty one and values can not shift between keys.
+        assert_ne!(
+            cache_key(&config, id, &[("user", None)]),
+            cache_key(&config, id, &[("user", Some(""))])
+        );
+        assert_ne!(
+            cache_key(&config, id, &[("ab", Some("c"))]),
+            cache_key(&config, id, &[("a", Some("bc"))])
+        );
+
+        let signed = SessionConfig::default().with_key(Key::generate());
+        let salted = signed.clone().with_cache_key_salt("salt");
+        assert_ne!(cache_key(&signed, id, &[("user", Some("42"))]), key);
+        assert_ne!(
+            cache_key(&salted, id, &[("user", Some("42"))]),
+            cache_key(&signed, id, &[("user", Some("42"))])
+        );
+    }
+}
@@ -1,12 +1,39 @@
-use chrono::Duration;
+use crate::{
+    headers::{cookie_expires, cookie_names},
+    sec::hash::hash_id,
+    PayloadCipher, SessionError, StaticKeyCipher,
+};
+use chrono::{DateTime, Duration, Utc};
 pub use cookie::{Key, SameSite};
+use futures::future::BoxFuture;
 use std::{
     borrow::Cow,
     fmt::{Debug, Formatter, Result},
     sync::Arc,
+    time::Instant,
 };
 use uuid::Uuid;
 
+/// Which transport wins when a request names a Session using both a cookie and a header.
+///
+/// # Examples
+/// ```rust
+/// use axum_session::{SessionConfig, TransportPrecedence};
+///
+/// let config = SessionConfig::default().with_transport_precedence(TransportPrecedence::CookieFirst);
+/// ```
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportPrecedence {
+    /// Use the cookie, falling back to the header when there is no valid cookie.
+    CookieFirst,
+    /// Use the header, falling back to the cookie when there is no valid header.
+    HeaderFirst,
+    /// Treat the request as having no Session when the cookie and header name different Sessions.
+    /// Otherwise whichever was sent is used.
+    RejectConflicts,
+}
+
 /// Mode at which the Session will function As.
 ///
 /// # Examples
@@ -55,6 +82,10 @@ pub struct CookieAndHeaderConfig {
     pub(crate) store_name: Cow<'static, str>,
     /// Session Cookie or Header name.
     pub(crate) session_name: Cow<'static, str>,
+    /// Former Session Cookie names still read when the Session Cookie is missing.
+    pub(crate) legacy_session_names: Vec<Cow<'static, str>>,
+    /// Former store Cookie names still read when the store Cookie is missing.
+    pub(crate) legacy_store_names: Vec<Cow<'static, str>>,
     /// Session cookie domain.
     pub(crate) cookie_domain: Option<Cow<'static, str>>,
     /// Session cookie http only flag.
@@ -68,6 +99,12 @@ pub struct CookieAndHeaderConfig {
     pub(crate) cookie_same_site: SameSite,
     /// Session cookie secure flag.
     pub(crate) cookie_secure: bool,
+    /// When true cookies are Secure for every host except localhost, 127.0.0.1 and ::1.
+    /// Overrides cookie_secure and is decided from the request's host and loopback connection.
+    pub(crate) secure_localhost_exception: bool,
+    /// When set both cookies and headers are read and this decides which one is used.
+    /// When None only the transport selected by the `rest_mode` feature is read.
+    pub(crate) transport_precedence: Option<TransportPrecedence>,
     /// Encyption Key used to sign cookies and header for integrity, and authenticity.
     pub(crate) key: Option<Key>,
     /// This is used to append __Host- to the front of all Cookie names to prevent sub domain usage.
@@ -77,6 +114,10 @@ pub struct CookieAndHeaderConfig {
     /// and header values. This will help prevent others from spoofing your cookies and headers.
     /// It is enabled by default.
     pub(crate) with_ip_and_user_agent: bool,
+    /// Name of the non-HttpOnly Cookie holding the double-submit CSRF token. Disabled when None.
+    pub(crate) double_submit_cookie: Option<Cow<'static, str>>,
+    /// What happens to the Session when the response redirects to another origin.
+    pub(crate) external_redirect_policy: ExternalRedirectPolicy,
 }
 
 impl Debug for CookieAndHeaderConfig {
@@ -87,11 +128,20 @@ impl Debug for CookieAndHeaderConfig {
             .field("cookie_http_only", &self.cookie_http_only)
             .field("cookie_max_age", &self.cookie_max_age)
             .field("session_name", &self.session_name)
+            .field("legacy_session_names", &self.legacy_session_names)
+            .field("legacy_store_names", &self.legacy_store_names)
             .field("cookie_path", &self.cookie_path)
             .field("cookie_same_site", &self.cookie_same_site)
             .field("cookie_secure", &self.cookie_secure)
+            .field(
+                "secure_localhost_exception",
+                &self.secure_localhost_exception,
+            )
+            .field("transport_precedence", &self.transport_precedence)
             .field("prefix_with_host", &self.prefix_with_host)
             .field("with_ip_and_user_agent", &self.with_ip_and_user_agent)
+            .field("double_submit_cookie", &self.double_submit_cookie)
+            .field("external_redirect_policy", &self.external_redirect_policy)
             .field("key", &"key hidden")
             .finish()
     }
@@ -99,23 +149,82 @@ impl Debug for CookieAndHeaderConfig {
 
 #[derive(Clone)]
 pub struct DatabaseConfig {
-    /// Encyption Key used to encypt Session data stored in the database for confidentiality.
+    /// Encyption Key used to encypt Session transfer blobs for confidentiality.
     pub(crate) database_key: Option<Key>,
+    /// Encrypts Session data stored in the database for confidentiality.
+    pub(crate) payload_cipher: Option<Arc<dyn PayloadCipher>>,
+    /// Only decrypt the rows stored by their ids, such as the ciphers rotated away from.
+    pub(crate) decrypt_ciphers: Vec<Arc<dyn PayloadCipher>>,
     /// Session Database table name default is sessions.
     pub(crate) table_name: Cow<'static, str>,
+    /// Prefix of every key for key-value databases. Used instead of the table name when set.
+    pub(crate) key_namespace: Option<Cow<'static, str>>,
+    /// Allows key-value databases to use an empty namespace, which makes count, get_ids
+    /// and delete_all act on the whole database.
+    pub(crate) allow_global_namespace: bool,
     /// This value represents the duration for how often session's data gets purged from the database per request.
     pub(crate) purge_database_update: Duration,
     /// Ignore's the update checks and will always save the session to the database if set to true.
     pub(crate) always_save: bool,
+    /// When true the database rows are keyed by SHA-256(id + pepper) instead of the raw id.
+    pub(crate) hashed_storage_ids: bool,
+    /// Pepper used when hashing the database ids. Required when hashed_storage_ids is true.
+    pub(crate) storage_id_pepper: Option<Vec<u8>>,
+    /// Decides what happens to sessions that could not be read from the database.
+    pub(crate) corrupt_session_handler: Option<CorruptSessionHandler>,
+    /// Decides what happens to sessions that could not be loaded because the database failed.
+    pub(crate) load_failure_behavior: LoadFailureBehavior,
+    /// Receives the ids of the sessions each database sweep removed as expired.
+    pub(crate) expired_sessions_handler: Option<ExpiredSessionsHandler>,
+    /// Maximum new sessions per IP within the window, counted within the database so every instance shares it.
+    pub(crate) distributed_creation_limit: Option<(u64, Duration)>,
+    /// Longest Session ID in bytes accepted from requests or the id generator.
+    pub(crate) max_id_length: usize,
+    /// Times the id generator may make an ID over the maximum length before it is an error.
+    pub(crate) id_length_attempts: usize,
 }
 
 impl Debug for DatabaseConfig {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         f.debug_struct("DatabaseConfig")
             .field("table_name", &self.table_name)
+            .field("key_namespace", &self.key_namespace)
+            .field("allow_global_namespace", &self.allow_global_namespace)
             .field("purge_database_update", &self.purge_database_update)
             .field("always_save", &self.always_save)
+            .field("hashed_storage_ids", &self.hashed_storage_ids)
             .field("database_key", &"key hidden")
+            .field(
+                "payload_cipher",
+                &self.payload_cipher.as_ref().map(|cipher| cipher.id()),
+            )
+            .field(
+                "decrypt_ciphers",
+                &self
+                    .decrypt_ciphers
+                    .iter()
+                    .map(|cipher| cipher.id())
+                    .collect::<Vec<_>>(),
+            )
+            .field("storage_id_pepper", &"pepper hidden")
+            .field("load_failure_behavior", &self.load_failure_behavior)
+            .field(
+                "corrupt_session_handler",
+                &self.corrupt_session_handler.as_ref().map(|_| "handler set"),
+            )
+            .field(
+                "expired_sessions_handler",
+                &self
+                    .expired_sessions_handler
+                    .as_ref()
+                    .map(|_| "handler set"),
+            )
+            .field(
+                "distributed_creation_limit",
+                &self.distributed_creation_limit,
+            )
+            .field("max_id_length", &self.max_id_length)
+            .field("id_length_attempts", &self.id_length_attempts)
             .finish()
     }
 }
@@ -139,6 +248,14 @@ pub struct MemoryConfig {
     /// the false positives it can give you can disable it by setting it to false. This will reduce memory usage.
     /// By default this is enabled unless the specific database cant function with it then disabled.
     pub(crate) use_bloom_filters: bool,
+    /// Maximum parallel requests a single session may have in flight. None is unlimited.
+    pub(crate) max_parallel_requests: Option<usize>,
+    /// Status returned to requests rejected by max_parallel_requests.
+    pub(crate) parallel_limit_status: http::StatusCode,
+    /// Maximum sessions which may be pinned in memory at once.
+    pub(crate) max_pinned_sessions: usize,
+    /// Warns when a session's stored size grows by more than the factor within the window.
+    pub(crate) size_growth_warning: Option<(f64, Duration)>,
 }
 
 impl Debug for MemoryConfig {
@@ -152,6 +269,10 @@ impl Debug for MemoryConfig {
                 "filter_false_positive_probability",
                 &self.filter_false_positive_probability,
             )
+            .field("max_parallel_requests", &self.max_parallel_requests)
+            .field("parallel_limit_status", &self.parallel_limit_status)
+            .field("max_pinned_sessions", &self.max_pinned_sessions)
+            .field("size_growth_warning", &self.size_growth_warning)
             .finish()
     }
 }
@@ -160,6 +281,10 @@ impl Debug for MemoryConfig {
 pub struct IpUserAgentConfig {
     /// Uses the Direct Socket IP addresss of the socket connection.
     pub(crate) use_ip: bool,
+    /// Reads the socket IP from the last Forwarded by= clause when there is no ConnectInfo.
+    pub(crate) use_forwarded_by: bool,
+    /// Fails requests without a socket IP instead of logging a warning when use_ip is set.
+    pub(crate) require_connect_info: bool,
     /// (XFF) request header is a de-facto standard header for identifying the originating
     /// IP address of a client connecting to a web server through a proxy server
     pub(crate) use_xforward_ip: bool,
@@ -175,6 +300,8 @@ impl Debug for IpUserAgentConfig {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         f.debug_struct("IpUserAgentConfig")
             .field("use_ip", &self.use_ip)
+            .field("use_forwarded_by", &self.use_forwarded_by)
+            .field("require_connect_info", &self.require_connect_info)
             .field("use_xforward_ip", &self.use_xforward_ip)
             .field("use_forward_ip", &self.use_forward_ip)
             .field("use_real_ip", &self.use_real_ip)
@@ -183,6 +310,236 @@ impl Debug for IpUserAgentConfig {
     }
 }
 
+/// Settings which can be changed while the [`crate::SessionStore`] is running using
+/// [`crate::SessionStore::update_runtime_config`].
+///
+/// Only the lifespans and purge intervals are hot-swappable. Keys, table name, cookie
+/// names and every other setting are fixed once the SessionStore is created.
+/// New lifespans apply the next time a session's expiry is refreshed, existing sessions
+/// keep their current expiry until then.
+///
+/// # Examples
+/// ```rust
+/// use axum_session::{RuntimeConfig, SessionConfig};
+/// use chrono::Duration;
+///
+/// let config = SessionConfig::default();
+/// let runtime = RuntimeConfig::from(&config).with_lifetime(Duration::hours(2));
+/// ```
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RuntimeConfig {
+    /// Minimal lifespan of database store and cookie before expiring.
+    pub(crate) lifespan: Duration,
+    /// Maximum lifespan of database store and cookie before expiring for long term sessions.
+    pub(crate) max_lifespan: Duration,
+    /// Hard limit on how long after creation a session may live regardless of activity.
+    pub(crate) absolute_max_age: Option<Duration>,
+    /// Session Memory lifespan, deturmines when to unload it from memory.
+    pub(crate) memory_lifespan: Duration,
+    /// How often session's data gets purged from memory.
+    pub(crate) purge_update: Duration,
+    /// How often session's data gets purged from the database.
+    pub(crate) purge_database_update: Duration,
+}
+
+impl RuntimeConfig {
+    /// Set's the session's lifetime (expiration time) within database storage.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::{RuntimeConfig, SessionConfig};
+    /// use chrono::Duration;
+    ///
+    /// let runtime = RuntimeConfig::from(&SessionConfig::default()).with_lifetime(Duration::days(32));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_lifetime(mut self, time: Duration) -> Self {
+        self.lifespan = time;
+        self
+    }
+
+    /// Set's the session's long term lifetime (expiration time) within database storage.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::{RuntimeConfig, SessionConfig};
+    /// use chrono::Duration;
+    ///
+    /// let runtime = RuntimeConfig::from(&SessionConfig::default()).with_max_lifetime(Duration::days(32));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_max_lifetime(mut self, time: Duration) -> Self {
+        self.max_lifespan = time;
+        self
+    }
+
+    /// Set's the absolute maximum age of a session counted from when it was created.
+    /// None lets sessions live as long as they are used.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::{RuntimeConfig, SessionConfig};
+    /// use chrono::Duration;
+    ///
+    /// let runtime = RuntimeConfig::from(&SessionConfig::default()).with_absolute_max_age(Some(Duration::days(30)));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_absolute_max_age(mut self, time: Option<Duration>) -> Self {
+        self.absolute_max_age = time;
+        self
+    }
+
+    /// Set's the session's lifetime (expiration time) within memory storage.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::{RuntimeConfig, SessionConfig};
+    /// use chrono::Duration;
+    ///
+    /// let runtime = RuntimeConfig::from(&SessionConfig::default()).with_memory_lifetime(Duration::minutes(30));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_memory_lifetime(mut self, time: Duration) -> Self {
+        self.memory_lifespan = time;
+        self
+    }
+
+    /// This value represents the offset duration for how often session purge for memory is ran.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::{RuntimeConfig, SessionConfig};
+    /// use chrono::Duration;
+    ///
+    /// let runtime = RuntimeConfig::from(&SessionConfig::default()).with_purge_update(Duration::minutes(10));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_purge_update(mut self, duration: Duration) -> Self {
+        self.purge_update = duration;
+        self
+    }
+
+    /// This value represents the offset duration for how often session purge for database is ran.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::{RuntimeConfig, SessionConfig};
+    /// use chrono::Duration;
+    ///
+    /// let runtime = RuntimeConfig::from(&SessionConfig::default()).with_purge_database_update(Duration::hours(1));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_purge_database_update(mut self, duration: Duration) -> Self {
+        self.purge_database_update = duration;
+        self
+    }
+
+    pub(crate) fn validate(&self) -> std::result::Result<(), SessionError> {
+        let fields = [
+            ("lifespan", self.lifespan),
+            ("max_lifespan", self.max_lifespan),
+            (
+                "absolute_max_age",
+                self.absolute_max_age.unwrap_or_default(),
+            ),
+            ("memory_lifespan", self.memory_lifespan),
+            ("purge_update", self.purge_update),
+            ("purge_database_update", self.purge_database_update),
+        ];
+
+        if let Some((name, _)) = fields.iter().find(|(_, d)| *d < Duration::zero()) {
+            return Err(SessionError::InvalidConfiguration(format!(
+                "{} can not be negative.",
+                name
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl From<&SessionConfig> for RuntimeConfig {
+    fn from(config: &SessionConfig) -> Self {
+        Self {
+            lifespan: config.lifespan,
+            max_lifespan: config.max_lifespan,
+            absolute_max_age: config.absolute_max_age,
+            memory_lifespan: config.memory.memory_lifespan,
+            purge_update: config.memory.purge_update,
+            purge_database_update: config.database.purge_database_update,
+        }
+    }
+}
+
+/// A session loaded from the database which could not be decrypted or deserialized.
+/// Given to the handler set with [`SessionConfig::with_corrupt_session_handler`].
+#[derive(Debug)]
+pub struct CorruptSession {
+    /// The Session ID from the cookie or header.
+    pub id: String,
+    /// The decrypted payload, or the raw row if decryption failed.
+    pub payload: String,
+    /// Why the payload could not be read.
+    pub error: SessionError,
+}
+
+/// What to do with a [`CorruptSession`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CorruptAction {
+    /// Delete the corrupt row and start a new empty session with the same ID.
+    #[default]
+    RecreateAndDelete,
+    /// Start a new empty session leaving the row in place until it is overwritten.
+    RecreateKeepRow,
+    /// Fail the request with `SessionError::CorruptSession`.
+    FailRequest,
+}
+
+/// What to do when a session could not be loaded because the database failed.
+///
+/// Only applies to errors where [`SessionError::is_retryable`] is true, others fail the request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LoadFailureBehavior {
+    /// Fail the request with a 500 leaving the session to be loaded by the next request.
+    #[default]
+    FailRequest,
+    /// Start a new empty session with the same ID which overwrites the row when saved.
+    RecreateEmpty,
+    /// Start a new empty session with the same ID which is only kept for this request.
+    /// It is neither saved to the database nor is its cookie refreshed, so the next
+    /// request tries loading the row again.
+    ServeEphemeral,
+}
+
+/// What happens to the Session when the response redirects to another origin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExternalRedirectPolicy {
+    /// Handle the response like any other.
+    #[default]
+    Normal,
+    /// Leave the Session the request carried as it is. Its expiry is not extended, renewals
+    /// wait for the next response and its cookies are not sent again, while destroying it
+    /// still removes them. Data changed by the handler is still saved.
+    FreezeSession,
+}
+
+/// Handler deciding the [`CorruptAction`] for a [`CorruptSession`].
+pub type CorruptSessionHandler = Arc<dyn Fn(CorruptSession) -> CorruptAction + Send + Sync>;
+
+/// Handler receiving the ids of the sessions a database sweep removed as expired.
+pub type ExpiredSessionsHandler = Arc<dyn Fn(Vec<String>) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// Default longest Session ID accepted from requests or generators, matching the
+/// `VARCHAR(128)` id column the included databases create.
+pub const DEFAULT_MAX_ID_LENGTH: usize = 128;
+
 pub trait IdGenerator: Debug + Send + Sync + 'static {
     fn generate(&self) -> String;
 }
@@ -193,6 +550,38 @@ impl IdGenerator for Uuid {
     }
 }
 
+/// Source of time used by the Session store.
+///
+/// Wall clock time is only used for values that leave the process, such as the expiry
+/// stored within the database. Timers within the process, such as the memory purge
+/// schedule and when unused sessions are unloaded from memory, use the monotonic
+/// instant so a wall clock stepping backwards or forwards can not unload sessions early.
+pub trait Clock: Debug + Send + Sync + 'static {
+    /// Returns the current wall clock time.
+    fn now(&self) -> DateTime<Utc>;
+    /// Returns the current monotonic time. This must never go backwards.
+    fn instant(&self) -> Instant;
+}
+
+/// [`Clock`] using the system's wall clock and monotonic clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Returns the instant after the duration, treating negative durations as zero.
+pub(crate) fn instant_after(instant: Instant, duration: Duration) -> Instant {
+    instant + duration.to_std().unwrap_or_default()
+}
+
 /// Configuration for how the Session and Cookies are used.
 ///
 /// # Examples
@@ -206,7 +595,15 @@ impl IdGenerator for Uuid {
 pub struct SessionConfig {
     /// Disables the need to avoid session saving.
     pub(crate) session_mode: SessionMode,
+    /// Treat OptIn sessions as storable whenever their data is non-empty.
+    pub(crate) auto_store_when_nonempty: bool,
+    /// Logs a short hash of Session IDs instead of the IDs themselves.
+    pub(crate) tracing_sensitive: bool,
+    /// Secret used to key `Session::cache_key`. Falls back to the Cookie key when None.
+    pub(crate) cache_key_salt: Option<Vec<u8>>,
     pub(crate) id_generator: Arc<dyn IdGenerator>,
+    /// Source of wall clock and monotonic time.
+    pub(crate) clock: Arc<dyn Clock>,
     /// Minimal lifespan of database store and cookie before expiring.
     /// This is set to the Cookie before sending and to the database before updating/inserting.
     pub(crate) lifespan: Duration,
@@ -214,6 +611,10 @@ pub struct SessionConfig {
     /// This is set to the Cookie before sending and to the database before updating/inserting.
     /// Only Set when Long Term is true.
     pub(crate) max_lifespan: Duration,
+    /// Hard limit on how long after creation a session may live regardless of activity.
+    pub(crate) absolute_max_age: Option<Duration>,
+    /// How long a blob made by `Session::export_json` can be imported for.
+    pub(crate) transfer_lifespan: Duration,
     /// This is to be used when your handling multiple Parallel Sessions to prevent the next one from unloaded data.
     pub(crate) clear_check_on_load: bool,
     /// where All Database Storage options exist.
@@ -230,12 +631,18 @@ impl Debug for SessionConfig {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         f.debug_struct("SessionConfig")
             .field("id_generator", &self.id_generator)
+            .field("clock", &self.clock)
             .field("database", &self.database)
             .field("memory", &self.memory)
             .field("cookie_and_header", &self.cookie_and_header)
             .field("session_mode", &self.session_mode)
+            .field("auto_store_when_nonempty", &self.auto_store_when_nonempty)
+            .field("tracing_sensitive", &self.tracing_sensitive)
+            .field("cache_key_salt", &"salt hidden")
             .field("lifespan", &self.lifespan)
             .field("max_lifespan", &self.max_lifespan)
+            .field("absolute_max_age", &self.absolute_max_age)
+            .field("transfer_lifespan", &self.transfer_lifespan)
             .field("clear_check_on_load", &self.clear_check_on_load)
             .finish()
     }
@@ -282,7 +689,24 @@ impl SessionConfig {
         self
     }
 
+    /// Set the [`Clock`] the Session store reads the time from.
+    /// Defaults to the [`SystemClock`]. Mostly useful to test time dependent behavior.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::{SessionConfig, SystemClock};
+    ///
+    /// let config = SessionConfig::default().with_clock(SystemClock);
+    /// ```
+    #[must_use]
+    pub fn with_clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
     /// Set the session's store Cookie or Header name.
+    /// The name may only contain letters, digits and ``!#$%&'*+-.^_`|~``,
+    /// otherwise `SessionStore::new` will return an error.
     ///
     /// # Examples
     /// ```rust
@@ -313,6 +737,8 @@ impl SessionConfig {
     }
 
     /// Set's the session's Cookie or Header name.
+    /// The name may only contain letters, digits and ``!#$%&'*+-.^_`|~``,
+    /// otherwise `SessionStore::new` will return an error.
     ///
     /// # Examples
     /// ```rust
@@ -327,6 +753,71 @@ impl SessionConfig {
         self
     }
 
+    /// Set's the former Session Cookie names still accepted while moving to a new session name.
+    ///
+    /// Requests without the Session Cookie are read from the first legacy name they carry,
+    /// using the same signing checks. Responses only set the current name and remove every
+    /// legacy Cookie the request carried, so clients move over on their next request.
+    /// Legacy names are used exactly as given without the `__Host-` prefix and are only read
+    /// from Cookies. Empty the list once the transition is over so the old names are ignored.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::SessionConfig;
+    ///
+    /// let config = SessionConfig::default()
+    ///     .with_session_name("sid")
+    ///     .with_prefix_with_host(true)
+    ///     .with_legacy_session_names(vec!["session".into()]);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_legacy_session_names(mut self, names: Vec<Cow<'static, str>>) -> Self {
+        self.cookie_and_header.legacy_session_names = names;
+        self
+    }
+
+    /// Set's the former store Cookie names still accepted while moving to a new store name.
+    /// Works the same as `with_legacy_session_names` but for the store Cookie.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::SessionConfig;
+    ///
+    /// let config = SessionConfig::default()
+    ///     .with_store_name("keep")
+    ///     .with_legacy_store_names(vec!["store".into()]);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_legacy_store_names(mut self, names: Vec<Cow<'static, str>>) -> Self {
+        self.cookie_and_header.legacy_store_names = names;
+        self
+    }
+
+    /// Enables the double-submit CSRF Cookie with the given name.
+    ///
+    /// Every response that sets the Session Cookie also sets this Cookie without HttpOnly,
+    /// so scripts can read it and send it back within the `x-csrf-token` Header which
+    /// [`crate::DoubleSubmitGuard`] then verifies. The token is an HMAC of the Session ID
+    /// made with the config key, so a key must be set with `with_key` and a new token is
+    /// issued whenever the Session is renewed. Only used with the Cookie transport.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::{Key, SessionConfig};
+    ///
+    /// let config = SessionConfig::default()
+    ///     .with_key(Key::generate())
+    ///     .with_double_submit_cookie("csrf");
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_double_submit_cookie(mut self, name: impl Into<Cow<'static, str>>) -> Self {
+        self.cookie_and_header.double_submit_cookie = Some(name.into());
+        self
+    }
+
     /// Set's the session's cookie's path.
     ///
     /// This is used to determine when the cookie takes effect within the website path.
@@ -377,6 +868,82 @@ impl SessionConfig {
         self
     }
 
+    /// Set's whether OptIn sessions decide their storable state from their data.
+    ///
+    /// When enabled, at the end of each request a session holding any data is stored
+    /// and gets its store cookie or header, while an empty session is dropped, without
+    /// calling `set_store`. Has no effect unless the mode is SessionMode::OptIn.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::{SessionMode, SessionConfig};
+    ///
+    /// let config = SessionConfig::default()
+    ///     .with_mode(SessionMode::OptIn)
+    ///     .with_auto_store_when_nonempty(true);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_auto_store_when_nonempty(mut self, enable: bool) -> Self {
+        self.auto_store_when_nonempty = enable;
+        self
+    }
+
+    /// Set's whether Session IDs are hashed within tracing events.
+    ///
+    /// When enabled the first 8 characters of the SHA-256 of an ID are logged instead of
+    /// the ID, so log lines of one Session can still be correlated without the logs
+    /// holding IDs that could be used to take over the Session. Defaults to false.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::SessionConfig;
+    ///
+    /// let config = SessionConfig::default().with_tracing_sensitive(true);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_tracing_sensitive(mut self, enable: bool) -> Self {
+        self.tracing_sensitive = enable;
+        self
+    }
+
+    /// Returns the Session ID as it should appear within tracing events.
+    pub(crate) fn log_id<'a>(&self, id: &'a str) -> Cow<'a, str> {
+        if self.tracing_sensitive {
+            Cow::Owned(Self::hashed_log_id(id))
+        } else {
+            Cow::Borrowed(id)
+        }
+    }
+
+    /// Returns the hash the Session ID appears as within tracing events when sensitive.
+    /// Used regardless of the setting for events logged at error level, which are more
+    /// likely to be forwarded to places the raw ID must not reach.
+    pub(crate) fn hashed_log_id(id: &str) -> String {
+        let mut hash = hash_id(id, &[]);
+        hash.truncate(8);
+        hash
+    }
+
+    /// Set's the secret used to key [`crate::Session::cache_key`] and [`crate::Session::etag`].
+    ///
+    /// When not Set the key from `with_key` is used. Set a dedicated salt to keep cache
+    /// keys unchanged when that key is rotated. Keep this secret.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::SessionConfig;
+    ///
+    /// let config = SessionConfig::default().with_cache_key_salt("my secret salt");
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_cache_key_salt(mut self, salt: impl Into<Vec<u8>>) -> Self {
+        self.cache_key_salt = Some(salt.into());
+        self
+    }
+
     /// Set's the session's cookie's to http only.
     ///
     /// # Examples
@@ -444,52 +1011,90 @@ impl SessionConfig {
         self
     }
 
-    /// Set's the session's lifetime (expiration time) within memory storage.
-    /// This setting should be Less than lifespan and max_lifespan. This is to
-    /// Unload the data from memory and allow it to stay stored in the database.
-    ///
-    /// Set this to Duration::zero() if you dont want it to stay in memory.
-    /// Warning: This will cause it to be loaded from the database each request.
+    /// Set's the absolute maximum age of a session counted from when it was created.
+    /// The lifespan and max_lifespan slide forward on every save, this does not, so
+    /// even a session in constant use expires once it is this old. It then starts over with
+    /// its data cleared and a newly generated ID.
+    /// Defaults to None which lets sessions live as long as they are used.
     ///
     /// # Examples
     /// ```rust
     /// use axum_session::SessionConfig;
     /// use chrono::Duration;
     ///
-    /// let config = SessionConfig::default().with_memory_lifetime(Duration::days(32));
+    /// let config = SessionConfig::default().with_absolute_max_age(Duration::days(30));
     /// ```
     ///
     #[must_use]
-    pub fn with_memory_lifetime(mut self, time: Duration) -> Self {
-        self.memory.memory_lifespan = time;
+    pub fn with_absolute_max_age(mut self, time: Duration) -> Self {
+        self.absolute_max_age = Some(time);
         self
     }
 
-    /// This value represents the offset duration for how often session purge for memory is ran.
+    /// Set's how long a blob made by `Session::export_json` can be imported for.
+    /// The blob carries when it was issued and when it expires, which are covered by its
+    /// signature or encryption. Defaults to 5 minutes.
     ///
     /// # Examples
     /// ```rust
     /// use axum_session::SessionConfig;
     /// use chrono::Duration;
     ///
-    /// let config = SessionConfig::default().with_purge_update(Duration::hours(1));
+    /// let config = SessionConfig::default().with_transfer_lifetime(Duration::minutes(2));
     /// ```
     ///
     #[must_use]
-    pub fn with_purge_update(mut self, duration: Duration) -> Self {
-        self.memory.purge_update = duration;
+    pub fn with_transfer_lifetime(mut self, time: Duration) -> Self {
+        self.transfer_lifespan = time;
         self
     }
 
-    /// This value represents the offset duration for how often session purge for database is ran.
-    /// If using Redis or any auto purge database this Setting will be ignored.
+    /// Set's the session's lifetime (expiration time) within memory storage.
+    /// This setting should be Less than lifespan and max_lifespan. This is to
+    /// Unload the data from memory and allow it to stay stored in the database.
+    ///
+    /// Set this to Duration::zero() if you dont want it to stay in memory.
+    /// Warning: This will cause it to be loaded from the database each request.
     ///
     /// # Examples
     /// ```rust
     /// use axum_session::SessionConfig;
     /// use chrono::Duration;
     ///
-    /// let config = SessionConfig::default().with_purge_database_update(Duration::hours(5));
+    /// let config = SessionConfig::default().with_memory_lifetime(Duration::days(32));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_memory_lifetime(mut self, time: Duration) -> Self {
+        self.memory.memory_lifespan = time;
+        self
+    }
+
+    /// This value represents the offset duration for how often session purge for memory is ran.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::SessionConfig;
+    /// use chrono::Duration;
+    ///
+    /// let config = SessionConfig::default().with_purge_update(Duration::hours(1));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_purge_update(mut self, duration: Duration) -> Self {
+        self.memory.purge_update = duration;
+        self
+    }
+
+    /// This value represents the offset duration for how often session purge for database is ran.
+    /// If using Redis or any auto purge database this Setting will be ignored.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::SessionConfig;
+    /// use chrono::Duration;
+    ///
+    /// let config = SessionConfig::default().with_purge_database_update(Duration::hours(5));
     /// ```
     ///
     #[must_use]
@@ -531,6 +1136,51 @@ impl SessionConfig {
         self
     }
 
+    /// Set's cookies to be Secure for every host except localhost, 127.0.0.1 and ::1 when enabled.
+    ///
+    /// The host is read from each request, so local HTTP testing and HTTPS production can share one
+    /// config. When enabled this overrides [`SessionConfig::with_secure`]. Disabled by default.
+    /// This does not apply to headers.
+    ///
+    /// As the Host header is chosen by the client, the exception only applies to requests whose
+    /// connection comes from a loopback address, read from `ConnectInfo<SocketAddr>`, and that carry
+    /// no `Forwarded`, `X-Forwarded-For` or `X-Real-IP` header. Serve the app using
+    /// `into_make_service_with_connect_info::<SocketAddr>()`, otherwise cookies stay Secure.
+    /// Cookies using the `__Host-` prefix from [`SessionConfig::with_prefix_with_host`] are kept
+    /// Secure, as browsers reject them otherwise.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::SessionConfig;
+    ///
+    /// let config = SessionConfig::default().with_secure_cookie_for_localhost_exception(true);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_secure_cookie_for_localhost_exception(mut self, enable: bool) -> Self {
+        self.cookie_and_header.secure_localhost_exception = enable;
+        self
+    }
+
+    /// Set's the session's transport precedence, enabling reading the Session from both cookies and headers.
+    ///
+    /// By default only cookies are read, or only headers when the `rest_mode` feature is enabled.
+    /// Once set both are read and the [`TransportPrecedence`] decides which is used when both name a Session.
+    /// Conflicts are logged with both IDs hashed. The response is only written to the transport used.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::{SessionConfig, TransportPrecedence};
+    ///
+    /// let config = SessionConfig::default().with_transport_precedence(TransportPrecedence::RejectConflicts);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_transport_precedence(mut self, precedence: TransportPrecedence) -> Self {
+        self.cookie_and_header.transport_precedence = Some(precedence);
+        self
+    }
+
     /// Set's the session's database table name.
     ///
     /// # Examples
@@ -546,6 +1196,46 @@ impl SessionConfig {
         self
     }
 
+    /// Set's the prefix of every key stored by key-value databases such as Redis, so several
+    /// applications can share one database without seeing each other's sessions.
+    /// Used instead of the table name when set. Databases with tables ignore it.
+    /// Redis rejects namespaces containing `:` or ending with `__counters` or `__rate`,
+    /// as their keys would mix with another namespace's sessions or counters.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::SessionConfig;
+    ///
+    /// let config = SessionConfig::default().with_key_namespace("billing_app");
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_key_namespace(mut self, namespace: impl Into<Cow<'static, str>>) -> Self {
+        self.database.key_namespace = Some(namespace.into());
+        self
+    }
+
+    /// Allows key-value databases to use an empty key namespace.
+    /// Without a namespace count and get_ids read every key in the database and
+    /// delete_all flushes the whole database, so only enable this if the database
+    /// holds nothing but this application's sessions.
+    /// Defaults to false, which makes [`crate::SessionStore::new`] fail with an empty namespace.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::SessionConfig;
+    ///
+    /// let config = SessionConfig::default()
+    ///     .with_table_name("")
+    ///     .with_allow_global_namespace(true);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_allow_global_namespace(mut self, allow: bool) -> Self {
+        self.database.allow_global_namespace = allow;
+        self
+    }
+
     /// Set's the session's cookie encyption key enabling private cookies.
     ///
     /// When Set it will enforce Private cookies across all Sessions.
@@ -554,8 +1244,6 @@ impl SessionConfig {
     /// For Extra Security Regenerate the key every so many months to a year.
     /// A new key will invalidate all old Sessions so it be wise to run session_store.clear_store() on reboot.
     ///
-    /// Must be Set to Some() in order to use Security::PerSession.
-    ///
     /// # Examples
     /// ```rust
     /// use axum_session::{Key, SessionConfig};
@@ -569,23 +1257,242 @@ impl SessionConfig {
         self
     }
 
-    /// Set's the session's database encyption key for per session key storage.
+    /// Set's the handler called when a session within the database could not be decrypted or deserialized.
+    ///
+    /// The handler receives the ID, payload and error so payloads can be quarantined for
+    /// inspection, and returns the [`CorruptAction`] to take. Without a handler corrupt rows
+    /// are deleted and a new session is created.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::{CorruptAction, SessionConfig};
+    /// use std::sync::Arc;
+    ///
+    /// let config = SessionConfig::default().with_corrupt_session_handler(Arc::new(|corrupt| {
+    ///     eprintln!("quarantined {}: {}", corrupt.id, corrupt.payload);
+    ///     CorruptAction::RecreateAndDelete
+    /// }));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_corrupt_session_handler(mut self, handler: CorruptSessionHandler) -> Self {
+        self.database.corrupt_session_handler = Some(handler);
+        self
+    }
+
+    /// Set's what happens when a session could not be loaded because the database failed.
+    ///
+    /// The default [`LoadFailureBehavior::FailRequest`] returns a 500 so users are not logged
+    /// out by a short outage. [`LoadFailureBehavior::ServeEphemeral`] serves an empty session
+    /// for the request instead, without overwriting the row or refreshing the cookie.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::{LoadFailureBehavior, SessionConfig};
+    ///
+    /// let config = SessionConfig::default()
+    ///     .with_load_failure_behavior(LoadFailureBehavior::ServeEphemeral);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_load_failure_behavior(mut self, behavior: LoadFailureBehavior) -> Self {
+        self.database.load_failure_behavior = behavior;
+        self
+    }
+
+    /// Set's the handler receiving the ids of the sessions removed by each database sweep.
     ///
-    /// Must be Set to Some() in order to use Security::PerSession or will panic if not.
+    /// It is called after the sweep made by a request and after every `SessionStore::cleanup` call
+    /// that removed at least one session. The handler is spawned onto the runtime rather than
+    /// awaited, so it never delays the request. On a current-thread runtime it runs once the
+    /// request yields, and not at all if the runtime shuts down first. The ids are the ones
+    /// stored within the database, which are hashed when `with_hashed_storage_ids` is used.
+    /// Databases that expire sessions on their own, like Redis, can not tell which sessions
+    /// expired so the handler is never called for them.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::SessionConfig;
+    /// use futures::FutureExt;
+    /// use std::sync::Arc;
+    ///
+    /// let config = SessionConfig::default().with_expired_sessions_handler(Arc::new(|ids| {
+    ///     async move {
+    ///         eprintln!("{} sessions expired", ids.len());
+    ///     }
+    ///     .boxed()
+    /// }));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_expired_sessions_handler(mut self, handler: ExpiredSessionsHandler) -> Self {
+        self.database.expired_sessions_handler = Some(handler);
+        self
+    }
+
+    /// Set's the maximum number of new sessions a single IP can create within the window across every instance.
+    ///
+    /// The counters are kept within the database using `DatabasePool::rate_increment` so instances sharing the
+    /// database share the limit. Only requests without a session cookie or header are counted and requests
+    /// past the limit receive 429 Too Many Requests. If the database can not count the request is allowed.
+    /// The IP is taken from the sources enabled by the `with_ip_and_user_agent` settings.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::SessionConfig;
+    /// use chrono::Duration;
+    ///
+    /// let config = SessionConfig::default().with_distributed_creation_limit(20, Duration::minutes(1));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_distributed_creation_limit(mut self, per_ip: u64, window: Duration) -> Self {
+        self.database.distributed_creation_limit = Some((per_ip, window));
+        self
+    }
+
+    /// Set's the longest Session ID in bytes, which should match the id column of the database.
+    ///
+    /// Longer IDs sent by requests are treated like an invalid Session, so a new one is created
+    /// without querying the database for them. Generated IDs that are longer are generated again
+    /// and `SessionError::IdTooLong` is returned if the generator keeps producing them.
+    /// Defaults to [`DEFAULT_MAX_ID_LENGTH`].
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::SessionConfig;
+    ///
+    /// let config = SessionConfig::default().with_max_id_length(64);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_max_id_length(mut self, length: usize) -> Self {
+        self.database.max_id_length = length;
+        self
+    }
+
+    /// Set's how many times the id generator may make an ID longer than the max id length
+    /// before `SessionError::IdTooLong` is returned. Must be at least 1. Defaults to 3.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::SessionConfig;
+    ///
+    /// let config = SessionConfig::default().with_id_length_attempts(5);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_id_length_attempts(mut self, attempts: usize) -> Self {
+        self.database.id_length_attempts = attempts;
+        self
+    }
+
+    /// Set's the session's database encyption key.
+    ///
+    /// When Set the Session data is encrypted using a [`StaticKeyCipher`] before it is stored
+    /// within the database, replacing any cipher set using `with_payload_cipher`.
+    /// The key also encrypts the blobs made by `Session::export_json`.
     ///
     /// # Examples
     /// ```rust
     /// use axum_session::{Key, SessionConfig};
     ///
-    /// let config = SessionConfig::default().with_key(Key::generate());
+    /// let config = SessionConfig::default().with_database_key(Key::generate());
     /// ```
     ///
     #[must_use]
     pub fn with_database_key(mut self, key: Key) -> Self {
+        self.database.payload_cipher = Some(Arc::new(StaticKeyCipher::new(key.clone())));
         self.database.database_key = Some(key);
         self
     }
 
+    /// Set's the cipher used to encrypt the session's data stored within the database.
+    ///
+    /// Replaces the cipher set by `with_database_key`, whose key still encrypts transfer blobs.
+    /// Rows stored by another cipher fail to load and are handled as corrupt sessions, unless
+    /// that cipher is added using `with_decrypt_cipher`.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::{Key, SessionConfig, StaticKeyCipher};
+    /// use std::sync::Arc;
+    ///
+    /// let cipher = StaticKeyCipher::new(Key::generate());
+    /// let config = SessionConfig::default().with_payload_cipher(Arc::new(cipher));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_payload_cipher(mut self, cipher: Arc<dyn PayloadCipher>) -> Self {
+        self.database.payload_cipher = Some(cipher);
+        self
+    }
+
+    /// Adds a cipher which only decrypts the rows it stored, found by its [`PayloadCipher::id`].
+    /// Used to rotate ciphers, as rows are encrypted with the payload cipher when next stored.
+    /// A [`StaticKeyCipher`] added here also reads rows stored before cipher ids.
+    ///
+    /// Needs a cipher set with `with_payload_cipher` or `with_database_key`, and every cipher
+    /// must have its own id.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::{Key, SessionConfig, StaticKeyCipher};
+    /// use std::sync::Arc;
+    ///
+    /// # let (old_key, new_cipher) = (Key::generate(), Arc::new(StaticKeyCipher::new(Key::generate())));
+    /// let config = SessionConfig::default()
+    ///     .with_payload_cipher(new_cipher)
+    ///     .with_decrypt_cipher(Arc::new(StaticKeyCipher::new(old_key)));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_decrypt_cipher(mut self, cipher: Arc<dyn PayloadCipher>) -> Self {
+        self.database.decrypt_ciphers.push(cipher);
+        self
+    }
+
+    /// Set's the session's database rows to be keyed by a hash of the session id.
+    ///
+    /// When enabled the database stores SHA-256(id + pepper) as the id while the
+    /// Cookie or Header still carries the raw id. This prevents anyone with read access
+    /// to the sessions table from correlating rows with live cookies or request logs.
+    /// The database encryption is also bound to the hashed id.
+    /// A pepper must be set using `with_storage_id_pepper` when enabled.
+    ///
+    /// Existing tables should be migrated using `SessionStore::rehash_existing_rows`.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::SessionConfig;
+    ///
+    /// let config = SessionConfig::default()
+    ///     .with_hashed_storage_ids(true)
+    ///     .with_storage_id_pepper("my secret pepper");
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_hashed_storage_ids(mut self, enable: bool) -> Self {
+        self.database.hashed_storage_ids = enable;
+        self
+    }
+
+    /// Set's the pepper used to hash the session ids stored within the database.
+    /// Keep this secret and do not change it or all stored sessions will be lost.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::SessionConfig;
+    ///
+    /// let config = SessionConfig::default().with_storage_id_pepper("my secret pepper");
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_storage_id_pepper(mut self, pepper: impl Into<Vec<u8>>) -> Self {
+        self.database.storage_id_pepper = Some(pepper.into());
+        self
+    }
+
     /// Set's the session's filters expected elements.
     /// Please Set this by a daily value.
     /// Example: 1000 * 60(secs) * 60(mins) * 24(hours) to get 1 days worth of visitors.
@@ -633,6 +1540,79 @@ impl SessionConfig {
         self
     }
 
+    /// Set's the maximum number of parallel requests a single session can have in flight.
+    /// Requests past the limit are rejected before reaching the inner service with the status
+    /// set by [`SessionConfig::with_parallel_limit_status`]. None disables the limit, which is the default.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::SessionConfig;
+    ///
+    /// let config = SessionConfig::default().with_max_parallel_requests(Some(16));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_max_parallel_requests(mut self, max: Option<usize>) -> Self {
+        self.memory.max_parallel_requests = max;
+        self
+    }
+
+    /// Set's the maximum number of sessions which can be pinned in memory at once using
+    /// [`crate::Session::pin_in_memory`]. Further pins return [`SessionError::PinnedSessionLimit`].
+    /// The default is 100.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::SessionConfig;
+    ///
+    /// let config = SessionConfig::default().with_max_pinned_sessions(10);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_max_pinned_sessions(mut self, max: usize) -> Self {
+        self.memory.max_pinned_sessions = max;
+        self
+    }
+
+    /// Set's when to warn about a session's stored payload growing quickly, which usually
+    /// means one key is collecting data without bound.
+    ///
+    /// A warning naming the keys that grew is logged when the size stored is more than factor
+    /// times the size of the oldest store within the window. Each session is warned about at
+    /// most once per window. The sizes of each session's latest stores are always kept and can
+    /// be read using [`crate::Session::size_history`] and
+    /// [`crate::SessionStore::largest_sessions`]. Disabled by default.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::SessionConfig;
+    /// use chrono::Duration;
+    ///
+    /// let config = SessionConfig::default().with_size_growth_warning(4.0, Duration::hours(1));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_size_growth_warning(mut self, factor: f64, window: Duration) -> Self {
+        self.memory.size_growth_warning = Some((factor, window));
+        self
+    }
+
+    /// Set's the status returned to requests rejected by [`SessionConfig::with_max_parallel_requests`].
+    /// Defaults to 429 Too Many Requests.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::SessionConfig;
+    ///
+    /// let config = SessionConfig::default().with_parallel_limit_status(http::StatusCode::SERVICE_UNAVAILABLE);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_parallel_limit_status(mut self, status: http::StatusCode) -> Self {
+        self.memory.parallel_limit_status = status;
+        self
+    }
+
     /// Get's the session's Cookie/Header name
     ///
     /// # Examples
@@ -646,6 +1626,20 @@ impl SessionConfig {
         self.cookie_and_header.session_name.to_string()
     }
 
+    /// Get's the longest Session ID in bytes the Session accepts.
+    /// Custom databases can use it to size their id column.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::SessionConfig;
+    ///
+    /// let length = SessionConfig::default().get_max_id_length();
+    /// ```
+    ///
+    pub fn get_max_id_length(&self) -> usize {
+        self.database.max_id_length
+    }
+
     /// Get's the session's store booleans Cookie/Header name
     ///
     /// # Examples
@@ -659,6 +1653,138 @@ impl SessionConfig {
         self.cookie_and_header.store_name.to_string()
     }
 
+    /// Validates the configuration returning the first problem found.
+    /// This is called by `SessionStore::new` so bad settings fail at start up
+    /// rather than silently producing Cookies or Headers that never get set.
+    pub(crate) fn validate(&self) -> std::result::Result<(), SessionError> {
+        validate_name("Session", &self.cookie_and_header.session_name)?;
+        validate_name("Store", &self.cookie_and_header.store_name)?;
+
+        let cookie_names = cookie_names(self);
+        for name in self
+            .cookie_and_header
+            .legacy_session_names
+            .iter()
+            .chain(&self.cookie_and_header.legacy_store_names)
+        {
+            validate_name("Legacy", name)?;
+
+            // The removal of the legacy Cookie would replace the Cookie being set.
+            if cookie_names.iter().any(|current| current == name) {
+                return Err(SessionError::InvalidConfiguration(format!(
+                    "Legacy name '{}' is still used by a current Cookie.",
+                    name
+                )));
+            }
+        }
+
+        if let Some(name) = &self.cookie_and_header.double_submit_cookie {
+            validate_name("Double submit", name)?;
+
+            if self.cookie_and_header.key.is_none() {
+                return Err(SessionError::InvalidConfiguration(
+                    "with_double_submit_cookie requires a key set with with_key.".to_owned(),
+                ));
+            }
+        }
+        RuntimeConfig::from(self).validate()?;
+
+        if self.transfer_lifespan <= Duration::zero() {
+            return Err(SessionError::InvalidConfiguration(
+                "transfer_lifespan must be positive.".to_owned(),
+            ));
+        }
+
+        if matches!(self.database.distributed_creation_limit, Some((per_ip, window)) if per_ip == 0 || window.num_seconds() < 1)
+        {
+            return Err(SessionError::InvalidConfiguration(
+                "distributed_creation_limit needs at least one session per window of one second or more."
+                    .to_owned(),
+            ));
+        }
+
+        if matches!(self.memory.size_growth_warning, Some((factor, window)) if !(factor > 1.0 && factor.is_finite()) || window <= Duration::zero())
+        {
+            return Err(SessionError::InvalidConfiguration(
+                "size_growth_warning needs a factor above 1 and a positive window.".to_owned(),
+            ));
+        }
+
+        if !self.database.decrypt_ciphers.is_empty() {
+            let Some(cipher) = &self.database.payload_cipher else {
+                return Err(SessionError::InvalidConfiguration(
+                    "with_decrypt_cipher requires a cipher set with with_payload_cipher or with_database_key."
+                        .to_owned(),
+                ));
+            };
+
+            let mut ids = vec![cipher.id()];
+            for id in self
+                .database
+                .decrypt_ciphers
+                .iter()
+                .map(|cipher| cipher.id())
+            {
+                if ids.contains(&id) {
+                    return Err(SessionError::InvalidConfiguration(format!(
+                        "Cipher id {} is used by more than one cipher.",
+                        id
+                    )));
+                }
+
+                ids.push(id);
+            }
+        }
+
+        if self.database.max_id_length == 0 {
+            return Err(SessionError::InvalidConfiguration(
+                "max_id_length must allow IDs of at least one byte.".to_owned(),
+            ));
+        }
+
+        if self.database.id_length_attempts == 0 {
+            return Err(SessionError::InvalidConfiguration(
+                "id_length_attempts must allow at least one attempt.".to_owned(),
+            ));
+        }
+
+        if self.memory.max_parallel_requests == Some(0) {
+            return Err(SessionError::InvalidConfiguration(
+                "max_parallel_requests must allow at least one request.".to_owned(),
+            ));
+        }
+
+        if self.database.hashed_storage_ids
+            && !matches!(&self.database.storage_id_pepper, Some(p) if !p.is_empty())
+        {
+            return Err(SessionError::InvalidConfiguration(
+                "with_hashed_storage_ids requires a pepper set with with_storage_id_pepper."
+                    .to_owned(),
+            ));
+        }
+
+        if let Some(max_age) = self.cookie_and_header.cookie_max_age {
+            if max_age < Duration::zero() || cookie_expires(max_age).is_none() {
+                return Err(SessionError::InvalidConfiguration(format!(
+                    "cookie_max_age of {} seconds must be positive and within the range of a Cookie expiry date.",
+                    max_age.num_seconds()
+                )));
+            }
+        }
+
+        if self.memory.filter_expected_elements == 0
+            || !(self.memory.filter_false_positive_probability > 0.0
+                && self.memory.filter_false_positive_probability < 1.0)
+        {
+            return Err(SessionError::InvalidConfiguration(
+                "the bloom filter needs at least one expected element and a false positive probability between 0 and 1."
+                    .to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
     /// Set's the session's loading to either true: unload data if checks fail or false: bypass.
     ///
     /// # Examples
@@ -674,6 +1800,32 @@ impl SessionConfig {
         self
     }
 
+    /// Set's what happens to the Session when the response is a redirect to another origin,
+    /// such as an OAuth provider or a payment page.
+    ///
+    /// With [`ExternalRedirectPolicy::FreezeSession`] responses with a 3xx status whose
+    /// `Location` names another origin than the request's leave the Session the request carried
+    /// as it is, so no freshly signed cookie travels along with the cross-origin navigation.
+    /// Origins compare the scheme, host and port, so `https://app.test:443` is the same origin
+    /// as `app.test` over https while `http://app.test` is not. The request's scheme is read from
+    /// its URI, then `X-Forwarded-Proto` or `Forwarded`, and otherwise is https when the cookies
+    /// are Secure. Relative locations are the same origin. Sessions created by the request still
+    /// get their cookie so they are not lost.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::{ExternalRedirectPolicy, SessionConfig};
+    ///
+    /// let config = SessionConfig::default()
+    ///     .with_external_redirect_policy(ExternalRedirectPolicy::FreezeSession);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_external_redirect_policy(mut self, policy: ExternalRedirectPolicy) -> Self {
+        self.cookie_and_header.external_redirect_policy = policy;
+        self
+    }
+
     /// Set's the session's prefix_with_host to either true: __Host- gets prefixed to the cookie names false: __Host- does not get prepended.
     ///
     /// __Host- prefix: Cookies with names starting with __Host- must be set with the secure flag, must be from a secure page (HTTPS),
@@ -727,6 +1879,46 @@ impl SessionConfig {
         self
     }
 
+    /// Set's the session's to read the Socket IP from the last `Forwarded` header's by= clause
+    /// when the request has no `ConnectInfo`.
+    ///
+    /// The by= clause names the interface the nearest proxy received the request on, so only
+    /// enable this behind a proxy which always sets it and strips it from incoming requests.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::SessionConfig;
+    ///
+    /// let config = SessionConfig::default().with_forwarded_by_as_socket_ip(true);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_forwarded_by_as_socket_ip(mut self, enable: bool) -> Self {
+        self.ip_user_agent.use_forwarded_by = enable;
+        self
+    }
+
+    /// Set's the session's to fail requests without a Socket IP while it is signed into the
+    /// cookies and headers, instead of logging a warning.
+    ///
+    /// The Socket IP comes from `ConnectInfo`, which only exists when the app is served using
+    /// `into_make_service_with_connect_info::<SocketAddr>()`. Served using `into_make_service()`
+    /// the IP is left out of the signature, and switching over later invalidates every cookie.
+    /// Strict deployments can enable this to fail the first request instead.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::SessionConfig;
+    ///
+    /// let config = SessionConfig::default().with_require_connect_info(true);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_require_connect_info(mut self, enable: bool) -> Self {
+        self.ip_user_agent.require_connect_info = enable;
+        self
+    }
+
     /// Set's the session's to use the xforward information when hashing and verifying the cookies integrity.
     /// Only if with_ip_and_user_agent is also enabled.
     ///
@@ -792,10 +1984,51 @@ impl SessionConfig {
     }
 }
 
+/// Checks that a Cookie or Header name only contains token characters
+/// as defined by RFC 7230 which both Cookies and Headers require.
+pub(crate) fn validate_name(kind: &str, name: &str) -> std::result::Result<(), SessionError> {
+    if name.is_empty() {
+        return Err(SessionError::InvalidConfiguration(format!(
+            "{} name can not be empty.",
+            kind
+        )));
+    }
+
+    if let Some(c) = name.chars().find(|c| {
+        !(c.is_ascii_alphanumeric()
+            || matches!(
+                c,
+                '!' | '#'
+                    | '$'
+                    | '%'
+                    | '&'
+                    | '\''
+                    | '*'
+                    | '+'
+                    | '-'
+                    | '.'
+                    | '^'
+                    | '_'
+                    | '`'
+                    | '|'
+                    | '~'
+            ))
+    }) {
+        return Err(SessionError::InvalidConfiguration(format!(
+            "{} name {:?} contains the invalid character {:?}.",
+            kind, name, c
+        )));
+    }
+
+    Ok(())
+}
+
 impl Default for SessionConfig {
     fn default() -> Self {
         Self {
             id_generator: Arc::new(Uuid::default()),
+            // The system clock. Only replaced to test time dependent behavior.
+            clock: Arc::new(SystemClock),
             // Set to a 6 hour default in Database Session stores unloading.
             lifespan: Duration::try_hours(6).unwrap_or_default(),
             cookie_and_header: CookieAndHeaderConfig::default(),
@@ -803,7 +2036,15 @@ impl Default for SessionConfig {
             memory: MemoryConfig::default(),
             // Unload long term session after 60 days if it has not been accessed.
             max_lifespan: Duration::try_days(60).unwrap_or_default(),
+            // Sessions live as long as they keep being used.
+            absolute_max_age: None,
+            // Transfer blobs are meant to be scanned right away.
+            transfer_lifespan: Duration::try_minutes(5).unwrap_or_default(),
             session_mode: SessionMode::Persistent,
+            auto_store_when_nonempty: false,
+            // Raw IDs keep logs easy to follow during development.
+            tracing_sensitive: false,
+            cache_key_salt: None,
             clear_check_on_load: true,
             ip_user_agent: IpUserAgentConfig::default(),
         }
@@ -824,6 +2065,12 @@ impl Default for MemoryConfig {
             filter_false_positive_probability: 0.01,
             // Always set to on.
             use_bloom_filters: true,
+            // Unlimited to keep long polling working as before.
+            max_parallel_requests: None,
+            parallel_limit_status: http::StatusCode::TOO_MANY_REQUESTS,
+            // Pinning is meant for a handful of long lived sessions.
+            max_pinned_sessions: 100,
+            size_growth_warning: None,
         }
     }
 }
@@ -833,11 +2080,25 @@ impl Default for DatabaseConfig {
         Self {
             // Set to a 6 hour default in Database Session stores unloading.
             table_name: "sessions".into(),
+            // Key-value databases use the table name as the namespace unless one is set.
+            key_namespace: None,
+            allow_global_namespace: false,
             // Default to purge old sessions in the database every 5 hours per request.
             purge_database_update: Duration::try_hours(5).unwrap_or_default(),
             always_save: false,
-            // Database key is set to None it will panic if you attempt to use SecurityMode::PerSession.
+            hashed_storage_ids: false,
+            storage_id_pepper: None,
+            corrupt_session_handler: None,
+            // Failing keeps users from being logged out by a short database outage.
+            load_failure_behavior: LoadFailureBehavior::FailRequest,
+            expired_sessions_handler: None,
+            distributed_creation_limit: None,
+            max_id_length: DEFAULT_MAX_ID_LENGTH,
+            id_length_attempts: 3,
+            // Session data is stored unencrypted unless a database key or cipher is set.
             database_key: None,
+            payload_cipher: None,
+            decrypt_ciphers: Vec::new(),
         }
     }
 }
@@ -846,15 +2107,23 @@ impl Default for CookieAndHeaderConfig {
     fn default() -> Self {
         Self {
             session_name: "session".into(),
+            legacy_session_names: Vec::new(),
+            legacy_store_names: Vec::new(),
             cookie_path: "/".into(),
             cookie_max_age: Some(Duration::try_days(100).unwrap_or_default()),
             cookie_http_only: true,
             cookie_secure: false,
+            // Opt in only so production hosts can never lose the Secure flag by default.
+            secure_localhost_exception: false,
+            // Only the transport selected by rest_mode so headers can not be injected into cookie sessions.
+            transport_precedence: None,
             cookie_domain: None,
             cookie_same_site: SameSite::Lax,
             store_name: "store".into(),
             // Key is set to None so Private cookies are not used by default. Please set this if you want to use private cookies.
             key: None,
+            double_submit_cookie: None,
+            external_redirect_policy: ExternalRedirectPolicy::Normal,
             prefix_with_host: false,
             with_ip_and_user_agent: true,
         }
@@ -865,6 +2134,8 @@ impl Default for IpUserAgentConfig {
     fn default() -> Self {
         Self {
             use_ip: true,
+            use_forwarded_by: false,
+            require_connect_info: false,
             use_xforward_ip: false,
             use_forward_ip: false,
             use_real_ip: false,
@@ -872,3 +2143,65 @@ impl Default for IpUserAgentConfig {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::SessionConfig;
+    use crate::SessionError;
+
+    #[test]
+    fn validate_names() {
+        assert!(SessionConfig::default().validate().is_ok());
+        assert!(SessionConfig::default()
+            .with_session_name("my-session_1")
+            .validate()
+            .is_ok());
+
+        for name in ["my session", "sess;ion", "", "tab\tname", "sess\u{e9}"] {
+            let err = SessionConfig::default()
+                .with_session_name(name)
+                .validate()
+                .unwrap_err();
+            assert!(matches!(err, SessionError::InvalidConfiguration(_)));
+        }
+
+        let err = SessionConfig::default()
+            .with_store_name("store=1")
+            .validate()
+            .unwrap_err();
+        assert!(err.to_string().contains("'='"));
+    }
+
+    #[test]
+    fn validate_legacy_names() {
+        assert!(SessionConfig::default()
+            .with_prefix_with_host(true)
+            .with_legacy_session_names(vec!["session".into()])
+            .validate()
+            .is_ok());
+
+        for config in [
+            SessionConfig::default().with_legacy_session_names(vec!["session".into()]),
+            SessionConfig::default().with_legacy_store_names(vec!["old store".into()]),
+        ] {
+            let err = config.validate().unwrap_err();
+            assert!(matches!(err, SessionError::InvalidConfiguration(_)));
+        }
+    }
+
+    #[test]
+    fn log_id() {
+        let id = "4a4e5d3b-6f1c-4d8e-9a2b-7c3d5e6f7a8b";
+        assert_eq!(SessionConfig::default().log_id(id), id);
+
+        let config = SessionConfig::default().with_tracing_sensitive(true);
+        let logged = config.log_id(id);
+        assert_eq!(logged.len(), 8);
+        assert!(!id.contains(logged.as_ref()));
+        // The same ID always logs the same so log lines can be correlated.
+        assert_eq!(config.log_id(id), logged);
+        assert_ne!(config.log_id("other"), logged);
+        // Errors log the same hash whether or not tracing is sensitive.
+        assert_eq!(SessionConfig::hashed_log_id(id), logged);
+    }
+}
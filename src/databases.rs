@@ -4,5 +4,11 @@ pub use self::any_db::*;
 mod null;
 pub use null::*;
 
+mod sharded;
+pub use sharded::*;
+
 mod database;
-pub use database::{DatabaseError, DatabasePool};
+pub use database::{DatabaseError, DatabasePool, IdsPage};
+
+#[cfg(any(test, feature = "bench"))]
+pub(crate) mod test_pool;
@@ -1,4 +1,4 @@
-use crate::{DatabaseError, DatabasePool, Session, SessionStore};
+use crate::{DatabaseError, DatabasePool, IdsPage, Session, SessionStore};
 use async_trait::async_trait;
 use std::fmt::Debug;
 use std::sync::Arc;
@@ -77,7 +77,87 @@ impl DatabasePool for SessionAnyPool {
         self.pool.get_ids(table_name).await
     }
 
+    async fn get_ids_page(
+        &self,
+        table_name: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<IdsPage, DatabaseError> {
+        self.pool.get_ids_page(table_name, cursor, limit).await
+    }
+
+    async fn count_active(
+        &self,
+        since: i64,
+        now: i64,
+        table_name: &str,
+    ) -> Result<Option<i64>, DatabaseError> {
+        self.pool.count_active(since, now, table_name).await
+    }
+
+    async fn touch_active(
+        &self,
+        id: &str,
+        now: i64,
+        table_name: &str,
+    ) -> Result<(), DatabaseError> {
+        self.pool.touch_active(id, now, table_name).await
+    }
+
+    async fn rate_increment(
+        &self,
+        bucket_key: &str,
+        window: i64,
+        table_name: &str,
+    ) -> Result<u64, DatabaseError> {
+        self.pool
+            .rate_increment(bucket_key, window, table_name)
+            .await
+    }
+
+    async fn counter_incr(
+        &self,
+        id: &str,
+        name: &str,
+        delta: i64,
+        expires: i64,
+        table_name: &str,
+    ) -> Result<Option<i64>, DatabaseError> {
+        self.pool
+            .counter_incr(id, name, delta, expires, table_name)
+            .await
+    }
+
+    async fn counter_get(
+        &self,
+        id: &str,
+        name: &str,
+        table_name: &str,
+    ) -> Result<Option<i64>, DatabaseError> {
+        self.pool.counter_get(id, name, table_name).await
+    }
+
+    async fn counter_rename(
+        &self,
+        id: &str,
+        new_id: &str,
+        expires: i64,
+        table_name: &str,
+    ) -> Result<(), DatabaseError> {
+        self.pool
+            .counter_rename(id, new_id, expires, table_name)
+            .await
+    }
+
     fn auto_handles_expiry(&self) -> bool {
         self.pool.auto_handles_expiry()
     }
+
+    fn uses_key_namespace(&self) -> bool {
+        self.pool.uses_key_namespace()
+    }
+
+    fn validate_table_name(&self, table_name: &str) -> Result<(), DatabaseError> {
+        self.pool.validate_table_name(table_name)
+    }
 }
@@ -29,7 +29,10 @@ pub trait DatabasePool {
     ) -> Result<(), DatabaseError>;
 
     /// This is called to receive the session from the database using the given table name.
-    /// if an error occurs it should be propagated to the caller.
+    /// Ok(None) must only be returned when the session does not exist or has expired.
+    /// Connection, query or decoding failures must be returned as an Err and never be
+    /// swallowed into Ok(None), as the Session service treats Ok(None) as a reason to
+    /// create a new Session while an Err will fail the request instead.
     async fn load(&self, id: &str, table_name: &str) -> Result<Option<String>, DatabaseError>;
 
     /// This is called to delete one session from the database using the given table name.
@@ -52,7 +55,158 @@ pub trait DatabasePool {
     /// if an error occurs it should be propagated to the caller.
     async fn get_ids(&self, table_name: &str) -> Result<Vec<String>, DatabaseError>;
 
+    /// This is called to get one page of the unexpired id's in the database.
+    /// Pass None as the cursor for the first page and the cursor returned with each page for
+    /// the next one, until a page is returned without a cursor. The cursor is opaque and only
+    /// valid for the database that returned it.
+    /// Pages may hold fewer than `limit` id's, or none, before the last one. Databases that page
+    /// by scanning, such as Redis, may return an id on more than one page.
+    ///
+    /// The default loads every id with `get_ids` on each call, so it is neither bounded in memory
+    /// nor linear in the number of pages. It is only a fallback for databases that can not page
+    /// within a query, which should override it.
+    /// if an error occurs it should be propagated to the caller.
+    async fn get_ids_page(
+        &self,
+        table_name: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<IdsPage, DatabaseError> {
+        let mut ids = self.get_ids(table_name).await?;
+        ids.sort_unstable();
+        ids.retain(|id| !cursor.is_some_and(|after| id.as_str() <= after));
+        let more = ids.len() > limit;
+        ids.truncate(limit);
+
+        Ok(IdsPage {
+            cursor: ids.last().filter(|_| more).cloned(),
+            ids,
+        })
+    }
+
+    /// This is called to count the unexpired sessions stored or touched at or after `since` using the given table name.
+    /// since and now are unix timestamps(number of non-leap seconds since January 1, 1970 0:00:00 UTC).
+    /// now comes from the SessionStore's clock and is used to leave out expired sessions.
+    /// Databases that track when a session was last stored should return Some(count) and implement `touch_active`.
+    /// The default returns Ok(None) which makes the SessionStore count the sessions within memory instead.
+    /// if an error occurs it should be propagated to the caller.
+    async fn count_active(
+        &self,
+        since: i64,
+        now: i64,
+        table_name: &str,
+    ) -> Result<Option<i64>, DatabaseError> {
+        let _ = (since, now, table_name);
+        Ok(None)
+    }
+
+    /// This is called to mark a session as active at now without storing it again, using the given table name.
+    /// now is a unix timestamp from the SessionStore's clock.
+    /// Used for requests that do not store the session so `count_active` still sees them.
+    /// The default does nothing.
+    /// if an error occurs it should be propagated to the caller.
+    async fn touch_active(
+        &self,
+        id: &str,
+        now: i64,
+        table_name: &str,
+    ) -> Result<(), DatabaseError> {
+        let _ = (id, now, table_name);
+        Ok(())
+    }
+
+    /// This is called to add one to the counter of bucket_key within the current window and return the new count.
+    /// window is the length of the window in seconds. Counters must reset once the window has passed.
+    /// Used for limits that need to be shared between every instance using the database.
+    /// The default returns a GenericNotSupportedError.
+    /// if an error occurs it should be propagated to the caller.
+    async fn rate_increment(
+        &self,
+        bucket_key: &str,
+        window: i64,
+        table_name: &str,
+    ) -> Result<u64, DatabaseError> {
+        let _ = (bucket_key, window, table_name);
+        Err(DatabaseError::GenericNotSupportedError(
+            "rate_increment is not supported by this database.".into(),
+        ))
+    }
+
+    /// This is called to add delta to the counter called name of the session and return its new value.
+    /// Counters start at 0 and are kept beside the session so they can change without storing it again.
+    /// expires is the session's expiry as given to store. Later calls to store must keep the counters
+    /// alive as long as the session, and delete_one_by_id, delete_all and delete_by_expiry must remove
+    /// them alongside it.
+    /// The default returns Ok(None) which makes the Session keep the counter within its data instead.
+    /// if an error occurs it should be propagated to the caller.
+    async fn counter_incr(
+        &self,
+        id: &str,
+        name: &str,
+        delta: i64,
+        expires: i64,
+        table_name: &str,
+    ) -> Result<Option<i64>, DatabaseError> {
+        let _ = (id, name, delta, expires, table_name);
+        Ok(None)
+    }
+
+    /// This is called to get the counter called name of the session, 0 if it was never incremented.
+    /// Must return Ok(None) whenever counter_incr does.
+    /// if an error occurs it should be propagated to the caller.
+    async fn counter_get(
+        &self,
+        id: &str,
+        name: &str,
+        table_name: &str,
+    ) -> Result<Option<i64>, DatabaseError> {
+        let _ = (id, name, table_name);
+        Ok(None)
+    }
+
+    /// This is called to move the counters of the session to new_id when the session is renewed,
+    /// before the old id is deleted. expires is the session's expiry as given to store.
+    /// The default does nothing, matching the default counter_incr.
+    /// if an error occurs it should be propagated to the caller.
+    async fn counter_rename(
+        &self,
+        id: &str,
+        new_id: &str,
+        expires: i64,
+        table_name: &str,
+    ) -> Result<(), DatabaseError> {
+        let _ = (id, new_id, expires, table_name);
+        Ok(())
+    }
+
     fn auto_handles_expiry(&self) -> bool;
+
+    /// Returns true for key-value databases without tables, which prefix every key with the
+    /// table name instead. The SessionStore then passes the key namespace as the table name and
+    /// refuses an empty one unless the global namespace is allowed, as an empty prefix makes
+    /// count, get_ids and delete_all act on the whole database.
+    /// The default returns false.
+    fn uses_key_namespace(&self) -> bool {
+        false
+    }
+
+    /// Checks that the database can use the table name, so names it would fail on or
+    /// misread are refused by `SessionStore::new` instead of failing at the first query.
+    /// The default accepts every name.
+    fn validate_table_name(&self, table_name: &str) -> Result<(), DatabaseError> {
+        let _ = table_name;
+        Ok(())
+    }
+}
+
+/// One page of id's returned by [`DatabasePool::get_ids_page`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdsPage {
+    /// The id's within the page.
+    pub ids: Vec<String>,
+    /// Passed to [`DatabasePool::get_ids_page`] to get the next page.
+    /// None once every id was returned.
+    pub cursor: Option<String>,
 }
 
 #[derive(Error, Debug)]
@@ -0,0 +1,414 @@
+use crate::{DatabaseError, DatabasePool, IdsPage, Session, SessionAnyPool, SessionStore};
+use async_trait::async_trait;
+use futures::future::try_join_all;
+use std::{fmt::Debug, sync::Arc};
+
+///Sharded Session Helper type for the DatabasePool.
+pub type SessionShardedSession<P> = Session<SessionShardedPool<P>>;
+///Sharded Session Store Helper type for the DatabasePool.
+pub type SessionShardedSessionStore<P> = SessionStore<SessionShardedPool<P>>;
+///Sharded pool whose shards can each use a different database backend.
+pub type SessionAnyShardedPool = SessionShardedPool<SessionAnyPool>;
+
+/// Used to pick which shard a Session ID is stored within.
+///
+/// The shard count is given so implementations like jump consistent hashing can be used.
+/// The returned index must be less than shard_count, otherwise it is wrapped around.
+pub trait ShardSelector: Debug + Send + Sync + 'static {
+    fn select(&self, id: &str, shard_count: usize) -> usize;
+}
+
+/// Default [`ShardSelector`] using a stable FNV-1a hash of the ID modulo the shard count.
+/// The hash does not change between releases or processes so rows can always be found again.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HashShardSelector;
+
+impl ShardSelector for HashShardSelector {
+    fn select(&self, id: &str, shard_count: usize) -> usize {
+        let hash = id.bytes().fold(0xcbf29ce484222325u64, |hash, b| {
+            (hash ^ b as u64).wrapping_mul(0x100000001b3)
+        });
+
+        (hash % shard_count.max(1) as u64) as usize
+    }
+}
+
+/// [`SessionShardedPool`] spreads Sessions across multiple database pools by their ID.
+///
+/// Operations on a single ID are routed to the shard picked by the [`ShardSelector`],
+/// as are `rate_increment` counters by their bucket key.
+/// Table wide operations such as `count`, `get_ids`, `delete_all` and `delete_by_expiry`
+/// are ran on every shard and their results merged. `count` is the sum across all shards.
+/// `get_ids_page` pages through one shard after another.
+///
+/// When a Session is renewed it gets a new ID which is routed on its own, so the
+/// Session may move to another shard. Rebalancing existing rows when the shard count
+/// changes is not handled. For the same reason [`crate::Session::counter`] keeps its
+/// counters within the Session data instead of using the shards' native counters.
+///
+/// # Examples
+/// ```rust ignore
+/// use axum_session::{SessionConfig, SessionShardedPool, SessionStore};
+/// use axum_session_sqlx::SessionPgPool;
+///
+/// let pool = SessionShardedPool::new(vec![pg_pool_a.into(), pg_pool_b.into()]);
+/// let session_store = SessionStore::<SessionShardedPool<SessionPgPool>>::new(Some(pool), SessionConfig::default())
+///     .await
+///     .unwrap();
+/// ```
+///
+/// Shards do not need to share a backend. Wrap each pool within a [`SessionAnyPool`]
+/// and use [`SessionAnyShardedPool`], `auto_handles_expiry` is then only true if every
+/// shard handles expiry on its own.
+///
+/// ```rust ignore
+/// use axum_session::{SessionAnyPool, SessionAnyShardedPool, SessionConfig, SessionStore};
+/// use axum_session_redispool::SessionRedisPool;
+/// use axum_session_sqlx::SessionPgPool;
+///
+/// let pool = SessionAnyShardedPool::new(vec![
+///     SessionAnyPool::new(SessionPgPool::from(pg_pool)),
+///     SessionAnyPool::new(SessionRedisPool::from(redis_pool)),
+/// ]);
+/// let session_store = SessionStore::new(Some(pool), SessionConfig::default())
+///     .await
+///     .unwrap();
+/// ```
+///
+#[derive(Clone)]
+pub struct SessionShardedPool<P>
+where
+    P: DatabasePool + Send + Sync,
+{
+    shards: Vec<P>,
+    selector: Arc<dyn ShardSelector>,
+}
+
+impl<P> SessionShardedPool<P>
+where
+    P: DatabasePool + Send + Sync,
+{
+    /// Creates a new Sharded pool using the [`HashShardSelector`].
+    pub fn new(shards: Vec<P>) -> Self {
+        Self {
+            shards,
+            selector: Arc::new(HashShardSelector),
+        }
+    }
+
+    /// Sets a custom [`ShardSelector`] used to route the Session IDs.
+    #[must_use]
+    pub fn with_selector(mut self, selector: impl ShardSelector) -> Self {
+        self.selector = Arc::new(selector);
+        self
+    }
+
+    /// Returns the shards within this pool.
+    pub fn shards(&self) -> &[P] {
+        &self.shards
+    }
+
+    /// Returns the index of the shard the ID is routed to.
+    pub fn shard_index(&self, id: &str) -> usize {
+        self.selector.select(id, self.shards.len()) % self.shards.len().max(1)
+    }
+
+    fn shard(&self, id: &str) -> Result<&P, DatabaseError> {
+        self.shards
+            .get(self.shard_index(id))
+            .ok_or_else(|| DatabaseError::GenericNotSupportedError("No shards were given.".into()))
+    }
+}
+
+impl<P> Debug for SessionShardedPool<P>
+where
+    P: DatabasePool + Send + Sync,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionShardedPool")
+            .field("shards", &self.shards.len())
+            .field("selector", &self.selector)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl<P> DatabasePool for SessionShardedPool<P>
+where
+    P: DatabasePool + Send + Sync,
+{
+    async fn initiate(&self, table_name: &str) -> Result<(), DatabaseError> {
+        if self.shards.is_empty() {
+            return Err(DatabaseError::GenericNotSupportedError(
+                "No shards were given.".into(),
+            ));
+        }
+
+        try_join_all(self.shards.iter().map(|s| s.initiate(table_name))).await?;
+        Ok(())
+    }
+
+    async fn count(&self, table_name: &str) -> Result<i64, DatabaseError> {
+        let counts = try_join_all(self.shards.iter().map(|s| s.count(table_name))).await?;
+        Ok(counts.into_iter().sum())
+    }
+
+    async fn store(
+        &self,
+        id: &str,
+        session: &str,
+        expires: i64,
+        table_name: &str,
+    ) -> Result<(), DatabaseError> {
+        self.shard(id)?
+            .store(id, session, expires, table_name)
+            .await
+    }
+
+    async fn load(&self, id: &str, table_name: &str) -> Result<Option<String>, DatabaseError> {
+        self.shard(id)?.load(id, table_name).await
+    }
+
+    async fn delete_one_by_id(&self, id: &str, table_name: &str) -> Result<(), DatabaseError> {
+        self.shard(id)?.delete_one_by_id(id, table_name).await
+    }
+
+    async fn exists(&self, id: &str, table_name: &str) -> Result<bool, DatabaseError> {
+        self.shard(id)?.exists(id, table_name).await
+    }
+
+    async fn delete_by_expiry(&self, table_name: &str) -> Result<Vec<String>, DatabaseError> {
+        let ids = try_join_all(self.shards.iter().map(|s| s.delete_by_expiry(table_name))).await?;
+        Ok(ids.into_iter().flatten().collect())
+    }
+
+    async fn delete_all(&self, table_name: &str) -> Result<(), DatabaseError> {
+        try_join_all(self.shards.iter().map(|s| s.delete_all(table_name))).await?;
+        Ok(())
+    }
+
+    async fn get_ids(&self, table_name: &str) -> Result<Vec<String>, DatabaseError> {
+        let ids = try_join_all(self.shards.iter().map(|s| s.get_ids(table_name))).await?;
+        Ok(ids.into_iter().flatten().collect())
+    }
+
+    async fn get_ids_page(
+        &self,
+        table_name: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<IdsPage, DatabaseError> {
+        // The cursor is the shard index followed by that shard's own cursor if it has one.
+        let (mut index, mut inner) = match cursor {
+            None => (0, None),
+            Some(cursor) => {
+                let (index, inner) = match cursor.split_once(':') {
+                    Some((index, inner)) => (index, Some(inner)),
+                    None => (cursor, None),
+                };
+                let index = index.parse::<usize>().map_err(|_| {
+                    DatabaseError::GenericSelectError(format!("invalid ids page cursor {cursor}"))
+                })?;
+                (index, inner)
+            }
+        };
+
+        while let Some(shard) = self.shards.get(index) {
+            let page = shard.get_ids_page(table_name, inner, limit).await?;
+
+            if let Some(next) = page.cursor {
+                return Ok(IdsPage {
+                    ids: page.ids,
+                    cursor: Some(format!("{index}:{next}")),
+                });
+            }
+
+            index += 1;
+            inner = None;
+
+            // Empty shards are skipped within the same call.
+            if !page.ids.is_empty() {
+                return Ok(IdsPage {
+                    ids: page.ids,
+                    cursor: (index < self.shards.len()).then(|| index.to_string()),
+                });
+            }
+        }
+
+        Ok(IdsPage::default())
+    }
+
+    async fn count_active(
+        &self,
+        since: i64,
+        now: i64,
+        table_name: &str,
+    ) -> Result<Option<i64>, DatabaseError> {
+        let counts = try_join_all(
+            self.shards
+                .iter()
+                .map(|s| s.count_active(since, now, table_name)),
+        )
+        .await?;
+        // A partial sum would undercount so fall back to memory if any shard can not count.
+        Ok(counts.into_iter().sum())
+    }
+
+    async fn touch_active(
+        &self,
+        id: &str,
+        now: i64,
+        table_name: &str,
+    ) -> Result<(), DatabaseError> {
+        self.shard(id)?.touch_active(id, now, table_name).await
+    }
+
+    async fn rate_increment(
+        &self,
+        bucket_key: &str,
+        window: i64,
+        table_name: &str,
+    ) -> Result<u64, DatabaseError> {
+        self.shard(bucket_key)?
+            .rate_increment(bucket_key, window, table_name)
+            .await
+    }
+
+    fn auto_handles_expiry(&self) -> bool {
+        self.shards.iter().all(|s| s.auto_handles_expiry())
+    }
+
+    fn uses_key_namespace(&self) -> bool {
+        self.shards.iter().any(|s| s.uses_key_namespace())
+    }
+
+    fn validate_table_name(&self, table_name: &str) -> Result<(), DatabaseError> {
+        self.shards
+            .iter()
+            .try_for_each(|s| s.validate_table_name(table_name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HashShardSelector, SessionAnyShardedPool, SessionShardedPool, ShardSelector};
+    use crate::{databases::test_pool::SessionTestPool, DatabasePool, SessionAnyPool};
+    use chrono::{Duration, Utc};
+
+    fn sharded() -> SessionShardedPool<SessionTestPool> {
+        SessionShardedPool::new(vec![
+            SessionTestPool::default(),
+            SessionTestPool::default(),
+            SessionTestPool::default(),
+        ])
+    }
+
+    #[test]
+    fn routing_is_stable() {
+        let pool = sharded();
+        let other = sharded();
+
+        for i in 0..100 {
+            let id = format!("session-{}", i);
+            assert_eq!(pool.shard_index(&id), other.shard_index(&id));
+            assert_eq!(pool.shard_index(&id), HashShardSelector.select(&id, 3));
+        }
+
+        // Known value so the hash never silently changes.
+        assert_eq!(HashShardSelector.select("session-0", 3), 0);
+    }
+
+    #[tokio::test]
+    async fn fan_out() {
+        let pool = sharded();
+        let expires = (Utc::now() + Duration::try_hours(1).unwrap()).timestamp();
+
+        for i in 0..30 {
+            let id = format!("session-{}", i);
+            pool.store(&id, "{}", expires, "sessions").await.unwrap();
+
+            let index = pool.shard_index(&id);
+            assert!(pool.shards()[index].row("sessions", &id).is_some());
+            assert!(pool.exists(&id, "sessions").await.unwrap());
+        }
+
+        assert!(pool.shards().iter().all(|s| !s.rows.is_empty()));
+        assert_eq!(pool.count("sessions").await.unwrap(), 30);
+
+        let mut ids = pool.get_ids("sessions").await.unwrap();
+        ids.sort();
+        let mut expected: Vec<String> = (0..30).map(|i| format!("session-{}", i)).collect();
+        expected.sort();
+        assert_eq!(ids, expected.clone());
+
+        // Pages run through the shards one after another without gaps or repeats.
+        let mut paged = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = pool
+                .get_ids_page("sessions", cursor.as_deref(), 7)
+                .await
+                .unwrap();
+            assert!(page.ids.len() <= 7);
+            paged.extend(page.ids);
+            cursor = page.cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        paged.sort();
+        assert_eq!(paged, expected);
+        assert!(pool.get_ids_page("sessions", Some("x"), 7).await.is_err());
+
+        pool.delete_all("sessions").await.unwrap();
+        assert_eq!(pool.count("sessions").await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn renewed_ids_route_on_their_own() {
+        let pool = sharded();
+        let expires = (Utc::now() + Duration::try_hours(1).unwrap()).timestamp();
+
+        pool.store("old-id", "{}", expires, "sessions")
+            .await
+            .unwrap();
+        // A renewal deletes the old id then stores under the new one.
+        pool.delete_one_by_id("old-id", "sessions").await.unwrap();
+        pool.store("new-id", "{}", expires, "sessions")
+            .await
+            .unwrap();
+
+        let index = pool.shard_index("new-id");
+        assert!(pool.shards()[index].row("sessions", "new-id").is_some());
+        assert_eq!(pool.count("sessions").await.unwrap(), 1);
+        assert!(pool.load("new-id", "sessions").await.unwrap().is_some());
+        assert!(pool.load("old-id", "sessions").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn dyn_shards() {
+        let first = SessionTestPool::default();
+        let second = SessionTestPool::default();
+        let pool = SessionAnyShardedPool::new(vec![
+            SessionAnyPool::new(first.clone()),
+            SessionAnyPool::new(second.clone()),
+        ]);
+        let expires = (Utc::now() + Duration::try_hours(1).unwrap()).timestamp();
+
+        for i in 0..20 {
+            let id = format!("session-{}", i);
+            pool.store(&id, "{}", expires, "sessions").await.unwrap();
+
+            let owner = [&first, &second][pool.shard_index(&id)];
+            assert!(owner.row("sessions", &id).is_some());
+            assert_eq!(
+                pool.load(&id, "sessions").await.unwrap().as_deref(),
+                Some("{}")
+            );
+        }
+
+        assert!(!first.rows.is_empty() && !second.rows.is_empty());
+        assert_eq!(pool.count("sessions").await.unwrap(), 20);
+        assert!(!pool.auto_handles_expiry());
+    }
+}
@@ -0,0 +1,299 @@
+use crate::{DatabaseError, DatabasePool};
+use async_trait::async_trait;
+use chrono::Utc;
+use dashmap::DashMap;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+
+/// Table name, id and name of a native counter.
+type CounterKey = (String, String, String);
+
+/// In Memory Pool used by the crates tests to act like a persistent database.
+/// Rows are keyed by table name and id and hold the raw session string and its expiry.
+#[derive(Debug, Clone, Default)]
+pub struct SessionTestPool {
+    pub(crate) rows: Arc<DashMap<(String, String), (String, i64)>>,
+    /// When set every load fails like a lost database connection.
+    pub(crate) fail_loads: Arc<AtomicBool>,
+    /// When set every store fails like a lost database connection.
+    pub(crate) fail_stores: Arc<AtomicBool>,
+    /// Rate counters keyed by table name, bucket key and window bucket.
+    pub(crate) rates: Arc<DashMap<(String, String, i64), u64>>,
+    /// When set every rate_increment fails like a lost database connection.
+    pub(crate) fail_rates: Arc<AtomicBool>,
+    /// When set the pool acts like a key-value database using the key namespace.
+    pub(crate) key_value: bool,
+    /// When set the pool claims to expire sessions on its own like Redis.
+    pub(crate) auto_expiry: bool,
+    /// Native counters holding their value and expiry.
+    pub(crate) counters: Arc<DashMap<CounterKey, (i64, i64)>>,
+    /// When set the pool keeps counters natively instead of within the session data.
+    pub(crate) native_counters: bool,
+    /// Ids passed to load and exists in the order they were queried.
+    pub(crate) lookups: Arc<Mutex<Vec<String>>>,
+    /// Ids and times passed to touch_active in the order they were touched.
+    pub(crate) touches: Arc<Mutex<Vec<(String, i64)>>>,
+}
+
+impl SessionTestPool {
+    /// Inserts a row directly into the pool bypassing the SessionStore.
+    pub(crate) fn insert_row(&self, table_name: &str, id: &str, session: &str, expires: i64) {
+        self.rows.insert(
+            (table_name.to_owned(), id.to_owned()),
+            (session.to_owned(), expires),
+        );
+    }
+
+    /// Returns the native counter stored for the id if any.
+    pub(crate) fn counter(&self, table_name: &str, id: &str, name: &str) -> Option<(i64, i64)> {
+        self.counters
+            .get(&(table_name.to_owned(), id.to_owned(), name.to_owned()))
+            .map(|r| *r.value())
+    }
+
+    /// Returns the raw row stored for the id if any.
+    pub(crate) fn row(&self, table_name: &str, id: &str) -> Option<(String, i64)> {
+        self.rows
+            .get(&(table_name.to_owned(), id.to_owned()))
+            .map(|r| r.value().clone())
+    }
+}
+
+#[async_trait]
+impl DatabasePool for SessionTestPool {
+    async fn initiate(&self, _table_name: &str) -> Result<(), DatabaseError> {
+        Ok(())
+    }
+
+    async fn count(&self, table_name: &str) -> Result<i64, DatabaseError> {
+        Ok(self.rows.iter().filter(|r| r.key().0 == table_name).count() as i64)
+    }
+
+    async fn store(
+        &self,
+        id: &str,
+        session: &str,
+        expires: i64,
+        table_name: &str,
+    ) -> Result<(), DatabaseError> {
+        if self.fail_stores.load(Ordering::Relaxed) {
+            return Err(DatabaseError::GenericAcquire("connection refused".into()));
+        }
+
+        self.insert_row(table_name, id, session, expires);
+
+        for mut counter in self.counters.iter_mut() {
+            if counter.key().0 == table_name && counter.key().1 == id {
+                counter.value_mut().1 = expires;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn load(&self, id: &str, table_name: &str) -> Result<Option<String>, DatabaseError> {
+        self.lookups.lock().unwrap().push(id.to_owned());
+
+        if self.fail_loads.load(Ordering::Relaxed) {
+            return Err(DatabaseError::GenericAcquire("connection refused".into()));
+        }
+
+        let now = Utc::now().timestamp();
+
+        Ok(self
+            .row(table_name, id)
+            .filter(|(_, expires)| *expires > now)
+            .map(|(session, _)| session))
+    }
+
+    async fn delete_one_by_id(&self, id: &str, table_name: &str) -> Result<(), DatabaseError> {
+        self.rows.remove(&(table_name.to_owned(), id.to_owned()));
+        self.counters.retain(|k, _| k.0 != table_name || k.1 != id);
+        Ok(())
+    }
+
+    async fn exists(&self, id: &str, table_name: &str) -> Result<bool, DatabaseError> {
+        self.lookups.lock().unwrap().push(id.to_owned());
+        let now = Utc::now().timestamp();
+
+        Ok(self
+            .row(table_name, id)
+            .is_some_and(|(_, expires)| expires > now))
+    }
+
+    async fn delete_by_expiry(&self, table_name: &str) -> Result<Vec<String>, DatabaseError> {
+        let now = Utc::now().timestamp();
+        let expired: Vec<String> = self
+            .rows
+            .iter()
+            .filter(|r| r.key().0 == table_name && r.value().1 < now)
+            .map(|r| r.key().1.clone())
+            .collect();
+
+        for id in &expired {
+            self.rows.remove(&(table_name.to_owned(), id.clone()));
+        }
+
+        self.counters.retain(|k, v| k.0 != table_name || v.1 >= now);
+
+        Ok(expired)
+    }
+
+    async fn delete_all(&self, table_name: &str) -> Result<(), DatabaseError> {
+        self.rows.retain(|k, _| k.0 != table_name);
+        self.counters.retain(|k, _| k.0 != table_name);
+        Ok(())
+    }
+
+    async fn get_ids(&self, table_name: &str) -> Result<Vec<String>, DatabaseError> {
+        let now = Utc::now().timestamp();
+
+        Ok(self
+            .rows
+            .iter()
+            .filter(|r| r.key().0 == table_name && r.value().1 > now)
+            .map(|r| r.key().1.clone())
+            .collect())
+    }
+
+    async fn rate_increment(
+        &self,
+        bucket_key: &str,
+        window: i64,
+        table_name: &str,
+    ) -> Result<u64, DatabaseError> {
+        if self.fail_rates.load(Ordering::Relaxed) {
+            return Err(DatabaseError::GenericAcquire("connection refused".into()));
+        }
+
+        let bucket = Utc::now().timestamp() / window;
+        let mut count = self
+            .rates
+            .entry((table_name.to_owned(), bucket_key.to_owned(), bucket))
+            .or_insert(0);
+        *count += 1;
+        Ok(*count)
+    }
+
+    async fn counter_incr(
+        &self,
+        id: &str,
+        name: &str,
+        delta: i64,
+        expires: i64,
+        table_name: &str,
+    ) -> Result<Option<i64>, DatabaseError> {
+        if !self.native_counters {
+            return Ok(None);
+        }
+
+        let mut counter = self
+            .counters
+            .entry((table_name.to_owned(), id.to_owned(), name.to_owned()))
+            .or_insert((0, expires));
+        counter.0 += delta;
+        counter.1 = expires;
+        Ok(Some(counter.0))
+    }
+
+    async fn counter_get(
+        &self,
+        id: &str,
+        name: &str,
+        table_name: &str,
+    ) -> Result<Option<i64>, DatabaseError> {
+        Ok(self.native_counters.then(|| {
+            self.counter(table_name, id, name)
+                .map_or(0, |(value, _)| value)
+        }))
+    }
+
+    async fn counter_rename(
+        &self,
+        id: &str,
+        new_id: &str,
+        expires: i64,
+        table_name: &str,
+    ) -> Result<(), DatabaseError> {
+        let names: Vec<String> = self
+            .counters
+            .iter()
+            .filter(|r| r.key().0 == table_name && r.key().1 == id)
+            .map(|r| r.key().2.clone())
+            .collect();
+
+        for name in names {
+            if let Some((_, (value, _))) =
+                self.counters
+                    .remove(&(table_name.to_owned(), id.to_owned(), name.clone()))
+            {
+                self.counters.insert(
+                    (table_name.to_owned(), new_id.to_owned(), name),
+                    (value, expires),
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn touch_active(
+        &self,
+        id: &str,
+        now: i64,
+        _table_name: &str,
+    ) -> Result<(), DatabaseError> {
+        self.touches.lock().unwrap().push((id.to_owned(), now));
+        Ok(())
+    }
+
+    fn auto_handles_expiry(&self) -> bool {
+        self.auto_expiry
+    }
+
+    fn uses_key_namespace(&self) -> bool {
+        self.key_value
+    }
+}
+
+/// Clock whose wall and monotonic time only move when the test moves them.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub(crate) struct FakeClock {
+    pub(crate) wall: Arc<std::sync::Mutex<chrono::DateTime<chrono::Utc>>>,
+    base: std::time::Instant,
+    offset: Arc<std::sync::Mutex<std::time::Duration>>,
+}
+
+#[cfg(test)]
+impl FakeClock {
+    pub(crate) fn new() -> Self {
+        Self {
+            wall: Arc::new(std::sync::Mutex::new(chrono::Utc::now())),
+            base: std::time::Instant::now(),
+            offset: Default::default(),
+        }
+    }
+
+    pub(crate) fn step_wall(&self, duration: chrono::Duration) {
+        *self.wall.lock().unwrap() += duration;
+    }
+
+    pub(crate) fn advance(&self, duration: chrono::Duration) {
+        *self.offset.lock().unwrap() += duration.to_std().unwrap();
+        self.step_wall(duration);
+    }
+}
+
+#[cfg(test)]
+impl crate::Clock for FakeClock {
+    fn now(&self) -> chrono::DateTime<chrono::Utc> {
+        *self.wall.lock().unwrap()
+    }
+
+    fn instant(&self) -> std::time::Instant {
+        self.base + *self.offset.lock().unwrap()
+    }
+}
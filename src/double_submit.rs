@@ -0,0 +1,212 @@
+use axum::extract::FromRequestParts;
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use cookie::Key;
+use hmac::{Hmac, Mac};
+use http::{request::Parts, StatusCode};
+use sha2::Sha256;
+
+/// Name of the Header the double-submit token must be sent back within.
+pub const DOUBLE_SUBMIT_HEADER: &str = "x-csrf-token";
+
+/// Separates the double-submit MAC from the other MACs made with the same key.
+const DOUBLE_SUBMIT_CONTEXT: &[u8] = b"axum_session double submit:";
+
+fn double_submit_mac(key: &Key, id: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.signing()).expect("HMAC accepts keys of any length");
+    mac.update(DOUBLE_SUBMIT_CONTEXT);
+    mac.update(id.as_bytes());
+    mac
+}
+
+/// Creates the double-submit token bound to the Session ID.
+pub(crate) fn double_submit_token(key: &Key, id: &str) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(double_submit_mac(key, id).finalize().into_bytes())
+}
+
+/// Checks in constant time that the token was made for the Session ID.
+pub(crate) fn verify_double_submit_token(key: &Key, id: &str, token: &str) -> bool {
+    BASE64_URL_SAFE_NO_PAD
+        .decode(token)
+        .is_ok_and(|digest| double_submit_mac(key, id).verify_slice(&digest).is_ok())
+}
+
+/// What [`DoubleSubmitGuard`] needs to verify a request.
+/// Set by the Session service when the double-submit Cookie is enabled.
+#[derive(Clone)]
+pub(crate) struct DoubleSubmitContext {
+    pub(crate) cookie_name: String,
+    pub(crate) key: Key,
+    pub(crate) id: String,
+}
+
+/// Extractor verifying the double-submit CSRF token of a request.
+///
+/// The `x-csrf-token` Header and the Cookie set by [`crate::SessionConfig::with_double_submit_cookie`]
+/// must both hold the token made for the request's Session ID. Otherwise the request is
+/// rejected with `403 Forbidden`. Add it to the handlers that change state.
+///
+/// # Examples
+/// ```rust ignore
+/// async fn transfer(_: DoubleSubmitGuard, session: Session<SessionNullPool>) {
+///     // Only reached when the token matched.
+/// }
+/// ```
+///
+#[derive(Debug, Clone, Copy)]
+pub struct DoubleSubmitGuard;
+
+impl<S> FromRequestParts<S> for DoubleSubmitGuard
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let context = parts.extensions.get::<DoubleSubmitContext>().ok_or((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Can't extract DoubleSubmitGuard. Is `SessionLayer` enabled with a double submit cookie?",
+        ))?;
+        let rejected = (StatusCode::FORBIDDEN, "CSRF token missing or invalid.");
+
+        let header = parts
+            .headers
+            .get(DOUBLE_SUBMIT_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .ok_or(rejected)?;
+        let cookies = crate::headers::get_cookies(&parts.headers);
+        let cookie = cookies.get(&context.cookie_name).ok_or(rejected)?;
+
+        if verify_double_submit_token(&context.key, &context.id, header)
+            && verify_double_submit_token(&context.key, &context.id, cookie.value())
+        {
+            Ok(DoubleSubmitGuard)
+        } else {
+            Err(rejected)
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "rest_mode")))]
+mod tests {
+    use super::DOUBLE_SUBMIT_HEADER;
+    use crate::databases::test_pool::SessionTestPool;
+    use crate::{DoubleSubmitGuard, Key, Session, SessionConfig, SessionLayer, SessionStore};
+    use axum::{body::Body, routing::get, Router};
+    use http::{header, Request, Response, StatusCode};
+    use tower::ServiceExt;
+
+    /// Returns the `name=value` pairs of every cookie set by the response.
+    fn response_cookies<B>(res: &Response<B>) -> Vec<String> {
+        res.headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok()?.split(';').next().map(str::to_owned))
+            .collect()
+    }
+
+    fn csrf_cookie(cookies: &[String]) -> String {
+        cookies
+            .iter()
+            .find_map(|c| c.strip_prefix("csrf="))
+            .unwrap()
+            .to_owned()
+    }
+
+    async fn app() -> (Router, SessionStore<SessionTestPool>) {
+        let config = SessionConfig::default()
+            .with_key(Key::generate())
+            .with_prefix_with_host(false)
+            .with_double_submit_cookie("csrf");
+        let store = SessionStore::<SessionTestPool>::new(None, config)
+            .await
+            .unwrap();
+        let app = Router::new()
+            .route("/", get(|| async {}))
+            .route(
+                "/renew",
+                get(|session: Session<SessionTestPool>| async move { session.renew() }),
+            )
+            .route("/protected", get(|_: DoubleSubmitGuard| async {}))
+            .layer(SessionLayer::new(store.clone()));
+
+        (app, store)
+    }
+
+    async fn send(
+        app: &Router,
+        path: &str,
+        cookies: &[String],
+        token: Option<&str>,
+    ) -> Response<Body> {
+        let mut req = Request::get(path).header(header::COOKIE, cookies.join("; "));
+
+        if let Some(token) = token {
+            req = req.header(DOUBLE_SUBMIT_HEADER, token);
+        }
+
+        app.clone()
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn double_submit_guard() {
+        let (app, store) = app().await;
+        let cookies = response_cookies(&send(&app, "/", &[], None).await);
+        let token = csrf_cookie(&cookies);
+
+        // The token is bound to the id but never is the id itself.
+        let id = store.inner.iter().next().unwrap().key().clone();
+        assert!(!token.contains(&id));
+        assert!(cookies.iter().any(|c| c.starts_with("session=")));
+
+        let status = |res: Response<Body>| res.status();
+        assert_eq!(
+            status(send(&app, "/protected", &cookies, None).await),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(send(&app, "/protected", &cookies, Some("mismatched")).await),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(send(&app, "/protected", &cookies, Some(&token)).await),
+            StatusCode::OK
+        );
+
+        // Renewing rotates the token and the old one stops working.
+        let renewed = response_cookies(&send(&app, "/renew", &cookies, None).await);
+        let rotated = csrf_cookie(&renewed);
+        assert_ne!(rotated, token);
+        assert_eq!(
+            status(send(&app, "/protected", &renewed, Some(&token)).await),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(send(&app, "/protected", &renewed, Some(&rotated)).await),
+            StatusCode::OK
+        );
+
+        // A token of another Session does not work with this Session's Cookies.
+        let other = token_of_new_session(&app).await;
+        assert_eq!(
+            status(send(&app, "/protected", &renewed, Some(&other)).await),
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    async fn token_of_new_session(app: &Router) -> String {
+        csrf_cookie(&response_cookies(&send(app, "/", &[], None).await))
+    }
+
+    #[tokio::test]
+    async fn double_submit_requires_key() {
+        let config = SessionConfig::default().with_double_submit_cookie("csrf");
+        let err = SessionStore::<SessionTestPool>::new(None, config)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, crate::SessionError::InvalidConfiguration(_)));
+    }
+}
@@ -20,11 +20,39 @@ pub enum SessionError {
     Unknown,
     #[error("{0}")]
     GenericNotSupportedError(String),
+    #[error("Invalid Session configuration: {0}")]
+    InvalidConfiguration(String),
     #[error("Session was not found. Either the session was unloaded or was never created.")]
     NoSessionError,
+    #[error("Session has too many parallel requests.")]
+    ParallelRequestLimit,
+    #[error("Too many Sessions are pinned in memory.")]
+    PinnedSessionLimit,
+    #[error("Session data within the database could not be read.")]
+    CorruptSession,
+    #[error("Session transfer data failed to verify or could not be read.")]
+    InvalidTransfer,
+    #[error("The id generator made an ID of {0} bytes which is longer than the maximum of {1}.")]
+    IdTooLong(usize, usize),
     #[error(
         "The Session Exists but is outdated, either renew it or remove it. \n
     Session will get removed on next Session request purge update if no changes are done."
     )]
     OldSessionError,
 }
+
+impl SessionError {
+    /// Returns true if the error came from the database or IO and may pass on its own,
+    /// so retrying the same operation later could succeed.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::SessionError;
+    ///
+    /// assert!(!SessionError::CorruptSession.is_retryable());
+    /// ```
+    ///
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, SessionError::DatabaseError(_) | SessionError::IO(_))
+    }
+}
@@ -1,108 +1,303 @@
-#[cfg(not(feature = "rest_mode"))]
-use crate::CookiesAdditionJar;
-use crate::{DatabasePool, Session, SessionConfig, SessionStore};
-#[cfg(not(feature = "rest_mode"))]
-use cookie::{Cookie, CookieJar, Key};
-use forwarded_header_value::{ForwardedHeaderValue, Identifier};
-#[cfg(not(feature = "rest_mode"))]
-use http::header::{COOKIE, SET_COOKIE};
-#[cfg(feature = "rest_mode")]
-use http::{header::HeaderName, HeaderValue};
+use crate::{
+    config::IpUserAgentConfig,
+    double_submit::{double_submit_token, DOUBLE_SUBMIT_HEADER},
+    sec::{hash::hash_id, sign_header, verify_header},
+    CookiesAdditionJar, DatabasePool, SessionConfig, SessionError, SessionStore,
+    TransportPrecedence,
+};
+use cookie::{time::OffsetDateTime, Cookie, CookieJar, Key};
+use forwarded_header_value::{ForwardedHeaderValue, Identifier, Protocol};
 use http::{
-    header::{FORWARDED, USER_AGENT},
+    header::{HeaderName, COOKIE, FORWARDED, HOST, LOCATION, SET_COOKIE, USER_AGENT},
     request::Request,
-    HeaderMap,
+    HeaderMap, HeaderValue, StatusCode,
 };
-#[cfg(feature = "rest_mode")]
-use std::collections::HashMap;
 use std::{
+    collections::HashMap,
     fmt::Debug,
     net::{IpAddr, SocketAddr},
+    sync::atomic::Ordering,
 };
 
 const X_REAL_IP: &str = "x-real-ip";
 const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+/// Milliseconds between the warnings about requests without a Socket IP.
+const CONNECT_INFO_WARNING_INTERVAL: i64 = 5 * 60 * 1000;
+
+/// Where the Session ID is read from and written back to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Transport {
+    Cookie,
+    Header,
+}
+
+impl Transport {
+    /// The transport selected by the `rest_mode` feature.
+    pub(crate) const PRIMARY: Transport = if cfg!(feature = "rest_mode") {
+        Transport::Header
+    } else {
+        Transport::Cookie
+    };
+}
 
 enum NameType {
     Store,
     Data,
+    DoubleSubmit,
 }
 
 impl NameType {
     #[inline]
-    pub(crate) fn get_name(&self, config: &SessionConfig) -> String {
+    pub(crate) fn get_name(&self, config: &SessionConfig, transport: Transport) -> String {
         let name = match self {
             NameType::Data => config.cookie_and_header.session_name.to_string(),
             NameType::Store => config.cookie_and_header.store_name.to_string(),
+            NameType::DoubleSubmit => config
+                .cookie_and_header
+                .double_submit_cookie
+                .as_deref()
+                .unwrap_or_default()
+                .to_owned(),
         };
 
-        #[cfg(not(feature = "rest_mode"))]
-        if config.cookie_and_header.prefix_with_host {
+        if transport == Transport::Cookie && config.cookie_and_header.prefix_with_host {
             let mut prefixed = "__Host-".to_owned();
             prefixed.push_str(&name);
             prefixed
         } else {
             name
         }
+    }
+}
+
+/// Returns the names of every Header that can carry a Session ID or token for the config.
+///
+/// This is the session and store Header names used by `rest_mode` and header transports,
+/// the double-submit Header when enabled, and `Cookie` and `Set-Cookie`. Cookie names such
+/// as the `__Host-` prefixed ones travel within the Cookie Headers, so they are covered by those.
+/// Pass the list to tower-http's `SetSensitiveHeadersLayer` so logging layers redact them.
+///
+/// # Examples
+/// ```rust ignore
+/// use tower_http::sensitive_headers::SetSensitiveHeadersLayer;
+///
+/// let layer = SetSensitiveHeadersLayer::new(axum_session::sensitive_headers(&config));
+/// ```
+///
+pub fn sensitive_headers(config: &SessionConfig) -> Vec<HeaderName> {
+    let mut names = vec![COOKIE, SET_COOKIE];
+
+    for name in [NameType::Data, NameType::Store]
+        .map(|name_type| name_type.get_name(config, Transport::Header))
+        .into_iter()
+        .chain(
+            config
+                .cookie_and_header
+                .double_submit_cookie
+                .as_ref()
+                .map(|_| DOUBLE_SUBMIT_HEADER.to_owned()),
+        )
+    {
+        if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+
+    names
+}
 
-        #[allow(clippy::let_and_return)]
-        #[cfg(feature = "rest_mode")]
-        name
+/// Marks the values of the named Headers as sensitive.
+pub(crate) fn mark_sensitive(headers: &mut HeaderMap, names: &[HeaderName]) {
+    for (name, value) in headers.iter_mut() {
+        if names.contains(name) {
+            value.set_sensitive(true);
+        }
     }
 }
 
-#[cfg(not(feature = "rest_mode"))]
-pub async fn get_headers_and_key<T>(
+/// The name of the double-submit Cookie including any host prefix, if one is configured.
+pub(crate) fn double_submit_cookie_name(config: &SessionConfig) -> Option<String> {
+    config
+        .cookie_and_header
+        .double_submit_cookie
+        .as_ref()
+        .map(|_| NameType::DoubleSubmit.get_name(config, Transport::Cookie))
+}
+
+/// The names of every Cookie set for the config including any host prefix.
+pub(crate) fn cookie_names(config: &SessionConfig) -> Vec<String> {
+    [NameType::Data, NameType::Store]
+        .map(|name_type| name_type.get_name(config, Transport::Cookie))
+        .into_iter()
+        .chain(double_submit_cookie_name(config))
+        .collect()
+}
+
+/// The legacy Cookie names the request carried, which the response removes.
+fn legacy_cookies(config: &SessionConfig, headers: &HeaderMap) -> Vec<String> {
+    let names = &config.cookie_and_header;
+
+    if names.legacy_session_names.is_empty() && names.legacy_store_names.is_empty() {
+        return Vec::new();
+    }
+
+    let cookies = get_cookies(headers);
+    names
+        .legacy_session_names
+        .iter()
+        .chain(&names.legacy_store_names)
+        .filter(|name| cookies.get(name).is_some())
+        .map(|name| name.to_string())
+        .collect()
+}
+
+/// Gets the Session ID and storable flag from the transport picked by the configured precedence.
+/// Without a precedence only the transport selected by the `rest_mode` feature is read.
+/// Returns the transport the response should be written back to and the legacy Cookies to remove.
+pub(crate) fn get_headers_and_key<T>(
     store: &SessionStore<T>,
-    cookies: CookieJar,
+    headers: &HeaderMap,
+    ip_user_agent: &str,
+) -> (Option<String>, bool, Transport, Vec<String>)
+where
+    T: DatabasePool + Clone + Debug + Sync + Send + 'static,
+{
+    let from_cookie = || get_cookie_session(store, &get_cookies(headers), ip_user_agent);
+    let from_header = || get_header_session(store, &get_headers(store, headers), ip_user_agent);
+
+    let Some(precedence) = store.config.cookie_and_header.transport_precedence else {
+        let (value, storable) = match Transport::PRIMARY {
+            Transport::Cookie => from_cookie(),
+            Transport::Header => from_header(),
+        };
+
+        let legacy = match Transport::PRIMARY {
+            Transport::Cookie => legacy_cookies(&store.config, headers),
+            Transport::Header => Vec::new(),
+        };
+
+        return (value, storable, Transport::PRIMARY, legacy);
+    };
+
+    let cookie = from_cookie();
+    let header = from_header();
+
+    if let (Some(cookie_id), Some(header_id)) = (&cookie.0, &header.0) {
+        if cookie_id != header_id {
+            // The IDs are hashed so they can not be taken from the logs and used.
+            tracing::warn!(
+                cookie.id = %hash_id(cookie_id, &[]),
+                header.id = %hash_id(header_id, &[]),
+                precedence = ?precedence,
+                "Session cookie and header name different Sessions."
+            );
+
+            if precedence == TransportPrecedence::RejectConflicts {
+                return (None, false, Transport::PRIMARY, Vec::new());
+            }
+        }
+    }
+
+    let (first, second) = match precedence {
+        TransportPrecedence::CookieFirst => {
+            ((cookie, Transport::Cookie), (header, Transport::Header))
+        }
+        TransportPrecedence::HeaderFirst => {
+            ((header, Transport::Header), (cookie, Transport::Cookie))
+        }
+        TransportPrecedence::RejectConflicts if Transport::PRIMARY == Transport::Cookie => {
+            ((cookie, Transport::Cookie), (header, Transport::Header))
+        }
+        TransportPrecedence::RejectConflicts => {
+            ((header, Transport::Header), (cookie, Transport::Cookie))
+        }
+    };
+
+    // The second transport is only used when the first has no Session ID.
+    let ((value, storable), transport) = if first.0 .0.is_some() || second.0 .0.is_none() {
+        first
+    } else {
+        second
+    };
+
+    let legacy = match transport {
+        Transport::Cookie => legacy_cookies(&store.config, headers),
+        Transport::Header => Vec::new(),
+    };
+
+    (value, storable, transport, legacy)
+}
+
+/// Checks the Session ID fits the configured maximum length. Longer IDs are treated like
+/// an invalid Session so they are never used to query the database.
+fn within_max_id_length(config: &SessionConfig, id: &str) -> bool {
+    if id.len() <= config.database.max_id_length {
+        return true;
+    }
+
+    tracing::debug!(
+        length = id.len(),
+        max = config.database.max_id_length,
+        "Session ID is longer than the maximum so a new Session will be used."
+    );
+    false
+}
+
+/// Reads the Session ID and storable flag from the cookies.
+/// Each falls back to its legacy names in order when the current Cookie is missing or invalid.
+fn get_cookie_session<T>(
+    store: &SessionStore<T>,
+    cookies: &CookieJar,
     ip_user_agent: &str,
 ) -> (Option<String>, bool)
 where
     T: DatabasePool + Clone + Debug + Sync + Send + 'static,
 {
-    let key = store.config.cookie_and_header.key.as_ref();
+    let config = &store.config.cookie_and_header;
+    let key = config.key.as_ref();
 
-    let value = cookies
-        .get_cookie(
-            &store.config.cookie_and_header.session_name,
-            key,
-            ip_user_agent.to_owned(),
-            false,
-        )
-        .and_then(|c| Some(c.value().to_string()));
-
-    let storable = cookies
-        .get_cookie(
-            &store.config.cookie_and_header.store_name,
-            key,
-            ip_user_agent.to_owned(),
-            true,
-        )
+    let session_name = NameType::Data.get_name(&store.config, Transport::Cookie);
+    let value = std::iter::once(session_name.as_str())
+        .chain(config.legacy_session_names.iter().map(AsRef::as_ref))
+        .find_map(|name| cookies.get_cookie(name, key, ip_user_agent.to_owned(), false))
+        .map(|c| c.value().to_string())
+        .filter(|id| within_max_id_length(&store.config, id));
+
+    let store_name = NameType::Store.get_name(&store.config, Transport::Cookie);
+    let storable = std::iter::once(store_name.as_str())
+        .chain(config.legacy_store_names.iter().map(AsRef::as_ref))
+        .find_map(|name| cookies.get_cookie(name, key, ip_user_agent.to_owned(), true))
         .is_some_and(|c| c.value().parse().unwrap_or(false));
 
     (value, storable)
 }
 
-#[cfg(feature = "rest_mode")]
-pub async fn get_headers_and_key<T>(
+/// Reads the Session ID and storable flag from the headers.
+fn get_header_session<T>(
     store: &SessionStore<T>,
-    headers: HashMap<String, String>,
+    headers: &HashMap<String, String>,
     ip_user_agent: &str,
 ) -> (Option<String>, bool)
 where
     T: DatabasePool + Clone + Debug + Sync + Send + 'static,
 {
-    use crate::sec::verify_header;
     let key = store.config.cookie_and_header.key.as_ref();
 
     let name = store.config.cookie_and_header.session_name.to_string();
-    let value = headers.get(&name).and_then(|c| {
-        if let Some(key) = key {
-            verify_header(c, key, ip_user_agent).ok()
-        } else {
-            Some(c.to_owned())
-        }
-    });
+    let value = headers
+        .get(&name)
+        .and_then(|c| {
+            if let Some(key) = key {
+                verify_header(c, key, ip_user_agent).ok()
+            } else {
+                Some(c.to_owned())
+            }
+        })
+        .filter(|id| within_max_id_length(&store.config, id));
 
     let name = store.config.cookie_and_header.store_name.to_string();
     let storable = headers
@@ -119,7 +314,6 @@ where
     (value, storable.unwrap_or(false))
 }
 
-#[cfg(not(feature = "rest_mode"))]
 pub(crate) trait CookiesExt {
     fn get_cookie(
         &self,
@@ -137,7 +331,6 @@ pub(crate) trait CookiesExt {
     );
 }
 
-#[cfg(not(feature = "rest_mode"))]
 impl CookiesExt for CookieJar {
     fn get_cookie(
         &self,
@@ -173,30 +366,42 @@ impl CookiesExt for CookieJar {
     }
 }
 
-#[cfg(not(feature = "rest_mode"))]
-fn create_cookie<'a>(config: &SessionConfig, value: String, cookie_type: NameType) -> Cookie<'a> {
-    let mut cookie_builder = Cookie::build((cookie_type.get_name(config), value))
-        .path(config.cookie_and_header.cookie_path.clone())
-        .secure(config.cookie_and_header.cookie_secure)
-        .http_only(config.cookie_and_header.cookie_http_only)
-        .same_site(config.cookie_and_header.cookie_same_site);
+fn create_cookie<'a>(
+    config: &SessionConfig,
+    value: String,
+    cookie_type: NameType,
+    secure: bool,
+) -> Cookie<'a> {
+    let mut cookie_builder =
+        Cookie::build((cookie_type.get_name(config, Transport::Cookie), value))
+            .path(config.cookie_and_header.cookie_path.clone())
+            .secure(secure)
+            .http_only(config.cookie_and_header.cookie_http_only)
+            .same_site(config.cookie_and_header.cookie_same_site);
 
     if let Some(domain) = &config.cookie_and_header.cookie_domain {
         cookie_builder = cookie_builder.domain(domain.clone());
     }
 
-    if let Some(max_age) = config.cookie_and_header.cookie_max_age {
-        let time_duration = max_age.to_std().expect("Max Age out of bounds");
-        cookie_builder =
-            cookie_builder.expires(Some((std::time::SystemTime::now() + time_duration).into()));
+    // The max age is checked by `SessionConfig::validate` so this only skips dates past the year 9999.
+    if let Some(expires) = config
+        .cookie_and_header
+        .cookie_max_age
+        .and_then(cookie_expires)
+    {
+        cookie_builder = cookie_builder.expires(expires);
     }
 
     cookie_builder.build()
 }
 
-#[cfg(not(feature = "rest_mode"))]
-fn remove_cookie<'a>(config: &SessionConfig, cookie_type: NameType) -> Cookie<'a> {
-    let mut cookie_builder = Cookie::build((cookie_type.get_name(config), ""))
+/// Returns the Cookie expiry date `max_age` from now, or None if it can not be represented.
+pub(crate) fn cookie_expires(max_age: chrono::Duration) -> Option<OffsetDateTime> {
+    OffsetDateTime::now_utc().checked_add(cookie::time::Duration::seconds(max_age.num_seconds()))
+}
+
+fn remove_cookie<'a>(config: &SessionConfig, name: String) -> Cookie<'a> {
+    let mut cookie_builder = Cookie::build((name, ""))
         .path(config.cookie_and_header.cookie_path.clone())
         .http_only(config.cookie_and_header.cookie_http_only)
         .same_site(cookie::SameSite::None);
@@ -214,7 +419,6 @@ fn remove_cookie<'a>(config: &SessionConfig, cookie_type: NameType) -> Cookie<'a
     cookie
 }
 
-#[cfg(not(feature = "rest_mode"))]
 /// This will get a CookieJar from the Headers.
 pub(crate) fn get_cookies(headers: &HeaderMap) -> CookieJar {
     let mut jar = CookieJar::new();
@@ -233,7 +437,6 @@ pub(crate) fn get_cookies(headers: &HeaderMap) -> CookieJar {
     jar
 }
 
-#[cfg(feature = "rest_mode")]
 /// This will get a Hashmap of all the headers that Exist.
 pub(crate) fn get_headers<T>(
     store: &SessionStore<T>,
@@ -258,7 +461,6 @@ where
     map
 }
 
-#[cfg(not(feature = "rest_mode"))]
 fn set_cookies(jar: CookieJar, headers: &mut HeaderMap) {
     for cookie in jar.delta() {
         if let Ok(header_value) = cookie.encoded().to_string().parse() {
@@ -267,65 +469,382 @@ fn set_cookies(jar: CookieJar, headers: &mut HeaderMap) {
     }
 }
 
-/// Used to Set either the Header Values or the Cookie Values.
-pub(crate) fn set_headers<T>(
-    session: &Session<T>,
+/// Checks if the host, with or without a port, is localhost, 127.0.0.1 or ::1.
+fn is_localhost(host: &str) -> bool {
+    let host = match host.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+
+    host.eq_ignore_ascii_case("localhost") || host == "127.0.0.1" || host == "::1"
+}
+
+/// Decides if the cookies set on the response to this request get the Secure flag.
+///
+/// The localhost exception never applies to `__Host-` cookies, and only applies when the
+/// connection itself comes from a loopback address without any proxy forwarding headers, as
+/// the Host header alone is chosen by the client.
+pub(crate) fn get_cookie_secure<T>(req: &Request<T>, config: &SessionConfig) -> bool {
+    if !config.cookie_and_header.secure_localhost_exception {
+        return config.cookie_and_header.cookie_secure;
+    }
+
+    if config.cookie_and_header.prefix_with_host {
+        return true;
+    }
+
+    !(request_host(req).is_some_and(is_localhost) && is_direct_loopback(req))
+}
+
+/// Checks the request was sent over a loopback connection and not forwarded by a proxy.
+fn is_direct_loopback<T>(req: &Request<T>) -> bool {
+    let headers = req.headers();
+
+    if headers.contains_key(FORWARDED)
+        || headers.contains_key(X_FORWARDED_FOR)
+        || headers.contains_key(X_REAL_IP)
+    {
+        return false;
+    }
+
+    req.extensions()
+        .get::<axum::extract::ConnectInfo<SocketAddr>>()
+        .is_some_and(|addr| addr.ip().is_loopback())
+}
+
+/// The host the request was sent to, with the port if one was given.
+pub(crate) fn request_host<T>(req: &Request<T>) -> Option<&str> {
+    // HTTP/2 requests carry the host within the URI instead of the Host header.
+    req.headers()
+        .get(HOST)
+        .and_then(|hv| hv.to_str().ok())
+        .or_else(|| req.uri().authority().map(|authority| authority.as_str()))
+}
+
+/// The scheme, host and port a request was sent to or a redirect points at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Origin {
+    scheme: &'static str,
+    host: String,
+    port: u16,
+}
+
+impl Origin {
+    /// Reads the origin from an authority such as `app.test`, `app.test:8443` or `[::1]:80`,
+    /// using the scheme's default port when none is given. Userinfo is ignored.
+    fn parse(scheme: &'static str, authority: &str) -> Option<Self> {
+        let authority = authority.rsplit('@').next().unwrap_or_default();
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(ipv6) => {
+                let (host, rest) = ipv6.split_once(']')?;
+                (format!("[{host}]"), rest.strip_prefix(':'))
+            }
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host.to_owned(), Some(port)),
+                None => (authority.to_owned(), None),
+            },
+        };
+
+        if host.is_empty() {
+            return None;
+        }
+
+        let port = match port {
+            Some(port) if !port.is_empty() => port.parse().ok()?,
+            _ if scheme == "https" => 443,
+            _ => 80,
+        };
+
+        Some(Self {
+            scheme,
+            host: host.to_ascii_lowercase(),
+            port,
+        })
+    }
+}
+
+/// Reads a scheme as http or https, which are the only ones a redirect stays on the origin with.
+fn web_scheme(scheme: &str) -> Option<&'static str> {
+    if scheme.eq_ignore_ascii_case("https") {
+        Some("https")
+    } else if scheme.eq_ignore_ascii_case("http") {
+        Some("http")
+    } else {
+        None
+    }
+}
+
+/// The origin the request was sent to.
+///
+/// The scheme is read from the URI, then the `X-Forwarded-Proto` or `Forwarded` proto set by a
+/// proxy, and otherwise is https when the cookies are Secure.
+pub(crate) fn request_origin<T>(req: &Request<T>, secure: bool) -> Option<Origin> {
+    let headers = req.headers();
+    let scheme = req
+        .uri()
+        .scheme_str()
+        .and_then(web_scheme)
+        .or_else(|| {
+            headers
+                .get(X_FORWARDED_PROTO)
+                .and_then(|hv| hv.to_str().ok())
+                .and_then(|s| web_scheme(s.split(',').next()?.trim()))
+        })
+        .or_else(|| {
+            headers.get_all(FORWARDED).iter().find_map(|hv| {
+                ForwardedHeaderValue::from_forwarded(hv.to_str().ok()?)
+                    .ok()?
+                    .iter()
+                    .find_map(|stanza| match stanza.forwarded_proto? {
+                        Protocol::Https => Some("https"),
+                        Protocol::Http => Some("http"),
+                    })
+            })
+        })
+        .unwrap_or(if secure { "https" } else { "http" });
+
+    Origin::parse(scheme, request_host(req)?)
+}
+
+/// Checks if the response redirects to another origin than the request was sent to.
+/// Origins differ when their scheme, host or port differ, with default ports filled in.
+/// Relative locations stay on the same origin. Locations with a scheme other than http
+/// or https, or that can not be read, count as another origin.
+pub(crate) fn is_external_redirect(
+    status: StatusCode,
+    headers: &HeaderMap,
+    request_origin: Option<&Origin>,
+) -> bool {
+    if !status.is_redirection() {
+        return false;
+    }
+
+    let Some(location) = headers.get(LOCATION) else {
+        return false;
+    };
+
+    let Ok(location) = location.to_str() else {
+        return true;
+    };
+
+    let Some(request_origin) = request_origin else {
+        return true;
+    };
+
+    // Browsers read backslashes like slashes, so `/\host` is scheme relative too.
+    let location = location.trim();
+    let is_slash = |c: char| c == '/' || c == '\\';
+    let (scheme, authority) = if let Some(rest) = location
+        .strip_prefix(is_slash)
+        .and_then(|rest| rest.strip_prefix(is_slash))
+    {
+        (request_origin.scheme, rest)
+    } else {
+        let Some((scheme, rest)) = location.split_once(':') else {
+            return false;
+        };
+
+        let is_scheme = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+
+        // A colon within a path or query such as `/a:b` is still relative.
+        if !is_scheme {
+            return false;
+        }
+
+        let Some(scheme) = web_scheme(scheme) else {
+            return true;
+        };
+
+        match rest
+            .strip_prefix(is_slash)
+            .and_then(|rest| rest.strip_prefix(is_slash))
+        {
+            Some(rest) => (scheme, rest),
+            None => return true,
+        }
+    };
+
+    let authority = authority
+        .split(['/', '\\', '?', '#'])
+        .next()
+        .unwrap_or_default();
+
+    Origin::parse(scheme, authority).as_ref() != Some(request_origin)
+}
+
+/// Options used by [`SessionStore::apply_session_headers`] to write a Session's cookie or header.
+///
+/// Made from a request by [`SessionStore::emit_options`] so the signing, Secure flag and
+/// transport match what the [`crate::SessionLayer`] would use for that same request.
+///
+/// # Examples
+/// ```rust ignore
+/// let options = session_store.emit_options(&req).with_storable(true);
+/// ```
+///
+#[derive(Debug, Clone)]
+pub struct EmitOptions {
+    pub(crate) destroy: bool,
+    pub(crate) storable: bool,
+    pub(crate) ip_user_agent: String,
+    pub(crate) secure: bool,
+    pub(crate) transport: Transport,
+    pub(crate) legacy_cookies: Vec<String>,
+}
+
+impl EmitOptions {
+    pub(crate) fn from_request<B, T>(req: &Request<B>, store: &SessionStore<T>) -> Self
+    where
+        T: DatabasePool + Clone + Debug + Sync + Send + 'static,
+    {
+        let ip_user_agent = get_ips_hash(req, store);
+        let (_, storable, transport, legacy_cookies) =
+            get_headers_and_key(store, req.headers(), &ip_user_agent);
+
+        Self {
+            destroy: false,
+            storable,
+            ip_user_agent,
+            secure: get_cookie_secure(req, &store.config),
+            transport,
+            legacy_cookies,
+        }
+    }
+
+    /// Options for when there is no request, which only differ from a request's by not
+    /// binding the signature to an IP or user agent.
+    pub(crate) fn without_request(config: &SessionConfig) -> Self {
+        Self {
+            destroy: false,
+            storable: false,
+            ip_user_agent: String::new(),
+            secure: config.cookie_and_header.cookie_secure,
+            transport: Transport::PRIMARY,
+            legacy_cookies: Vec::new(),
+        }
+    }
+
+    /// Removes the Session's cookies instead of setting them.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let options = session_store.emit_options(&req).with_destroy(true);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_destroy(mut self, destroy: bool) -> Self {
+        self.destroy = destroy;
+        self
+    }
+
+    /// Sets if the Session is storable. Defaults to the storable value the request was sent with.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let options = session_store.emit_options(&req).with_storable(true);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_storable(mut self, storable: bool) -> Self {
+        self.storable = storable;
+        self
+    }
+}
+
+/// Used to Set either the Header Values or the Cookie Values of the options transport.
+/// The Secure flag is only used by cookies.
+pub(crate) fn set_headers(
+    config: &SessionConfig,
+    id: &str,
     headers: &mut HeaderMap,
-    ip_user_agent: &str,
-    destroy: bool,
-    storable: bool,
-) where
-    T: DatabasePool + Clone + Debug + Sync + Send + 'static,
-{
+    options: &EmitOptions,
+) {
+    let EmitOptions {
+        destroy,
+        storable,
+        ref ip_user_agent,
+        secure,
+        transport,
+        ref legacy_cookies,
+    } = *options;
+
     // Lets make a new jar as we only want to add our cookies to the Response cookie header.\
-    #[cfg(not(feature = "rest_mode"))]
-    {
+    if transport == Transport::Cookie {
         let mut cookies = CookieJar::new();
 
         // Add SessionID
-        if (storable || !session.store.config.session_mode.is_opt_in()) && !destroy {
+        if (storable || !config.session_mode.is_opt_in()) && !destroy {
             cookies.add_cookie(
-                create_cookie(&session.store.config, session.id.clone(), NameType::Data),
-                &session.store.config.cookie_and_header.key,
+                create_cookie(config, id.to_owned(), NameType::Data, secure),
+                &config.cookie_and_header.key,
                 ip_user_agent.to_owned(),
                 false,
             );
         } else {
             cookies.add_cookie(
-                remove_cookie(&session.store.config, NameType::Data),
-                &session.store.config.cookie_and_header.key,
+                remove_cookie(config, NameType::Data.get_name(config, Transport::Cookie)),
+                &config.cookie_and_header.key,
                 ip_user_agent.to_owned(),
                 false,
             );
         }
 
         // Add Session Store Boolean
-        if session.store.config.session_mode.is_opt_in() && storable && !destroy {
+        if config.session_mode.is_opt_in() && storable && !destroy {
             cookies.add_cookie(
-                create_cookie(&session.store.config, storable.to_string(), NameType::Store),
-                &session.store.config.cookie_and_header.key,
+                create_cookie(config, storable.to_string(), NameType::Store, secure),
+                &config.cookie_and_header.key,
                 ip_user_agent.to_owned(),
                 true,
             );
         } else {
             cookies.add_cookie(
-                remove_cookie(&session.store.config, NameType::Store),
-                &session.store.config.cookie_and_header.key,
+                remove_cookie(config, NameType::Store.get_name(config, Transport::Cookie)),
+                &config.cookie_and_header.key,
                 ip_user_agent.to_owned(),
                 true,
             );
         }
 
+        // Add the double-submit token. It is never signed so scripts can read it as is.
+        if let (Some(_), Some(key)) = (
+            &config.cookie_and_header.double_submit_cookie,
+            &config.cookie_and_header.key,
+        ) {
+            if (storable || !config.session_mode.is_opt_in()) && !destroy {
+                let mut cookie = create_cookie(
+                    config,
+                    double_submit_token(key, id),
+                    NameType::DoubleSubmit,
+                    secure,
+                );
+                cookie.set_http_only(false);
+                cookies.add(cookie);
+            } else {
+                let mut cookie = remove_cookie(
+                    config,
+                    NameType::DoubleSubmit.get_name(config, Transport::Cookie),
+                );
+                cookie.set_http_only(false);
+                cookies.add(cookie);
+            }
+        }
+
+        // Remove the legacy Cookies the Session was sent with now that the current names are set.
+        for name in legacy_cookies {
+            cookies.add(remove_cookie(config, name.clone()));
+        }
+
         set_cookies(cookies, headers);
-    }
-    #[cfg(feature = "rest_mode")]
-    {
-        use crate::sec::sign_header;
+    } else {
         // Add SessionID
-        if (storable || !session.store.config.session_mode.is_opt_in()) && !destroy {
-            let name = NameType::Data.get_name(&session.store.config);
-            let value = if let Some(key) = session.store.config.cookie_and_header.key.as_ref() {
-                match sign_header(&session.id, key, ip_user_agent) {
+        if (storable || !config.session_mode.is_opt_in()) && !destroy {
+            let name = NameType::Data.get_name(config, Transport::Header);
+            let value = if let Some(key) = config.cookie_and_header.key.as_ref() {
+                match sign_header(id, key, ip_user_agent) {
                     Ok(v) => v,
                     Err(err) => {
                         tracing::error!(err = %err, "Failed to sign Session ID so blank will be used.");
@@ -333,7 +852,7 @@ pub(crate) fn set_headers<T>(
                     }
                 }
             } else {
-                session.id.clone()
+                id.to_owned()
             };
 
             if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
@@ -344,8 +863,8 @@ pub(crate) fn set_headers<T>(
         }
 
         // Add Session Store Boolean
-        if session.store.config.session_mode.is_opt_in() && storable && !destroy {
-            let name = NameType::Store.get_name(&session.store.config);
+        if config.session_mode.is_opt_in() && storable && !destroy {
+            let name = NameType::Store.get_name(config, Transport::Header);
             //storable doesn't need signing or encryption.
             let value = storable.to_string();
 
@@ -358,6 +877,143 @@ pub(crate) fn set_headers<T>(
     }
 }
 
+fn socket_ip<T>(req: &Request<T>, config: &IpUserAgentConfig) -> Option<IpAddr> {
+    req.extensions()
+        .get::<axum::extract::ConnectInfo<SocketAddr>>()
+        .map(|addr| addr.ip())
+        .or_else(|| {
+            config
+                .use_forwarded_by
+                .then(|| forwarded_by_ip(req.headers()))
+                .flatten()
+        })
+}
+
+/// The by= clause of the last proxy, which is the one that connected to us.
+fn forwarded_by_ip(headers: &HeaderMap) -> Option<IpAddr> {
+    headers
+        .get_all(FORWARDED)
+        .iter()
+        .filter_map(|hv| ForwardedHeaderValue::from_forwarded(hv.to_str().ok()?).ok())
+        .flat_map(|f| f.into_iter().collect::<Vec<_>>())
+        .last()?
+        .forwarded_by
+        .and_then(|fb| match fb {
+            Identifier::SocketAddr(a) => Some(a.ip()),
+            Identifier::IpAddr(ip) => Some(ip),
+            _ => None,
+        })
+}
+
+/// Checks the Socket IP exists when it is signed into the cookies and headers.
+///
+/// Without it the IP is left out of the signature, so the cookies stop verifying once the app
+/// is served with connect info. Fails if connect info is required, otherwise logs a warning
+/// at most every few minutes.
+pub(crate) fn check_connect_info<T, D>(
+    req: &Request<T>,
+    store: &SessionStore<D>,
+) -> Result<(), SessionError>
+where
+    D: DatabasePool + Clone + Debug + Sync + Send + 'static,
+{
+    let config = &store.config;
+
+    if config.cookie_and_header.key.is_none()
+        || !config.cookie_and_header.with_ip_and_user_agent
+        || !config.ip_user_agent.use_ip
+        || socket_ip(req, &config.ip_user_agent).is_some()
+    {
+        return Ok(());
+    }
+
+    if config.ip_user_agent.require_connect_info {
+        return Err(SessionError::InvalidConfiguration(
+            "with_hashed_ip is enabled but the request has no ConnectInfo. Serve the app using \
+            into_make_service_with_connect_info::<SocketAddr>() or disable with_hashed_ip."
+                .to_owned(),
+        ));
+    }
+
+    let now = config.clock.now().timestamp_millis();
+    let last = store.warned_connect_info.load(Ordering::Relaxed);
+
+    if now.saturating_sub(last) >= CONNECT_INFO_WARNING_INTERVAL
+        && store
+            .warned_connect_info
+            .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+    {
+        tracing::warn!(
+            "with_hashed_ip is enabled but the request has no ConnectInfo, so the IP is left out \
+            of the cookie signatures and serving with connect info later invalidates every cookie. \
+            Serve the app using into_make_service_with_connect_info::<SocketAddr>(), \
+            or disable with_hashed_ip."
+        );
+    }
+
+    Ok(())
+}
+
+fn x_forward_for_ip(headers: &HeaderMap) -> Option<IpAddr> {
+    headers
+        .get(X_FORWARDED_FOR)
+        .and_then(|hv| hv.to_str().ok())
+        .and_then(|s| s.split(',').find_map(|s| s.trim().parse::<IpAddr>().ok()))
+}
+
+fn forwarded_ip(headers: &HeaderMap) -> Option<IpAddr> {
+    headers.get_all(FORWARDED).iter().find_map(|hv| {
+        hv.to_str()
+            .ok()
+            .and_then(|s| ForwardedHeaderValue::from_forwarded(s).ok())
+            .and_then(|f| {
+                f.iter()
+                    .filter_map(|fs| fs.forwarded_for.as_ref())
+                    .find_map(|ff| match ff {
+                        Identifier::SocketAddr(a) => Some(a.ip()),
+                        Identifier::IpAddr(ip) => Some(*ip),
+                        _ => None,
+                    })
+            })
+    })
+}
+
+fn real_ip(headers: &HeaderMap) -> Option<IpAddr> {
+    headers
+        .get(X_REAL_IP)
+        .and_then(|hv| hv.to_str().ok())
+        .and_then(|s| s.parse::<IpAddr>().ok())
+}
+
+/// Gets the client's IP from the sources enabled within the ip_user_agent config.
+/// Proxy set headers are preferred over the socket IP which would be the proxy's.
+pub(crate) fn get_client_ip<T, D>(req: &Request<T>, store: &SessionStore<D>) -> Option<IpAddr>
+where
+    D: DatabasePool + Clone + Debug + Sync + Send + 'static,
+{
+    let config = &store.config.ip_user_agent;
+    let headers = req.headers();
+
+    config
+        .use_real_ip
+        .then(|| real_ip(headers))
+        .flatten()
+        .or_else(|| {
+            config
+                .use_forward_ip
+                .then(|| forwarded_ip(headers))
+                .flatten()
+        })
+        .or_else(|| {
+            config
+                .use_xforward_ip
+                .then(|| x_forward_for_ip(headers))
+                .flatten()
+        })
+        .or_else(|| config.use_ip.then(|| socket_ip(req, config)).flatten())
+}
+
 ///Rather than getting a single IP from the x_real, X forwarded and socket ip
 ///It is better to use all 3 to ensure none of them have changed. Setting the default
 /// to be a empty String if it is not present. we will combine these together in a single Message String.
@@ -368,64 +1024,26 @@ where
     if store.config.cookie_and_header.key.is_some()
         && store.config.cookie_and_header.with_ip_and_user_agent
     {
+        let config = &store.config.ip_user_agent;
         let headers = req.headers();
+        let to_string = |ip: Option<IpAddr>| ip.map(|ip| ip.to_string()).unwrap_or_default();
 
-        let ip = if store.config.ip_user_agent.use_ip {
-            req.extensions()
-                .get::<axum::extract::ConnectInfo<SocketAddr>>()
-                .map(|addr| addr.ip().to_string())
-                .unwrap_or_default()
-        } else {
-            "".to_owned()
-        };
-
-        let x_forward_for_ip = if store.config.ip_user_agent.use_xforward_ip {
-            headers
-                .get(X_FORWARDED_FOR)
-                .and_then(|hv| hv.to_str().ok())
-                .and_then(|s| s.split(',').find_map(|s| s.trim().parse::<IpAddr>().ok()))
-                .map(|ip| ip.to_string())
-                .unwrap_or_default()
-        } else {
-            "".to_owned()
-        };
-
-        let forwarded_ip = if store.config.ip_user_agent.use_forward_ip {
-            headers
-                .get_all(FORWARDED)
-                .iter()
-                .find_map(|hv| {
-                    hv.to_str()
-                        .ok()
-                        .and_then(|s| ForwardedHeaderValue::from_forwarded(s).ok())
-                        .and_then(|f| {
-                            f.iter()
-                                .filter_map(|fs| fs.forwarded_for.as_ref())
-                                .find_map(|ff| match ff {
-                                    Identifier::SocketAddr(a) => Some(a.ip()),
-                                    Identifier::IpAddr(ip) => Some(*ip),
-                                    _ => None,
-                                })
-                        })
-                })
-                .map(|ip| ip.to_string())
-                .unwrap_or_default()
-        } else {
-            "".to_owned()
-        };
-
-        let real_ip = if store.config.ip_user_agent.use_real_ip {
-            headers
-                .get(X_REAL_IP)
-                .and_then(|hv| hv.to_str().ok())
-                .and_then(|s| s.parse::<IpAddr>().ok())
-                .map(|ip| ip.to_string())
-                .unwrap_or_default()
-        } else {
-            "".to_owned()
-        };
+        let ip = to_string(config.use_ip.then(|| socket_ip(req, config)).flatten());
+        let x_forward_for_ip = to_string(
+            config
+                .use_xforward_ip
+                .then(|| x_forward_for_ip(headers))
+                .flatten(),
+        );
+        let forwarded_ip = to_string(
+            config
+                .use_forward_ip
+                .then(|| forwarded_ip(headers))
+                .flatten(),
+        );
+        let real_ip = to_string(config.use_real_ip.then(|| real_ip(headers)).flatten());
 
-        let user_agent = if store.config.ip_user_agent.use_user_agent {
+        let user_agent = if config.use_user_agent {
             headers
                 .get(USER_AGENT)
                 .and_then(|hv| hv.to_str().ok())
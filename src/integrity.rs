@@ -0,0 +1,373 @@
+use crate::{DatabasePool, SessionError, SessionStore};
+#[cfg(feature = "key-store")]
+use fastbloom_rs::{FilterBuilder, Membership};
+use std::fmt::Debug;
+
+/// How many id's are read from the database or checked against it at once.
+const PAGE_SIZE: usize = 500;
+/// How many ids are kept as samples for each kind of issue.
+const MAX_SAMPLES: usize = 10;
+
+/// One kind of inconsistency found by [`SessionStore::verify_integrity`].
+///
+/// Samples are storage ids, which are the raw session ids unless hashed storage ids
+/// are enabled, so treat them as sensitive.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityIssue {
+    /// How many sessions have this issue.
+    pub count: usize,
+    /// Up to 10 storage ids of the sessions with this issue.
+    pub samples: Vec<String>,
+}
+
+impl IntegrityIssue {
+    fn record(&mut self, id: &str) {
+        self.count += 1;
+
+        if self.samples.len() < MAX_SAMPLES {
+            self.samples.push(id.to_owned());
+        }
+    }
+}
+
+/// Result of [`SessionStore::verify_integrity`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// Storable sessions in memory with unsaved changes and no unexpired database row.
+    /// This includes sessions whose first request is still in flight.
+    pub unpersisted: IntegrityIssue,
+    /// Storable sessions in memory without unsaved changes whose database row is
+    /// expired or missing, most often because another instance removed it.
+    pub expired_in_database: IntegrityIssue,
+    /// Unexpired database rows whose id is not within the bloom filter.
+    /// Always empty unless the `key-store` feature and bloom filters are enabled.
+    pub missing_from_filter: IntegrityIssue,
+    /// Estimated number of bloom filter entries that match no session in memory or the database.
+    /// Filter entries can not be listed so there are no samples.
+    /// Always 0 unless the `key-store` feature and bloom filters are enabled.
+    pub dangling_filter_entries: usize,
+    /// How many database ids were read. Databases that page by scanning, such as Redis,
+    /// may read an id more than once.
+    pub database_ids_scanned: usize,
+    /// How many sessions in memory were checked against the database.
+    pub memory_sessions_checked: usize,
+    /// How many issues were fixed when ran with repair.
+    pub repaired: usize,
+}
+
+impl IntegrityReport {
+    /// Returns true if no issues were found.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let report = session_store.verify_integrity(false).await?;
+    /// assert!(report.is_consistent());
+    /// ```
+    ///
+    pub fn is_consistent(&self) -> bool {
+        self.unpersisted.count == 0
+            && self.expired_in_database.count == 0
+            && self.missing_from_filter.count == 0
+            && self.dangling_filter_entries == 0
+    }
+}
+
+impl<T> SessionStore<T>
+where
+    T: DatabasePool + Clone + Debug + Sync + Send + 'static,
+{
+    /// Audits the sessions in memory, the bloom filter and the database against each other.
+    ///
+    /// Database ids are read in pages and memory sessions are checked in pages, yielding to
+    /// other tasks between each, so this is safe to run on a live store. Without repair nothing is
+    /// changed. With repair, database ids missing from the bloom filter are added back and
+    /// unpersisted memory sessions are saved. Other issues are only reported.
+    ///
+    /// Requests running during the audit can change the results, so run it again before
+    /// acting on a small number of issues.
+    ///
+    /// If client is None only the bloom filter is checked against memory.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let report = session_store.verify_integrity(false).await?;
+    ///
+    /// if !report.is_consistent() {
+    ///     tracing::warn!(?report, "Session store is inconsistent");
+    /// }
+    /// ```
+    ///
+    pub async fn verify_integrity(&self, repair: bool) -> Result<IntegrityReport, SessionError> {
+        let mut report = IntegrityReport::default();
+        let table_name = &self.config.database.table_name;
+
+        // Rebuilt from every id that should be within the filter to estimate stale entries.
+        #[cfg(feature = "key-store")]
+        let mut expected = if self.config.memory.use_bloom_filters {
+            Some(
+                FilterBuilder::new(
+                    self.config.memory.filter_expected_elements,
+                    self.config.memory.filter_false_positive_probability,
+                )
+                .build_counting_bloom_filter(),
+            )
+        } else {
+            None
+        };
+
+        if let Some(client) = &self.client {
+            let mut cursor: Option<String> = None;
+
+            loop {
+                let page = client
+                    .get_ids_page(table_name, cursor.as_deref(), PAGE_SIZE)
+                    .await?;
+                report.database_ids_scanned += page.ids.len();
+
+                #[cfg(feature = "key-store")]
+                if let Some(expected) = expected.as_mut() {
+                    let missing: Vec<&String> = {
+                        let filter = self.filter.read().await;
+                        page.ids
+                            .iter()
+                            .filter(|id| !filter.contains(id.as_bytes()))
+                            .collect()
+                    };
+
+                    for id in &missing {
+                        report.missing_from_filter.record(id);
+                    }
+
+                    if repair && !missing.is_empty() {
+                        let mut filter = self.filter.write().await;
+                        for id in missing {
+                            if !filter.contains(id.as_bytes()) {
+                                filter.add(id.as_bytes());
+                                report.repaired += 1;
+                            }
+                        }
+                    }
+
+                    page.ids.iter().for_each(|id| add_once(expected, id));
+                }
+
+                cursor = page.cursor;
+
+                if cursor.is_none() {
+                    break;
+                }
+
+                // Lets requests on the same runtime run between pages. Yielding instead of
+                // sleeping keeps the audit working on runtimes without the time driver.
+                tokio::task::yield_now().await;
+            }
+        }
+
+        // Only the ids are copied so the memory map is never locked across an await.
+        let ids: Vec<String> = self.inner.iter().map(|entry| entry.key().clone()).collect();
+
+        #[cfg(feature = "key-store")]
+        if let Some(expected) = expected.as_mut() {
+            ids.iter()
+                .for_each(|id| add_once(expected, &self.storage_id(id)));
+        }
+
+        if let Some(client) = &self.client {
+            for (index, chunk) in ids.chunks(PAGE_SIZE).enumerate() {
+                if index > 0 {
+                    tokio::task::yield_now().await;
+                }
+
+                for id in chunk {
+                    let now = self.now();
+                    let dirty = match self.inner.get(id) {
+                        Some(session) if session.store && !session.destroy => {
+                            // Expired sessions are left for the memory purge to remove.
+                            if session.expired(now) && !session.update {
+                                continue;
+                            }
+
+                            session.update
+                        }
+                        _ => continue,
+                    };
+
+                    report.memory_sessions_checked += 1;
+                    let storage_id = self.storage_id(id);
+
+                    if client.exists(&storage_id, table_name).await? {
+                        continue;
+                    }
+
+                    if !dirty {
+                        report.expired_in_database.record(&storage_id);
+                        continue;
+                    }
+
+                    report.unpersisted.record(&storage_id);
+
+                    if repair {
+                        let session = self.inner.get_mut(id).map(|mut session| {
+                            session.expires =
+                                session.refreshed_expires(self.now(), &self.runtime_config());
+                            session.update = false;
+                            session.clone()
+                        });
+
+                        if let Some(session) = session {
+                            self.store_session(&session).await?;
+                            report.repaired += 1;
+                        }
+                    }
+                }
+            }
+        }
+
+        #[cfg(feature = "key-store")]
+        if let Some(expected) = expected {
+            let filter = self.filter.read().await;
+            let config = filter.config();
+            let excess: usize = (0..config.size)
+                .map(|index| {
+                    filter
+                        .counter_at(index)
+                        .saturating_sub(expected.counter_at(index))
+                })
+                .sum();
+
+            report.dangling_filter_entries = excess.div_ceil(config.hashes.max(1) as usize);
+        }
+
+        Ok(report)
+    }
+}
+
+/// Adds the id the same way [`crate::Session`] does so the counters line up with the live filter.
+#[cfg(feature = "key-store")]
+fn add_once(filter: &mut fastbloom_rs::CountingBloomFilter, id: &str) {
+    if !filter.contains(id.as_bytes()) {
+        filter.add(id.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{databases::test_pool::SessionTestPool, SessionConfig, SessionData, SessionStore};
+    use chrono::{Duration, Utc};
+    #[cfg(feature = "key-store")]
+    use fastbloom_rs::Membership;
+
+    async fn store() -> (SessionStore<SessionTestPool>, SessionTestPool) {
+        let pool = SessionTestPool::default();
+        let store = SessionStore::new(Some(pool.clone()), SessionConfig::default())
+            .await
+            .unwrap();
+        (store, pool)
+    }
+
+    fn memory_session(store: &SessionStore<SessionTestPool>, id: &str, dirty: bool) {
+        let mut data = SessionData::new(
+            id.to_owned(),
+            true,
+            &store.runtime_config(),
+            store.config.clock.as_ref(),
+        );
+        data.update = dirty;
+        store.inner.insert(id.to_owned(), data);
+    }
+
+    #[tokio::test]
+    async fn consistent_store() {
+        let (store, pool) = store().await;
+        let expires = (Utc::now() + Duration::try_hours(1).unwrap()).timestamp();
+
+        // Enough rows to need several pages.
+        for index in 0..1_200 {
+            let id = format!("row-{index:04}");
+            pool.insert_row("sessions", &id, "{}", expires);
+        }
+
+        memory_session(&store, "row-0001", false);
+        // Sessions that are not storable are never expected within the database.
+        let mut data = SessionData::new(
+            "memory-only".to_owned(),
+            false,
+            &store.runtime_config(),
+            store.config.clock.as_ref(),
+        );
+        data.update = true;
+        store.inner.insert("memory-only".to_owned(), data);
+
+        let report = store.verify_integrity(false).await.unwrap();
+        assert_eq!(report.database_ids_scanned, 1_200);
+        assert_eq!(report.memory_sessions_checked, 1);
+        assert_eq!(report.unpersisted.count, 0);
+        assert_eq!(report.expired_in_database.count, 0);
+    }
+
+    #[tokio::test]
+    async fn memory_and_database_mismatches() {
+        let (store, pool) = store().await;
+        let expired = (Utc::now() - Duration::try_hours(1).unwrap()).timestamp();
+
+        memory_session(&store, "unsaved", true);
+        memory_session(&store, "expired-row", false);
+        pool.insert_row("sessions", "expired-row", "{}", expired);
+
+        let report = store.verify_integrity(false).await.unwrap();
+        assert_eq!(report.unpersisted.count, 1);
+        assert_eq!(report.unpersisted.samples, vec!["unsaved".to_owned()]);
+        assert_eq!(report.expired_in_database.count, 1);
+        assert_eq!(
+            report.expired_in_database.samples,
+            vec!["expired-row".to_owned()]
+        );
+        assert_eq!(report.repaired, 0);
+        assert!(!report.is_consistent());
+        // Auditing without repair changes nothing.
+        assert!(pool.row("sessions", "unsaved").is_none());
+
+        let report = store.verify_integrity(true).await.unwrap();
+        assert_eq!(report.repaired, 1);
+        assert!(pool.row("sessions", "unsaved").is_some());
+        assert!(!store.inner.get("unsaved").unwrap().update);
+
+        let report = store.verify_integrity(false).await.unwrap();
+        assert_eq!(report.unpersisted.count, 0);
+        // Expired rows are only reported.
+        assert_eq!(report.expired_in_database.count, 1);
+    }
+
+    #[cfg(feature = "key-store")]
+    #[tokio::test]
+    async fn filter_mismatches() {
+        let config = SessionConfig::default().with_bloom_filter(true);
+        let pool = SessionTestPool::default();
+        let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+        let expires = (Utc::now() + Duration::try_hours(1).unwrap()).timestamp();
+
+        let report = store.verify_integrity(false).await.unwrap();
+        assert!(report.is_consistent());
+
+        // Written by another instance after the filter was filled.
+        pool.insert_row("sessions", "other-instance", "{}", expires);
+        // Left behind by a session that no longer exists anywhere.
+        store.filter.write().await.add(b"gone");
+
+        let report = store.verify_integrity(false).await.unwrap();
+        assert_eq!(report.missing_from_filter.count, 1);
+        assert_eq!(
+            report.missing_from_filter.samples,
+            vec!["other-instance".to_owned()]
+        );
+        assert_eq!(report.dangling_filter_entries, 1);
+
+        let report = store.verify_integrity(true).await.unwrap();
+        assert_eq!(report.repaired, 1);
+        assert!(store.filter.read().await.contains(b"other-instance"));
+
+        let report = store.verify_integrity(false).await.unwrap();
+        assert_eq!(report.missing_from_filter.count, 0);
+        assert_eq!(report.dangling_filter_entries, 1);
+    }
+}
@@ -1,8 +1,15 @@
-use std::fmt;
+use std::{
+    fmt,
+    sync::{Arc, OnceLock},
+};
 
-use crate::{DatabasePool, SessionService, SessionStore};
+use crate::{DatabasePool, Session, SessionService, SessionStore};
+use http::HeaderMap;
 use tower_layer::Layer;
 
+/// Hook receiving the Session and the response Headers after the Session's Cookies or Headers are set.
+pub type ResponseHook<T> = Arc<dyn Fn(&Session<T>, &mut HeaderMap) + Send + Sync>;
+
 /// Sessions Layer used with Axum to activate the Service.
 ///
 /// # Examples
@@ -21,6 +28,8 @@ where
     T: DatabasePool + Clone + fmt::Debug + std::marker::Sync + std::marker::Send + 'static,
 {
     session_store: SessionStore<T>,
+    mark_sensitive: bool,
+    response_hook: Option<ResponseHook<T>>,
 }
 
 impl<T> SessionLayer<T>
@@ -41,7 +50,54 @@ where
     ///
     #[inline]
     pub fn new(session_store: SessionStore<T>) -> Self {
-        SessionLayer { session_store }
+        SessionLayer {
+            session_store,
+            mark_sensitive: false,
+            response_hook: None,
+        }
+    }
+
+    /// Set's whether the Service marks the crate's own Headers as sensitive.
+    ///
+    /// When enabled the values of the Headers returned by [`crate::sensitive_headers`] are
+    /// marked sensitive on the request before the inner service sees it and on the response,
+    /// so logging layers wrapping the Session layer redact them. Defaults to false.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let layer = SessionLayer::new(session_store).with_mark_sensitive(true);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_mark_sensitive(mut self, enable: bool) -> Self {
+        self.mark_sensitive = enable;
+        self
+    }
+
+    /// Set's a hook that can change the response Headers based on the Session.
+    ///
+    /// It runs for every response the layer handles once the Session's Cookies or Headers
+    /// are set, including error responses from the inner service. Responses the layer
+    /// rejects on its own, such as the parallel request limit, have no Session and skip it.
+    /// It runs before the Session is unloaded from memory, so it sees the Session's data even
+    /// with a zero memory lifespan.
+    /// Defaults to None.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let layer = SessionLayer::new(session_store).with_response_hook(Arc::new(
+    ///     |session: &Session<SessionNullPool>, headers: &mut HeaderMap| {
+    ///         if let Ok(value) = HeaderValue::from_str(&session.etag(&["user-id"])) {
+    ///             headers.insert(ETAG, value);
+    ///         }
+    ///     },
+    /// ));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_response_hook(mut self, hook: ResponseHook<T>) -> Self {
+        self.response_hook = Some(hook);
+        self
     }
 }
 
@@ -55,6 +111,9 @@ where
         SessionService {
             session_store: self.session_store.clone(),
             inner,
+            mark_sensitive: self.mark_sensitive,
+            sensitive_names: Arc::new(OnceLock::new()),
+            response_hook: self.response_hook.clone(),
         }
     }
 }
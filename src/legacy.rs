@@ -0,0 +1,155 @@
+use crate::SessionData;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::{collections::HashMap, time::Instant};
+
+/// Session payload layout written by axum_database_sessions 0.x and 1.x.
+///
+/// Those releases serialized every field, used a Uuid for the id and had no
+/// longterm or storable flags.
+#[derive(Debug, Deserialize)]
+pub(crate) struct LegacySessionData {
+    pub(crate) id: String,
+    pub(crate) data: HashMap<String, String>,
+    pub(crate) expires: DateTime<Utc>,
+    pub(crate) autoremove: DateTime<Utc>,
+    #[serde(default)]
+    pub(crate) destroy: bool,
+}
+
+impl LegacySessionData {
+    /// Upgrades the legacy payload into the current layout using the store's clock times.
+    /// The session is marked for update so the row gets rewritten in the new layout.
+    pub(crate) fn upgrade(self, id: String, now: DateTime<Utc>, instant: Instant) -> SessionData {
+        SessionData {
+            id,
+            data: self.data,
+            expires: self.expires,
+            // Replaced with the memory lifespan once the loaded session is added to memory.
+            autoremove: instant,
+            destroy: self.destroy,
+            renew: false,
+            longterm: false,
+            // Only storable sessions were ever saved to the database.
+            store: true,
+            update: true,
+            requests: 1,
+            pinned: false,
+            ephemeral: false,
+            renewing: false,
+            last_active: now,
+            // Never stored by those releases so the absolute max age counts from the upgrade.
+            created_at: now,
+            sizes: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        databases::test_pool::{FakeClock, SessionTestPool},
+        Clock, Session, SessionConfig, SessionData, SessionLayer, SessionStore,
+    };
+    use axum::{body::Body, routing::get, Router};
+    use chrono::{DateTime, Duration, SecondsFormat, Utc};
+    use http::{header, Request};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    /// Rows as written by axum_database_sessions 0.1 through 1.x, with nanosecond and whole
+    /// second timestamps, expiring an hour after the clock's time.
+    fn legacy_fixtures(now: DateTime<Utc>) -> [String; 2] {
+        let expires = now + Duration::try_hours(1).unwrap();
+        let autoremove = now + Duration::try_minutes(5).unwrap();
+        let row = |format: SecondsFormat, destroy: bool| {
+            format!(
+                r#"{{"id":"0b6ef4a4-4d48-4bb5-9b8e-6a8f36bd3c1f","data":{{"user":"\"42\"","count":"3"}},"expires":"{}","autoremove":"{}","destroy":{destroy}}}"#,
+                expires.to_rfc3339_opts(format, true),
+                autoremove.to_rfc3339_opts(format, true),
+            )
+        };
+
+        [
+            row(SecondsFormat::Nanos, false),
+            row(SecondsFormat::Secs, true),
+        ]
+    }
+
+    #[tokio::test]
+    async fn legacy_payloads_load_and_upgrade() {
+        let clock = FakeClock::new();
+        let pool = SessionTestPool::default();
+        let config = SessionConfig::default().with_clock(clock.clone());
+        let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+        // The upgrade takes its times from the store's clock, not the system clock.
+        clock.advance(Duration::try_minutes(10).unwrap());
+        let now = clock.now();
+        let expires = (now + Duration::try_hours(1).unwrap()).timestamp();
+
+        for (index, fixture) in legacy_fixtures(now).iter().enumerate() {
+            let id = format!("legacy-{index}");
+            pool.insert_row("sessions", &id, fixture, expires);
+
+            let session = store.load_session(id.clone()).await.unwrap().unwrap();
+            assert_eq!(session.id, id);
+            assert_eq!(session.data.get("user").unwrap(), "\"42\"");
+            assert_eq!(session.data.get("count").unwrap(), "3");
+            assert_eq!(session.expires.timestamp(), expires);
+            assert_eq!(session.last_active, now);
+            assert_eq!(session.created_at, now);
+            assert_eq!(session.autoremove, clock.instant());
+            assert_eq!(session.destroy, index == 1);
+            assert!(session.update);
+            assert!(session.store);
+            assert!(!session.longterm);
+
+            // Storing rewrites the row in the current layout.
+            store.store_session(&session).await.unwrap();
+            let (payload, _) = pool.row("sessions", &id).unwrap();
+            assert!(!payload.contains("autoremove"));
+            let upgraded = serde_json::from_str::<SessionData>(&payload).unwrap();
+            assert_eq!(upgraded.data, session.data);
+        }
+    }
+
+    #[tokio::test]
+    async fn legacy_sessions_load_through_the_layer() {
+        let clock = FakeClock::new();
+        let pool = SessionTestPool::default();
+        let config = SessionConfig::default().with_clock(clock.clone());
+        let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+        let app = Router::new()
+            .route(
+                "/",
+                get(|session: Session<SessionTestPool>| async move {
+                    session.get::<String>("user").unwrap_or_default()
+                }),
+            )
+            .layer(SessionLayer::new(store));
+
+        let id = "0b6ef4a4-4d48-4bb5-9b8e-6a8f36bd3c1f";
+        let [fixture, _] = legacy_fixtures(clock.now());
+        let expires = (clock.now() + Duration::try_hours(1).unwrap()).timestamp();
+        pool.insert_row("sessions", id, &fixture, expires);
+
+        // rest_mode reads the id from the session header instead of the cookie.
+        let request = if cfg!(feature = "rest_mode") {
+            Request::get("/").header("session", id)
+        } else {
+            Request::get("/").header(header::COOKIE, format!("session={id}"))
+        };
+        let res = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"42");
+
+        // The response phase rewrote the row in the current layout under the same id.
+        let (payload, _) = pool.row("sessions", id).unwrap();
+        assert!(!payload.contains("autoremove"));
+        let upgraded = serde_json::from_str::<SessionData>(&payload).unwrap();
+        assert_eq!(upgraded.data.get("count").map(String::as_str), Some("3"));
+    }
+}
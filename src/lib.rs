@@ -4,24 +4,72 @@
 #![forbid(unsafe_code)]
 #![cfg_attr(docsrs, feature(doc_auto_cfg, doc_cfg))]
 
+// Lets the SessionEntry derive refer to `::axum_session` from within this crate.
+#[cfg(feature = "derive")]
+extern crate self as axum_session;
+
+mod accounting;
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench;
+mod cache_key;
 mod config;
 pub mod databases;
+mod double_submit;
 mod errors;
 pub(crate) mod headers;
+mod integrity;
 mod layer;
+#[cfg(feature = "legacy-compat")]
+mod legacy;
 mod sec;
 mod service;
 mod session;
+mod session_change_set;
+mod session_counter;
 mod session_data;
+mod session_entry;
+mod session_id;
+mod session_size;
+mod session_snapshot;
+mod session_stats;
 mod session_store;
 
-pub use config::{IdGenerator, Key, SameSite, SessionConfig, SessionMode};
+#[cfg(feature = "derive")]
+pub use axum_session_derive::SessionEntry;
+pub use config::{
+    Clock, CorruptAction, CorruptSession, CorruptSessionHandler, ExpiredSessionsHandler,
+    ExternalRedirectPolicy, IdGenerator, Key, LoadFailureBehavior, RuntimeConfig, SameSite,
+    SessionConfig, SessionMode, SystemClock, TransportPrecedence, DEFAULT_MAX_ID_LENGTH,
+};
 pub use databases::*;
+pub use double_submit::{DoubleSubmitGuard, DOUBLE_SUBMIT_HEADER};
 pub use errors::SessionError;
-pub use layer::SessionLayer;
+pub use headers::{sensitive_headers, EmitOptions};
+pub use integrity::{IntegrityIssue, IntegrityReport};
+pub use layer::{ResponseHook, SessionLayer};
 pub use sec::*;
+
+/// Used by the SessionEntry derive so users do not need serde_json as a direct dependency.
+#[cfg(feature = "derive")]
+#[doc(hidden)]
+pub mod __private {
+    pub use serde_json;
+}
+#[cfg(feature = "advanced")]
+pub use session::SessionStatus;
 pub use session::{ReadOnlySession, Session};
+pub use session_change_set::SessionChangeSet;
+pub use session_counter::SessionCounter;
+pub use session_entry::SessionEntry;
+pub use session_id::SessionId;
+pub use session_snapshot::SnapshotGuard;
+pub use session_stats::SessionStats;
 pub use session_store::SessionStore;
 
 pub(crate) use service::SessionService;
-pub(crate) use session_data::{SessionData, SessionTimers};
+#[cfg(feature = "test-utils")]
+pub use session_data::SessionData;
+#[cfg(not(feature = "test-utils"))]
+pub(crate) use session_data::SessionData;
+pub(crate) use session_data::SessionTimers;
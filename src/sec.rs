@@ -1,4 +1,8 @@
+mod cipher;
 pub(crate) mod encrypt;
+pub(crate) mod hash;
 mod signed;
 
+pub(crate) use cipher::{decrypt_payload, encrypt_payload};
+pub use cipher::{PayloadCipher, StaticKeyCipher, STATIC_KEY_CIPHER_ID};
 pub use signed::*;
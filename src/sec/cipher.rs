@@ -0,0 +1,193 @@
+use crate::{sec::encrypt, SessionError};
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine as _};
+use cookie::Key;
+use std::sync::Arc;
+
+/// Marks payloads which start with a cipher id.
+/// Rows written before cipher ids were added are plain base64 which never contains it.
+const CIPHER_PREFIX: char = '$';
+
+/// Cipher id of [`StaticKeyCipher`].
+pub const STATIC_KEY_CIPHER_ID: u8 = 1;
+
+/// Encrypts Session data before it is stored within the database.
+///
+/// Each stored payload starts with the [`PayloadCipher::id`] of the cipher that made it, so
+/// loading a row fails instead of handing the bytes to a cipher that can not read them.
+/// Ids 0 through 127 are reserved for ciphers within this crate, use 128 through 255 for your own.
+///
+/// The aad is the id the row is stored under and must be authenticated by the cipher so a
+/// payload can not be moved to another Session's row.
+///
+/// Both functions are called by many requests at the same time.
+/// Rows stored by a cipher being rotated away from are read by adding it with
+/// [`crate::SessionConfig::with_decrypt_cipher`].
+///
+/// # Examples
+/// ```rust
+/// use axum_session::{PayloadCipher, SessionConfig, SessionError};
+/// use std::sync::Arc;
+///
+/// /// Only shows the shape of a cipher. XOR with a fixed byte does not keep anything secret.
+/// struct XorCipher(u8);
+///
+/// #[async_trait::async_trait]
+/// impl PayloadCipher for XorCipher {
+///     fn id(&self) -> u8 {
+///         200
+///     }
+///
+///     async fn encrypt(&self, aad: &str, plaintext: &[u8]) -> Result<Vec<u8>, SessionError> {
+///         // The aad is stored ahead of the payload and compared when decrypting.
+///         let mut data = (aad.len() as u32).to_be_bytes().to_vec();
+///         data.extend(aad.as_bytes());
+///         data.extend(plaintext.iter().map(|byte| byte ^ self.0));
+///         Ok(data)
+///     }
+///
+///     async fn decrypt(&self, aad: &str, ciphertext: &[u8]) -> Result<Vec<u8>, SessionError> {
+///         let payload = ciphertext
+///             .strip_prefix((aad.len() as u32).to_be_bytes().as_slice())
+///             .and_then(|rest| rest.strip_prefix(aad.as_bytes()))
+///             .ok_or_else(|| SessionError::GenericNotSupportedError("aad mismatch".to_owned()))?;
+///         Ok(payload.iter().map(|byte| byte ^ self.0).collect())
+///     }
+/// }
+///
+/// let config = SessionConfig::default().with_payload_cipher(Arc::new(XorCipher(0x5a)));
+/// ```
+///
+#[async_trait]
+pub trait PayloadCipher: Send + Sync {
+    /// Identifies the cipher within the stored payloads. Must never change once rows are stored.
+    fn id(&self) -> u8;
+
+    /// Encrypts the plaintext, authenticating the aad alongside it.
+    async fn encrypt(&self, aad: &str, plaintext: &[u8]) -> Result<Vec<u8>, SessionError>;
+
+    /// Decrypts bytes made by [`PayloadCipher::encrypt`] using the same aad.
+    async fn decrypt(&self, aad: &str, ciphertext: &[u8]) -> Result<Vec<u8>, SessionError>;
+}
+
+/// AES-256-GCM using a static key. Used by [`crate::SessionConfig::with_database_key`].
+///
+/// Also decrypts rows written before stored payloads started with a cipher id.
+///
+/// # Examples
+/// ```rust
+/// use axum_session::{Key, SessionConfig, StaticKeyCipher};
+/// use std::sync::Arc;
+///
+/// let cipher = StaticKeyCipher::new(Key::generate());
+/// let config = SessionConfig::default().with_payload_cipher(Arc::new(cipher));
+/// ```
+///
+#[derive(Clone)]
+pub struct StaticKeyCipher {
+    key: Key,
+}
+
+impl StaticKeyCipher {
+    /// Constructs a StaticKeyCipher from the encryption half of the key.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session::{Key, StaticKeyCipher};
+    ///
+    /// let cipher = StaticKeyCipher::new(Key::generate());
+    /// ```
+    ///
+    pub fn new(key: Key) -> Self {
+        Self { key }
+    }
+}
+
+#[async_trait]
+impl PayloadCipher for StaticKeyCipher {
+    fn id(&self) -> u8 {
+        STATIC_KEY_CIPHER_ID
+    }
+
+    async fn encrypt(&self, aad: &str, plaintext: &[u8]) -> Result<Vec<u8>, SessionError> {
+        encrypt::seal(aad, plaintext, &self.key).map_err(|e| {
+            SessionError::GenericNotSupportedError(format!(
+                "Error: {} Occurred when encrypting a Session.",
+                e
+            ))
+        })
+    }
+
+    async fn decrypt(&self, aad: &str, ciphertext: &[u8]) -> Result<Vec<u8>, SessionError> {
+        encrypt::open(aad, ciphertext, &self.key)
+    }
+}
+
+/// Encrypts the payload and prefixes it with the cipher's id.
+pub(crate) async fn encrypt_payload(
+    cipher: &dyn PayloadCipher,
+    aad: &str,
+    payload: &str,
+) -> Result<String, SessionError> {
+    let mut data = vec![cipher.id()];
+    data.extend(cipher.encrypt(aad, payload.as_bytes()).await?);
+
+    Ok(format!(
+        "{CIPHER_PREFIX}{}",
+        general_purpose::STANDARD.encode(data)
+    ))
+}
+
+/// Decrypts a payload made by [`encrypt_payload`] or by the static key before cipher ids,
+/// using the cipher or the decrypt only cipher whose id made it.
+pub(crate) async fn decrypt_payload(
+    cipher: &dyn PayloadCipher,
+    decrypt_ciphers: &[Arc<dyn PayloadCipher>],
+    aad: &str,
+    raw: &str,
+) -> Result<String, SessionError> {
+    let find = |id: u8| {
+        std::iter::once(cipher)
+            .chain(decrypt_ciphers.iter().map(|cipher| cipher.as_ref()))
+            .find(|cipher| cipher.id() == id)
+    };
+
+    let plaintext = match raw.strip_prefix(CIPHER_PREFIX) {
+        Some(encoded) => {
+            let data = general_purpose::STANDARD.decode(encoded)?;
+
+            match data.split_first() {
+                Some((id, ciphertext)) => match find(*id) {
+                    Some(cipher) => cipher.decrypt(aad, ciphertext).await?,
+                    None => {
+                        return Err(SessionError::GenericNotSupportedError(format!(
+                            "payload was encrypted by cipher {} but cipher {} is configured",
+                            id,
+                            cipher.id()
+                        )))
+                    }
+                },
+                None => {
+                    return Err(SessionError::GenericNotSupportedError(
+                        "payload is missing its cipher id".to_owned(),
+                    ))
+                }
+            }
+        }
+        None => match find(STATIC_KEY_CIPHER_ID) {
+            Some(cipher) => {
+                cipher
+                    .decrypt(aad, &general_purpose::STANDARD.decode(raw)?)
+                    .await?
+            }
+            None => {
+                return Err(SessionError::GenericNotSupportedError(format!(
+                    "payload has no cipher id and cipher {} can not read legacy rows",
+                    cipher.id()
+                )))
+            }
+        },
+    };
+
+    Ok(String::from_utf8(plaintext)?)
+}
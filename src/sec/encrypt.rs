@@ -11,8 +11,17 @@ pub(crate) const KEY_LEN: usize = 32;
 
 ///Used to encrypt the database Values
 pub(crate) fn encrypt(name: &str, value: &str, key: &Key) -> Result<String, &'static str> {
-    let val = value.as_bytes();
+    Ok(general_purpose::STANDARD.encode(seal(name, value.as_bytes(), key)?))
+}
 
+///Used to decrypt the database Values.
+pub(crate) fn decrypt(name: &str, value: &str, key: &Key) -> Result<String, SessionError> {
+    let data = general_purpose::STANDARD.decode(value)?;
+    Ok(String::from_utf8(open(name, &data, key)?)?)
+}
+
+/// Encrypts the bytes into nonce, ciphertext and tag bound to the name.
+pub(crate) fn seal(name: &str, val: &[u8], key: &Key) -> Result<Vec<u8>, &'static str> {
     let mut data = vec![0; NONCE_LEN + val.len() + TAG_LEN];
     let (nonce, in_out) = data.split_at_mut(NONCE_LEN);
     let (in_out, tag) = in_out.split_at_mut(val.len());
@@ -33,12 +42,11 @@ pub(crate) fn encrypt(name: &str, value: &str, key: &Key) -> Result<String, &'st
 
     tag.copy_from_slice(aad_tag.as_slice());
 
-    Ok(general_purpose::STANDARD.encode(&data))
+    Ok(data)
 }
 
-///Used to decrypt the database Values.
-pub(crate) fn decrypt(name: &str, value: &str, key: &Key) -> Result<String, SessionError> {
-    let data = general_purpose::STANDARD.decode(value)?;
+/// Decrypts bytes made by [`seal`] using the same name.
+pub(crate) fn open(name: &str, data: &[u8], key: &Key) -> Result<Vec<u8>, SessionError> {
     if data.len() <= NONCE_LEN {
         return Err(SessionError::GenericNotSupportedError(
             "length of decoded data is <= NONCE_LEN".to_owned(),
@@ -52,12 +60,8 @@ pub(crate) fn decrypt(name: &str, value: &str, key: &Key) -> Result<String, Sess
     };
 
     let aead = Aes256Gcm::new(GenericArray::from_slice(key.encryption()));
-    Ok(String::from_utf8(
-        aead.decrypt(GenericArray::from_slice(nonce), payload)
-            .map_err(|_| {
-                SessionError::GenericNotSupportedError(
-                    "invalid key/nonce/value: bad seal".to_owned(),
-                )
-            })?,
-    )?)
+    aead.decrypt(GenericArray::from_slice(nonce), payload)
+        .map_err(|_| {
+            SessionError::GenericNotSupportedError("invalid key/nonce/value: bad seal".to_owned())
+        })
 }
@@ -0,0 +1,23 @@
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+
+/// Hashes a Session ID with the pepper so the ID stored within the database
+/// can not be correlated with the ID sent within the Cookie or Header.
+pub(crate) fn hash_id(id: &str, pepper: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(id.as_bytes());
+    hasher.update(pepper);
+
+    hasher
+        .finalize()
+        .iter()
+        .fold(String::with_capacity(64), |mut out, b| {
+            let _ = write!(out, "{:02x}", b);
+            out
+        })
+}
+
+/// Checks if the ID looks like it was produced by `hash_id`.
+pub(crate) fn is_hashed_id(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
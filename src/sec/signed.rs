@@ -57,7 +57,11 @@ impl<J> AdditionalSignedJar<J> {
     pub(crate) fn new(parent: J, key: &Key, message: String) -> AdditionalSignedJar<J> {
         AdditionalSignedJar {
             parent,
-            key: key.signing().try_into().expect("sign key len"),
+            // `Key` always holds a 32 byte signing key so this can not fail.
+            key: key
+                .signing()
+                .try_into()
+                .expect("cookie::Key signing keys are 32 bytes"),
             message,
         }
     }
@@ -1,15 +1,24 @@
-use crate::{headers::*, DatabasePool, Session, SessionData, SessionError, SessionStore};
+use crate::{
+    accounting::{self, Join},
+    config::instant_after,
+    double_submit::DoubleSubmitContext,
+    headers::*,
+    layer::ResponseHook,
+    DatabasePool, ExternalRedirectPolicy, LoadFailureBehavior, Session, SessionChangeSet,
+    SessionConfig, SessionData, SessionError, SessionStore,
+};
 use axum::{response::Response, BoxError};
 use bytes::Bytes;
-use chrono::Utc;
 #[cfg(feature = "key-store")]
 use fastbloom_rs::Deletable;
 use futures::future::BoxFuture;
-use http::Request;
+use http::{HeaderName, Request};
 use http_body::Body as HttpBody;
 use std::{
     convert::Infallible,
     fmt::{self, Debug, Formatter},
+    net::IpAddr,
+    sync::{Arc, OnceLock},
     task::{Context, Poll},
 };
 use tower_service::Service;
@@ -21,6 +30,12 @@ where
 {
     pub(crate) session_store: SessionStore<T>,
     pub(crate) inner: S,
+    /// Marks the crate's own Headers as sensitive on the request and response.
+    pub(crate) mark_sensitive: bool,
+    /// The sensitive Header names, built by the first request as the config can not change.
+    pub(crate) sensitive_names: Arc<OnceLock<Vec<HeaderName>>>,
+    /// Changes the response Headers after the Session's are set.
+    pub(crate) response_hook: Option<ResponseHook<T>>,
 }
 
 pub(crate) fn trace_error<ResBody>(
@@ -37,6 +52,44 @@ where
     Ok(res)
 }
 
+/// Counts a brand new session against the creation limit shared by every instance.
+/// Returns true once the client's IP created more sessions than allowed.
+async fn creation_limited<T>(store: &SessionStore<T>, ip: Option<IpAddr>) -> bool
+where
+    T: DatabasePool + Clone + Debug + Sync + Send + 'static,
+{
+    let Some(ip) = ip else {
+        return false;
+    };
+
+    if store.creation_allowed(&ip.to_string()).await {
+        return false;
+    }
+
+    tracing::warn!(ip = %ip, "Session creation limit reached.");
+    true
+}
+
+fn parallel_limited<T, ResBody: Default>(store: &SessionStore<T>, id: &str) -> Response<ResBody>
+where
+    T: DatabasePool + Clone + Debug + Sync + Send + 'static,
+{
+    tracing::warn!(
+        session.id = %store.config.log_id(id),
+        err = %SessionError::ParallelRequestLimit,
+        "Request rejected."
+    );
+    let mut res = Response::default();
+    *res.status_mut() = store.config.memory.parallel_limit_status;
+    res
+}
+
+fn too_many_requests<ResBody: Default>() -> Response<ResBody> {
+    let mut res = Response::default();
+    *res.status_mut() = http::StatusCode::TOO_MANY_REQUESTS;
+    res
+}
+
 impl<S, T, ReqBody, ResBody> Service<Request<ReqBody>> for SessionService<S, T>
 where
     S: Service<Request<ReqBody>, Response = Response<ResBody>, Error = Infallible>
@@ -62,21 +115,28 @@ where
         let store = self.session_store.clone();
         let not_ready_inner = self.inner.clone();
         let mut ready_inner = std::mem::replace(&mut self.inner, not_ready_inner);
+        let sensitive = self.mark_sensitive.then(|| {
+            self.sensitive_names
+                .get_or_init(|| sensitive_headers(&store.config));
+            self.sensitive_names.clone()
+        });
+        let response_hook = self.response_hook.clone();
 
         Box::pin(async move {
-            let ip_user_agent = get_ips_hash(&req, &store);
+            if let Some(names) = sensitive.as_deref().and_then(OnceLock::get) {
+                mark_sensitive(req.headers_mut(), names);
+            }
 
-            #[cfg(not(feature = "rest_mode"))]
-            let cookies = get_cookies(req.headers());
+            if let Err(err) = check_connect_info(&req, &store) {
+                return trace_error(err, "request has no Socket IP to sign the Session with");
+            }
 
-            #[cfg(not(feature = "rest_mode"))]
-            let (session_id, storable) = get_headers_and_key(&store, cookies, &ip_user_agent).await;
+            let ip_user_agent = get_ips_hash(&req, &store);
 
-            #[cfg(feature = "rest_mode")]
-            let headers = get_headers(&store, req.headers());
+            let (session_id, storable, transport, legacy_cookies) =
+                get_headers_and_key(&store, req.headers(), &ip_user_agent);
 
-            #[cfg(feature = "rest_mode")]
-            let (session_id, storable) = get_headers_and_key(&store, headers, &ip_user_agent).await;
+            let client_ip = get_client_ip(&req, &store);
 
             let (mut session, is_new) = match Session::new(store, session_id).await {
                 Ok(v) => v,
@@ -88,38 +148,129 @@ where
             // Check if the session id exists if not lets check if it exists in the database or generate a new session.
             // If manual mode is enabled then do not check for a Session unless the ID is not new.
             let check_database: bool = if is_new && !session.store.config.session_mode.is_manual() {
-                let sess = SessionData::new(session.id.clone(), storable, &session.store.config);
+                if creation_limited(&session.store, client_ip).await {
+                    return Ok(too_many_requests());
+                }
+
+                let sess = SessionData::new(
+                    session.id.clone(),
+                    storable,
+                    &session.store.runtime_config(),
+                    session.store.config.clock.as_ref(),
+                );
                 session.store.inner.insert(session.id.clone(), sess);
                 false
-            } else if !is_new || !session.store.config.session_mode.is_manual() {
-                !session.store.service_session_data(&session)
             } else {
-                false
+                !is_new || !session.store.config.session_mode.is_manual()
             };
 
+            // Requests sending an ID which is being renewed wait for the renewal and start over,
+            // as the ID is revoked once it finished.
             if check_database {
-                let mut fresh_session = session
-                    .store
-                    .load_session(session.id.clone())
-                    .await
-                    .ok()
-                    .flatten()
-                    .unwrap_or_else(|| {
-                        tracing::info!(
-                            "Session {} did not exist in Database. So it was Recreated.",
-                            session.id.clone()
-                        );
-                        SessionData::new(session.id.clone(), storable, &session.store.config)
+                loop {
+                    // Created before checking so a renewal finishing in between still wakes it.
+                    let renewed = session.store.renewed.notified();
+
+                    match session.store.service_session_data(&session) {
+                        Join::Joined => break,
+                        Join::Renewing => {
+                            renewed.await;
+                            continue;
+                        }
+                        Join::Limited => {
+                            return Ok(parallel_limited(&session.store, &session.id));
+                        }
+                        Join::Missing => {}
+                    }
+
+                    // Database failures must not be treated as a missing session by default,
+                    // otherwise a short outage would recreate and log out every user loaded during it.
+                    // Corrupt sessions are already handled by the corrupt session handler.
+                    let (loaded, ephemeral) =
+                        match session.store.load_session(session.id.clone()).await {
+                            Ok(loaded) => {
+                                if loaded.is_none() {
+                                    tracing::info!(
+                                        session.id = %session.store.config.log_id(&session.id),
+                                        "Session did not exist in Database. So it was Recreated."
+                                    );
+                                }
+
+                                (loaded, false)
+                            }
+                            Err(err) => {
+                                tracing::error!(
+                                    session.id = %SessionConfig::hashed_log_id(&session.id),
+                                    err = %err,
+                                    "failed to load session from database"
+                                );
+
+                                let behavior = if err.is_retryable() {
+                                    session.store.config.database.load_failure_behavior
+                                } else {
+                                    LoadFailureBehavior::FailRequest
+                                };
+
+                                match behavior {
+                                    LoadFailureBehavior::FailRequest => {
+                                        let mut res = Response::default();
+                                        *res.status_mut() = http::StatusCode::INTERNAL_SERVER_ERROR;
+                                        return Ok(res);
+                                    }
+                                    LoadFailureBehavior::RecreateEmpty => (None, false),
+                                    LoadFailureBehavior::ServeEphemeral => (None, true),
+                                }
+                            }
+                        };
+
+                    // An unknown ID creates a session as well, so it counts against the limit too.
+                    if loaded.is_none()
+                        && !ephemeral
+                        && creation_limited(&session.store, client_ip).await
+                    {
+                        return Ok(too_many_requests());
+                    }
+
+                    let mut fresh_session = loaded.unwrap_or_else(|| {
+                        SessionData::new(
+                            session.id.clone(),
+                            storable,
+                            &session.store.runtime_config(),
+                            session.store.config.clock.as_ref(),
+                        )
                     });
+                    fresh_session.ephemeral = ephemeral;
 
-                fresh_session.autoremove = Utc::now() + session.store.config.memory.memory_lifespan;
-                fresh_session.store = storable;
-                fresh_session.update = true;
-                fresh_session.requests = 1;
-                session
-                    .store
-                    .inner
-                    .insert(session.id.clone(), fresh_session);
+                    fresh_session.autoremove = session.store.memory_autoremove();
+                    // Keep the storable flag of a loaded session even if the store cookie or header was not sent.
+                    fresh_session.store = storable || fresh_session.store;
+                    fresh_session.update = true;
+                    fresh_session.requests = 1;
+                    fresh_session.last_active = session.store.now();
+                    // The row may predate a lower absolute max age set at runtime.
+                    fresh_session.clear_past_max_age(
+                        fresh_session.last_active,
+                        &session.store.runtime_config(),
+                        &session.changes,
+                    );
+                    // Another request may have loaded it while we waited on the database so join theirs.
+                    let renewed = session.store.renewed.notified();
+                    let now = session.store.now();
+
+                    match accounting::insert_loaded(
+                        &*session.store.inner,
+                        &session.id,
+                        fresh_session,
+                        session.store.config.memory.max_parallel_requests,
+                        now,
+                    ) {
+                        Join::Renewing => renewed.await,
+                        Join::Limited => {
+                            return Ok(parallel_limited(&session.store, &session.id));
+                        }
+                        Join::Joined | Join::Missing => break,
+                    }
+                }
             }
 
             let (last_sweep, last_database_sweep) = {
@@ -131,14 +282,13 @@ where
             // let's check if any sessions expired. We don't want to hog memory
             // forever by abandoned sessions (e.g. when a client lost their cookie)
             // throttle by memory lifespan - e.g. sweep every hour
-            let current_time = Utc::now();
+            // Monotonic so a wall clock stepping forwards can not unload sessions early.
+            let current_time = session.store.config.clock.instant();
 
-            if last_sweep <= current_time && !session.store.config.memory.memory_lifespan.is_zero()
+            if last_sweep <= current_time
+                && !session.store.runtime_config().memory_lifespan.is_zero()
             {
-                tracing::info!(
-                    "Session id {}: Session Memory Cleaning Started",
-                    session.id.clone()
-                );
+                tracing::info!(session.id = %session.store.config.log_id(&session.id), "Session Memory Cleaning Started");
                 // Only unload these from filter if the Client is None as this means no database.
                 // Otherwise only unload from the filter if removed from the Database.
                 #[cfg(feature = "key-store")]
@@ -151,29 +301,22 @@ where
                         .store
                         .inner
                         .iter()
-                        .filter(|r| r.autoremove < current_time)
-                        .for_each(|r| filter.remove(r.key().as_bytes()));
+                        .filter(|r| !r.kept_in_memory(current_time))
+                        .for_each(|r| filter.remove(session.store.storage_id(r.key()).as_bytes()));
                 }
 
-                session
-                    .store
-                    .inner
-                    .retain(|_k, v| v.autoremove > current_time);
-
-                session.store.timers.write().await.last_expiry_sweep =
-                    Utc::now() + session.store.config.memory.purge_update;
-                tracing::info!(
-                    "Session id {}: Session Memory Cleaning Finished",
-                    session.id
+                accounting::sweep_memory(&*session.store.inner, current_time);
+
+                session.store.timers.write().await.last_expiry_sweep = instant_after(
+                    session.store.config.clock.instant(),
+                    session.store.runtime_config().purge_update,
                 );
+                tracing::info!(session.id = %session.store.config.log_id(&session.id), "Session Memory Cleaning Finished");
             }
 
             // Throttle by database lifespan - e.g. sweep every 6 hours
             if last_database_sweep <= current_time && session.store.is_persistent() {
-                tracing::info!(
-                    "Session id {}: Session Database Cleaning Started",
-                    session.id
-                );
+                tracing::info!(session.id = %session.store.config.log_id(&session.id), "Session Database Cleaning Started");
                 //Remove any old keys that expired and Remove them from our loaded filter.
                 #[cfg(feature = "key-store")]
                 let expired = match session.store.cleanup().await {
@@ -202,74 +345,111 @@ where
                     .timers
                     .write()
                     .await
-                    .last_database_expiry_sweep =
-                    Utc::now() + session.store.config.database.purge_database_update;
-                tracing::info!(
-                    "Session id {}: Session Database Cleaning Finished",
-                    session.id
+                    .last_database_expiry_sweep = instant_after(
+                    session.store.config.clock.instant(),
+                    session.store.runtime_config().purge_database_update,
                 );
+                tracing::info!(session.id = %session.store.config.log_id(&session.id), "Session Database Cleaning Finished");
             }
 
+            // The request is moved into the inner service so decide this before then.
+            let mut emit = EmitOptions {
+                destroy: false,
+                storable,
+                ip_user_agent,
+                secure: get_cookie_secure(&req, &session.store.config),
+                transport,
+                legacy_cookies,
+            };
+
+            let origin = request_origin(&req, emit.secure);
+
             // Sets a clone of the Store in the Extensions for Direct usage and sets the Session for Direct usage
             //req.extensions_mut().insert(store.clone());
+            session.emit = Some(Arc::new(emit.clone()));
+
+            if let (Some(cookie_name), Some(key)) = (
+                double_submit_cookie_name(&session.store.config),
+                &session.store.config.cookie_and_header.key,
+            ) {
+                req.extensions_mut().insert(DoubleSubmitContext {
+                    cookie_name,
+                    key: key.clone(),
+                    id: session.id.clone(),
+                });
+            }
+
             req.extensions_mut().insert(session.clone());
 
             let mut response = ready_inner.call(req).await?;
 
-            let (renew, storable, destroy, loaded) =
-                if let Some(session_data) = session.store.inner.get(&session.id) {
+            // The Session the request carried is left as it is while navigating to another origin.
+            let frozen = !is_new
+                && session
+                    .store
+                    .config
+                    .cookie_and_header
+                    .external_redirect_policy
+                    == ExternalRedirectPolicy::FreezeSession
+                && is_external_redirect(response.status(), response.headers(), origin.as_ref());
+
+            let auto_store = session.store.config.session_mode.is_opt_in()
+                && session.store.config.auto_store_when_nonempty;
+
+            let (renew, storable, destroy, loaded, ephemeral, change_set) =
+                if let Some(mut session_data) = session.store.inner.get_mut(&session.id) {
+                    let nonempty = !session_data.data.is_empty();
+
+                    if auto_store && session_data.store != nonempty {
+                        session_data.set_store(nonempty);
+                    }
+
+                    // Reserved while the entry is locked so only the last of several parallel
+                    // requests regenerates the ID, the others leave the Session as is.
+                    // Frozen responses leave it for the next response to renew.
+                    // Ephemeral sessions never renew as that would delete the row they stand in for.
+                    let renew = !frozen
+                        && !session_data.destroy
+                        && !session_data.ephemeral
+                        && accounting::take_renew(&mut session_data);
+
                     (
-                        session_data.renew,
+                        renew,
                         session_data.store,
                         session_data.destroy,
                         true,
+                        session_data.ephemeral,
+                        session.changes.take(&session_data.data),
                     )
                 } else {
-                    (false, false, false, false)
+                    (
+                        false,
+                        false,
+                        false,
+                        false,
+                        false,
+                        SessionChangeSet::default(),
+                    )
                 };
 
+            response.extensions_mut().insert(change_set);
+
             tracing::trace!(
+                session.id = %session.store.config.log_id(&session.id),
                 renew = renew,
                 storable = storable,
                 destroy = destroy,
                 loaded = loaded,
-                "Session id: {}",
-                session.id
+                "Session request finished"
             );
 
-            if !destroy && (!session.store.config.session_mode.is_manual() || loaded) && renew {
+            if renew {
                 // Lets change the Session ID and destory the old Session from the database.
-                let session_id = match Session::generate_id(&session.store).await {
-                    Ok(v) => v,
+                match session.store.renew_session(&session.id).await {
+                    Ok(session_id) => session.id = session_id,
                     Err(err) => {
-                        return trace_error(err, "failed to Generate Session ID");
+                        return trace_error(err, "failed to renew the Session ID");
                     }
-                };
-
-                // Lets remove it from the database first.
-                if session.store.is_persistent() {
-                    if let Err(err) = session
-                        .store
-                        .database_remove_session(session.id.clone())
-                        .await
-                    {
-                        return trace_error(err, "failed to remove session from database");
-                    };
-                }
-
-                //lets remove it from the filter. if the bottom fails just means it did not exist or was already unloaded.
-                #[cfg(feature = "key-store")]
-                if session.store.config.memory.use_bloom_filters {
-                    let mut filter = session.store.filter.write().await;
-                    filter.remove(session.id.as_bytes());
-                }
-
-                // Lets remove update and reinsert.
-                if let Some((_, mut session_data)) = session.store.inner.remove(&session.id) {
-                    session_data.id = session_id.clone();
-                    session_data.renew = false;
-                    session.id = session_id.clone();
-                    session.store.inner.insert(session.id.clone(), session_data);
                 }
             }
 
@@ -278,21 +458,31 @@ where
                 && session.store.is_persistent()
                 && !destroy
             {
-                let clone_session = if let Some(mut sess) =
-                    session.store.inner.get_mut(&session.id.clone())
+                let now = session.store.now();
+                let mut touch = false;
+                let clone_session = if let Some(mut sess) = session.store.inner.get_mut(&session.id)
                 {
                     // Check if Database needs to be updated or not. TODO: Make updatable based on a timer for in memory only.
-                    if session.store.config.database.always_save || sess.update || !sess.expired() {
-                        if sess.longterm {
-                            sess.expires = Utc::now() + session.store.config.max_lifespan;
-                        } else {
-                            sess.expires = Utc::now() + session.store.config.lifespan;
-                        };
+                    // Frozen sessions only save changed data without extending the expiry.
+                    if session.store.config.database.always_save
+                        || sess.update
+                        || (!frozen && sess.validate(now))
+                    {
+                        // Capped by the absolute max age so active sessions still expire.
+                        // A session loaded this request does not know its stored expiry, so it
+                        // is refreshed even when frozen rather than saved as expired.
+                        if !frozen || sess.expired(now) {
+                            sess.expires =
+                                sess.refreshed_expires(now, &session.store.runtime_config());
+                        }
 
                         sess.update = false;
 
                         Some(sess.clone())
                     } else {
+                        // Only a frozen session is left unsaved while still usable. Expired rows
+                        // are not counted as active, so they are not touched.
+                        touch = frozen && !sess.expired(now) && !sess.ephemeral;
                         None
                     }
                 } else {
@@ -303,32 +493,56 @@ where
                     if let Err(err) = session.store.store_session(&sess).await {
                         return trace_error(err, "failed to save session to database");
                     } else {
-                        tracing::info!("Session id {}: was saved to the database.", session.id);
+                        tracing::info!(session.id = %session.store.config.log_id(&session.id), "Session was saved to the database.");
+                    }
+                } else if touch {
+                    // Still counts as activity for active_count even though nothing was saved.
+                    if let Err(err) = session.store.touch_active(&session.id).await {
+                        tracing::warn!(
+                            session.id = %session.store.config.log_id(&session.id),
+                            err = %err,
+                            "Failed to mark the Session as active within the database."
+                        );
                     }
                 }
             }
 
+            // The cookie of an ephemeral session is left as it is so it does not outlive the row,
+            // and that of a frozen one so no freshly signed cookie goes along to another origin.
+            if !(ephemeral || frozen) || destroy {
+                emit.destroy = destroy;
+                emit.storable = storable;
+                set_headers(
+                    &session.store.config,
+                    &session.id,
+                    response.headers_mut(),
+                    &emit,
+                );
+            }
+
+            // Ran while the request still holds the Session so the hook sees its data
+            // before it is unloaded.
+            if let Some(hook) = &response_hook {
+                hook(&session, response.headers_mut());
+            }
+
             //lets tell the system we can unload this request now.
             //If there are still more left the bottom wont unload anything.
+            //The checks are made under the same lock as the unload so a request joining in between keeps it.
             session.remove_request();
 
             if ((session.store.config.session_mode.is_opt_in() && !storable) || destroy)
-                && !session.is_parallel()
+                && accounting::unload_if_idle(&*session.store.inner, &session.id, |_| true)
+                    .is_some()
             {
                 #[cfg(feature = "key-store")]
                 if session.store.config.memory.use_bloom_filters {
                     let mut filter = session.store.filter.write().await;
-                    filter.remove(session.id.as_bytes());
+                    filter.remove(session.store.storage_id(&session.id).as_bytes());
                 }
 
-                let _ = session.store.inner.remove(&session.id);
-
                 if session.store.is_persistent() {
-                    if let Err(err) = session
-                        .store
-                        .database_remove_session(session.id.clone())
-                        .await
-                    {
+                    if let Err(err) = session.store.database_remove_session(&session.id).await {
                         return trace_error(err, "failed to remove session from database");
                     }
                 }
@@ -336,23 +550,23 @@ where
 
             // We will Deleted the data in memory as it should be stored in the database instead.
             // if user is using this without a database then it will only work as a per request data store.
-            if session.store.config.memory.memory_lifespan.is_zero() && !session.is_parallel() {
+            // Ephemeral sessions are unloaded as well so the next request loads the row again.
+            if (session.store.runtime_config().memory_lifespan.is_zero() || ephemeral)
+                && accounting::unload_if_idle(&*session.store.inner, &session.id, |data| {
+                    !data.pinned
+                })
+                .is_some()
+            {
                 #[cfg(feature = "key-store")]
                 if !session.store.is_persistent() && session.store.config.memory.use_bloom_filters {
                     let mut filter = session.store.filter.write().await;
-                    filter.remove(session.id.as_bytes());
+                    filter.remove(session.store.storage_id(&session.id).as_bytes());
                 }
-
-                session.store.inner.remove(&session.id);
             }
 
-            set_headers(
-                &session,
-                response.headers_mut(),
-                &ip_user_agent,
-                destroy,
-                storable,
-            );
+            if let Some(names) = sensitive.as_deref().and_then(OnceLock::get) {
+                mark_sensitive(response.headers_mut(), names);
+            }
 
             Ok(response)
         })
@@ -368,6 +582,2182 @@ where
         f.debug_struct("SessionService")
             .field("session_store", &self.session_store)
             .field("inner", &self.inner)
+            .field("mark_sensitive", &self.mark_sensitive)
+            .field("response_hook", &self.response_hook.is_some())
             .finish()
     }
 }
+
+#[cfg(all(test, not(feature = "rest_mode")))]
+mod tests {
+    use crate::{
+        databases::test_pool::{FakeClock, SessionTestPool},
+        ExternalRedirectPolicy, IdGenerator, Key, LoadFailureBehavior, ResponseHook, Session,
+        SessionChangeSet, SessionConfig, SessionLayer, SessionMode, SessionNullPool, SessionStats,
+        SessionStore, TransportPrecedence,
+    };
+    use axum::{body::Body, extract::ConnectInfo, response::Response, routing::get, Router};
+    use http::{header, Request, StatusCode};
+    use http_body_util::BodyExt;
+    use std::{
+        net::{IpAddr, Ipv6Addr, SocketAddr},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::{Barrier, Semaphore},
+    };
+    use tower::ServiceExt;
+
+    /// Returns the `name=value` pairs of every cookie set by the response.
+    fn response_cookies<B>(res: &Response<B>) -> Vec<String> {
+        res.headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok()?.split(';').next().map(str::to_owned))
+            .collect()
+    }
+
+    /// Sends a request to the path with the cookies and returns the body and the new cookies.
+    async fn counter_request(app: &Router, path: &str, cookies: &mut String) -> String {
+        let res = app
+            .clone()
+            .oneshot(
+                Request::get(path)
+                    .header(header::COOKIE, cookies.as_str())
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let set = response_cookies(&res);
+
+        if !set.is_empty() {
+            *cookies = set.join("; ");
+        }
+
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    fn counter_app<P>(store: SessionStore<P>) -> Router
+    where
+        P: crate::DatabasePool + Clone + std::fmt::Debug + Sync + Send + 'static,
+    {
+        Router::new()
+            .route(
+                "/",
+                get(|session: Session<P>| async move {
+                    session
+                        .counter("api_calls")
+                        .incr(1)
+                        .await
+                        .unwrap()
+                        .to_string()
+                }),
+            )
+            .route(
+                "/get",
+                get(|session: Session<P>| async move {
+                    session
+                        .counter("api_calls")
+                        .get()
+                        .await
+                        .unwrap()
+                        .to_string()
+                }),
+            )
+            .route(
+                "/legacy",
+                get(|session: Session<P>| async move {
+                    session.set("api_calls", 10);
+                }),
+            )
+            .route(
+                "/renew",
+                get(|session: Session<P>| async move { session.renew() }),
+            )
+            .route(
+                "/destroy",
+                get(|session: Session<P>| async move { session.destroy() }),
+            )
+            .layer(SessionLayer::new(store))
+    }
+
+    fn session_id(cookies: &str) -> String {
+        cookies
+            .split("; ")
+            .find_map(|c| c.strip_prefix("session="))
+            .unwrap()
+            .to_owned()
+    }
+
+    #[tokio::test]
+    async fn native_counters_follow_the_session() {
+        let pool = SessionTestPool {
+            native_counters: true,
+            ..Default::default()
+        };
+        let store = SessionStore::new(Some(pool.clone()), SessionConfig::default())
+            .await
+            .unwrap();
+        let app = counter_app(store);
+        let mut cookies = String::new();
+
+        assert_eq!(counter_request(&app, "/", &mut cookies).await, "1");
+        assert_eq!(counter_request(&app, "/", &mut cookies).await, "2");
+        let id = session_id(&cookies);
+        let (value, expires) = pool.counter("sessions", &id, "api_calls").unwrap();
+        assert_eq!(value, 2);
+        // The counter expires with the row and is not part of it.
+        let (row, row_expires) = pool.row("sessions", &id).unwrap();
+        assert_eq!(expires, row_expires);
+        assert!(!row.contains("api_calls"));
+
+        counter_request(&app, "/renew", &mut cookies).await;
+        let renewed = session_id(&cookies);
+        assert_ne!(renewed, id);
+        assert!(pool.counter("sessions", &id, "api_calls").is_none());
+        assert_eq!(counter_request(&app, "/get", &mut cookies).await, "2");
+        assert_eq!(counter_request(&app, "/", &mut cookies).await, "3");
+
+        // A value set within the data before is moved into the counter.
+        counter_request(&app, "/legacy", &mut cookies).await;
+        assert_eq!(counter_request(&app, "/get", &mut cookies).await, "13");
+        assert_eq!(counter_request(&app, "/", &mut cookies).await, "14");
+        assert!(!pool
+            .row("sessions", &renewed)
+            .unwrap()
+            .0
+            .contains("api_calls"));
+
+        counter_request(&app, "/destroy", &mut cookies).await;
+        assert!(pool.counter("sessions", &renewed, "api_calls").is_none());
+        assert!(pool.counters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn counters_fall_back_to_session_data() {
+        let store = SessionStore::<SessionNullPool>::new(None, SessionConfig::default())
+            .await
+            .unwrap();
+        let app = counter_app(store.clone());
+        let mut cookies = String::new();
+
+        assert_eq!(counter_request(&app, "/", &mut cookies).await, "1");
+        assert_eq!(counter_request(&app, "/", &mut cookies).await, "2");
+        assert_eq!(counter_request(&app, "/get", &mut cookies).await, "2");
+        let id = session_id(&cookies);
+        assert_eq!(store.get::<i64>(&id, "api_calls"), Some(2));
+
+        // Values set before counters were used carry on.
+        counter_request(&app, "/legacy", &mut cookies).await;
+        assert_eq!(counter_request(&app, "/", &mut cookies).await, "11");
+    }
+
+    /// Hosts embedding the layer may run it on a current-thread runtime without the IO or
+    /// time drivers, so none of the core paths may need a driver or a second thread.
+    #[test]
+    fn core_paths_without_runtime_drivers() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let clock = FakeClock::new();
+            let pool = SessionTestPool {
+                native_counters: true,
+                ..Default::default()
+            };
+            let config = SessionConfig::default()
+                .with_memory_lifetime(chrono::Duration::try_minutes(1).unwrap())
+                .with_purge_update(chrono::Duration::try_minutes(1).unwrap())
+                .with_purge_database_update(chrono::Duration::try_minutes(1).unwrap())
+                .with_clock(clock.clone());
+            let store = SessionStore::new(Some(pool), config).await.unwrap();
+            let app = counter_app(store.clone());
+            let mut cookies = String::new();
+
+            assert_eq!(counter_request(&app, "/", &mut cookies).await, "1");
+            counter_request(&app, "/renew", &mut cookies).await;
+            assert_eq!(counter_request(&app, "/", &mut cookies).await, "2");
+
+            // Both sweeps are due, while parallel requests of the same session contend for
+            // the store's locks on the one thread.
+            clock.advance(chrono::Duration::try_minutes(61).unwrap());
+            let requests = (0..8).map(|_| {
+                app.clone().oneshot(
+                    Request::get("/")
+                        .header(header::COOKIE, cookies.as_str())
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+            });
+            for res in futures::future::join_all(requests).await {
+                assert_eq!(res.unwrap().status(), StatusCode::OK);
+            }
+            assert_eq!(counter_request(&app, "/get", &mut cookies).await, "10");
+
+            store.verify_integrity(true).await.unwrap();
+            store.cleanup().await.unwrap();
+            counter_request(&app, "/destroy", &mut cookies).await;
+            assert_eq!(store.count().await.unwrap(), 0);
+        });
+    }
+
+    /// Core path the MSRV job in CI runs on the oldest supported toolchain.
+    #[tokio::test]
+    async fn null_pool_cookie_round_trip() {
+        let store = SessionStore::<SessionNullPool>::new(None, SessionConfig::default())
+            .await
+            .unwrap();
+        let app = Router::new()
+            .route(
+                "/",
+                get(|session: Session<SessionNullPool>| async move {
+                    let count = session.get::<u32>("count").unwrap_or_default() + 1;
+                    session.set("count", count);
+                    count.to_string()
+                }),
+            )
+            .layer(SessionLayer::new(store).with_mark_sensitive(true));
+
+        let mut cookies = String::new();
+
+        for expected in ["1", "2", "3"] {
+            let res = app
+                .clone()
+                .oneshot(
+                    Request::get("/")
+                        .header(header::COOKIE, &cookies)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert!(res.headers()[header::SET_COOKIE].is_sensitive());
+
+            let set = response_cookies(&res);
+            if !set.is_empty() {
+                cookies = set.join("; ");
+            }
+
+            let body = res.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn max_parallel_requests() {
+        const LIMIT: usize = 3;
+
+        let config = SessionConfig::default().with_max_parallel_requests(Some(LIMIT));
+        let store = SessionStore::new(Some(SessionTestPool::default()), config)
+            .await
+            .unwrap();
+        // Accepted handlers wait until the test has checked the counter.
+        let entered = Arc::new(Barrier::new(LIMIT + 1));
+        let release = Arc::new(Barrier::new(LIMIT + 1));
+        let (handler_entered, handler_release) = (entered.clone(), release.clone());
+        let app = Router::new()
+            .route("/", get(|| async {}))
+            .route(
+                "/wait",
+                get(move |session: Session<SessionTestPool>| async move {
+                    assert!((1..=LIMIT).contains(&session.parallel_count()));
+                    handler_entered.wait().await;
+                    handler_release.wait().await;
+                }),
+            )
+            .layer(SessionLayer::new(store.clone()));
+
+        let res = app
+            .clone()
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let cookie = response_cookies(&res).join("; ");
+        let request = || {
+            Request::get("/wait")
+                .header(header::COOKIE, cookie.clone())
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let tasks = (0..LIMIT + 2)
+            .map(|_| tokio::spawn(app.clone().oneshot(request())))
+            .collect::<Vec<_>>();
+
+        entered.wait().await;
+        assert_eq!(store.parallel_requests(), LIMIT);
+        assert_eq!(
+            store.stats(),
+            SessionStats {
+                loaded: 1,
+                pinned: 0,
+                parallel_requests: LIMIT,
+                max_parallel_requests: LIMIT,
+            }
+        );
+        release.wait().await;
+
+        let mut rejected = 0;
+        for task in tasks {
+            match task.await.unwrap().unwrap().status() {
+                StatusCode::OK => {}
+                StatusCode::TOO_MANY_REQUESTS => rejected += 1,
+                status => panic!("unexpected status {status}"),
+            }
+        }
+
+        assert_eq!(rejected, 2);
+        assert_eq!(store.parallel_requests(), 0);
+
+        // The counter recovered so the session can reach the limit again.
+        let tasks = (0..LIMIT)
+            .map(|_| tokio::spawn(app.clone().oneshot(request())))
+            .collect::<Vec<_>>();
+        entered.wait().await;
+        release.wait().await;
+        for task in tasks {
+            assert_eq!(task.await.unwrap().unwrap().status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn opt_in_survives_memory_eviction() {
+        let pool = SessionTestPool::default();
+        let config = SessionConfig::default().with_mode(SessionMode::OptIn);
+        let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+        let app = Router::new()
+            .route("/", get(|| async {}))
+            .route(
+                "/opt-in",
+                get(|session: Session<SessionTestPool>| async move {
+                    session.set_store(true);
+                    session.set("user", 42);
+                }),
+            )
+            .layer(SessionLayer::new(store.clone()));
+
+        let res = app
+            .clone()
+            .oneshot(Request::get("/opt-in").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let id = store.inner.iter().next().unwrap().key().clone();
+        assert!(pool.row("sessions", &id).is_some());
+
+        // Unload it from memory and only send the session cookie back.
+        store.inner.clear();
+        let cookie = response_cookies(&res)
+            .into_iter()
+            .filter(|c| c.starts_with("session="))
+            .collect::<Vec<_>>()
+            .join("; ");
+        let res = app
+            .oneshot(
+                Request::get("/")
+                    .header(header::COOKIE, cookie)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(pool.row("sessions", &id).is_some());
+        assert!(store.inner.get(&id).unwrap().store);
+    }
+
+    #[tokio::test]
+    async fn legacy_cookie_names() {
+        let pool = SessionTestPool::default();
+        let key = Key::generate();
+        let app = |config: SessionConfig| {
+            let pool = pool.clone();
+            let config = config.with_mode(SessionMode::OptIn).with_key(key.clone());
+            async move {
+                let store = SessionStore::new(Some(pool), config).await.unwrap();
+                Router::new()
+                    .route(
+                        "/opt-in",
+                        get(|session: Session<SessionTestPool>| async move {
+                            session.set_store(true);
+                            session.set("user", 42);
+                        }),
+                    )
+                    .route(
+                        "/",
+                        get(|session: Session<SessionTestPool>| async move {
+                            format!("{:?}", session.get::<i32>("user"))
+                        }),
+                    )
+                    .layer(SessionLayer::new(store))
+            }
+        };
+        let send = |app: Router, cookie: String| async move {
+            let res = app
+                .oneshot(
+                    Request::get("/")
+                        .header(header::COOKIE, cookie)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let cookies = response_cookies(&res);
+            let body = res.into_body().collect().await.unwrap().to_bytes();
+            (String::from_utf8(body.to_vec()).unwrap(), cookies)
+        };
+        let renamed = || {
+            SessionConfig::default()
+                .with_session_name("sid")
+                .with_store_name("keep")
+                .with_prefix_with_host(true)
+        };
+
+        let res = app(SessionConfig::default())
+            .await
+            .oneshot(Request::get("/opt-in").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let old = response_cookies(&res).join("; ");
+        assert!(old.contains("session=") && old.contains("store="));
+
+        // A client with only the old cookies keeps its Session and is moved to the new names.
+        let migrating = renamed()
+            .with_legacy_session_names(vec!["session".into()])
+            .with_legacy_store_names(vec!["store".into()]);
+        let (body, cookies) = send(app(migrating.clone()).await, old.clone()).await;
+        assert_eq!(body, "Some(42)");
+        assert!(cookies.contains(&"session=".to_owned()));
+        assert!(cookies.contains(&"store=".to_owned()));
+        assert!(cookies
+            .iter()
+            .any(|c| c.starts_with("__Host-sid=") && c.len() > 11));
+        assert!(cookies
+            .iter()
+            .any(|c| c.starts_with("__Host-keep=") && c.len() > 12));
+
+        // The new cookies are read on their own and nothing is left to remove.
+        let current = cookies
+            .into_iter()
+            .filter(|c| c.starts_with("__Host-"))
+            .collect::<Vec<_>>()
+            .join("; ");
+        let (body, new) = send(app(migrating).await, current).await;
+        assert_eq!(body, "Some(42)");
+        assert!(new.iter().all(|c| c.starts_with("__Host-")));
+
+        // Once the transition is over the old names are ignored.
+        let (body, cookies) = send(app(renamed()).await, old).await;
+        assert_eq!(body, "None");
+        assert!(!cookies.iter().any(|c| c.starts_with("session=")));
+    }
+
+    #[tokio::test]
+    async fn oversized_inbound_id() {
+        let pool = SessionTestPool::default();
+        let store = SessionStore::new(Some(pool.clone()), SessionConfig::default())
+            .await
+            .unwrap();
+        let app = Router::new()
+            .route("/", get(|| async {}))
+            .layer(SessionLayer::new(store));
+        let oversized = "a".repeat(500);
+
+        let res = app
+            .oneshot(
+                Request::get("/")
+                    .header(header::COOKIE, format!("session={oversized}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        // The ID is dropped like an invalid Session and a new one is issued.
+        assert_eq!(res.status(), StatusCode::OK);
+        let id = session_id(&response_cookies(&res).join("; "));
+        assert!(!id.is_empty() && id != oversized);
+        let lookups = pool.lookups.lock().unwrap();
+        assert!(!lookups.is_empty());
+        assert!(!lookups.contains(&oversized));
+    }
+
+    #[tokio::test]
+    async fn size_history_tracks_growth() {
+        let pool = SessionTestPool::default();
+        let config = SessionConfig::default()
+            .with_size_growth_warning(2.0, chrono::Duration::try_hours(1).unwrap());
+        let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+        let app = Router::new()
+            .route(
+                "/grow",
+                get(|session: Session<SessionTestPool>| async move {
+                    let mut log = session.get::<String>("log").unwrap_or_default();
+                    log.push_str(&"x".repeat(100));
+                    session.set("log", log);
+                    session.size_history().len().to_string()
+                }),
+            )
+            .route(
+                "/small",
+                get(|session: Session<SessionTestPool>| async move {
+                    session.set("user", 1);
+                }),
+            )
+            .layer(SessionLayer::new(store.clone()));
+
+        let mut cookies = String::new();
+        for stored in 0..4 {
+            assert_eq!(
+                counter_request(&app, "/grow", &mut cookies).await,
+                stored.to_string()
+            );
+        }
+        counter_request(&app, "/small", &mut String::new()).await;
+        let id = session_id(&cookies);
+
+        let sizes = store
+            .size_history(&id)
+            .into_iter()
+            .map(|(_, size)| size)
+            .collect::<Vec<_>>();
+        assert_eq!(sizes.len(), 4);
+        assert!(sizes.windows(2).all(|w| w[1] - w[0] == 100));
+        assert_eq!(pool.row("sessions", &id).unwrap().0.len(), sizes[3]);
+
+        let largest = store.largest_sessions(5);
+        assert_eq!(largest.len(), 2);
+        assert_eq!(largest[0], (id, sizes[3]));
+        assert!(largest[1].1 < sizes[0]);
+        assert_eq!(store.largest_sessions(1).len(), 1);
+    }
+
+    #[test]
+    fn external_redirect_locations() {
+        let redirects_from = |uri: &str, location: &str| {
+            let req = Request::get(uri).body(()).unwrap();
+            let origin = crate::headers::request_origin(&req, false);
+            let mut headers = http::HeaderMap::new();
+            headers.insert(header::LOCATION, location.parse().unwrap());
+            crate::headers::is_external_redirect(StatusCode::FOUND, &headers, origin.as_ref())
+        };
+        let redirects = |location: &str| redirects_from("https://app.test/", location);
+
+        for location in [
+            "/next",
+            "next?to=https://other.test",
+            "https://APP.test/next",
+            "https://user@app.test",
+            "https://app.test:443/next",
+            "//app.test/next",
+            "/a:b",
+        ] {
+            assert!(!redirects(location), "{location}");
+        }
+
+        for location in [
+            "https://other.test/authorize",
+            "//other.test",
+            "/\\other.test",
+            "https://app.test.other.test",
+            "https://app.test:8443/",
+            "http://app.test/next",
+            "javascript:alert(1)",
+            "https:other.test",
+        ] {
+            assert!(redirects(location), "{location}");
+        }
+
+        // Default ports follow the scheme of each side.
+        assert!(!redirects_from("http://app.test/", "http://app.test:80/"));
+        assert!(redirects_from("http://app.test/", "https://app.test/"));
+        assert!(!redirects_from(
+            "https://[::1]:8443/",
+            "https://[::1]:8443/next"
+        ));
+        assert!(redirects_from("https://[::1]:8443/", "https://[::1]/next"));
+
+        let mut headers = http::HeaderMap::new();
+        headers.insert(header::LOCATION, "https://other.test".parse().unwrap());
+        let req = Request::get("https://app.test/").body(()).unwrap();
+        assert!(!crate::headers::is_external_redirect(
+            StatusCode::OK,
+            &headers,
+            crate::headers::request_origin(&req, false).as_ref()
+        ));
+    }
+
+    #[test]
+    fn request_origin_scheme() {
+        let scheme_of = |req: Request<()>, secure: bool| {
+            let origin = crate::headers::request_origin(&req, secure).unwrap();
+            let mut headers = http::HeaderMap::new();
+            headers.insert(header::LOCATION, "https://app.test/".parse().unwrap());
+            if crate::headers::is_external_redirect(StatusCode::FOUND, &headers, Some(&origin)) {
+                "http"
+            } else {
+                "https"
+            }
+        };
+        let host = |headers: &[(&str, &str)]| {
+            let mut req = Request::get("/").header(header::HOST, "app.test");
+            for (name, value) in headers {
+                req = req.header(*name, *value);
+            }
+            req.body(()).unwrap()
+        };
+
+        // Without a scheme within the URI the Secure setting decides.
+        assert_eq!(scheme_of(host(&[]), false), "http");
+        assert_eq!(scheme_of(host(&[]), true), "https");
+        // Proxies tell the scheme the client used.
+        assert_eq!(
+            scheme_of(host(&[("x-forwarded-proto", "https")]), false),
+            "https"
+        );
+        assert_eq!(
+            scheme_of(host(&[("forwarded", "for=1.2.3.4;proto=https")]), false),
+            "https"
+        );
+        assert_eq!(
+            scheme_of(host(&[("x-forwarded-proto", "http")]), true),
+            "http"
+        );
+    }
+
+    #[tokio::test]
+    async fn external_redirect_policy() {
+        let app = |policy: ExternalRedirectPolicy| async move {
+            let pool = SessionTestPool::default();
+            let config = SessionConfig::default().with_external_redirect_policy(policy);
+            let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+            let app = Router::new()
+                .route(
+                    "/",
+                    get(|session: Session<SessionTestPool>| async move {
+                        session.set("user", 42);
+                    }),
+                )
+                .route(
+                    "/same",
+                    get(|session: Session<SessionTestPool>| async move {
+                        session.renew();
+                        axum::response::Redirect::to("/home")
+                    }),
+                )
+                .route(
+                    "/external",
+                    get(|session: Session<SessionTestPool>| async move {
+                        session.set("state", "abc");
+                        session.renew();
+                        axum::response::Redirect::to("https://provider.test/authorize")
+                    }),
+                )
+                .route(
+                    "/destroy",
+                    get(|session: Session<SessionTestPool>| async move {
+                        session.destroy();
+                        axum::response::Redirect::to("https://provider.test/logout")
+                    }),
+                )
+                .layer(SessionLayer::new(store.clone()));
+            (app, pool, store)
+        };
+        let send = |app: &Router, path: &str, cookie: &str| {
+            app.clone().oneshot(
+                Request::get(path)
+                    .header(header::HOST, "app.test")
+                    .header(header::COOKIE, cookie)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        for policy in [
+            ExternalRedirectPolicy::Normal,
+            ExternalRedirectPolicy::FreezeSession,
+        ] {
+            let (app, pool, store) = app(policy).await;
+            let cookie = response_cookies(&send(&app, "/", "").await.unwrap()).join("; ");
+            let id = session_id(&cookie);
+
+            // Redirects within the origin renew and refresh the cookie under both policies.
+            let res = send(&app, "/same", &cookie).await.unwrap();
+            assert_eq!(res.status(), StatusCode::SEE_OTHER);
+            let cookie = response_cookies(&res).join("; ");
+            let renewed = session_id(&cookie);
+            assert!(!renewed.is_empty() && renewed != id);
+
+            // Moved back so an extension would show within the stored expiry.
+            let expires = {
+                let mut data = store.inner.get_mut(&renewed).unwrap();
+                data.expires -= chrono::Duration::try_minutes(1).unwrap();
+                data.expires.timestamp()
+            };
+            let res = send(&app, "/external", &cookie).await.unwrap();
+            let cookies = response_cookies(&res);
+
+            if policy == ExternalRedirectPolicy::Normal {
+                assert_ne!(session_id(&cookies.join("; ")), renewed);
+                continue;
+            }
+
+            // The frozen Session keeps its cookie, ID and expiry but still saves its data.
+            assert!(cookies.is_empty());
+            let (row, stored_expires) = pool.row("sessions", &renewed).unwrap();
+            assert!(row.contains("state"));
+            assert_eq!(stored_expires, expires);
+
+            // The renewal waits for the next response.
+            let res = send(&app, "/", &cookie).await.unwrap();
+            let next = session_id(&response_cookies(&res).join("; "));
+            assert!(!next.is_empty() && next != renewed);
+
+            let cookie = response_cookies(&res).join("; ");
+
+            // Loaded again from the database its stored expiry is not known, so it is refreshed
+            // instead of being saved as already expired.
+            store.inner.remove(&next);
+            let res = send(&app, "/external", &cookie).await.unwrap();
+            assert!(response_cookies(&res).is_empty());
+            let (_, stored_expires) = pool.row("sessions", &next).unwrap();
+            assert!(stored_expires > chrono::Utc::now().timestamp());
+
+            // Destroying still removes the cookie.
+            let res = send(&app, "/destroy", &cookie).await.unwrap();
+            assert!(response_cookies(&res).contains(&"session=".to_owned()));
+        }
+    }
+
+    #[tokio::test]
+    async fn frozen_requests_touch_active() {
+        let clock = FakeClock::new();
+        let pool = SessionTestPool::default();
+        let config = SessionConfig::default()
+            .with_clock(clock.clone())
+            .with_external_redirect_policy(ExternalRedirectPolicy::FreezeSession);
+        let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+        let app = Router::new()
+            .route(
+                "/",
+                get(|session: Session<SessionTestPool>| async move {
+                    session.set("user", 42);
+                }),
+            )
+            .route(
+                "/away",
+                get(|| async { axum::response::Redirect::to("https://provider.test/") }),
+            )
+            .layer(SessionLayer::new(store.clone()));
+        let send = |path: &str, cookie: &str| {
+            app.clone().oneshot(
+                Request::get(path)
+                    .header(header::HOST, "app.test")
+                    .header(header::COOKIE, cookie)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let cookie = response_cookies(&send("/", "").await.unwrap()).join("; ");
+        let id = session_id(&cookie);
+        // Saved sessions get their activity from the store instead.
+        send("/", &cookie).await.unwrap();
+        assert!(pool.touches.lock().unwrap().is_empty());
+
+        // The frozen response saves nothing but still marks the session active at the store's time.
+        clock.advance(chrono::Duration::try_minutes(10).unwrap());
+        let window = chrono::Duration::try_minutes(5).unwrap();
+        assert_eq!(store.active_count(window).await.unwrap(), 0);
+        send("/away", &cookie).await.unwrap();
+        assert_eq!(
+            *pool.touches.lock().unwrap(),
+            [(id.clone(), store.now().timestamp())]
+        );
+        assert_eq!(store.active_count(window).await.unwrap(), 1);
+
+        // Expired sessions are not counted as active so they are not touched either.
+        {
+            let mut data = store.inner.get_mut(&id).unwrap();
+            data.expires = store.now() - chrono::Duration::try_minutes(1).unwrap();
+            data.update = false;
+        }
+        send("/away", &cookie).await.unwrap();
+        assert_eq!(pool.touches.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn auto_store_when_nonempty() {
+        let pool = SessionTestPool::default();
+        let config = SessionConfig::default()
+            .with_mode(SessionMode::OptIn)
+            .with_auto_store_when_nonempty(true);
+        let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+        let app = Router::new()
+            .route("/", get(|| async {}))
+            .route(
+                "/set",
+                get(|session: Session<SessionTestPool>| async move {
+                    session.set("user", 42);
+                }),
+            )
+            .route(
+                "/clear",
+                get(|session: Session<SessionTestPool>| async move {
+                    session.clear();
+                }),
+            )
+            .layer(SessionLayer::new(store.clone()));
+        let send = |path: &str, cookie: &str| {
+            app.clone().oneshot(
+                Request::get(path)
+                    .header(header::COOKIE, cookie)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        // An empty session is neither kept nor persisted.
+        let res = send("/", "").await.unwrap();
+        assert!(!response_cookies(&res).contains(&"store=true".to_owned()));
+        assert!(store.inner.is_empty());
+
+        // Setting data promotes the session without calling set_store.
+        let res = send("/set", "").await.unwrap();
+        let cookies = response_cookies(&res);
+        assert!(cookies.contains(&"store=true".to_owned()));
+        let id = store.inner.iter().next().unwrap().key().clone();
+        assert!(pool.row("sessions", &id).is_some());
+
+        // Emptying the data drops the session again.
+        let res = send("/clear", &cookies.join("; ")).await.unwrap();
+        assert!(!response_cookies(&res).contains(&"store=true".to_owned()));
+        assert!(store.inner.is_empty());
+        assert!(pool.row("sessions", &id).is_none());
+    }
+
+    async fn creation_limited_app(pool: &SessionTestPool) -> Router {
+        let config = SessionConfig::default()
+            .with_distributed_creation_limit(2, chrono::Duration::try_hours(1).unwrap());
+        let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+        Router::new()
+            .route("/", get(|| async {}))
+            .layer(SessionLayer::new(store))
+    }
+
+    fn from_ip(ip: [u8; 4]) -> Request<Body> {
+        let mut req = Request::get("/").body(Body::empty()).unwrap();
+        req.extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from((ip, 4000))));
+        req
+    }
+
+    #[tokio::test]
+    async fn distributed_creation_limit() {
+        // Two instances sharing one database share the limit.
+        let pool = SessionTestPool::default();
+        let first = creation_limited_app(&pool).await;
+        let second = creation_limited_app(&pool).await;
+
+        let res = first.clone().oneshot(from_ip([10, 0, 0, 1])).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let cookie = response_cookies(&res).join("; ");
+        let res = second
+            .clone()
+            .oneshot(from_ip([10, 0, 0, 1]))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = first.clone().oneshot(from_ip([10, 0, 0, 1])).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let res = second
+            .clone()
+            .oneshot(from_ip([10, 0, 0, 1]))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Other IPs and requests using an existing session are not limited.
+        let res = second
+            .clone()
+            .oneshot(from_ip([10, 0, 0, 2]))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let mut req = from_ip([10, 0, 0, 1]);
+        req.headers_mut()
+            .insert(header::COOKIE, cookie.parse().unwrap());
+        let res = second.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // An unknown ID would create a session as well so it is limited too.
+        let mut req = from_ip([10, 0, 0, 1]);
+        req.headers_mut()
+            .insert(header::COOKIE, "session=unknown".parse().unwrap());
+        let res = second.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn distributed_creation_limit_fails_open() {
+        let pool = SessionTestPool::default();
+        pool.fail_rates
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        let app = creation_limited_app(&pool).await;
+
+        for _ in 0..5 {
+            let res = app.clone().oneshot(from_ip([10, 0, 0, 1])).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+    }
+
+    /// Returns if each session cookie set for a request to the host from the peer is Secure.
+    async fn secure_flags(
+        config: SessionConfig,
+        host: &str,
+        peer: Option<IpAddr>,
+        forwarded: bool,
+    ) -> Vec<bool> {
+        let store = SessionStore::<SessionTestPool>::new(None, config)
+            .await
+            .unwrap();
+        let app = Router::new()
+            .route("/", get(|| async {}))
+            .layer(SessionLayer::new(store));
+        let mut req = Request::get("/")
+            .header(header::HOST, host)
+            .body(Body::empty())
+            .unwrap();
+
+        if let Some(ip) = peer {
+            req.extensions_mut()
+                .insert(ConnectInfo(SocketAddr::new(ip, 4000)));
+        }
+
+        if forwarded {
+            req.headers_mut()
+                .insert("x-forwarded-for", "203.0.113.7".parse().unwrap());
+        }
+
+        let res = app.oneshot(req).await.unwrap();
+
+        res.headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            // Removal cookies use SameSite=None which always requires Secure.
+            .filter(|v| v.starts_with("session=") || v.starts_with("__Host-session="))
+            .map(|v| v.contains("Secure"))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn secure_cookie_for_localhost_exception() {
+        let config = SessionConfig::default().with_secure_cookie_for_localhost_exception(true);
+        let v4 = Some(IpAddr::from([127, 0, 0, 1]));
+        let v6 = Some(IpAddr::from(Ipv6Addr::LOCALHOST));
+
+        for (host, peer) in [
+            ("localhost", v4),
+            ("localhost:3000", v4),
+            ("127.0.0.1:8080", v4),
+            ("[::1]:8080", v6),
+        ] {
+            let flags = secure_flags(config.clone(), host, peer, false).await;
+            assert!(!flags.is_empty());
+            assert!(flags.iter().all(|secure| !secure), "{host}");
+        }
+
+        for host in ["example.com", "example.com:443", "localhost.example.com"] {
+            let flags = secure_flags(config.clone(), host, v4, false).await;
+            assert!(!flags.is_empty());
+            assert!(flags.iter().all(|secure| *secure), "{host}");
+        }
+
+        // Without the exception the Secure setting applies to every host.
+        let flags = secure_flags(SessionConfig::default(), "example.com", None, false).await;
+        assert!(flags.iter().all(|secure| !secure));
+        let flags = secure_flags(
+            SessionConfig::default().with_secure(true),
+            "localhost",
+            v4,
+            false,
+        )
+        .await;
+        assert!(flags.iter().all(|secure| *secure));
+    }
+
+    #[tokio::test]
+    async fn localhost_exception_ignores_untrusted_hosts() {
+        let config = SessionConfig::default().with_secure_cookie_for_localhost_exception(true);
+        let local = Some(IpAddr::from([127, 0, 0, 1]));
+
+        // A spoofed Host header from a remote client, or without connect info, stays Secure.
+        for peer in [None, Some(IpAddr::from([203, 0, 113, 7]))] {
+            let flags = secure_flags(config.clone(), "localhost", peer, false).await;
+            assert!(!flags.is_empty());
+            assert!(flags.iter().all(|secure| *secure), "{peer:?}");
+        }
+
+        // A local reverse proxy forwarding a remote request stays Secure.
+        let flags = secure_flags(config.clone(), "localhost", local, true).await;
+        assert!(!flags.is_empty());
+        assert!(flags.iter().all(|secure| *secure));
+
+        // __Host- cookies are rejected by browsers without Secure.
+        let flags = secure_flags(
+            config.with_prefix_with_host(true),
+            "localhost",
+            local,
+            false,
+        )
+        .await;
+        assert!(!flags.is_empty());
+        assert!(flags.iter().all(|secure| *secure));
+    }
+
+    /// Runs a request with the cookie and header given and returns the response and the Session ID used.
+    async fn transport_request(
+        app: &Router,
+        cookie: Option<&str>,
+        header: Option<&str>,
+    ) -> (Response<Body>, String) {
+        let mut req = Request::get("/id");
+
+        if let Some(cookie) = cookie {
+            req = req.header(header::COOKIE, format!("session={cookie}"));
+        }
+
+        if let Some(header) = header {
+            req = req.header("session", header);
+        }
+
+        let res = app
+            .clone()
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let (parts, body) = res.into_parts();
+        let id = String::from_utf8(body.collect().await.unwrap().to_bytes().to_vec()).unwrap();
+        (Response::from_parts(parts, Body::empty()), id)
+    }
+
+    async fn transport_app(precedence: Option<TransportPrecedence>) -> (Router, String, String) {
+        let mut config = SessionConfig::default();
+
+        if let Some(precedence) = precedence {
+            config = config.with_transport_precedence(precedence);
+        }
+
+        let store = SessionStore::<SessionTestPool>::new(None, config)
+            .await
+            .unwrap();
+        let app = Router::new()
+            .route(
+                "/id",
+                get(|session: Session<SessionTestPool>| async move { session.get_session_id() }),
+            )
+            .layer(SessionLayer::new(store));
+
+        // Two existing Sessions, one to name with the cookie and one with the header.
+        let (_, cookie_id) = transport_request(&app, None, None).await;
+        let (_, header_id) = transport_request(&app, None, None).await;
+        (app, cookie_id, header_id)
+    }
+
+    fn written_to(res: &Response<Body>) -> (bool, bool) {
+        let cookie = response_cookies(res)
+            .iter()
+            .any(|c| c.starts_with("session=") && c != "session=");
+        (cookie, res.headers().contains_key("session"))
+    }
+
+    #[tokio::test]
+    async fn transport_precedence_default_ignores_headers() {
+        let (app, cookie_id, header_id) = transport_app(None).await;
+
+        let (_, id) = transport_request(&app, Some(&cookie_id), Some(&header_id)).await;
+        assert_eq!(id, cookie_id);
+        // A header alone can not name a cookie Session.
+        let (res, id) = transport_request(&app, None, Some(&header_id)).await;
+        assert_ne!(id, header_id);
+        assert_eq!(written_to(&res), (true, false));
+    }
+
+    #[tokio::test]
+    async fn transport_precedence_cookie_first() {
+        let (app, cookie_id, header_id) =
+            transport_app(Some(TransportPrecedence::CookieFirst)).await;
+
+        let (res, id) = transport_request(&app, Some(&cookie_id), Some(&header_id)).await;
+        assert_eq!(id, cookie_id);
+        assert_eq!(written_to(&res), (true, false));
+
+        let (res, id) = transport_request(&app, None, Some(&header_id)).await;
+        assert_eq!(id, header_id);
+        assert_eq!(written_to(&res), (false, true));
+    }
+
+    #[tokio::test]
+    async fn transport_precedence_header_first() {
+        let (app, cookie_id, header_id) =
+            transport_app(Some(TransportPrecedence::HeaderFirst)).await;
+
+        let (res, id) = transport_request(&app, Some(&cookie_id), Some(&header_id)).await;
+        assert_eq!(id, header_id);
+        assert_eq!(written_to(&res), (false, true));
+
+        let (res, id) = transport_request(&app, Some(&cookie_id), None).await;
+        assert_eq!(id, cookie_id);
+        assert_eq!(written_to(&res), (true, false));
+    }
+
+    #[tokio::test]
+    async fn transport_precedence_reject_conflicts() {
+        let (app, cookie_id, header_id) =
+            transport_app(Some(TransportPrecedence::RejectConflicts)).await;
+
+        // Conflicts are treated like an invalid Session so a new one is created.
+        let (res, id) = transport_request(&app, Some(&cookie_id), Some(&header_id)).await;
+        assert_ne!(id, cookie_id);
+        assert_ne!(id, header_id);
+        assert_eq!(written_to(&res), (true, false));
+
+        let (_, id) = transport_request(&app, Some(&cookie_id), Some(&cookie_id)).await;
+        assert_eq!(id, cookie_id);
+        let (res, id) = transport_request(&app, None, Some(&header_id)).await;
+        assert_eq!(id, header_id);
+        assert_eq!(written_to(&res), (false, true));
+    }
+
+    /// Returns every Set-Cookie value sorted, as the jar does not keep the order cookies were added.
+    fn set_cookie_headers(headers: &http::HeaderMap) -> Vec<String> {
+        let mut cookies: Vec<String> = headers
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .map(|v| v.to_str().unwrap().to_owned())
+            .collect();
+        cookies.sort();
+        cookies
+    }
+
+    #[tokio::test]
+    async fn apply_session_headers_matches_layer() {
+        // Without a max age the cookies do not depend on the time they were made.
+        let config = SessionConfig::default()
+            .with_mode(SessionMode::OptIn)
+            .with_key(Key::generate())
+            .with_max_age(None);
+        let store = SessionStore::<SessionTestPool>::new(None, config)
+            .await
+            .unwrap();
+        let app = Router::new()
+            .route(
+                "/create",
+                get(|session: Session<SessionTestPool>| async move { session.get_session_id() }),
+            )
+            .route(
+                "/store",
+                get(|session: Session<SessionTestPool>| async move {
+                    session.set_store(true);
+                    session.get_session_id()
+                }),
+            )
+            .route(
+                "/destroy",
+                get(|session: Session<SessionTestPool>| async move {
+                    session.destroy();
+                    session.get_session_id()
+                }),
+            )
+            .layer(SessionLayer::new(store.clone()));
+
+        for (path, storable, destroy) in [
+            ("/create", false, false),
+            ("/store", true, false),
+            ("/destroy", false, true),
+        ] {
+            let request = || Request::get(path).body(Body::empty()).unwrap();
+            let res = app.clone().oneshot(request()).await.unwrap();
+            let (parts, body) = res.into_parts();
+            let id = String::from_utf8(body.collect().await.unwrap().to_bytes().to_vec()).unwrap();
+
+            let options = store
+                .emit_options(&request())
+                .with_storable(storable)
+                .with_destroy(destroy);
+            let mut headers = http::HeaderMap::new();
+            store.apply_session_headers(&mut headers, &id, &options);
+
+            assert!(!headers.is_empty());
+            assert_eq!(
+                set_cookie_headers(&headers),
+                set_cookie_headers(&parts.headers),
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn sensitive_headers_follow_config() {
+        let names = |config: &SessionConfig| {
+            crate::sensitive_headers(config)
+                .iter()
+                .map(|name| name.as_str().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            names(&SessionConfig::default()),
+            ["cookie", "set-cookie", "session", "store"]
+        );
+
+        // Header names never get the __Host- prefix cookie names get.
+        let config = SessionConfig::default()
+            .with_session_name("Sid")
+            .with_store_name("keep")
+            .with_prefix_with_host(true)
+            .with_key(Key::generate())
+            .with_double_submit_cookie("csrf");
+        assert_eq!(
+            names(&config),
+            ["cookie", "set-cookie", "sid", "keep", "x-csrf-token"]
+        );
+    }
+
+    #[tokio::test]
+    async fn mark_sensitive_headers() {
+        let store = SessionStore::<SessionTestPool>::new(None, SessionConfig::default())
+            .await
+            .unwrap();
+        let app = |mark: bool| {
+            Router::new()
+                .route(
+                    "/",
+                    get(|req: Request<Body>| async move {
+                        req.headers()[header::COOKIE].is_sensitive().to_string()
+                    }),
+                )
+                .layer(SessionLayer::new(store.clone()).with_mark_sensitive(mark))
+        };
+
+        for mark in [true, false] {
+            let res = app(mark)
+                .oneshot(
+                    Request::get("/")
+                        .header(header::COOKIE, "other=1")
+                        .header(header::USER_AGENT, "test-agent")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let (parts, body) = res.into_parts();
+            let cookies = parts.headers.get_all(header::SET_COOKIE);
+
+            assert!(cookies.iter().count() > 0);
+            assert!(cookies.iter().all(|v| v.is_sensitive() == mark));
+            assert!(!parts.headers[header::CONTENT_TYPE].is_sensitive());
+            let body = body.collect().await.unwrap().to_bytes();
+            assert_eq!(body, mark.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn append_cookies_matches_layer() {
+        let config = SessionConfig::default()
+            .with_mode(SessionMode::OptIn)
+            .with_key(Key::generate())
+            .with_max_age(None);
+        let store = SessionStore::<SessionTestPool>::new(None, config)
+            .await
+            .unwrap();
+        // Returns the cookies the handler appended itself within the body.
+        async fn appended(session: Session<SessionTestPool>, path: &str) -> String {
+            match path {
+                "store" => session.set_store(true),
+                "destroy" => session.destroy(),
+                _ => {}
+            }
+
+            let mut headers = http::HeaderMap::new();
+            session.append_cookies(&mut headers);
+            set_cookie_headers(&headers).join("\n")
+        }
+        let app = Router::new()
+            .route(
+                "/{path}",
+                get(
+                    |session: Session<SessionTestPool>,
+                     axum::extract::Path(path): axum::extract::Path<String>| async move {
+                        appended(session, &path).await
+                    },
+                ),
+            )
+            .layer(SessionLayer::new(store));
+
+        for path in ["/create", "/store", "/destroy"] {
+            let res = app
+                .clone()
+                .oneshot(
+                    Request::get(path)
+                        .header(header::USER_AGENT, "test-agent")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let (parts, body) = res.into_parts();
+            let body =
+                String::from_utf8(body.collect().await.unwrap().to_bytes().to_vec()).unwrap();
+
+            assert!(!body.is_empty());
+            assert_eq!(
+                body,
+                set_cookie_headers(&parts.headers).join("\n"),
+                "{path}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn wall_clock_steps_do_not_expire_sessions() {
+        let clock = FakeClock::new();
+        let config = SessionConfig::default()
+            .with_memory_lifetime(chrono::Duration::try_hours(2).unwrap())
+            .with_purge_update(chrono::Duration::try_minutes(5).unwrap())
+            .with_clock(clock.clone());
+        let store = SessionStore::new(Some(SessionTestPool::default()), config)
+            .await
+            .unwrap();
+        let app = Router::new()
+            .route("/", get(|| async {}))
+            .layer(SessionLayer::new(store.clone()));
+        let request = || {
+            app.clone()
+                .oneshot(Request::get("/").body(Body::empty()).unwrap())
+        };
+
+        request().await.unwrap();
+        assert_eq!(store.inner.len(), 1);
+        let first_sweep = store.timers.read().await.last_expiry_sweep;
+
+        // Stepping the wall clock either way leaves the in-memory timers alone.
+        clock.step_wall(-chrono::Duration::try_hours(2).unwrap());
+        request().await.unwrap();
+        assert_eq!(store.inner.len(), 2);
+        assert!(
+            store
+                .warned_wall_clock
+                .load(std::sync::atomic::Ordering::Relaxed)
+                > 0
+        );
+
+        clock.step_wall(chrono::Duration::try_hours(4).unwrap());
+        request().await.unwrap();
+        assert_eq!(store.inner.len(), 3);
+        assert_eq!(store.timers.read().await.last_expiry_sweep, first_sweep);
+
+        // Monotonic time passing the first sweep runs it without evicting.
+        clock.advance(chrono::Duration::try_minutes(61).unwrap());
+        request().await.unwrap();
+        assert_eq!(store.inner.len(), 4);
+        assert!(store.timers.read().await.last_expiry_sweep > first_sweep);
+
+        // Monotonic time passing the memory lifespan does evict unused sessions.
+        clock.advance(chrono::Duration::try_hours(2).unwrap());
+        request().await.unwrap();
+        assert_eq!(store.inner.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn load_failures_keep_the_row() {
+        for behavior in [
+            LoadFailureBehavior::FailRequest,
+            LoadFailureBehavior::RecreateEmpty,
+            LoadFailureBehavior::ServeEphemeral,
+        ] {
+            let pool = SessionTestPool::default();
+            let config = SessionConfig::default()
+                .with_memory_lifetime(chrono::Duration::zero())
+                .with_load_failure_behavior(behavior);
+            let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+            let app = Router::new()
+                .route(
+                    "/login",
+                    get(|session: Session<SessionTestPool>| async move {
+                        session.set("user", 5);
+                    }),
+                )
+                .route(
+                    "/user",
+                    get(|session: Session<SessionTestPool>| async move {
+                        session.get::<u32>("user").unwrap_or_default().to_string()
+                    }),
+                )
+                .layer(SessionLayer::new(store.clone()));
+
+            let res = app
+                .clone()
+                .oneshot(Request::get("/login").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let cookie = response_cookies(&res).join("; ");
+            let user = || {
+                app.clone().oneshot(
+                    Request::get("/user")
+                        .header(header::COOKIE, cookie.clone())
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+            };
+            let id = cookie
+                .split("; ")
+                .find_map(|c| c.strip_prefix("session="))
+                .unwrap();
+            let row = pool.row("sessions", id).unwrap();
+
+            pool.fail_loads.store(true, Ordering::Relaxed);
+            let res = user().await.unwrap();
+
+            match behavior {
+                LoadFailureBehavior::FailRequest => {
+                    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+                }
+                LoadFailureBehavior::RecreateEmpty => {
+                    assert_eq!(res.status(), StatusCode::OK);
+                    assert!(!response_cookies(&res).is_empty());
+                }
+                LoadFailureBehavior::ServeEphemeral => {
+                    assert_eq!(res.status(), StatusCode::OK);
+                    // Neither the cookie nor the row are touched and nothing stays in memory.
+                    assert!(response_cookies(&res).is_empty());
+                    assert!(store.inner.is_empty());
+                    let body = res.into_body().collect().await.unwrap().to_bytes();
+                    assert_eq!(&body[..], b"0");
+                }
+            }
+
+            // Only the recreated empty session is saved over the row.
+            let recreated = behavior == LoadFailureBehavior::RecreateEmpty;
+            assert_eq!(pool.row("sessions", id) != Some(row), recreated);
+
+            pool.fail_loads.store(false, Ordering::Relaxed);
+            let body = user().await.unwrap().into_body().collect().await.unwrap();
+            let expected: &[u8] = if recreated { b"0" } else { b"5" };
+            assert_eq!(&body.to_bytes()[..], expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn pinned_sessions_survive_memory_sweeps() {
+        let clock = FakeClock::new();
+        let config = SessionConfig::default()
+            .with_memory_lifetime(chrono::Duration::try_minutes(1).unwrap())
+            .with_purge_update(chrono::Duration::try_minutes(1).unwrap())
+            .with_max_pinned_sessions(1)
+            .with_clock(clock.clone());
+        let store = SessionStore::new(Some(SessionTestPool::default()), config)
+            .await
+            .unwrap();
+        let app = Router::new()
+            .route("/", get(|| async {}))
+            .route(
+                "/pin",
+                get(|session: Session<SessionTestPool>| async move {
+                    match session.pin_in_memory(true) {
+                        Ok(()) => StatusCode::OK,
+                        Err(_) => StatusCode::CONFLICT,
+                    }
+                }),
+            )
+            .route(
+                "/unpin",
+                get(|session: Session<SessionTestPool>| async move {
+                    session.pin_in_memory(false).unwrap();
+                }),
+            )
+            .layer(SessionLayer::new(store.clone()));
+        let request = |path: &str, cookie: Option<&str>| {
+            let mut req = Request::get(path);
+            if let Some(cookie) = cookie {
+                req = req.header(header::COOKIE, cookie);
+            }
+            app.clone().oneshot(req.body(Body::empty()).unwrap())
+        };
+        let session_id = |cookie: &str| {
+            cookie
+                .split("; ")
+                .find_map(|c| c.strip_prefix("session="))
+                .unwrap()
+                .to_owned()
+        };
+        // The first memory sweep is due an hour after start.
+        let sweep = || {
+            clock.advance(chrono::Duration::try_minutes(61).unwrap());
+            request("/", None)
+        };
+
+        let res = request("/pin", None).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let pinned = response_cookies(&res).join("; ");
+        let pinned_id = session_id(&pinned);
+
+        // The cap is enforced, while pinning the same session again is allowed.
+        let res = request("/pin", None).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let unpinned = response_cookies(&res).join("; ");
+        let unpinned_id = session_id(&unpinned);
+        let res = request("/pin", Some(&pinned)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(store.pinned_count(), 1);
+
+        sweep().await.unwrap();
+        assert!(store.inner.contains_key(&pinned_id));
+        assert!(!store.inner.contains_key(&unpinned_id));
+
+        sweep().await.unwrap();
+        assert!(store.inner.contains_key(&pinned_id));
+
+        // Unpinned it is unloaded again once unused for the memory lifespan.
+        request("/unpin", Some(&pinned)).await.unwrap();
+        assert_eq!(store.pinned_count(), 0);
+        sweep().await.unwrap();
+        assert!(!store.inner.contains_key(&pinned_id));
+
+        // A zero memory lifespan leaves pinned sessions in memory as well.
+        let config = SessionConfig::default()
+            .with_memory_lifetime(chrono::Duration::zero())
+            .with_clock(clock.clone());
+        let store = SessionStore::new(Some(SessionTestPool::default()), config)
+            .await
+            .unwrap();
+        let app = Router::new()
+            .route("/", get(|| async {}))
+            .route(
+                "/pin",
+                get(|session: Session<SessionTestPool>| async move {
+                    session.pin_in_memory(true).unwrap();
+                }),
+            )
+            .layer(SessionLayer::new(store.clone()));
+
+        for path in ["/pin", "/"] {
+            app.clone()
+                .oneshot(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(store.inner.len(), 1);
+        assert_eq!(store.pinned_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn request_sweep_reports_expired_sessions() {
+        use futures::FutureExt;
+
+        let clock = FakeClock::new();
+        let pool = SessionTestPool::default();
+        let (tx, mut reported) = tokio::sync::mpsc::unbounded_channel();
+        let config = SessionConfig::default()
+            .with_clock(clock.clone())
+            .with_expired_sessions_handler(Arc::new(move |ids| {
+                let tx = tx.clone();
+                async move {
+                    let _ = tx.send(ids);
+                }
+                .boxed()
+            }));
+        let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+        let app = Router::new()
+            .route("/", get(|| async {}))
+            .layer(SessionLayer::new(store));
+        pool.insert_row("sessions", "gone", "", chrono::Utc::now().timestamp() - 60);
+
+        // The first database sweep is due six hours after start.
+        app.clone()
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        tokio::task::yield_now().await;
+        assert!(reported.try_recv().is_err());
+
+        clock.advance(chrono::Duration::try_hours(7).unwrap());
+        app.oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(reported.recv().await.unwrap(), ["gone"]);
+        assert!(pool.row("sessions", "gone").is_none());
+    }
+
+    #[tokio::test]
+    async fn absolute_max_age_expires_active_sessions() {
+        let clock = FakeClock::new();
+        let created = chrono::Utc::now();
+        *clock.wall.lock().unwrap() = created;
+        let pool = SessionTestPool::default();
+        let config = SessionConfig::default()
+            .with_lifetime(chrono::Duration::try_hours(1).unwrap())
+            .with_absolute_max_age(chrono::Duration::try_hours(3).unwrap())
+            .with_clock(clock.clone());
+        let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+        let app = Router::new()
+            .route(
+                "/",
+                get(|session: Session<SessionTestPool>| async move {
+                    let user = session.get::<u32>("user").unwrap_or_else(|| {
+                        session.set("user", 42);
+                        0
+                    });
+                    user.to_string()
+                }),
+            )
+            .layer(SessionLayer::new(store.clone()));
+
+        let res = app
+            .clone()
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let cookie = response_cookies(&res).join("; ");
+        let id = store.inner.iter().next().unwrap().key().clone();
+        let request = |cookie: String| async {
+            let res = app
+                .clone()
+                .oneshot(
+                    Request::get("/")
+                        .header(header::COOKIE, cookie)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let cookie = response_cookies(&res).join("; ");
+            let body = res.into_body().collect().await.unwrap().to_bytes();
+            (String::from_utf8(body.to_vec()).unwrap(), cookie)
+        };
+        let cap = (created + chrono::Duration::try_hours(3).unwrap()).timestamp();
+
+        // Used well within the lifespan so only the cap can expire it.
+        for _ in 0..5 {
+            clock.advance(chrono::Duration::try_minutes(35).unwrap());
+            assert_eq!(request(cookie.clone()).await.0, "42");
+            assert!(pool.row("sessions", &id).unwrap().1 <= cap);
+        }
+
+        assert_eq!(pool.row("sessions", &id).unwrap().1, cap);
+
+        clock.advance(chrono::Duration::try_minutes(5).unwrap());
+        let (user, renewed) = request(cookie.clone()).await;
+        assert_eq!(user, "0");
+        // The session starts over under a new ID so the old one no longer reaches it.
+        let new_id = store.inner.iter().next().unwrap().key().clone();
+        assert_ne!(new_id, id);
+        assert!(pool.row("sessions", &id).is_none());
+        assert_eq!(request(cookie).await.0, "0");
+        // It gets a new cap.
+        assert_eq!(request(renewed).await.0, "42");
+        assert!(pool.row("sessions", &new_id).unwrap().1 > cap);
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_do_not_panic() {
+        let config = SessionConfig::default()
+            .with_key(Key::generate())
+            .with_database_key(Key::generate())
+            .with_max_age(Some(chrono::Duration::try_days(3650).unwrap()))
+            .with_transport_precedence(TransportPrecedence::RejectConflicts);
+        let store = SessionStore::new(Some(SessionTestPool::default()), config)
+            .await
+            .unwrap();
+        let app = Router::new()
+            .route(
+                "/",
+                get(|session: Session<SessionTestPool>| async move {
+                    // Only available within SessionMode::Manual.
+                    assert!(session.create_data().is_err());
+                    session.set("user", 42);
+                }),
+            )
+            .layer(SessionLayer::new(store));
+
+        let values = [
+            "",
+            "=",
+            ";;;",
+            "session",
+            "session=",
+            "session=not-a-uuid",
+            "session=%00",
+            "session=AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA",
+            "session=%FF%FE; store=maybe",
+            "store=true; session=4a4e5d3b-6f1c-4d8e-9a2b-7c3d5e6f7a8b",
+            "session=a; session=b; store=1",
+        ];
+
+        for value in values {
+            let res = app
+                .clone()
+                .oneshot(
+                    Request::get("/")
+                        .header(header::COOKIE, value)
+                        .header("session", value)
+                        .header(header::USER_AGENT, value)
+                        .header("x-forwarded-for", value)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK, "{value:?}");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn parallel_renew_generates_one_id() {
+        #[derive(Debug, Default)]
+        struct CountingIds(AtomicUsize);
+
+        impl IdGenerator for Arc<CountingIds> {
+            fn generate(&self) -> String {
+                // Slow renewals give the other request time to read its flags.
+                if self.0.fetch_add(1, Ordering::SeqCst) > 0 {
+                    std::thread::sleep(std::time::Duration::from_millis(50));
+                }
+                uuid::Uuid::new_v4().to_string()
+            }
+        }
+
+        let generated = Arc::new(CountingIds::default());
+        let pool = SessionTestPool::default();
+        let config = SessionConfig::default().with_id_generator(generated.clone());
+        let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+        // Both handlers flag the renewal before either response is processed.
+        let renewed = Arc::new(Barrier::new(3));
+        let release = Arc::new(Semaphore::new(0));
+        let (handler_renewed, handler_release) = (renewed.clone(), release.clone());
+        let app = Router::new()
+            .route(
+                "/",
+                get(|session: Session<SessionTestPool>| async move {
+                    session.set("user", 42);
+                }),
+            )
+            .route(
+                "/renew",
+                get(move |session: Session<SessionTestPool>| async move {
+                    session.renew();
+                    handler_renewed.wait().await;
+                    let _permit = handler_release.acquire().await.unwrap();
+                }),
+            )
+            .layer(SessionLayer::new(store.clone()));
+
+        let res = app
+            .clone()
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let cookie = response_cookies(&res).join("; ");
+        let old_id = store.inner.iter().next().unwrap().key().clone();
+        let request = || {
+            Request::get("/renew")
+                .header(header::COOKIE, cookie.clone())
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let tasks = (0..2)
+            .map(|_| tokio::spawn(app.clone().oneshot(request())))
+            .collect::<Vec<_>>();
+        renewed.wait().await;
+        release.add_permits(2);
+
+        let mut ids = Vec::new();
+        for task in tasks {
+            let res = task.await.unwrap().unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            ids.extend(
+                response_cookies(&res)
+                    .into_iter()
+                    .filter_map(|c| c.strip_prefix("session=").map(str::to_owned))
+                    .filter(|id| *id != old_id),
+            );
+        }
+
+        // One ID for the new Session and one for the renewal.
+        assert_eq!(generated.0.load(Ordering::SeqCst), 2);
+        assert_eq!(ids.len(), 1);
+        assert_eq!(store.inner.len(), 1);
+        assert!(store.inner.contains_key(&ids[0]));
+        assert_eq!(pool.rows.len(), 1);
+        assert!(pool.row("sessions", &ids[0]).is_some());
+        assert!(pool.row("sessions", &old_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn cache_key_follows_listed_values() {
+        let config = SessionConfig::default().with_cache_key_salt("salt");
+        let store = SessionStore::new(Some(SessionTestPool::default()), config)
+            .await
+            .unwrap();
+        let app = Router::new()
+            .route(
+                "/",
+                get(|session: Session<SessionTestPool>| async move { session.etag(&["user"]) }),
+            )
+            .route(
+                "/user",
+                get(|session: Session<SessionTestPool>| async move {
+                    session.set("user", 42);
+                    session.etag(&["user"])
+                }),
+            )
+            .route(
+                "/other",
+                get(|session: Session<SessionTestPool>| async move {
+                    session.set("other", 7);
+                    session.etag(&["user"])
+                }),
+            )
+            .layer(SessionLayer::new(store.clone()));
+
+        let first = app
+            .clone()
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let cookie = response_cookies(&first).join("; ");
+        let id = store.inner.iter().next().unwrap().key().clone();
+        let etag = |path: &'static str| {
+            let app = app.clone();
+            let cookie = cookie.clone();
+            async move {
+                let res = app
+                    .oneshot(
+                        Request::get(path)
+                            .header(header::COOKIE, cookie)
+                            .body(Body::empty())
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                let body = res.into_body().collect().await.unwrap().to_bytes();
+                String::from_utf8(body.to_vec()).unwrap()
+            }
+        };
+        let body = first.into_body().collect().await.unwrap().to_bytes();
+        let initial = String::from_utf8(body.to_vec()).unwrap();
+
+        assert!(initial.starts_with("W/\"") && initial.ends_with('"'));
+        assert!(!initial.contains(&id));
+        assert!(!initial.contains(&id.replace('-', "")));
+        assert_eq!(etag("/").await, initial);
+        // Unlisted values do not change the key.
+        assert_eq!(etag("/other").await, initial);
+        let changed = etag("/user").await;
+        assert_ne!(changed, initial);
+        assert_eq!(etag("/").await, changed);
+
+        // Another Session with the same values gets its own key.
+        let res = app
+            .clone()
+            .oneshot(Request::get("/user").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_ne!(String::from_utf8(body.to_vec()).unwrap(), changed);
+    }
+
+    #[tokio::test]
+    async fn response_hook_sees_session() {
+        let store = SessionStore::new(Some(SessionTestPool::default()), SessionConfig::default())
+            .await
+            .unwrap();
+        let hook: ResponseHook<SessionTestPool> = Arc::new(|session, headers| {
+            let user = session.get::<u32>("user").unwrap_or_default();
+            headers.insert("x-user", user.into());
+        });
+        let app = Router::new()
+            .route(
+                "/",
+                get(|session: Session<SessionTestPool>| async move {
+                    session.set("user", 42);
+                }),
+            )
+            .route("/fail", get(|| async { StatusCode::INTERNAL_SERVER_ERROR }))
+            .layer(SessionLayer::new(store).with_response_hook(hook));
+
+        let res = app
+            .clone()
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.headers()["x-user"], "42");
+        // The Session's Cookies are already set when the hook runs.
+        assert!(!response_cookies(&res).is_empty());
+
+        let res = app
+            .oneshot(Request::get("/fail").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(res.headers()["x-user"], "0");
+    }
+
+    #[tokio::test]
+    async fn response_hook_runs_before_unload() {
+        let config = SessionConfig::default().with_memory_lifetime(chrono::Duration::zero());
+        let store = SessionStore::new(Some(SessionTestPool::default()), config)
+            .await
+            .unwrap();
+        let hook: ResponseHook<SessionTestPool> = Arc::new(|session, headers| {
+            let user = session.get::<u32>("user").unwrap_or_default();
+            headers.insert("x-user", user.into());
+        });
+        let app = Router::new()
+            .route(
+                "/",
+                get(|session: Session<SessionTestPool>| async move {
+                    session.set("user", 42);
+                }),
+            )
+            .layer(SessionLayer::new(store.clone()).with_response_hook(hook));
+
+        let res = app
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.headers()["x-user"], "42");
+        // The Session was still unloaded afterwards.
+        assert_eq!(store.inner.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn change_set_reaches_outer_layers() {
+        let store = SessionStore::new(Some(SessionTestPool::default()), SessionConfig::default())
+            .await
+            .unwrap();
+        let captured = Arc::new(Mutex::new(Vec::new()));
+        let capture = captured.clone();
+        let app = Router::new()
+            .route(
+                "/login",
+                get(|session: Session<SessionTestPool>| async move {
+                    session.set("user", 1);
+                    session.set("cart", 3);
+                    session.set("theme", "dark");
+                    session.set_flash("notice", "welcome");
+                    // Taken changes are left out of the one in the response extensions.
+                    assert_eq!(session.take_change_set().added.len(), 4);
+                    session.set("tab", 1);
+                }),
+            )
+            .route(
+                "/update",
+                get(|session: Session<SessionTestPool>| async move {
+                    let _ = session.take_flash::<String>("notice");
+                    session.set("user", 2);
+                    session.set("theme", "dark");
+                    session.remove("cart");
+                    session.remove_many(&["tab", "missing"]);
+                    session.set("lang", "en");
+                }),
+            )
+            .route(
+                "/logout",
+                get(|session: Session<SessionTestPool>| async move { session.clear() }),
+            )
+            .layer(SessionLayer::new(store))
+            .layer(axum::middleware::map_response(move |res: Response| {
+                let capture = capture.clone();
+                async move {
+                    let change_set = res.extensions().get::<SessionChangeSet>().cloned();
+                    capture.lock().unwrap().push(change_set.unwrap());
+                    res
+                }
+            }));
+
+        let res = app
+            .clone()
+            .oneshot(Request::get("/login").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let cookie = response_cookies(&res).join("; ");
+
+        for path in ["/update", "/logout"] {
+            app.clone()
+                .oneshot(
+                    Request::get(path)
+                        .header(header::COOKIE, cookie.clone())
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let captured = captured.lock().unwrap();
+        assert_eq!(captured[0].added, ["tab"]);
+        assert!(captured[0].modified.is_empty() && !captured[0].cleared);
+
+        assert_eq!(captured[1].added, ["lang"]);
+        assert_eq!(captured[1].modified, ["user"]);
+        assert_eq!(
+            captured[1].removed,
+            ["__axum_session_flash.notice", "cart", "tab"]
+        );
+        assert_eq!(
+            captured[1].clone().without_internal().removed,
+            ["cart", "tab"]
+        );
+
+        assert!(captured[2].cleared);
+        assert_eq!(captured[2].removed, ["lang", "theme", "user"]);
+        assert!(captured[2].added.is_empty() && captured[2].modified.is_empty());
+    }
+
+    #[tokio::test]
+    async fn parallel_requests_keep_their_own_change_sets() {
+        let store = SessionStore::new(Some(SessionTestPool::default()), SessionConfig::default())
+            .await
+            .unwrap();
+        let captured = Arc::new(Mutex::new(Vec::new()));
+        let capture = captured.clone();
+        // Both handlers change the session before either response finishes.
+        let changed = Arc::new(Barrier::new(2));
+        let app = Router::new()
+            .route("/", get(|| async {}))
+            .route(
+                "/{key}",
+                get(
+                    move |session: Session<SessionTestPool>, axum::extract::Path(key): axum::extract::Path<String>| async move {
+                        session.set(&key, 1);
+                        changed.wait().await;
+                    },
+                ),
+            )
+            .layer(SessionLayer::new(store))
+            .layer(axum::middleware::map_response(move |res: Response| {
+                let capture = capture.clone();
+                async move {
+                    let change_set = res.extensions().get::<SessionChangeSet>().cloned();
+                    capture.lock().unwrap().push(change_set.unwrap().added);
+                    res
+                }
+            }));
+
+        let res = app
+            .clone()
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let cookie = response_cookies(&res).join("; ");
+        let tasks = ["/first", "/second"].map(|path| {
+            tokio::spawn(
+                app.clone().oneshot(
+                    Request::get(path)
+                        .header(header::COOKIE, cookie.clone())
+                        .body(Body::empty())
+                        .unwrap(),
+                ),
+            )
+        });
+
+        for task in tasks {
+            assert_eq!(task.await.unwrap().unwrap().status(), StatusCode::OK);
+        }
+
+        let mut captured = captured.lock().unwrap().clone();
+        captured.sort();
+        assert_eq!(captured, [vec![], vec!["first"], vec!["second"]]);
+    }
+
+    /// Sends a GET / over a real connection and returns the raw response.
+    async fn raw_get(addr: SocketAddr, headers: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request =
+            format!("GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n{headers}\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        res
+    }
+
+    #[tokio::test]
+    async fn missing_connect_info_warns_or_fails() {
+        let config = SessionConfig::default().with_key(Key::generate());
+        let app = |store| {
+            Router::new()
+                .route("/", get(|_: Session<SessionTestPool>| async {}))
+                .layer(SessionLayer::new(store))
+        };
+        let listen = || async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            (listener, addr)
+        };
+
+        // into_make_service() has no ConnectInfo, so the IP is missing from the signature.
+        let store = SessionStore::new(Some(SessionTestPool::default()), config.clone())
+            .await
+            .unwrap();
+        let (listener, addr) = listen().await;
+        let make_service = app(store.clone()).into_make_service();
+        tokio::spawn(async move { axum::serve(listener, make_service).await });
+
+        assert!(raw_get(addr, "").await.starts_with("HTTP/1.1 200"));
+        let warned = store.warned_connect_info.load(Ordering::Relaxed);
+        assert!(warned > 0);
+        // The warning is rate limited.
+        assert!(raw_get(addr, "").await.starts_with("HTTP/1.1 200"));
+        assert_eq!(store.warned_connect_info.load(Ordering::Relaxed), warned);
+
+        // Strict deployments fail the first request instead.
+        let strict = config.clone().with_require_connect_info(true);
+        let store = SessionStore::new(Some(SessionTestPool::default()), strict.clone())
+            .await
+            .unwrap();
+        let (listener, addr) = listen().await;
+        let make_service = app(store.clone()).into_make_service();
+        tokio::spawn(async move { axum::serve(listener, make_service).await });
+
+        assert!(raw_get(addr, "").await.starts_with("HTTP/1.1 500"));
+        assert!(store.inner.is_empty());
+
+        // The Forwarded by= clause can stand in for the missing ConnectInfo.
+        let store = SessionStore::new(
+            Some(SessionTestPool::default()),
+            strict.clone().with_forwarded_by_as_socket_ip(true),
+        )
+        .await
+        .unwrap();
+        let (listener, addr) = listen().await;
+        let make_service = app(store.clone()).into_make_service();
+        tokio::spawn(async move { axum::serve(listener, make_service).await });
+
+        let forwarded = "forwarded: for=192.0.2.60;by=203.0.113.43\r\n";
+        assert!(raw_get(addr, forwarded).await.starts_with("HTTP/1.1 200"));
+        assert!(raw_get(addr, "").await.starts_with("HTTP/1.1 500"));
+
+        // Served with connect info neither warns nor fails.
+        let store = SessionStore::new(Some(SessionTestPool::default()), strict)
+            .await
+            .unwrap();
+        let (listener, addr) = listen().await;
+        let make_service = app(store.clone()).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, make_service).await });
+
+        assert!(raw_get(addr, "").await.starts_with("HTTP/1.1 200"));
+        assert_eq!(store.warned_connect_info.load(Ordering::Relaxed), 0);
+    }
+}
@@ -1,11 +1,40 @@
-use crate::{DatabasePool, SessionData, SessionError, SessionStore};
-use axum::extract::FromRequestParts;
+use crate::{
+    headers::{set_headers, EmitOptions},
+    session_change_set::ChangeTracker,
+    DatabasePool, SessionChangeSet, SessionCounter, SessionData, SessionEntry, SessionError,
+    SessionId, SessionStore, SnapshotGuard,
+};
+use axum::{extract::FromRequestParts, response::Redirect};
 
+use chrono::{DateTime, Duration, Utc};
 #[cfg(feature = "key-store")]
 use fastbloom_rs::Membership;
-use http::{request::Parts, StatusCode};
+use http::{request::Parts, HeaderMap, StatusCode};
 use serde::Serialize;
-use std::fmt::Debug;
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
+
+/// Reserved key used to store when the session last re-authenticated.
+pub(crate) const REAUTHENTICATED_KEY: &str = "__axum_session_reauthenticated";
+
+/// Prefix of the reserved keys flash messages are stored under.
+pub(crate) const FLASH_PREFIX: &str = "__axum_session_flash.";
+
+/// The Status of a Session returned by `Session::verify()`.
+#[cfg(feature = "advanced")]
+#[cfg_attr(docsrs, doc(cfg(feature = "advanced")))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionStatus {
+    /// The Session is loaded and not outdated.
+    Valid,
+    /// The Session is loaded but is outdated, either renew it or remove it.
+    /// Session will get removed on next Session request purge update if no changes are done.
+    Expired {
+        /// When the Session expired.
+        at: DateTime<Utc>,
+    },
+    /// The Session was not found. Either the session was unloaded or was never created.
+    Missing,
+}
 
 /// A Session Store.
 ///
@@ -21,6 +50,11 @@ where
     pub(crate) store: SessionStore<T>,
     /// The Sessions current ID for looking up its store.
     pub(crate) id: String,
+    /// How the request that loaded the Session expects its cookie or header.
+    /// None unless the Session was handed out by the SessionLayer.
+    pub(crate) emit: Option<Arc<EmitOptions>>,
+    /// The keys this request changed, shared by the clones handed to its handlers.
+    pub(crate) changes: ChangeTracker,
 }
 
 /// Adds `FromRequestParts<B>` for Session
@@ -45,6 +79,41 @@ impl<S> Session<S>
 where
     S: DatabasePool + Clone + Debug + Sync + Send + 'static,
 {
+    /// Creates a Session holding the data under a newly generated ID, so handlers
+    /// taking a `Session` can be unit tested without the `SessionLayer`.
+    /// The data stays within the store until removed, see [`SessionStore::with_session`].
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let mut data = SessionData::for_testing(&store);
+    /// data.set("user-id", 5);
+    /// let session = Session::for_testing(&store, data);
+    ///
+    /// my_handler(session.clone()).await;
+    /// assert_eq!(session.get::<u32>("visits"), Some(1));
+    /// ```
+    ///
+    #[cfg(feature = "test-utils")]
+    pub fn for_testing(store: &SessionStore<S>, mut data: SessionData) -> Self {
+        let id = loop {
+            let id = store.config.id_generator.generate();
+
+            if !store.inner.contains_key(&id) {
+                break id;
+            }
+        };
+
+        data.id = id.clone();
+        store.inner.insert(id.clone(), data);
+
+        Self {
+            id,
+            store: store.clone(),
+            emit: None,
+            changes: ChangeTracker::default(),
+        }
+    }
+
     #[allow(clippy::needless_pass_by_ref_mut)]
     pub(crate) async fn new(
         store: SessionStore<S>,
@@ -57,45 +126,69 @@ where
 
         #[cfg(feature = "key-store")]
         if store.config.memory.use_bloom_filters {
+            let storage_id = store.storage_id(&id);
             let contained = {
                 let filter = store.filter.read().await;
-                filter.contains(id.as_bytes())
+                filter.contains(storage_id.as_bytes())
             };
 
             if !contained {
                 let mut filter = store.filter.write().await;
-                filter.add(id.as_bytes());
+                filter.add(storage_id.as_bytes());
+            }
+        }
+
+        Ok((
+            Self {
+                id,
+                store,
+                emit: None,
+                changes: ChangeTracker::default(),
+            },
+            is_new,
+        ))
+    }
+
+    /// Generates an ID within the configured maximum length, generating again up to
+    /// the configured id length attempts before giving up on the generator.
+    fn generate_token(store: &SessionStore<S>) -> Result<String, SessionError> {
+        let max = store.config.database.max_id_length;
+        let mut length = 0;
+
+        for _ in 0..store.config.database.id_length_attempts {
+            let token = store.config.id_generator.generate();
+
+            if token.len() <= max {
+                return Ok(token);
             }
+
+            length = token.len();
         }
 
-        Ok((Self { id, store }, is_new))
+        Err(SessionError::IdTooLong(length, max))
     }
 
     #[cfg(feature = "key-store")]
     pub(crate) async fn generate_id(store: &SessionStore<S>) -> Result<String, SessionError> {
         loop {
-            let token = store.config.id_generator.generate();
+            let token = Self::generate_token(store)?;
 
-            if (!store.config.memory.use_bloom_filters || store.auto_handles_expiry())
-                && !store.inner.contains_key(&token)
+            // The filter only rules out ids quickly. A cold or lossy filter can miss ids
+            // that exist, so a miss is still checked against memory and the database.
+            if store.config.memory.use_bloom_filters
+                && !store.auto_handles_expiry()
+                && store
+                    .filter
+                    .read()
+                    .await
+                    .contains(store.storage_id(&token).as_bytes())
             {
-                //This fixes an already used but in database issue.
-                if let Some(client) = &store.client {
-                    // Unwrap should be safe to use as we would want it to crash if there was a major database error.
-                    // This would mean the database no longer is online or the table missing etc.
-                    if !client
-                        .exists(&token.to_string(), &store.config.database.table_name)
-                        .await?
-                    {
-                        return Ok(token);
-                    }
-                } else {
-                    return Ok(token);
-                }
-            } else {
-                let filter = store.filter.read().await;
+                continue;
+            }
 
-                if !filter.contains(token.to_string().as_bytes()) {
+            if !store.inner.contains_key(&token) {
+                //This fixes an already used but in database issue.
+                if !store.database_exists(&token).await? {
                     return Ok(token);
                 }
             }
@@ -105,44 +198,44 @@ where
     #[cfg(not(feature = "key-store"))]
     pub(crate) async fn generate_id(store: &SessionStore<S>) -> Result<String, SessionError> {
         loop {
-            let token = store.config.id_generator.generate();
+            let token = Self::generate_token(store)?;
 
             if !store.inner.contains_key(&token) {
                 //This fixes an already used but in database issue.
-                if let Some(client) = &store.client {
-                    // Unwrap should be safe to use as we would want it to crash if there was a major database error.
-                    // This would mean the database no longer is online or the table missing etc.
-                    if !client
-                        .exists(&token.to_string(), &store.config.database.table_name)
-                        .await?
-                    {
-                        return Ok(token);
-                    }
-                } else {
+                if !store.database_exists(&token).await? {
                     return Ok(token);
                 }
             }
         }
     }
     /// Sets the Session to create the SessionData based on the current Session ID.
-    /// You can only use this if SessionMode::Manual is set or it will Panic.
     /// This will also set the store to true similar to session.set_store(true);
     ///
+    /// # Errors
+    /// - ['SessionError::InvalidConfiguration'] is returned if SessionMode::Manual is not set,
+    ///   as the Session data is created already.
+    ///
     /// # Examples
     /// ```rust ignore
-    /// session.create_data();
+    /// session.create_data()?;
     /// ```
     ///
     #[inline]
-    pub fn create_data(&self) {
+    pub fn create_data(&self) -> Result<(), SessionError> {
         if !self.store.config.session_mode.is_manual() {
-            panic!(
-                "Session must be set to SessionMode::Manual in order to use create_data,
-                as the Session data is created already."
-            );
+            return Err(SessionError::InvalidConfiguration(
+                "Session must be set to SessionMode::Manual in order to use create_data, as the Session data is created already."
+                    .to_owned(),
+            ));
         }
-        let session_data = SessionData::new(self.id.clone(), true, &self.store.config);
+        let session_data = SessionData::new(
+            self.id.clone(),
+            true,
+            &self.store.runtime_config(),
+            self.store.config.clock.as_ref(),
+        );
         self.store.inner.insert(self.id.clone(), session_data);
+        Ok(())
     }
 
     /// Checks if the SessionData was created or not.
@@ -172,7 +265,7 @@ where
     ///
     #[inline]
     pub fn renew(&self) {
-        self.store.renew(self.id.clone());
+        self.store.renew(&self.id);
     }
 
     /// Sets the Session to force update the database.
@@ -186,7 +279,7 @@ where
     ///
     #[inline]
     pub fn update(&self) {
-        self.store.update(self.id.clone());
+        self.store.update(&self.id);
     }
 
     /// Sets the Current Session to be Destroyed.
@@ -199,7 +292,7 @@ where
     ///
     #[inline]
     pub fn destroy(&self) {
-        self.store.destroy(self.id.clone());
+        self.store.destroy(&self.id);
     }
 
     /// Sets the Current Session to a long term expiration. Useful for Remember Me setups.
@@ -212,7 +305,7 @@ where
     ///
     #[inline]
     pub fn set_longterm(&self, longterm: bool) {
-        self.store.set_longterm(self.id.clone(), longterm);
+        self.store.set_longterm(&self.id, longterm);
     }
 
     /// Allows the Current Session to store.
@@ -229,7 +322,7 @@ where
     ///
     #[inline]
     pub fn set_store(&self, can_store: bool) {
-        self.store.set_store(self.id.clone(), can_store);
+        self.store.set_store(&self.id, can_store);
     }
 
     /// Gets data from the Session's HashMap
@@ -246,7 +339,28 @@ where
     ///
     #[inline]
     pub fn get<T: serde::de::DeserializeOwned>(&self, key: &str) -> Option<T> {
-        self.store.get(self.id.clone(), key)
+        self.store.get(&self.id, key)
+    }
+
+    /// Gets data from the Session's HashMap, loading the Session from the database first
+    /// if it is not within memory.
+    ///
+    /// This covers sessions unloaded from memory or not loaded in `SessionMode::Manual`
+    /// at the cost of a database lookup. Use `get` when the session is known to be in memory.
+    ///
+    /// # Errors
+    /// - ['SessionError::DatabaseError'] is returned if the database failed to load the session.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let id = session.get_or_load("user-id").await?.unwrap_or(0);
+    /// ```
+    ///
+    pub async fn get_or_load<T: serde::de::DeserializeOwned>(
+        &self,
+        key: &str,
+    ) -> Result<Option<T>, SessionError> {
+        self.store.get_or_load(&self.id, key).await
     }
 
     /// Removes a Key from the Current Session's HashMap returning it.
@@ -263,7 +377,36 @@ where
     ///
     #[inline]
     pub fn get_remove<T: serde::de::DeserializeOwned>(&self, key: &str) -> Option<T> {
-        self.store.get_remove(self.id.clone(), key)
+        self.store.get_remove(&self.id, &self.changes, key)
+    }
+
+    /// Returns every value within the Current Session's HashMap as the stored strings.
+    /// Values are not parsed, so this never fails on data that is not valid for a type.
+    /// Returns an empty map if the Session is not loaded.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let raw = session.get_all_raw();
+    /// ```
+    ///
+    #[inline]
+    pub fn get_all_raw(&self) -> HashMap<String, String> {
+        self.store.get_all_raw(&self.id)
+    }
+
+    /// Merges stored strings, such as those from [`Session::get_all_raw`], into the Current Session's HashMap.
+    /// Existing keys are replaced. Values are stored as given so they must already be encoded
+    /// the way [`Session::set`] would have stored them.
+    /// This will also update the database on Response Phase.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// session.merge_raw(other_session.get_all_raw());
+    /// ```
+    ///
+    #[inline]
+    pub fn merge_raw(&self, data: HashMap<String, String>) {
+        self.store.merge_raw(&self.id, &self.changes, data);
     }
 
     /// Sets data to the Current Session's HashMap.
@@ -276,7 +419,36 @@ where
     ///
     #[inline]
     pub fn set(&self, key: &str, value: impl Serialize) {
-        self.store.set(self.id.clone(), key, value);
+        self.store.set(&self.id, &self.changes, key, value);
+    }
+
+    /// Sets a [`SessionEntry`] within the Session under its key wrapped with its current version.
+    /// This will also update the database on Response Phase.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// session.set_versioned(&Cart { items: vec![1, 2] });
+    /// ```
+    ///
+    #[inline]
+    pub fn set_versioned<E: SessionEntry>(&self, value: &E) {
+        self.store.set_versioned(&self.id, &self.changes, value);
+    }
+
+    /// Gets a [`SessionEntry`] from the Session.
+    ///
+    /// Data stored with an older version is passed to [`SessionEntry::migrate`] and the
+    /// result is written back with the current version. Returns None if the key does not exist,
+    /// the data could not be migrated or was stored by a newer version.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let cart: Option<Cart> = session.get_versioned();
+    /// ```
+    ///
+    #[inline]
+    pub fn get_versioned<E: SessionEntry>(&self) -> Option<E> {
+        self.store.get_versioned(&self.id, &self.changes)
     }
 
     /// Removes a Key from the Current Session's HashMap.
@@ -290,7 +462,21 @@ where
     ///
     #[inline]
     pub fn remove(&self, key: &str) {
-        self.store.remove(self.id.clone(), key);
+        self.store.remove(&self.id, &self.changes, key);
+    }
+
+    /// Removes the listed Keys from the Current Session's HashMap at once.
+    /// Keys that do not exist are skipped.
+    /// This will also update the database on Response Phase.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// session.remove_many(&["user-id", "token"]);
+    /// ```
+    ///
+    #[inline]
+    pub fn remove_many(&self, keys: &[&str]) {
+        self.store.remove_many(&self.id, &self.changes, keys);
     }
 
     /// Clears all data from the Current Session's HashMap instantly.
@@ -303,7 +489,255 @@ where
     ///
     #[inline]
     pub fn clear(&self) {
-        self.store.clear_session_data(self.id.clone());
+        self.store.clear_session_data(&self.id, &self.changes);
+    }
+
+    /// Returns when the Session's latest saves to the database were made and the size of
+    /// their payload in bytes, oldest first. Only the last few saves since the Session was
+    /// loaded into memory are kept. Returns an empty list if the Session is not loaded.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// if let Some((_, size)) = session.size_history().last() {
+    ///     println!("last saved {size} bytes");
+    /// }
+    /// ```
+    ///
+    #[inline]
+    pub fn size_history(&self) -> Vec<(DateTime<Utc>, usize)> {
+        self.store.size_history(&self.id)
+    }
+
+    /// Pins the Session in memory so it is never unloaded by the memory sweeps or
+    /// a zero memory lifespan, or unpins it again to restore the normal lifecycle.
+    ///
+    /// Useful for long lived sessions, such as service accounts streaming over a websocket,
+    /// which would otherwise be reloaded from the database after each sweep.
+    /// Destroying the Session still removes it.
+    ///
+    /// # Errors
+    /// - [`SessionError::PinnedSessionLimit`] is returned if `with_max_pinned_sessions` are already pinned.
+    /// - [`SessionError::NoSessionError`] is returned if the Session is no longer loaded.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// session.pin_in_memory(true)?;
+    /// ```
+    ///
+    #[inline]
+    pub fn pin_in_memory(&self, pin: bool) -> Result<(), SessionError> {
+        self.store.pin_in_memory(&self.id, pin)
+    }
+
+    /// Returns the keys this request changed since the change set was last taken and starts over.
+    /// The Service takes the rest once the request is handled and inserts it into the
+    /// response extensions, so changes taken here are not part of that one.
+    ///
+    /// Each request keeps its own change set, so changes made by parallel requests to the
+    /// same Session are left out.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// session.set("user-id", 1);
+    /// let change_set = session.take_change_set();
+    /// ```
+    ///
+    #[inline]
+    pub fn take_change_set(&self) -> SessionChangeSet {
+        self.store.take_change_set(&self.id, &self.changes)
+    }
+
+    /// Returns the counter called name kept for the Current Session.
+    /// See [`SessionCounter`] for where the value is stored.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let calls = session.counter("api_calls").incr(1).await?;
+    /// ```
+    ///
+    #[inline]
+    pub fn counter<'a>(&'a self, name: &'a str) -> SessionCounter<'a, S> {
+        SessionCounter {
+            session: self,
+            name,
+        }
+    }
+
+    /// Copies the data of another Session into the Current Session under its own ID.
+    /// The source Session is loaded from memory or the database and is left untouched.
+    /// Existing keys within the Current Session are replaced by the source's keys.
+    /// This will also update the database on Response Phase.
+    ///
+    /// Useful for admin impersonation ("view as user") or cloning a session.
+    ///
+    /// # Errors
+    /// - ['SessionError::NoSessionError'] is returned if the source Session does not exist
+    ///   or the Current Session is not loaded.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// session.duplicate_from(&other_session_id).await?;
+    /// ```
+    ///
+    #[inline]
+    pub async fn duplicate_from(&self, source_id: &str) -> Result<(), SessionError> {
+        self.store
+            .duplicate_from(&self.id, &self.changes, source_id)
+            .await
+    }
+
+    /// Exports the Current Session's data so it can be handed to another device,
+    /// for example within a QR code, without a shared backend.
+    ///
+    /// The blob is encrypted with the database key if one is set, otherwise it is signed
+    /// with the cookie key if one is set. Without either key it is plain JSON and can be
+    /// read and altered by anyone holding it. The blob expires after the lifetime set with
+    /// [`crate::SessionConfig::with_transfer_lifetime`], yet until then treat it like a password
+    /// and only hand it over a channel you trust. Keys the Session stores for itself, such as
+    /// flash values and the re-authentication time, are not exported.
+    ///
+    /// # Errors
+    /// - ['SessionError::NoSessionError'] is returned if the Current Session is not loaded.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let blob = session.export_json()?;
+    /// ```
+    ///
+    #[inline]
+    pub fn export_json(&self) -> Result<String, SessionError> {
+        self.store.export_json(&self.id)
+    }
+
+    /// Imports a blob made by [`Session::export_json`] into the Current Session.
+    /// Existing keys within the Current Session are replaced by the blob's keys.
+    /// This will also update the database on Response Phase.
+    ///
+    /// The blob must have been exported by a store configured with the same keys.
+    /// Encrypted or signed blobs are verified before any data is loaded. When no key is
+    /// configured the blob can not be verified, so only import blobs you trust.
+    ///
+    /// # Errors
+    /// - ['SessionError::InvalidTransfer'] is returned if the blob was tampered with,
+    ///   made with other keys, has expired, holds keys reserved for the Session such as
+    ///   `__axum_session_reauthenticated` or is not valid JSON.
+    /// - ['SessionError::NoSessionError'] is returned if the Current Session is not loaded.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// session.import_json(&blob)?;
+    /// ```
+    ///
+    #[inline]
+    pub fn import_json(&self, blob: &str) -> Result<(), SessionError> {
+        self.store.import_json(&self.id, &self.changes, blob)
+    }
+
+    /// Marks the Current Session as having just re-authenticated.
+    /// Use this after the user re-enters their password for a sensitive action.
+    /// The timestamp is stored like normal data so it is persisted with the session.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// session.mark_reauthenticated();
+    /// ```
+    ///
+    #[inline]
+    pub fn mark_reauthenticated(&self) {
+        self.set(REAUTHENTICATED_KEY, self.store.now());
+    }
+
+    /// Checks if the Current Session re-authenticated within the given age.
+    /// Returns false if it never re-authenticated or if the stored time lies in the future.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// if !session.reauthenticated_within(Duration::minutes(5)) {
+    ///     return Redirect::to("/confirm-password");
+    /// }
+    /// ```
+    ///
+    #[inline]
+    pub fn reauthenticated_within(&self, age: Duration) -> bool {
+        let now = self.store.now();
+
+        // A future time was not set by this clock, so it can not prove a recent re-authentication.
+        self.get::<DateTime<Utc>>(REAUTHENTICATED_KEY)
+            .is_some_and(|at| at <= now && now - at <= age)
+    }
+
+    /// Sets a flash message which is kept until [`Session::take_flash`] reads it.
+    /// Flash messages are stored like normal data under a reserved key so they survive
+    /// a redirect to the next request.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// session.set_flash("notice", "Profile saved.");
+    /// ```
+    ///
+    #[inline]
+    pub fn set_flash(&self, key: &str, value: impl Serialize) {
+        self.set(&format!("{FLASH_PREFIX}{key}"), value);
+    }
+
+    /// Gets and removes a flash message set by [`Session::set_flash`].
+    /// Returns None if there is no flash message for the key or if serde_json failed to deserialize.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let notice: Option<String> = session.take_flash("notice");
+    /// ```
+    ///
+    #[inline]
+    pub fn take_flash<T: serde::de::DeserializeOwned>(&self, key: &str) -> Option<T> {
+        self.get_remove(&format!("{FLASH_PREFIX}{key}"))
+    }
+
+    /// Sets a flash message and returns a `303 See Other` [`Redirect`] to the location,
+    /// for showing a message after a form POST. The destination reads it with [`Session::take_flash`].
+    /// In [`crate::SessionMode::OptIn`] the Session must still be storable for the message to survive.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// async fn save_profile(session: Session<SessionNullPool>) -> Redirect {
+    ///     session.redirect_with_flash("/profile", "notice", "Profile saved.")
+    /// }
+    /// ```
+    ///
+    #[inline]
+    pub fn redirect_with_flash(&self, location: &str, key: &str, msg: impl Serialize) -> Redirect {
+        self.set_flash(key, msg);
+        Redirect::to(location)
+    }
+
+    /// Returns a stable key for caching responses personalized by this Session.
+    ///
+    /// The key is a HMAC over the Session ID and the current values of the listed keys,
+    /// so it changes whenever one of them changes but not when unlisted values do.
+    /// The Session ID can not be recovered from it. Keyed by
+    /// [`crate::SessionConfig::with_cache_key_salt`], else by the Cookie key.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let key = session.cache_key(&["user-id", "locale"]);
+    /// ```
+    ///
+    #[inline]
+    pub fn cache_key(&self, parts: &[&str]) -> String {
+        self.store.cache_key(&self.id, parts)
+    }
+
+    /// Returns [`Session::cache_key`] formatted as a weak ETag.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let etag = session.etag(&["user-id", "locale"]);
+    /// response.headers_mut().insert(ETAG, HeaderValue::from_str(&etag)?);
+    /// ```
+    ///
+    #[inline]
+    pub fn etag(&self, parts: &[&str]) -> String {
+        format!("W/\"{}\"", self.cache_key(parts))
     }
 
     /// Returns a i64 count of how many Sessions exist.
@@ -311,6 +745,9 @@ where
     /// If the Session is persistent it will return all sessions within the database.
     /// If the Session is not persistent it will return a count within SessionStore.
     ///
+    /// This includes idle sessions which have not yet expired, which for long term sessions can
+    /// be weeks old. Use `SessionStore::active_count` for counts such as users online.
+    ///
     /// # Examples
     /// ```rust ignore
     /// let count = session.count().await;
@@ -322,6 +759,7 @@ where
     }
 
     /// Returns the SessionID for this Session.
+    /// Use [`Session::session_id`] to log or compare it without cloning.
     ///
     /// # Examples
     /// ```rust ignore
@@ -333,6 +771,78 @@ where
         self.id.clone()
     }
 
+    /// Returns the SessionID for this Session borrowed, implementing `Display` and `AsRef<str>`.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// tracing::info!(session.id = %session.session_id(), "Checkout started");
+    /// ```
+    ///
+    #[inline]
+    pub fn session_id(&self) -> SessionId<'_> {
+        SessionId(&self.id)
+    }
+
+    /// Records the keys changed from now on so they can be rolled back if a speculative
+    /// operation fails. They are rolled back when the returned [`SnapshotGuard`] is dropped
+    /// unless [`SnapshotGuard::commit`] was called.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let snapshot = session.begin_snapshot();
+    /// session.set("order", &order);
+    /// payment.charge().await?;
+    /// snapshot.commit();
+    /// ```
+    ///
+    pub fn begin_snapshot(&self) -> SnapshotGuard<S> {
+        SnapshotGuard::new(self.clone())
+    }
+
+    /// Appends the Session's Set-Cookie, or header in rest mode, to headers the SessionLayer
+    /// does not write itself, such as a WebSocket upgrade or a custom streaming response.
+    ///
+    /// The cookie is signed and flagged the same way the SessionLayer would for the request
+    /// that loaded this Session and reflects its current storable and destroy state.
+    /// A Session not handed out by the SessionLayer has no request, so the cookie is not bound
+    /// to an IP or user agent and the Secure flag follows the config. Use
+    /// [`SessionStore::apply_session_headers`] with a request's options in that case.
+    ///
+    /// Responses that pass back through the SessionLayer already get the cookie, calling this
+    /// for them only adds a duplicate.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let mut response = ws.on_upgrade(handle_socket);
+    /// session.append_cookies(response.headers_mut());
+    /// ```
+    ///
+    pub fn append_cookies(&self, headers: &mut HeaderMap) {
+        let mut options = match &self.emit {
+            Some(options) => options.as_ref().clone(),
+            None => EmitOptions::without_request(&self.store.config),
+        };
+
+        if let Some(data) = self.store.inner.get(&self.id) {
+            options.storable = data.store;
+            options.destroy = data.destroy;
+        }
+
+        set_headers(&self.store.config, &self.id, headers, &options);
+    }
+
+    /// Returns how many requests for this Session are currently in flight, including this one.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let in_flight = session.parallel_count();
+    /// ```
+    ///
+    #[inline]
+    pub fn parallel_count(&self) -> usize {
+        self.store.parallel_count(&self.id)
+    }
+
     /// Returns the store for this Session.
     ///
     /// The store contains everything that all sessions need.
@@ -351,11 +861,34 @@ where
     ///
     /// The store contains everything that all sessions need.
     ///
+    /// Deprecated and removed in the next major release. The store is shared by every request,
+    /// so the mutable reference grants no exclusive access. Every SessionStore method only needs
+    /// [`Session::get_store`] and acts on all of its clones, while changing the config or client
+    /// through this reference only changed the copy held by this Session.
+    ///
+    /// - Lifespans and purge intervals are changed using [`SessionStore::update_runtime_config`].
+    /// - All sessions within the database are removed using [`SessionStore::clear_store`].
+    /// - The current Session's data is removed using [`Session::clear`].
+    ///
     /// # Examples
-    /// ```rust ignore
-    /// let store = session.get_store_mut();
+    /// ```rust
+    /// use axum_session::{Session, SessionError, SessionNullPool};
+    /// use chrono::Duration;
+    ///
+    /// async fn handler(session: Session<SessionNullPool>) -> Result<(), SessionError> {
+    ///     // Previously session.get_mut_store().clear_store().await
+    ///     session.get_store().clear_store().await?;
+    ///
+    ///     let store = session.get_store();
+    ///     let runtime = store.runtime_config().with_lifetime(Duration::hours(2));
+    ///     store.update_runtime_config(runtime).await
+    /// }
     /// ```
     ///
+    #[deprecated(
+        since = "0.17.0",
+        note = "use `get_store`, the store's methods act on every clone without a mutable reference"
+    )]
     #[inline]
     pub fn get_mut_store(&mut self) -> &mut SessionStore<S> {
         &mut self.store
@@ -372,7 +905,7 @@ where
     ///
     #[inline]
     pub(crate) fn remove_request(&self) {
-        self.store.remove_session_request(self.id.clone());
+        self.store.remove_session_request(&self.id);
     }
 
     /// Removes a Request from the request counter
@@ -386,7 +919,7 @@ where
     ///
     #[inline]
     pub(crate) fn set_request(&self) {
-        self.store.set_session_request(self.id.clone());
+        self.store.set_session_request(&self.id);
     }
 
     /// checks if a session has more than one request.
@@ -398,25 +931,33 @@ where
     ///
     #[inline]
     pub(crate) fn is_parallel(&self) -> bool {
-        self.store.is_session_parallel(self.id.clone())
+        self.store.is_session_parallel(&self.id)
     }
 
     /// checks if a session exists and if it is outdated.
     ///
+    /// Returns [`SessionStatus::Missing`] if the session is not loaded in memory,
+    /// [`SessionStatus::Expired`] with the time it expired at if outdated,
+    /// otherwise [`SessionStatus::Valid`].
+    ///
     /// # Examples
     /// ```rust ignore
-    /// session.verify();
+    /// match session.verify() {
+    ///     SessionStatus::Valid => {}
+    ///     SessionStatus::Expired { at } => println!("expired at {}", at),
+    ///     SessionStatus::Missing => println!("not loaded"),
+    /// }
     /// ```
     ///
     #[cfg(feature = "advanced")]
     #[cfg_attr(docsrs, doc(cfg(feature = "advanced")))]
     #[inline]
-    pub fn verify(&self) -> Result<(), SessionError> {
-        self.store.verify(self.id)
+    pub fn verify(&self) -> SessionStatus {
+        self.store.verify(&self.id)
     }
 
     /// Updates the sessions stored database expire time.
-    /// Use this before forcing a update to the database store.
+    /// Use `persist_now` instead if you also want to store it to the database.
     /// will update the database expires based on
     /// if the session is longterm then configs max_lifespan.
     /// if not then configs lifespan.
@@ -432,7 +973,7 @@ where
     #[cfg_attr(docsrs, doc(cfg(feature = "advanced")))]
     #[inline]
     pub fn update_database_expires(&self) -> Result<(), SessionError> {
-        self.store.update_database_expires(self.id)
+        self.store.update_database_expires(&self.id)
     }
 
     /// Updates the Sessions In memory auto remove timer.
@@ -447,12 +988,12 @@ where
     #[cfg_attr(docsrs, doc(cfg(feature = "advanced")))]
     #[inline]
     pub fn update_memory_expires(&self) -> Result<(), SessionError> {
-        self.store.update_memory_expires(self.id)
+        self.store.update_memory_expires(&self.id)
     }
 
     /// forces a update to the databases stored data for the session.
-    /// Make sure to update the databases expire time before running this or
-    /// the data could be unloaded by a request checking for outdated sessions.
+    /// This also refreshes the databases expire time so it is the same as
+    /// calling [`Session::persist_now`].
     ///
     /// # Examples
     /// ```rust ignore
@@ -463,7 +1004,25 @@ where
     #[cfg_attr(docsrs, doc(cfg(feature = "advanced")))]
     #[inline]
     pub async fn force_database_update(&self) -> Result<(), SessionError> {
-        self.store.force_database_update(self.id).await
+        self.store.persist_now(&self.id).await
+    }
+
+    /// Refreshes the sessions database expire time and stores the session
+    /// to the database in a single step.
+    /// will update the database expires based on
+    /// if the session is longterm then configs max_lifespan.
+    /// if not then configs lifespan.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// session.persist_now().await;
+    /// ```
+    ///
+    #[cfg(feature = "advanced")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "advanced")))]
+    #[inline]
+    pub async fn persist_now(&self) -> Result<(), SessionError> {
+        self.store.persist_now(&self.id).await
     }
 
     /// Removes the session from the memory store if it is not parallel.
@@ -481,7 +1040,7 @@ where
     #[cfg_attr(docsrs, doc(cfg(feature = "advanced")))]
     #[inline]
     pub fn memory_remove_session(&self) -> Result<(), SessionError> {
-        self.store.memory_remove_session(self.id)
+        self.store.memory_remove_session(&self.id)
     }
 
     /// Removes the session from the Database store.
@@ -497,7 +1056,7 @@ where
     #[cfg_attr(docsrs, doc(cfg(feature = "advanced")))]
     #[inline]
     pub async fn database_remove_session(&self) -> Result<(), SessionError> {
-        self.store.database_remove_session(self.id).await
+        self.store.database_remove_session(&self.id).await
     }
 }
 
@@ -560,7 +1119,21 @@ where
     ///
     #[inline]
     pub fn get<T: serde::de::DeserializeOwned>(&self, key: &str) -> Option<T> {
-        self.store.get(self.id.clone(), key)
+        self.store.get(&self.id, key)
+    }
+
+    /// Returns every value within the Session's HashMap as the stored strings.
+    /// Values are not parsed, so this never fails on data that is not valid for a type.
+    /// Returns an empty map if the Session is not loaded.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let raw = session.get_all_raw();
+    /// ```
+    ///
+    #[inline]
+    pub fn get_all_raw(&self) -> HashMap<String, String> {
+        self.store.get_all_raw(&self.id)
     }
 
     /// Returns a i64 count of how many Sessions exist.
@@ -577,4 +1150,711 @@ where
     pub async fn count(&self) -> i64 {
         self.store.count_sessions().await
     }
+
+    /// Returns the SessionID for this Session borrowed, implementing `Display` and `AsRef<str>`.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// tracing::info!(session.id = %session.session_id(), "Page viewed");
+    /// ```
+    ///
+    #[inline]
+    pub fn session_id(&self) -> SessionId<'_> {
+        SessionId(&self.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "advanced")]
+    use crate::SessionStatus;
+    use crate::{
+        databases::test_pool::{FakeClock, SessionTestPool},
+        session_change_set::ChangeTracker,
+        Key, ReadOnlySession, Session, SessionConfig, SessionData, SessionEntry, SessionError,
+        SessionStore, DEFAULT_MAX_ID_LENGTH,
+    };
+    use axum::response::IntoResponse;
+    use chrono::{Duration, Utc};
+    use http::StatusCode;
+    use serde::{Deserialize, Serialize};
+
+    use super::REAUTHENTICATED_KEY;
+
+    /// First layout of the cart which only stored the item ids.
+    #[derive(Serialize, Deserialize)]
+    struct CartV1 {
+        items: Vec<u32>,
+    }
+
+    impl SessionEntry for CartV1 {
+        const KEY: &'static str = "cart";
+        const VERSION: u32 = 1;
+    }
+
+    /// Second layout of the cart which added quantities.
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Cart {
+        items: Vec<(u32, u32)>,
+    }
+
+    impl SessionEntry for Cart {
+        const KEY: &'static str = "cart";
+        const VERSION: u32 = 2;
+
+        fn migrate(from_version: u32, raw: serde_json::Value) -> Option<Self> {
+            match from_version {
+                1 => {
+                    let old: CartV1 = serde_json::from_value(raw).ok()?;
+                    Some(Cart {
+                        items: old.items.into_iter().map(|id| (id, 1)).collect(),
+                    })
+                }
+                _ => None,
+            }
+        }
+    }
+
+    async fn loaded_session() -> (Session<SessionTestPool>, SessionTestPool) {
+        loaded_session_with(SessionConfig::default()).await
+    }
+
+    async fn loaded_session_with(
+        config: SessionConfig,
+    ) -> (Session<SessionTestPool>, SessionTestPool) {
+        let pool = SessionTestPool::default();
+        let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+        let (session, _) = Session::new(store, None).await.unwrap();
+        let data = SessionData::new(
+            session.id.clone(),
+            true,
+            &session.store.runtime_config(),
+            session.store.config.clock.as_ref(),
+        );
+        session.store.inner.insert(session.id.clone(), data);
+        (session, pool)
+    }
+
+    /// Hands out the given ids in order.
+    #[derive(Debug)]
+    struct SequenceIds(std::sync::Mutex<std::collections::VecDeque<&'static str>>);
+
+    impl crate::IdGenerator for SequenceIds {
+        fn generate(&self) -> String {
+            self.0.lock().unwrap().pop_front().unwrap().to_owned()
+        }
+    }
+
+    #[cfg(feature = "key-store")]
+    #[tokio::test]
+    async fn generate_id_checks_filter_misses() {
+        let ids = SequenceIds(std::sync::Mutex::new(["taken", "loaded", "fresh"].into()));
+        let config = SessionConfig::default()
+            .with_bloom_filter(true)
+            .with_id_generator(ids);
+        let pool = SessionTestPool::default();
+        let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+
+        // Neither id was added to the filter, like after a restart with a cold filter.
+        pool.insert_row(
+            "sessions",
+            "taken",
+            "",
+            (Utc::now() + Duration::try_hours(1).unwrap()).timestamp(),
+        );
+        let data = SessionData::new(
+            "loaded".to_owned(),
+            true,
+            &store.runtime_config(),
+            store.config.clock.as_ref(),
+        );
+        store.inner.insert("loaded".to_owned(), data);
+
+        assert_eq!(Session::generate_id(&store).await.unwrap(), "fresh");
+    }
+
+    #[tokio::test]
+    async fn generate_id_limits_length() {
+        let long: &'static str = "x".repeat(DEFAULT_MAX_ID_LENGTH + 1).leak();
+        let store = |ids: Vec<&'static str>, attempts: Option<usize>| {
+            let mut config = SessionConfig::default()
+                .with_id_generator(SequenceIds(std::sync::Mutex::new(ids.into())));
+
+            if let Some(attempts) = attempts {
+                config = config.with_id_length_attempts(attempts);
+            }
+
+            SessionStore::new(Some(SessionTestPool::default()), config)
+        };
+
+        // Long IDs are generated again a few times before it is an error.
+        let retried = store(vec![long, long, "short"], None).await.unwrap();
+        assert_eq!(Session::generate_id(&retried).await.unwrap(), "short");
+
+        let failing = store(vec![long, long, long], None).await.unwrap();
+        let err = Session::generate_id(&failing).await.unwrap_err();
+        assert!(matches!(
+            err,
+            SessionError::IdTooLong(length, DEFAULT_MAX_ID_LENGTH) if length == long.len()
+        ));
+
+        // The number of attempts is configurable.
+        let single = store(vec![long, "short"], Some(1)).await.unwrap();
+        assert!(matches!(
+            Session::generate_id(&single).await,
+            Err(SessionError::IdTooLong(..))
+        ));
+        let patient = store(vec![long, long, long, long, "short"], Some(5))
+            .await
+            .unwrap();
+        assert_eq!(Session::generate_id(&patient).await.unwrap(), "short");
+
+        assert!(matches!(
+            store(vec![], Some(0)).await,
+            Err(SessionError::InvalidConfiguration(_))
+        ));
+    }
+
+    #[cfg(feature = "advanced")]
+    #[tokio::test]
+    async fn verify() {
+        let (session, _) = loaded_session().await;
+        assert_eq!(session.verify(), SessionStatus::Valid);
+
+        let at = Utc::now() - Duration::try_minutes(1).unwrap();
+        session.store.inner.get_mut(&session.id).unwrap().expires = at;
+        assert_eq!(session.verify(), SessionStatus::Expired { at });
+
+        session.store.inner.remove(&session.id);
+        assert_eq!(session.verify(), SessionStatus::Missing);
+    }
+
+    #[cfg(feature = "advanced")]
+    #[tokio::test]
+    async fn expires_updates() {
+        let (session, _) = loaded_session().await;
+        let past = Utc::now() - Duration::try_minutes(1).unwrap();
+
+        {
+            let mut data = session.store.inner.get_mut(&session.id).unwrap();
+            data.expires = past;
+            data.autoremove = std::time::Instant::now();
+        }
+
+        session.update_database_expires().unwrap();
+        session.update_memory_expires().unwrap();
+
+        let data = session.store.inner.get(&session.id).unwrap();
+        assert!(data.expires > Utc::now());
+        assert!(data.autoremove > std::time::Instant::now());
+    }
+
+    #[cfg(feature = "advanced")]
+    #[tokio::test]
+    async fn persist_now() {
+        let (session, pool) = loaded_session().await;
+        session.set("a", 1);
+        session.store.inner.get_mut(&session.id).unwrap().expires =
+            Utc::now() - Duration::try_minutes(1).unwrap();
+
+        session.persist_now().await.unwrap();
+
+        let (row, expires) = pool.row("sessions", &session.id).unwrap();
+        assert!(expires > Utc::now().timestamp());
+        assert!(row.contains("\"a\""));
+        assert!(!session.store.inner.get(&session.id).unwrap().update);
+
+        // A failed store keeps the change marked so the response phase saves it again.
+        session.set("c", 3);
+        pool.fail_stores
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        assert!(session.persist_now().await.is_err());
+        assert!(session.store.inner.get(&session.id).unwrap().update);
+        pool.fail_stores
+            .store(false, std::sync::atomic::Ordering::Relaxed);
+        session.persist_now().await.unwrap();
+        assert!(!session.store.inner.get(&session.id).unwrap().update);
+
+        session.set("b", 2);
+        session.force_database_update().await.unwrap();
+        assert!(pool
+            .row("sessions", &session.id)
+            .unwrap()
+            .0
+            .contains("\"b\""));
+    }
+
+    #[cfg(feature = "advanced")]
+    #[tokio::test]
+    async fn remove_sessions() {
+        let (session, pool) = loaded_session().await;
+        session.persist_now().await.unwrap();
+
+        // A second request is still holding the session so it must stay loaded.
+        session.set_request();
+        session.memory_remove_session().unwrap();
+        assert!(session.data_exists());
+
+        session.memory_remove_session().unwrap();
+        assert!(!session.data_exists());
+        assert!(matches!(
+            session.memory_remove_session(),
+            Err(SessionError::NoSessionError)
+        ));
+
+        session.database_remove_session().await.unwrap();
+        assert!(pool.row("sessions", &session.id).is_none());
+    }
+
+    #[tokio::test]
+    async fn shared_store_without_mut_store() {
+        let (session, pool) = loaded_session().await;
+        let other_request = session.get_store().clone();
+        session.set("user", 5);
+        let data = session.store.inner.get(&session.id).unwrap().clone();
+        session.store.store_session(&data).await.unwrap();
+
+        let store = session.get_store();
+        let runtime = store
+            .runtime_config()
+            .with_lifetime(Duration::try_hours(2).unwrap());
+        store.update_runtime_config(runtime).await.unwrap();
+        assert_eq!(
+            other_request.runtime_config().lifespan,
+            Duration::try_hours(2).unwrap()
+        );
+
+        assert_eq!(other_request.count().await.unwrap(), 1);
+        session.get_store().clear_store().await.unwrap();
+        assert!(pool.row("sessions", &session.id).is_none());
+        assert_eq!(other_request.count().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn remove_many() {
+        let (session, _) = loaded_session().await;
+        session.set("user", 5);
+        session.set("token", "abc");
+        session.set("theme", "dark");
+        session.store.inner.get_mut(&session.id).unwrap().update = false;
+
+        session.remove_many(&["user", "token", "missing"]);
+        assert_eq!(session.get::<u32>("user"), None);
+        assert_eq!(session.get::<String>("token"), None);
+        assert_eq!(session.get::<String>("theme").as_deref(), Some("dark"));
+        assert!(session.store.inner.get(&session.id).unwrap().update);
+    }
+
+    #[cfg(feature = "test-utils")]
+    #[tokio::test]
+    async fn handler_with_fabricated_session() {
+        async fn count_visit(session: Session<SessionTestPool>) -> u32 {
+            let visits = session.get::<u32>("visits").unwrap_or(0) + 1;
+            session.set("visits", visits);
+            visits
+        }
+
+        let store = SessionStore::new(Some(SessionTestPool::default()), SessionConfig::default())
+            .await
+            .unwrap();
+        let mut data = SessionData::for_testing(&store);
+        data.set("visits", 2);
+        let session = Session::for_testing(&store, data);
+
+        assert_eq!(count_visit(session.clone()).await, 3);
+        assert_eq!(session.get::<u32>("visits"), Some(3));
+        assert!(store.inner.get(&session.id).unwrap().update);
+
+        let (visits, id) = store
+            .with_session(SessionData::for_testing(&store), |session| async move {
+                count_visit(session.clone()).await;
+                (session.get::<u32>("visits"), session.get_session_id())
+            })
+            .await;
+        assert_eq!(visits, Some(1));
+        assert!(!store.inner.contains_key(&id));
+        assert_eq!(store.inner.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reauthenticated() {
+        let (session, _) = loaded_session().await;
+        let window = Duration::try_minutes(5).unwrap();
+        assert!(!session.reauthenticated_within(window));
+
+        session.mark_reauthenticated();
+        assert!(session.reauthenticated_within(window));
+
+        session.set(
+            REAUTHENTICATED_KEY,
+            Utc::now() - Duration::try_minutes(10).unwrap(),
+        );
+        assert!(!session.reauthenticated_within(window));
+
+        // A marker from the future, such as a tampered database row, never counts.
+        session.set(
+            REAUTHENTICATED_KEY,
+            Utc::now() + Duration::try_days(365).unwrap(),
+        );
+        assert!(!session.reauthenticated_within(window));
+    }
+
+    #[tokio::test]
+    async fn redirect_with_flash() {
+        let (session, _) = loaded_session().await;
+        let res = session
+            .redirect_with_flash("/profile", "notice", "Profile saved.")
+            .into_response();
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(res.headers()[http::header::LOCATION], "/profile");
+
+        // Flash messages do not collide with normal data of the same key.
+        assert_eq!(session.get::<String>("notice"), None);
+        assert_eq!(
+            session.take_flash::<String>("notice").as_deref(),
+            Some("Profile saved.")
+        );
+        assert_eq!(session.take_flash::<String>("notice"), None);
+    }
+
+    #[tokio::test]
+    async fn duplicate_from() {
+        let (session, pool) = loaded_session().await;
+        let mut source = SessionData::new(
+            "source".to_owned(),
+            true,
+            &session.store.runtime_config(),
+            session.store.config.clock.as_ref(),
+        );
+        source.set("user", 5);
+        session.store.store_session(&source).await.unwrap();
+
+        session.set("theme", "dark");
+        session.store.inner.get_mut(&session.id).unwrap().update = false;
+        session.duplicate_from("source").await.unwrap();
+
+        assert_eq!(session.get::<i32>("user"), Some(5));
+        assert_eq!(session.get::<String>("theme").as_deref(), Some("dark"));
+        assert!(session.store.inner.get(&session.id).unwrap().update);
+        assert!(pool.row("sessions", "source").is_some());
+
+        assert!(session.duplicate_from("missing").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn export_and_import_json() {
+        let key = Key::generate();
+        let configs = [
+            SessionConfig::default(),
+            SessionConfig::default().with_key(key.clone()),
+            SessionConfig::default().with_database_key(key.clone()),
+        ];
+
+        for (index, config) in configs.into_iter().enumerate() {
+            let (source, _) = loaded_session_with(config.clone()).await;
+            source.set("user", 5);
+            let blob = source.export_json().unwrap();
+            // Only the encrypted blob hides the data.
+            assert_eq!(blob.contains("user"), index < 2);
+
+            let (target, _) = loaded_session_with(config).await;
+            target.set("theme", "dark");
+            target.store.inner.get_mut(&target.id).unwrap().update = false;
+            target.import_json(&blob).unwrap();
+            assert_eq!(target.get::<i32>("user"), Some(5));
+            assert_eq!(target.get::<String>("theme").as_deref(), Some("dark"));
+            assert!(target.store.inner.get(&target.id).unwrap().update);
+
+            if index > 0 {
+                let mut tampered = blob.into_bytes();
+                let middle = tampered.len() / 2;
+                tampered[middle] = if tampered[middle] == b'A' { b'B' } else { b'A' };
+                let tampered = String::from_utf8(tampered).unwrap();
+                assert!(matches!(
+                    target.import_json(&tampered),
+                    Err(SessionError::InvalidTransfer)
+                ));
+            }
+        }
+
+        // Blobs made with another key are rejected.
+        let (source, _) = loaded_session_with(SessionConfig::default().with_key(key)).await;
+        source.set("user", 5);
+        let blob = source.export_json().unwrap();
+        let (target, _) =
+            loaded_session_with(SessionConfig::default().with_key(Key::generate())).await;
+        assert!(matches!(
+            target.import_json(&blob),
+            Err(SessionError::InvalidTransfer)
+        ));
+        assert!(matches!(
+            target.import_json("not json"),
+            Err(SessionError::InvalidTransfer)
+        ));
+        assert_eq!(target.get::<i32>("user"), None);
+    }
+
+    #[tokio::test]
+    async fn export_json_expires_and_skips_reserved_keys() {
+        let clock = FakeClock::new();
+        let config = SessionConfig::default()
+            .with_key(Key::generate())
+            .with_transfer_lifetime(Duration::try_minutes(2).unwrap())
+            .with_clock(clock.clone());
+
+        let (source, _) = loaded_session_with(config.clone()).await;
+        source.set("user", 5);
+        source.mark_reauthenticated();
+        let blob = source.export_json().unwrap();
+        assert!(!blob.contains(REAUTHENTICATED_KEY));
+
+        let (target, _) = loaded_session_with(config.clone()).await;
+        target.import_json(&blob).unwrap();
+        assert_eq!(target.get::<i32>("user"), Some(5));
+        assert!(target.get::<String>(REAUTHENTICATED_KEY).is_none());
+
+        clock.advance(Duration::try_minutes(2).unwrap());
+        let (late, _) = loaded_session_with(config).await;
+        assert!(matches!(
+            late.import_json(&blob),
+            Err(SessionError::InvalidTransfer)
+        ));
+        assert_eq!(late.get::<i32>("user"), None);
+
+        // Unsigned blobs can not smuggle in keys the Session relies on.
+        let (unsigned, _) = loaded_session().await;
+        let exp = unsigned.store.now().timestamp() + 60;
+        let forged = format!(r#"{{"iat":0,"exp":{exp},"data":{{"{REAUTHENTICATED_KEY}":"0"}}}}"#);
+        assert!(matches!(
+            unsigned.import_json(&forged),
+            Err(SessionError::InvalidTransfer)
+        ));
+        assert!(unsigned.get::<String>(REAUTHENTICATED_KEY).is_none());
+    }
+
+    #[tokio::test]
+    async fn session_id_borrows_the_id() {
+        let (session, _) = loaded_session().await;
+        let id = session.session_id();
+        assert_eq!(id, session.get_session_id().as_str());
+        assert_eq!(id.to_string(), session.get_session_id());
+        assert_eq!(id.as_ref(), session.id);
+        assert_eq!(String::from(id), session.id);
+
+        let read_only = ReadOnlySession::from(session.clone());
+        assert_eq!(read_only.session_id(), session.session_id());
+    }
+
+    #[tokio::test]
+    async fn get_all_raw_round_trip() {
+        let (source, _) = loaded_session().await;
+        source.set("user", 5);
+        source.set("name", "Ferris");
+        // Values that are not valid JSON are still returned as stored.
+        source
+            .store
+            .inner
+            .get_mut(&source.id)
+            .unwrap()
+            .data
+            .insert("legacy".to_owned(), "not json".to_owned());
+
+        let raw = source.get_all_raw();
+        assert_eq!(raw.len(), 3);
+        assert_eq!(raw["user"], "5");
+        assert_eq!(raw["name"], "\"Ferris\"");
+        assert_eq!(raw["legacy"], "not json");
+        assert_eq!(ReadOnlySession::from(source.clone()).get_all_raw(), raw);
+
+        let (target, _) = loaded_session().await;
+        target.set("user", 1);
+        target.store.inner.get_mut(&target.id).unwrap().update = false;
+        target.merge_raw(raw.clone());
+        assert_eq!(target.get_all_raw(), raw);
+        assert_eq!(target.get::<String>("name").as_deref(), Some("Ferris"));
+        assert!(target.store.inner.get(&target.id).unwrap().update);
+
+        target.store.inner.remove(&target.id);
+        assert!(target.get_all_raw().is_empty());
+    }
+
+    #[tokio::test]
+    async fn versioned_entries_migrate() {
+        let (session, _) = loaded_session().await;
+
+        session.set_versioned(&CartV1 { items: vec![3, 4] });
+        let raw: serde_json::Value = session.get("cart").unwrap();
+        assert_eq!(raw["v"], 1);
+
+        let cart: Cart = session.get_versioned().unwrap();
+        assert_eq!(cart.items, vec![(3, 1), (4, 1)]);
+        // The migrated value is written back with the current version.
+        let raw: serde_json::Value = session.get("cart").unwrap();
+        assert_eq!(raw["v"], 2);
+        assert_eq!(session.get_versioned::<Cart>(), Some(cart));
+
+        session.set_versioned(&Cart {
+            items: vec![(9, 2)],
+        });
+        let raw: serde_json::Value = session.get("cart").unwrap();
+        assert_eq!(raw["v"], 2);
+        // Older code can not read data stored by a newer version.
+        assert!(session.get_versioned::<CartV1>().is_none());
+    }
+
+    #[cfg(feature = "derive")]
+    #[tokio::test]
+    async fn derived_versioned_entries() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize, crate::SessionEntry)]
+        #[session_entry(key = "cart", version = 2, migrate = DerivedCart::from_v1)]
+        struct DerivedCart {
+            items: Vec<(u32, u32)>,
+        }
+
+        impl DerivedCart {
+            fn from_v1(from_version: u32, raw: serde_json::Value) -> Option<Self> {
+                Cart::migrate(from_version, raw).map(|cart| DerivedCart { items: cart.items })
+            }
+        }
+
+        #[derive(Serialize, Deserialize, crate::SessionEntry)]
+        struct Defaults {}
+
+        assert_eq!(DerivedCart::KEY, "cart");
+        assert_eq!(DerivedCart::VERSION, 2);
+        assert_eq!(Defaults::KEY, "Defaults");
+        assert_eq!(Defaults::VERSION, 1);
+
+        let (session, _) = loaded_session().await;
+        session.set_versioned(&CartV1 { items: vec![7] });
+        let cart: DerivedCart = session.get_versioned().unwrap();
+        assert_eq!(cart.items, vec![(7, 1)]);
+    }
+
+    #[tokio::test]
+    async fn get_or_load() {
+        let (session, _) = loaded_session().await;
+        session.set("user", 3);
+        let data = session.store.inner.get(&session.id).unwrap().clone();
+        session.store.store_session(&data).await.unwrap();
+        session.store.inner.remove(&session.id);
+
+        assert_eq!(session.get::<i32>("user"), None);
+        assert_eq!(session.get_or_load::<i32>("user").await.unwrap(), Some(3));
+        assert_eq!(session.get::<i32>("user"), Some(3));
+
+        {
+            let loaded = session.store.inner.get(&session.id).unwrap();
+            assert_eq!(loaded.requests, 1);
+            assert!(loaded.store);
+            assert!(!loaded.update);
+        }
+
+        let (missing, _) = loaded_session().await;
+        missing.store.inner.remove(&missing.id);
+        assert_eq!(missing.get_or_load::<i32>("user").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn snapshot_commit_and_rollback() {
+        let (session, _) = loaded_session().await;
+        session.set("kept", 1);
+        session.set("removed", 2);
+
+        let snapshot = session.begin_snapshot();
+        session.set("added", 3);
+        session.set("kept", 10);
+        snapshot.commit();
+        assert_eq!(session.get::<i32>("added"), Some(3));
+        assert_eq!(session.get::<i32>("kept"), Some(10));
+
+        // Dropping the guard without a commit rolls removals, additions and changes back.
+        {
+            let _snapshot = session.begin_snapshot();
+            session.remove("removed");
+            session.remove("added");
+            session.set("new", 4);
+            session.set("kept", 20);
+        }
+        assert_eq!(session.get::<i32>("removed"), Some(2));
+        assert_eq!(session.get::<i32>("added"), Some(3));
+        assert_eq!(session.get::<i32>("new"), None);
+        assert_eq!(session.get::<i32>("kept"), Some(10));
+
+        // Nested snapshots stack, each rolling back the keys changed since it was made.
+        let outer = session.begin_snapshot();
+        session.set("outer", 5);
+        let inner = session.begin_snapshot();
+        session.set("inner", 6);
+        inner.rollback();
+        assert_eq!(session.get::<i32>("inner"), None);
+        assert_eq!(session.get::<i32>("outer"), Some(5));
+        outer.rollback();
+        assert_eq!(session.get::<i32>("outer"), None);
+    }
+
+    #[tokio::test]
+    async fn snapshot_flags() {
+        let (session, _) = loaded_session().await;
+
+        let snapshot = session.begin_snapshot();
+        session.set_longterm(true);
+        session.renew();
+        snapshot.rollback();
+        // Flags are left alone by default.
+        let data = session.store.inner.get(&session.id).unwrap().clone();
+        assert!(data.longterm && data.renew);
+
+        let snapshot = session.begin_snapshot().with_flags(true);
+        session.set_longterm(false);
+        session.destroy();
+        snapshot.rollback();
+        let data = session.store.inner.get(&session.id).unwrap().clone();
+        assert!(data.longterm && data.renew && !data.destroy);
+    }
+
+    #[tokio::test]
+    async fn snapshot_keeps_parallel_changes() {
+        let (session, _) = loaded_session().await;
+        // Another request for the same Session shares its data but not its changes.
+        let parallel = Session {
+            changes: ChangeTracker::default(),
+            ..session.clone()
+        };
+        parallel.set("cart", 1);
+
+        let snapshot = session.begin_snapshot();
+        session.set("order", 2);
+        parallel.set("language", "en");
+        parallel.remove("cart");
+        drop(snapshot);
+        assert_eq!(session.get::<i32>("order"), None);
+        assert_eq!(parallel.get::<String>("language").as_deref(), Some("en"));
+        assert_eq!(parallel.get::<i32>("cart"), None);
+
+        // A key changed by both is set back to its value when the snapshot was made.
+        let snapshot = session.begin_snapshot();
+        session.set("language", "de");
+        parallel.set("language", "fr");
+        drop(snapshot);
+        assert_eq!(session.get::<String>("language").as_deref(), Some("en"));
+    }
+
+    #[tokio::test]
+    async fn snapshot_dropped_out_of_order() {
+        let (session, _) = loaded_session().await;
+
+        let outer = session.begin_snapshot();
+        session.set("outer", 1);
+        let inner = session.begin_snapshot();
+        session.set("inner", 2);
+        // The outer rollback takes the inner snapshot's changes with it.
+        drop(outer);
+        assert_eq!(session.get::<i32>("outer"), None);
+        assert_eq!(session.get::<i32>("inner"), None);
+
+        session.set("after", 3);
+        drop(inner);
+        assert_eq!(session.get::<i32>("after"), Some(3));
+    }
 }
@@ -0,0 +1,326 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// Prefix of the keys the Session stores for itself such as flash values.
+const INTERNAL_KEY_PREFIX: &str = "__axum_session";
+
+/// Keys of a Session's data which a request changed since the change set was last taken.
+///
+/// Returned by [`crate::Session::take_change_set`] and inserted into the response extensions
+/// once the inner service has run so outer middleware can read what a request changed.
+/// Changes made by parallel requests to the same Session are not part of it.
+/// Keys the Session stores for itself, such as flash values, keep their `__axum_session`
+/// prefix and can be filtered using [`SessionChangeSet::is_internal_key`].
+///
+/// Each list is sorted. A key which was changed and then set back to its value is left out.
+///
+/// # Examples
+/// ```rust ignore
+/// let change_set = response.extensions().get::<SessionChangeSet>();
+/// ```
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionChangeSet {
+    /// Keys which did not exist before.
+    pub added: Vec<String>,
+    /// Keys which existed before and now hold a different value.
+    pub modified: Vec<String>,
+    /// Keys which existed before and no longer do.
+    pub removed: Vec<String>,
+    /// Whether the data was cleared, either by [`crate::Session::clear`] or on expiry.
+    pub cleared: bool,
+}
+
+impl SessionChangeSet {
+    /// Returns true if nothing changed.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// if !session.take_change_set().is_empty() { invalidate(); }
+    /// ```
+    ///
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.modified.is_empty()
+            && self.removed.is_empty()
+            && !self.cleared
+    }
+
+    /// Returns true if the key is one the Session stores for itself, such as a flash value.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// change_set.added.retain(|key| !SessionChangeSet::is_internal_key(key));
+    /// ```
+    ///
+    #[inline]
+    pub fn is_internal_key(key: &str) -> bool {
+        key.starts_with(INTERNAL_KEY_PREFIX)
+    }
+
+    /// Returns the change set without the keys the Session stores for itself.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let change_set = session.take_change_set().without_internal();
+    /// ```
+    ///
+    #[must_use]
+    pub fn without_internal(mut self) -> Self {
+        for keys in [&mut self.added, &mut self.modified, &mut self.removed] {
+            keys.retain(|key| !Self::is_internal_key(key));
+        }
+
+        self
+    }
+}
+
+/// Records the value each key held before a request first changed it since the last take.
+/// Clones share the record, so every handle to the request's Session adds to the same one.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ChangeTracker(Arc<Mutex<Changes>>);
+
+#[derive(Debug, Default)]
+struct Changes {
+    original: HashMap<String, Option<String>>,
+    cleared: bool,
+    /// Values before each open snapshot first changed them, oldest snapshot first.
+    snapshots: Vec<(u64, HashMap<String, Option<String>>)>,
+    next_snapshot: u64,
+}
+
+impl Changes {
+    fn touch(&mut self, data: &HashMap<String, String>, key: &str) {
+        let originals = std::iter::once(&mut self.original)
+            .chain(self.snapshots.iter_mut().map(|(_, original)| original));
+
+        for original in originals {
+            if !original.contains_key(key) {
+                original.insert(key.to_owned(), data.get(key).cloned());
+            }
+        }
+    }
+}
+
+/// How [`ChangeTracker::rollback_snapshot`] went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Rollback {
+    /// Only the snapshot's changes were rolled back.
+    Done,
+    /// Snapshots made after it were still open and were rolled back with it.
+    OutOfOrder,
+    /// An older snapshot already rolled it back.
+    Closed,
+}
+
+impl ChangeTracker {
+    fn lock(&self) -> MutexGuard<'_, Changes> {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Must be called before the key is changed in the data.
+    #[inline]
+    pub(crate) fn touch(&self, data: &HashMap<String, String>, key: &str) {
+        self.lock().touch(data, key);
+    }
+
+    /// Must be called before the keys are changed in the data.
+    pub(crate) fn touch_many<'a>(
+        &self,
+        data: &HashMap<String, String>,
+        keys: impl IntoIterator<Item = &'a str>,
+    ) {
+        let mut changes = self.lock();
+
+        for key in keys {
+            changes.touch(data, key);
+        }
+    }
+
+    /// Must be called before the data is cleared.
+    pub(crate) fn clear(&self, data: &HashMap<String, String>) {
+        let mut changes = self.lock();
+
+        for key in data.keys() {
+            changes.touch(data, key);
+        }
+
+        changes.cleared = true;
+    }
+
+    /// Starts recording the values keys held before they are changed, until the returned
+    /// snapshot is committed or rolled back.
+    pub(crate) fn begin_snapshot(&self) -> u64 {
+        let mut changes = self.lock();
+        let snapshot = changes.next_snapshot;
+        changes.next_snapshot += 1;
+        changes.snapshots.push((snapshot, HashMap::new()));
+        snapshot
+    }
+
+    /// Stops recording for the snapshot, keeping its changes.
+    pub(crate) fn commit_snapshot(&self, snapshot: u64) {
+        self.lock().snapshots.retain(|(id, _)| *id != snapshot);
+    }
+
+    /// Sets the keys changed since the snapshot began back to their values at that time.
+    /// Keys not changed through this tracker, such as by parallel requests, are left alone.
+    pub(crate) fn rollback_snapshot(
+        &self,
+        snapshot: u64,
+        data: &mut HashMap<String, String>,
+    ) -> Rollback {
+        let mut changes = self.lock();
+        let Some(index) = changes.snapshots.iter().position(|(id, _)| *id == snapshot) else {
+            return Rollback::Closed;
+        };
+
+        let nested = changes.snapshots.len() - index - 1;
+        let (_, original) = changes.snapshots.remove(index);
+        changes.snapshots.truncate(index);
+
+        for (key, value) in original {
+            changes.touch(data, &key);
+
+            match value {
+                Some(value) => data.insert(key, value),
+                None => data.remove(&key),
+            };
+        }
+
+        if nested == 0 {
+            Rollback::Done
+        } else {
+            Rollback::OutOfOrder
+        }
+    }
+
+    /// Compares the recorded values with the current data and starts over.
+    pub(crate) fn take(&self, data: &HashMap<String, String>) -> SessionChangeSet {
+        let mut changes = self.lock();
+        let mut change_set = SessionChangeSet {
+            cleared: std::mem::take(&mut changes.cleared),
+            ..Default::default()
+        };
+
+        for (key, original) in changes.original.drain() {
+            match (original, data.get(&key)) {
+                (None, Some(_)) => change_set.added.push(key),
+                (Some(_), None) => change_set.removed.push(key),
+                (Some(original), Some(value)) if original != *value => {
+                    change_set.modified.push(key)
+                }
+                _ => {}
+            }
+        }
+
+        change_set.added.sort_unstable();
+        change_set.modified.sort_unstable();
+        change_set.removed.sort_unstable();
+        change_set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChangeTracker, Rollback, SessionChangeSet};
+    use std::collections::HashMap;
+
+    #[test]
+    fn compares_with_first_value() {
+        let mut data = HashMap::from([
+            ("kept".to_owned(), "1".to_owned()),
+            ("changed".to_owned(), "1".to_owned()),
+            ("reverted".to_owned(), "1".to_owned()),
+            ("gone".to_owned(), "1".to_owned()),
+        ]);
+        let tracker = ChangeTracker::default();
+
+        for (key, value) in [
+            ("changed", "2"),
+            ("reverted", "2"),
+            ("reverted", "1"),
+            ("new", "1"),
+        ] {
+            tracker.touch(&data, key);
+            data.insert(key.to_owned(), value.to_owned());
+        }
+
+        for key in ["gone", "temporary"] {
+            tracker.touch(&data, key);
+            data.remove(key);
+        }
+
+        let change_set = tracker.take(&data);
+        assert_eq!(change_set.added, ["new"]);
+        assert_eq!(change_set.modified, ["changed"]);
+        assert_eq!(change_set.removed, ["gone"]);
+        assert!(!change_set.cleared);
+        assert!(tracker.take(&data).is_empty());
+
+        tracker.clear(&data);
+        data.clear();
+        let change_set = tracker.take(&data);
+        assert!(change_set.cleared);
+        assert_eq!(change_set.removed, ["changed", "kept", "new", "reverted"]);
+    }
+
+    #[test]
+    fn snapshots_roll_back_their_own_keys() {
+        let mut data = HashMap::from([("kept".to_owned(), "1".to_owned())]);
+        let tracker = ChangeTracker::default();
+        let outer = tracker.begin_snapshot();
+        tracker.touch(&data, "outer");
+        data.insert("outer".to_owned(), "1".to_owned());
+        let inner = tracker.begin_snapshot();
+        tracker.touch(&data, "kept");
+        data.insert("kept".to_owned(), "2".to_owned());
+        // Changed without the tracker, as a parallel request would.
+        data.insert("parallel".to_owned(), "1".to_owned());
+
+        // Rolling back the outer snapshot first rolls back the inner one with it.
+        assert_eq!(
+            tracker.rollback_snapshot(outer, &mut data),
+            Rollback::OutOfOrder
+        );
+        assert_eq!(
+            tracker.rollback_snapshot(inner, &mut data),
+            Rollback::Closed
+        );
+        assert_eq!(
+            data,
+            HashMap::from([
+                ("kept".to_owned(), "1".to_owned()),
+                ("parallel".to_owned(), "1".to_owned()),
+            ])
+        );
+
+        let snapshot = tracker.begin_snapshot();
+        tracker.clear(&data);
+        data.clear();
+        assert_eq!(
+            tracker.rollback_snapshot(snapshot, &mut data),
+            Rollback::Done
+        );
+        assert_eq!(data.len(), 2);
+        assert!(tracker.take(&data).cleared);
+    }
+
+    #[test]
+    fn filters_internal_keys() {
+        let change_set = SessionChangeSet {
+            added: vec!["__axum_session_flash.notice".to_owned(), "user".to_owned()],
+            removed: vec!["__axum_session_reauthenticated".to_owned()],
+            ..Default::default()
+        }
+        .without_internal();
+
+        assert_eq!(change_set.added, ["user"]);
+        assert!(change_set.removed.is_empty());
+    }
+}
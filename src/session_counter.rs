@@ -0,0 +1,71 @@
+use crate::{DatabasePool, Session, SessionError};
+use std::fmt::Debug;
+
+/// A number kept per Session which can change without storing the whole Session again.
+///
+/// Returned by [`Session::counter`]. Databases with native counters such as Redis and Postgres
+/// keep them beside the Session row, so incrementing one is a single atomic query instead of
+/// serializing and storing the Session on every request. Other databases, Sessions which are
+/// not stored yet and Sessions without a database keep the counter within the Session data
+/// under its name, as if it was set using [`Session::set`].
+///
+/// Native counters follow the Session. They expire with it, move to the new ID when it is
+/// renewed and are deleted when it is destroyed. A value set under the counter's name before
+/// native counters were used is added to the native counter on its first increment.
+///
+/// # Examples
+/// ```rust ignore
+/// let calls = session.counter("api_calls").incr(1).await?;
+/// ```
+///
+#[derive(Debug)]
+pub struct SessionCounter<'a, T>
+where
+    T: DatabasePool + Clone + Debug + Sync + Send + 'static,
+{
+    pub(crate) session: &'a Session<T>,
+    pub(crate) name: &'a str,
+}
+
+impl<T> SessionCounter<'_, T>
+where
+    T: DatabasePool + Clone + Debug + Sync + Send + 'static,
+{
+    /// Adds delta to the counter and returns its new value. Counters start at 0.
+    ///
+    /// # Errors
+    /// - ['SessionError::DatabaseError'] is returned if the database failed to change the counter.
+    /// - ['SessionError::NoSessionError'] is returned if the Session is not loaded.
+    /// - ['SessionError::GenericNotSupportedError'] is returned if a counter within the
+    ///   Session data would overflow.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let calls = session.counter("api_calls").incr(1).await?;
+    /// ```
+    ///
+    pub async fn incr(&self, delta: i64) -> Result<i64, SessionError> {
+        self.session
+            .store
+            .counter_incr(&self.session.id, &self.session.changes, self.name, delta)
+            .await
+    }
+
+    /// Returns the counter's value, 0 if it was never incremented.
+    ///
+    /// # Errors
+    /// - ['SessionError::DatabaseError'] is returned if the database failed to read the counter.
+    /// - ['SessionError::NoSessionError'] is returned if the Session is not loaded.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let calls = session.counter("api_calls").get().await?;
+    /// ```
+    ///
+    pub async fn get(&self) -> Result<i64, SessionError> {
+        self.session
+            .store
+            .counter_get(&self.session.id, self.name)
+            .await
+    }
+}
@@ -1,18 +1,21 @@
-use crate::SessionConfig;
+use crate::{
+    config::instant_after, session_change_set::ChangeTracker, session_size::SizeHistory, Clock,
+    RuntimeConfig,
+};
 use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fmt::Debug};
+use std::{collections::HashMap, fmt::Debug, time::Instant};
 
 /// The Store and Configured Data for a Session.
 ///
 /// # Examples
 /// ```rust ignore
-/// use axum_session::{SessionConfig, SessionData};
+/// use axum_session::{RuntimeConfig, SessionConfig, SessionData, SystemClock};
 /// use uuid::Uuid;
 ///
-/// let config = SessionConfig::default();
+/// let runtime = RuntimeConfig::from(&SessionConfig::default());
 /// let token = Uuid::new_v4();
-/// let session_data = SessionData::new(token.to_string(), true, &config);
+/// let session_data = SessionData::new(token.to_string(), true, &runtime, &SystemClock);
 /// ```
 ///
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -22,19 +25,44 @@ pub struct SessionData {
     pub(crate) data: HashMap<String, String>,
     #[serde(skip)]
     pub(crate) expires: DateTime<Utc>,
-    #[serde(skip)]
-    pub(crate) autoremove: DateTime<Utc>,
+    /// Monotonic so wall clock changes can not unload the session from memory early.
+    #[serde(skip, default = "Instant::now")]
+    pub(crate) autoremove: Instant,
     #[serde(skip)]
     pub(crate) destroy: bool,
     #[serde(skip)]
     pub(crate) renew: bool,
     pub(crate) longterm: bool,
-    #[serde(skip)]
+    /// Persisted so OptIn sessions stay storable when reloaded after being unloaded from memory.
+    /// Rows saved before this was stored only exist because they were storable.
+    #[serde(default = "stored_default")]
     pub(crate) store: bool,
     #[serde(skip)]
     pub(crate) update: bool,
     #[serde(skip)]
     pub(crate) requests: usize,
+    /// Kept in memory regardless of the memory lifespan until unpinned.
+    #[serde(skip)]
+    pub(crate) pinned: bool,
+    /// Stands in for a session the database failed to load, so it is never saved.
+    #[serde(skip)]
+    pub(crate) ephemeral: bool,
+    /// Set while the ID is renewed, so requests sending it wait for the renewal.
+    #[serde(skip)]
+    pub(crate) renewing: bool,
+    #[serde(skip)]
+    pub(crate) last_active: DateTime<Utc>,
+    /// Persisted so the absolute max age counts from creation across reloads.
+    /// Rows saved before this was stored count from when they are next loaded.
+    #[serde(default = "Utc::now")]
+    pub(crate) created_at: DateTime<Utc>,
+    /// Sizes of the payload stored by the latest saves.
+    #[serde(skip)]
+    pub(crate) sizes: SizeHistory,
+}
+
+fn stored_default() -> bool {
+    true
 }
 
 impl SessionData {
@@ -42,27 +70,83 @@ impl SessionData {
     ///
     /// # Examples
     /// ```rust ignore
-    /// use axum_session::{SessionConfig, SessionData};
+    /// use axum_session::{RuntimeConfig, SessionConfig, SessionData, SystemClock};
     /// use uuid::Uuid;
     ///
-    /// let config = SessionConfig::default();
+    /// let runtime = RuntimeConfig::from(&SessionConfig::default());
     /// let token = Uuid::new_v4();
-    /// let session_data = SessionData::new(token.to_string(), true, &config);
+    /// let session_data = SessionData::new(token.to_string(), true, &runtime, &SystemClock);
     /// ```
     ///
     #[inline]
-    pub(crate) fn new(id: String, storable: bool, config: &SessionConfig) -> Self {
+    pub(crate) fn new(
+        id: String,
+        storable: bool,
+        runtime: &RuntimeConfig,
+        clock: &dyn Clock,
+    ) -> Self {
+        let now = clock.now();
+
         Self {
             id,
             data: HashMap::new(),
-            expires: Utc::now() + config.lifespan,
+            expires: now + runtime.lifespan,
             destroy: false,
             renew: false,
-            autoremove: Utc::now() + config.memory.memory_lifespan,
+            autoremove: instant_after(clock.instant(), runtime.memory_lifespan),
             longterm: false,
             store: storable,
             update: true,
             requests: 1,
+            pinned: false,
+            ephemeral: false,
+            renewing: false,
+            last_active: now,
+            created_at: now,
+            sizes: SizeHistory::default(),
+        }
+    }
+
+    /// Constructs new storable SessionData for [`crate::Session::for_testing`].
+    /// Fill it using the SessionData setters before handing it over.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let mut data = SessionData::for_testing(&store);
+    /// data.set("user-id", 5);
+    /// ```
+    ///
+    #[cfg(feature = "test-utils")]
+    pub fn for_testing<T>(store: &crate::SessionStore<T>) -> Self
+    where
+        T: crate::DatabasePool + Clone + Debug + Sync + Send + 'static,
+    {
+        Self::new(
+            String::new(),
+            true,
+            &store.runtime_config(),
+            store.config.clock.as_ref(),
+        )
+    }
+
+    /// Returns the expiry the session gets when saved now.
+    /// Longterm sessions use max_lifespan, others lifespan, both capped by the
+    /// absolute max age counted from when the session was created.
+    #[inline]
+    pub(crate) fn refreshed_expires(
+        &self,
+        now: DateTime<Utc>,
+        runtime: &RuntimeConfig,
+    ) -> DateTime<Utc> {
+        let expires = if self.longterm {
+            now + runtime.max_lifespan
+        } else {
+            now + runtime.lifespan
+        };
+
+        match runtime.absolute_max_age {
+            Some(max_age) => expires.min(self.created_at + max_age),
+            None => expires,
         }
     }
 
@@ -70,18 +154,37 @@ impl SessionData {
     ///
     /// # Examples
     /// ```rust ignore
-    /// use axum_session::{SessionConfig, SessionData};
+    /// use axum_session::{RuntimeConfig, SessionConfig, SessionData, SystemClock};
     /// use uuid::Uuid;
     ///
-    /// let config = SessionConfig::default();
+    /// let runtime = RuntimeConfig::from(&SessionConfig::default());
     /// let token = Uuid::new_v4();
-    /// let session_data = SessionData::new(token.to_string(), true, &config);
-    /// let expired = session_data.expired();
+    /// let session_data = SessionData::new(token.to_string(), true, &runtime, &SystemClock);
+    /// let expired = session_data.expired(Utc::now());
     /// ```
     ///
     #[inline]
-    pub(crate) fn expired(&self) -> bool {
-        self.expires < Utc::now()
+    pub(crate) fn expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires < now
+    }
+
+    /// Determines whether the session is still usable, meaning it has not expired
+    /// and is not set to be destroyed.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// use axum_session::{RuntimeConfig, SessionConfig, SessionData, SystemClock};
+    /// use uuid::Uuid;
+    ///
+    /// let runtime = RuntimeConfig::from(&SessionConfig::default());
+    /// let token = Uuid::new_v4();
+    /// let session_data = SessionData::new(token.to_string(), true, &runtime, &SystemClock);
+    /// let valid = session_data.validate(Utc::now());
+    /// ```
+    ///
+    #[inline]
+    pub(crate) fn validate(&self, now: DateTime<Utc>) -> bool {
+        !self.expired(now) && !self.destroy
     }
 
     /// Validates and checks if the Session is to be destroyed.
@@ -90,26 +193,59 @@ impl SessionData {
     ///
     /// # Examples
     /// ```rust ignore
-    /// use axum_session::{SessionConfig, SessionData};
+    /// use axum_session::{RuntimeConfig, SessionConfig, SessionData, SystemClock};
     /// use uuid::Uuid;
     ///
-    /// let config = SessionConfig::default();
+    /// let runtime = RuntimeConfig::from(&SessionConfig::default());
     /// let token = Uuid::new_v4();
-    /// let mut session_data = SessionData::new(token.to_string(), true, &config);
-    /// let expired = session_data.service_clear(Duration::days(5));
+    /// let mut session_data = SessionData::new(token.to_string(), true, &runtime, &SystemClock);
+    /// session_data.service_clear(Duration::days(5), true, &SystemClock, &changes);
     /// ```
     ///
     #[inline]
-    pub(crate) fn service_clear(&mut self, memory_lifespan: Duration, clear_check: bool) {
-        if clear_check && self.autoremove < Utc::now() {
+    pub(crate) fn service_clear(
+        &mut self,
+        memory_lifespan: Duration,
+        clear_check: bool,
+        clock: &dyn Clock,
+        changes: &ChangeTracker,
+    ) {
+        let instant = clock.instant();
+
+        if clear_check && self.autoremove < instant {
             self.update = true;
 
-            if self.expired() {
+            if self.expired(clock.now()) {
+                changes.clear(&self.data);
                 self.data.clear();
             }
         }
 
-        self.autoremove = Utc::now() + memory_lifespan;
+        self.autoremove = instant_after(instant, memory_lifespan);
+    }
+
+    /// Clears the session if it is older than the absolute max age.
+    /// Sessions in use stay in memory past their expiry so the cap is checked on each request.
+    /// The session starts over as if newly created and its ID is renewed once the request
+    /// finishes, so the old ID no longer reaches it.
+    #[inline]
+    pub(crate) fn clear_past_max_age(
+        &mut self,
+        now: DateTime<Utc>,
+        runtime: &RuntimeConfig,
+        changes: &ChangeTracker,
+    ) {
+        if let Some(max_age) = runtime.absolute_max_age {
+            if self.created_at + max_age <= now {
+                changes.clear(&self.data);
+                self.data.clear();
+                self.longterm = false;
+                self.created_at = now;
+                self.expires = now + runtime.lifespan;
+                self.update = true;
+                self.renew = true;
+            }
+        }
     }
 
     /// Set session flags to renew/regenerate the ID.
@@ -256,6 +392,37 @@ impl SessionData {
         self.update = true;
     }
 
+    /// Removes the listed Keys from the Current Session's HashMap.
+    /// This will also update the database on Response Phase.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// session_data.remove_many(&["user-id", "token"]);
+    /// ```
+    ///
+    #[inline]
+    pub fn remove_many(&mut self, keys: &[&str]) {
+        for key in keys {
+            let _ = self.data.remove(*key);
+        }
+        self.update = true;
+    }
+
+    /// Merges the given data into the Current Session's HashMap
+    /// replacing any existing keys.
+    /// This will also update the database on Response Phase.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// session_data.merge(other.data.clone());
+    /// ```
+    ///
+    #[inline]
+    pub(crate) fn merge(&mut self, data: HashMap<String, String>) {
+        self.data.extend(data);
+        self.update = true;
+    }
+
     /// Clears all data from the Current Session's HashMap.
     /// This will also update the database on Response Phase.
     ///
@@ -290,12 +457,31 @@ impl SessionData {
     ///
     /// # Examples
     /// ```rust ignore
-    /// session.set_request();
+    /// session.set_request(Utc::now());
     /// ```
     ///
     #[inline]
-    pub(crate) fn set_request(&mut self) {
+    pub(crate) fn set_request(&mut self, now: DateTime<Utc>) {
         self.requests = self.requests.saturating_add(1);
+        self.last_active = now;
+    }
+
+    /// Adds a Request to the request counter unless it would exceed max.
+    /// Returns false when the request was not added.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// session.try_set_request(Some(8), Utc::now());
+    /// ```
+    ///
+    #[inline]
+    pub(crate) fn try_set_request(&mut self, max: Option<usize>, now: DateTime<Utc>) -> bool {
+        if matches!(max, Some(max) if self.requests >= max) {
+            return false;
+        }
+
+        self.set_request(now);
+        true
     }
 
     /// checks if a session has a request still.
@@ -309,6 +495,19 @@ impl SessionData {
     pub(crate) fn is_parallel(&self) -> bool {
         self.requests >= 1
     }
+
+    /// Checks if the session must stay loaded at now, as it is pinned, a request
+    /// still holds it, its ID is being renewed or its memory lifespan did not pass yet.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// session.kept_in_memory(Instant::now());
+    /// ```
+    ///
+    #[inline]
+    pub(crate) fn kept_in_memory(&self, now: Instant) -> bool {
+        self.pinned || self.is_parallel() || self.renewing || self.autoremove > now
+    }
 }
 
 /// Internal Timers
@@ -317,6 +516,39 @@ impl SessionData {
 ///
 #[derive(Debug)]
 pub(crate) struct SessionTimers {
-    pub(crate) last_expiry_sweep: DateTime<Utc>,
-    pub(crate) last_database_expiry_sweep: DateTime<Utc>,
+    pub(crate) last_expiry_sweep: Instant,
+    pub(crate) last_database_expiry_sweep: Instant,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{RuntimeConfig, SessionConfig, SessionData, SystemClock};
+    use chrono::{Duration, Utc};
+
+    #[test]
+    fn validate() {
+        let runtime = RuntimeConfig::from(&SessionConfig::default());
+        let now = Utc::now();
+
+        for (expired, destroy, valid) in [
+            (false, false, true),
+            (false, true, false),
+            (true, false, false),
+            (true, true, false),
+        ] {
+            let mut data = SessionData::new("id".to_owned(), true, &runtime, &SystemClock);
+            data.expires = if expired {
+                now - Duration::try_seconds(1).unwrap()
+            } else {
+                now + Duration::try_seconds(1).unwrap()
+            };
+            data.destroy = destroy;
+
+            assert_eq!(
+                data.validate(now),
+                valid,
+                "expired={expired} destroy={destroy}"
+            );
+        }
+    }
 }
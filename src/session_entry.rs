@@ -0,0 +1,63 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// A typed value stored within the Session under its own key with a version attached.
+///
+/// Used with [`crate::Session::set_versioned`] and [`crate::Session::get_versioned`] so
+/// multiple structs can live within one Session and evolve without breaking older sessions.
+/// The value is stored as an envelope of `{"v": VERSION, "d": value}`. When an envelope with an
+/// older version is loaded [`SessionEntry::migrate`] is called to upgrade it, and the upgraded
+/// value is written back with the current version.
+///
+/// Can be derived with the `derive` feature using `#[session_entry(key = "..", version = ..)]`
+/// and optionally `migrate = path::to::fn` pointing to a function matching [`SessionEntry::migrate`].
+///
+/// # Examples
+/// ```rust ignore
+/// use axum_session::SessionEntry;
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct Cart {
+///     items: Vec<u32>,
+/// }
+///
+/// impl SessionEntry for Cart {
+///     const KEY: &'static str = "cart";
+///     const VERSION: u32 = 2;
+///
+///     fn migrate(from_version: u32, raw: serde_json::Value) -> Option<Self> {
+///         match from_version {
+///             1 => Some(Cart { items: serde_json::from_value(raw).ok()? }),
+///             _ => None,
+///         }
+///     }
+/// }
+/// ```
+///
+pub trait SessionEntry: Serialize + DeserializeOwned {
+    /// Key the envelope is stored under within the Session.
+    const KEY: &'static str;
+    /// Current version of the stored data. Bump this when the layout changes.
+    const VERSION: u32;
+
+    /// Upgrades data stored under an older version.
+    /// Returning None drops the old data as if it did not exist.
+    fn migrate(from_version: u32, raw: serde_json::Value) -> Option<Self> {
+        let _ = (from_version, raw);
+        None
+    }
+}
+
+/// Envelope written when setting a [`SessionEntry`].
+#[derive(Serialize)]
+pub(crate) struct VersionedEntry<'a, T> {
+    pub(crate) v: u32,
+    pub(crate) d: &'a T,
+}
+
+/// Envelope read back before the version is checked.
+#[derive(Deserialize)]
+pub(crate) struct RawVersionedEntry {
+    pub(crate) v: u32,
+    pub(crate) d: serde_json::Value,
+}
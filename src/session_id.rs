@@ -0,0 +1,54 @@
+use std::fmt::{self, Display, Formatter};
+
+/// The ID of a Session, borrowed from it so it can be logged or compared without a clone.
+///
+/// Returned by [`crate::Session::session_id`] and [`crate::ReadOnlySession::session_id`].
+/// Formats as the raw ID, so use it as a structured tracing field such as
+/// `session.id = %session.session_id()`, which is only formatted when the event is enabled.
+/// Use [`crate::Session::get_session_id`] for an owned copy.
+///
+/// # Examples
+/// ```rust ignore
+/// tracing::info!(session.id = %session.session_id(), "Checkout started");
+/// ```
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SessionId<'a>(pub(crate) &'a str);
+
+impl<'a> SessionId<'a> {
+    /// Returns the ID as a str borrowed from the Session.
+    #[inline]
+    pub fn as_str(&self) -> &'a str {
+        self.0
+    }
+}
+
+impl Display for SessionId<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl AsRef<str> for SessionId<'_> {
+    fn as_ref(&self) -> &str {
+        self.0
+    }
+}
+
+impl PartialEq<str> for SessionId<'_> {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for SessionId<'_> {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+impl From<SessionId<'_>> for String {
+    fn from(id: SessionId<'_>) -> Self {
+        id.0.to_owned()
+    }
+}
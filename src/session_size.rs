@@ -0,0 +1,150 @@
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{HashMap, VecDeque};
+
+/// Number of stored sizes kept for each Session.
+pub(crate) const SIZE_HISTORY_LEN: usize = 8;
+
+/// One store of a Session's payload.
+#[derive(Debug, Clone)]
+struct SizeSample {
+    at: DateTime<Utc>,
+    size: usize,
+    /// Serialized length of each value, used to name the keys that grew.
+    /// Left empty unless growth warnings are enabled.
+    keys: HashMap<String, usize>,
+}
+
+/// Serialized payload sizes of a Session's latest stores, oldest first.
+/// Only kept in memory so a reloaded Session starts over.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SizeHistory {
+    samples: VecDeque<SizeSample>,
+    /// When the Session was last warned about, so it is warned at most once per window.
+    warned_at: Option<DateTime<Utc>>,
+}
+
+impl SizeHistory {
+    /// Records the size of a store made at `at`.
+    ///
+    /// Returns the keys that grew, largest growth first, if the size grew by more than the
+    /// factor since the oldest store within the window and no warning was given within it.
+    pub(crate) fn record(
+        &mut self,
+        at: DateTime<Utc>,
+        size: usize,
+        data: &HashMap<String, String>,
+        growth_warning: Option<(f64, Duration)>,
+    ) -> Option<Vec<String>> {
+        // Only needed to name the keys that grew, so not built for every store otherwise.
+        let keys: HashMap<String, usize> = if growth_warning.is_some() {
+            data.iter()
+                .map(|(key, value)| (key.clone(), value.len()))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+
+        let grown = growth_warning.and_then(|(factor, window)| {
+            let since = at - window;
+
+            if self.warned_at.is_some_and(|warned_at| warned_at > since) {
+                return None;
+            }
+
+            let baseline = self.samples.iter().find(|sample| sample.at >= since)?;
+
+            if size as f64 <= baseline.size as f64 * factor {
+                return None;
+            }
+
+            let mut grown: Vec<(&String, usize)> = keys
+                .iter()
+                .filter_map(|(key, len)| {
+                    let before = baseline.keys.get(key).copied().unwrap_or_default();
+                    (*len > before).then(|| (key, len - before))
+                })
+                .collect();
+            grown.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+
+            Some(grown.into_iter().map(|(key, _)| key.clone()).collect())
+        });
+
+        if grown.is_some() {
+            self.warned_at = Some(at);
+        }
+
+        if self.samples.len() == SIZE_HISTORY_LEN {
+            self.samples.pop_front();
+        }
+
+        self.samples.push_back(SizeSample { at, size, keys });
+        grown
+    }
+
+    /// Returns when each recorded store was made and its size in bytes, oldest first.
+    pub(crate) fn sizes(&self) -> Vec<(DateTime<Utc>, usize)> {
+        self.samples
+            .iter()
+            .map(|sample| (sample.at, sample.size))
+            .collect()
+    }
+
+    /// Returns the size of the latest store if any was recorded.
+    pub(crate) fn latest(&self) -> Option<usize> {
+        self.samples.back().map(|sample| sample.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SizeHistory, SIZE_HISTORY_LEN};
+    use chrono::{Duration, Utc};
+    use std::collections::HashMap;
+
+    #[test]
+    fn warns_once_per_window() {
+        let growth = Some((2.0, Duration::try_hours(1).unwrap()));
+        let start = Utc::now();
+        let mut history = SizeHistory::default();
+        let mut data = HashMap::from([
+            ("user".to_owned(), "42".to_owned()),
+            ("cart".to_owned(), "[]".to_owned()),
+        ]);
+        assert_eq!(history.record(start, 100, &data, growth), None);
+
+        // Doubling is allowed, growing past it names the key that grew.
+        data.insert("cart".to_owned(), "[1,2,3]".to_owned());
+        let at = start + Duration::try_minutes(1).unwrap();
+        assert_eq!(history.record(at, 200, &data, growth), None);
+        data.insert("cart".to_owned(), "[1,2,3,4,5,6]".to_owned());
+        let at = start + Duration::try_minutes(2).unwrap();
+        assert_eq!(
+            history.record(at, 201, &data, growth),
+            Some(vec!["cart".to_owned()])
+        );
+
+        // Further growth within the window is not warned about again.
+        let at = start + Duration::try_minutes(3).unwrap();
+        assert_eq!(history.record(at, 1000, &data, growth), None);
+        assert_eq!(history.record(at, 1000, &data, None), None);
+
+        // Once the window passed growth is measured from the stores within it.
+        let at = start + Duration::try_minutes(63).unwrap();
+        assert_eq!(history.record(at, 1000, &data, growth), None);
+        let at = start + Duration::try_minutes(64).unwrap();
+        assert_eq!(history.record(at, 2001, &data, growth), Some(Vec::new()));
+
+        let sizes = history.sizes();
+        assert_eq!(sizes.len(), 7);
+        assert_eq!(sizes[0], (start, 100));
+        assert_eq!(history.latest(), Some(2001));
+
+        for _ in 0..SIZE_HISTORY_LEN {
+            history.record(at, 5, &data, None);
+        }
+        assert_eq!(history.sizes().len(), SIZE_HISTORY_LEN);
+        assert_eq!(history.latest(), Some(5));
+        // Without warnings only the sizes are kept.
+        assert!(history.samples.iter().all(|sample| sample.keys.is_empty()));
+    }
+}
@@ -0,0 +1,120 @@
+use crate::{DatabasePool, Session};
+use std::fmt::Debug;
+
+/// Control flags of a Session which a [`SnapshotGuard`] can restore alongside the data.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SessionFlags {
+    pub(crate) destroy: bool,
+    pub(crate) renew: bool,
+    pub(crate) longterm: bool,
+    pub(crate) store: bool,
+}
+
+/// Records the keys the request changes after [`Session::begin_snapshot`] was called.
+///
+/// Unless [`SnapshotGuard::commit`] is called those keys are set back to the values they held
+/// when the guard was made once it is dropped. Keys removed since are restored and keys added
+/// since are removed. The destroy, renew, longterm and storable flags are left as they are
+/// unless [`SnapshotGuard::with_flags`] is set.
+///
+/// Snapshots stack and are meant to be dropped in the reverse order they were made, which
+/// scoping them does on its own. Rolling back a snapshot while snapshots made after it are
+/// still open rolls those back with it and logs a warning. Dropping them later does nothing.
+///
+/// Keys changed by parallel requests to the same Session are left alone, unless the guarded
+/// section changed the same key, in which case the rollback wins.
+///
+/// # Examples
+/// ```rust ignore
+/// let snapshot = session.begin_snapshot();
+/// session.set("order", &order);
+///
+/// if payment.charge().await.is_ok() {
+///     snapshot.commit();
+/// }
+/// // Otherwise the order is removed from the Session again here.
+/// ```
+///
+#[must_use = "dropping the guard immediately rolls the Session back"]
+#[derive(Debug)]
+pub struct SnapshotGuard<T>
+where
+    T: DatabasePool + Clone + Debug + Sync + Send + 'static,
+{
+    session: Session<T>,
+    snapshot: u64,
+    flags: SessionFlags,
+    restore_flags: bool,
+    committed: bool,
+}
+
+impl<T> SnapshotGuard<T>
+where
+    T: DatabasePool + Clone + Debug + Sync + Send + 'static,
+{
+    pub(crate) fn new(session: Session<T>) -> Self {
+        let flags = session.store.snapshot_flags(&session.id);
+        let snapshot = session.changes.begin_snapshot();
+
+        Self {
+            session,
+            snapshot,
+            flags,
+            restore_flags: false,
+            committed: false,
+        }
+    }
+
+    /// Sets if the destroy, renew, longterm and storable flags are rolled back with the data.
+    /// Defaults to false.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let snapshot = session.begin_snapshot().with_flags(true);
+    /// ```
+    ///
+    pub fn with_flags(mut self, restore: bool) -> Self {
+        self.restore_flags = restore;
+        self
+    }
+
+    /// Keeps every change made since the snapshot was taken.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// snapshot.commit();
+    /// ```
+    ///
+    pub fn commit(mut self) {
+        self.committed = true;
+    }
+
+    /// Rolls the Session back now instead of when the guard is dropped.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// snapshot.rollback();
+    /// ```
+    ///
+    pub fn rollback(self) {}
+}
+
+impl<T> Drop for SnapshotGuard<T>
+where
+    T: DatabasePool + Clone + Debug + Sync + Send + 'static,
+{
+    fn drop(&mut self) {
+        if self.committed {
+            self.session.changes.commit_snapshot(self.snapshot);
+            return;
+        }
+
+        let flags = self.restore_flags.then_some(self.flags);
+        self.session.store.restore_snapshot(
+            &self.session.id,
+            &self.session.changes,
+            self.snapshot,
+            flags,
+        );
+    }
+}
@@ -0,0 +1,24 @@
+/// Snapshot of the sessions loaded in memory, for metrics.
+///
+/// Returned by [`crate::SessionStore::stats`]. Every field is counted in the same pass over
+/// the loaded sessions, so they describe one moment as closely as the store allows without
+/// locking every session at once.
+///
+/// # Examples
+/// ```rust ignore
+/// let stats = session_store.stats();
+/// gauge!("sessions_loaded").set(stats.loaded as f64);
+/// ```
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionStats {
+    /// Sessions loaded in memory.
+    pub loaded: usize,
+    /// Sessions pinned in memory.
+    pub pinned: usize,
+    /// Requests in flight across every loaded session.
+    pub parallel_requests: usize,
+    /// Most requests in flight for any one session, which
+    /// [`crate::SessionConfig::with_max_parallel_requests`] limits.
+    pub max_parallel_requests: usize,
+}
@@ -1,17 +1,52 @@
+#[cfg(feature = "advanced")]
+use crate::SessionStatus;
 use crate::{
-    sec::encrypt, DatabasePool, Session, SessionConfig, SessionData, SessionError, SessionTimers,
+    accounting::{self, Join},
+    config::instant_after,
+    headers::{set_headers, EmitOptions},
+    sec::{decrypt_payload, encrypt, encrypt_payload, hash, sign_header, verify_header},
+    session_change_set::{ChangeTracker, Rollback},
+    session_entry::{RawVersionedEntry, VersionedEntry},
+    session_snapshot::SessionFlags,
+    CorruptAction, CorruptSession, DatabasePool, RuntimeConfig, Session, SessionChangeSet,
+    SessionConfig, SessionData, SessionEntry, SessionError, SessionStats, SessionTimers,
 };
 use axum::extract::FromRequestParts;
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, Utc};
 use dashmap::DashMap;
 #[cfg(feature = "key-store")]
 use fastbloom_rs::Deletable;
 #[cfg(feature = "key-store")]
 use fastbloom_rs::{CountingBloomFilter, FilterBuilder, Membership};
-use http::{request::Parts, StatusCode};
-use serde::Serialize;
-use std::{fmt::Debug, sync::Arc};
-use tokio::sync::RwLock;
+use http::{request::Parts, HeaderMap, Request, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+use tokio::sync::{Notify, RwLock};
+
+/// Binds transfer blobs to their purpose so other signed or encrypted values can not be imported.
+const TRANSFER_CONTEXT: &str = "axum_session_transfer";
+
+/// The signed or encrypted contents of a transfer blob.
+#[derive(Serialize, Deserialize)]
+struct TransferPayload {
+    /// Unix timestamp the blob was exported at.
+    iat: i64,
+    /// Unix timestamp after which the blob can no longer be imported.
+    exp: i64,
+    data: HashMap<String, String>,
+}
+
+/// How far the wall clock must go backwards before a warning is logged.
+const CLOCK_REGRESSION_WARNING: i64 = 5_000;
 
 /// Contains the main Services storage for all session's and database access for persistent Sessions.
 ///
@@ -34,8 +69,20 @@ where
     pub(crate) inner: Arc<DashMap<String, SessionData>>,
     /// Session Configuration.
     pub config: SessionConfig,
+    /// Hot-swappable lifespans and purge intervals, shared between every clone of the store.
+    pub(crate) runtime: Arc<std::sync::RwLock<RuntimeConfig>>,
     /// Session Timers used for Clearing Memory and Database.
     pub(crate) timers: Arc<RwLock<SessionTimers>>,
+    /// Latest wall clock time in milliseconds returned by the clock, used to notice it going backwards.
+    pub(crate) latest_wall_clock: Arc<AtomicI64>,
+    /// Latest wall clock time a regression was already logged for so it is only logged once.
+    pub(crate) warned_wall_clock: Arc<AtomicI64>,
+    /// Wall clock time in milliseconds a request without a Socket IP was last logged.
+    pub(crate) warned_connect_info: Arc<AtomicI64>,
+    /// Held while pinning so concurrent pins can not pass max_pinned_sessions.
+    pub(crate) pin_lock: Arc<std::sync::Mutex<()>>,
+    /// Notified whenever a renewal finishes, waking the requests waiting on the renewed ID.
+    pub(crate) renewed: Arc<Notify>,
     #[cfg(feature = "key-store")]
     /// Filter used to keep track of what session IDs exist.
     pub(crate) filter: Arc<RwLock<CountingBloomFilter>>,
@@ -65,6 +112,14 @@ where
     /// Constructs a New `SessionStore` and Creates the Database Table
     /// needed for the Session if it does not exist if client is not `None`.
     ///
+    /// # Errors
+    /// - ['SessionError::InvalidConfiguration'] is returned if the Cookie or Header names contain invalid characters.
+    /// - ['SessionError::InvalidConfiguration'] is returned if a key-value database would use an empty key namespace
+    ///   without [`SessionConfig::with_allow_global_namespace`].
+    /// - ['SessionError::InvalidConfiguration'] is returned if the database can not use the table name.
+    /// - ['SessionError::InvalidConfiguration'] is returned if the Cookie max age is negative or out of range,
+    ///   or the bloom filter settings could not build a filter.
+    ///
     /// # Examples
     /// ```rust ignore
     /// use axum_session::{SessionNullPool, SessionConfig, SessionStore};
@@ -74,8 +129,26 @@ where
     /// ```
     ///
     #[inline]
-    pub async fn new(client: Option<T>, config: SessionConfig) -> Result<Self, SessionError> {
+    pub async fn new(client: Option<T>, mut config: SessionConfig) -> Result<Self, SessionError> {
+        config.validate()?;
+
+        // Key-value databases get the namespace passed as their table name.
+        if client.as_ref().is_some_and(|c| c.uses_key_namespace()) {
+            if let Some(namespace) = config.database.key_namespace.clone() {
+                config.database.table_name = namespace;
+            }
+
+            if config.database.table_name.is_empty() && !config.database.allow_global_namespace {
+                return Err(SessionError::InvalidConfiguration(
+                    "An empty key namespace makes count, get_ids and delete_all act on the whole database. Set a key namespace or allow the global namespace.".into(),
+                ));
+            }
+        }
+
         if let Some(client) = &client {
+            client
+                .validate_table_name(&config.database.table_name)
+                .map_err(|err| SessionError::InvalidConfiguration(err.to_string()))?;
             client.initiate(&config.database.table_name).await?
         }
 
@@ -84,16 +157,27 @@ where
         #[cfg(feature = "key-store")]
         let filter = Self::create_filter(&client, &config).await?;
 
+        let start = config.clock.instant();
+
         Ok(Self {
             client,
             inner: Default::default(),
+            runtime: Arc::new(std::sync::RwLock::new(RuntimeConfig::from(&config))),
             config,
             timers: Arc::new(RwLock::new(SessionTimers {
                 // the first expiry sweep is scheduled one lifetime from start-up
-                last_expiry_sweep: Utc::now() + Duration::try_hours(1).unwrap_or_default(),
+                last_expiry_sweep: instant_after(start, Duration::try_hours(1).unwrap_or_default()),
                 // the first expiry sweep is scheduled one lifetime from start-up
-                last_database_expiry_sweep: Utc::now() + Duration::try_hours(6).unwrap_or_default(),
+                last_database_expiry_sweep: instant_after(
+                    start,
+                    Duration::try_hours(6).unwrap_or_default(),
+                ),
             })),
+            latest_wall_clock: Default::default(),
+            warned_wall_clock: Default::default(),
+            warned_connect_info: Default::default(),
+            pin_lock: Default::default(),
+            renewed: Default::default(),
             #[cfg(feature = "key-store")]
             filter: Arc::new(RwLock::new(filter)),
         })
@@ -141,7 +225,182 @@ where
         self.client.is_some()
     }
 
+    /// Runs the closure with a Session made by [`Session::for_testing`] from the data,
+    /// then removes the Session from memory again. Returns the closure's output.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let visits = store
+    ///     .with_session(SessionData::for_testing(&store), |session| async move {
+    ///         my_handler(session.clone()).await;
+    ///         session.get::<u32>("visits")
+    ///     })
+    ///     .await;
+    /// ```
+    ///
+    #[cfg(feature = "test-utils")]
+    pub async fn with_session<F, Fut, R>(&self, data: SessionData, f: F) -> R
+    where
+        F: FnOnce(Session<T>) -> Fut,
+        Fut: std::future::Future<Output = R>,
+    {
+        let session = Session::for_testing(self, data);
+        let id = session.id.clone();
+        let output = f(session).await;
+
+        self.inner.remove(&id);
+        output
+    }
+
+    /// Builds the [`EmitOptions`] the SessionLayer would use to write a Session's cookie or
+    /// header onto the response of this request.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let options = session_store.emit_options(&req).with_storable(true);
+    /// session_store.apply_session_headers(response.headers_mut(), &session_id, &options);
+    /// ```
+    ///
+    pub fn emit_options<B>(&self, req: &Request<B>) -> EmitOptions {
+        EmitOptions::from_request(req, self)
+    }
+
+    /// Writes the Session's cookie or header onto a response built outside of the SessionLayer,
+    /// for example a redirect made by a tower layer sitting in front of it.
+    ///
+    /// This uses the same signing, naming and removal as the SessionLayer's response phase.
+    /// Only the headers are written, the Session is not loaded, saved or removed from memory
+    /// or the database, so the Session ID must belong to a Session the SessionLayer manages.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let options = session_store.emit_options(&req);
+    /// session_store.apply_session_headers(response.headers_mut(), &session_id, &options);
+    /// ```
+    ///
+    pub fn apply_session_headers(
+        &self,
+        headers: &mut HeaderMap,
+        session_id: &str,
+        options: &EmitOptions,
+    ) {
+        set_headers(&self.config, session_id, headers, options);
+    }
+
+    /// Returns the [`RuntimeConfig`] currently used by this SessionStore and all of its clones.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let lifespan = session_store.runtime_config();
+    /// ```
+    ///
+    #[inline]
+    pub fn runtime_config(&self) -> RuntimeConfig {
+        // RuntimeConfig is Copy and only ever replaced whole so a poisoned lock still holds valid data.
+        *self.runtime.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Replaces the hot-swappable settings of this SessionStore and all of its clones at runtime.
+    ///
+    /// Only the lifespans and purge intervals within [`RuntimeConfig`] can be changed. Keys,
+    /// table name and the remaining [`SessionConfig`] settings are fixed once the store is created.
+    /// New lifespans apply the next time a session's expiry is refreshed. Shorter purge intervals
+    /// move the next scheduled purge forward so they take effect without waiting out the old one.
+    ///
+    /// # Errors
+    /// - ['SessionError::InvalidConfiguration'] is returned if any of the durations are negative.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// use axum_session::RuntimeConfig;
+    /// use chrono::Duration;
+    ///
+    /// let runtime = session_store.runtime_config().with_lifetime(Duration::hours(2));
+    /// session_store.update_runtime_config(runtime).await.unwrap();
+    /// ```
+    ///
+    pub async fn update_runtime_config(&self, runtime: RuntimeConfig) -> Result<(), SessionError> {
+        runtime.validate()?;
+
+        let mut timers = self.timers.write().await;
+        *self.runtime.write().unwrap_or_else(|e| e.into_inner()) = runtime;
+
+        let now = self.config.clock.instant();
+        timers.last_expiry_sweep = timers
+            .last_expiry_sweep
+            .min(instant_after(now, runtime.purge_update));
+        timers.last_database_expiry_sweep = timers
+            .last_database_expiry_sweep
+            .min(instant_after(now, runtime.purge_database_update));
+        Ok(())
+    }
+
+    /// Returns the wall clock time of the configured [`crate::Clock`].
+    ///
+    /// Logs a warning once whenever the wall clock goes backwards further than the
+    /// latest time it returned. Timers within the process are monotonic and are not
+    /// affected, but expiry times written to the database are.
+    pub(crate) fn now(&self) -> DateTime<Utc> {
+        let now = self.config.clock.now();
+        let millis = now.timestamp_millis();
+        let latest = self
+            .latest_wall_clock
+            .fetch_max(millis, Ordering::Relaxed)
+            .max(millis);
+
+        if latest - millis > CLOCK_REGRESSION_WARNING
+            && self.warned_wall_clock.swap(latest, Ordering::Relaxed) != latest
+        {
+            tracing::warn!(
+                regression_ms = latest - millis,
+                "Wall clock went backwards. Session expiry times stored in the database are behind until it catches up."
+            );
+        }
+
+        now
+    }
+
+    /// Returns the monotonic instant a session unused from now on is unloaded from memory.
+    pub(crate) fn memory_autoremove(&self) -> Instant {
+        instant_after(
+            self.config.clock.instant(),
+            self.runtime_config().memory_lifespan,
+        )
+    }
+
+    /// Returns the ID used to key the session within the database and filter.
+    ///
+    /// This is the raw id unless hashed storage ids are enabled in which case
+    /// it is SHA-256(id + pepper).
+    #[inline]
+    pub(crate) fn storage_id<'a>(&self, id: &'a str) -> Cow<'a, str> {
+        if self.config.database.hashed_storage_ids {
+            if let Some(pepper) = &self.config.database.storage_id_pepper {
+                return Cow::Owned(hash::hash_id(id, pepper));
+            }
+        }
+
+        Cow::Borrowed(id)
+    }
+
+    /// Checks if the session id exists within the database.
+    ///
+    /// If client is None it will return Ok(false).
+    #[inline]
+    pub(crate) async fn database_exists(&self, id: &str) -> Result<bool, SessionError> {
+        if let Some(client) = &self.client {
+            Ok(client
+                .exists(&self.storage_id(id), &self.config.database.table_name)
+                .await?)
+        } else {
+            Ok(false)
+        }
+    }
+
     /// Cleans Expired sessions from the Database based on Utc::now().
+    /// Sessions still in memory whose rows were removed are unloaded as well, unless they
+    /// have requests in flight or their expiry was refreshed in memory since.
+    /// The removed ids are passed to the expired sessions handler when one is set.
     ///
     /// If client is None it will return Ok(()).
     ///
@@ -162,14 +421,41 @@ where
     #[inline]
     pub async fn cleanup(&self) -> Result<Vec<String>, SessionError> {
         if let Some(client) = &self.client {
-            Ok(client
+            let expired = client
                 .delete_by_expiry(&self.config.database.table_name)
-                .await?)
+                .await?;
+            self.unload_expired(&expired);
+
+            // Databases expiring sessions on their own can not report them.
+            if let Some(handler) = &self.config.database.expired_sessions_handler {
+                if !expired.is_empty() && !client.auto_handles_expiry() {
+                    tokio::spawn(handler(expired.clone()));
+                }
+            }
+
+            Ok(expired)
         } else {
             Ok(Vec::new())
         }
     }
 
+    /// Unloads sessions from memory whose rows were removed from the database as expired.
+    /// Otherwise a memory lifespan longer than the lifespan keeps expired sessions serving.
+    fn unload_expired(&self, storage_ids: &[String]) {
+        if storage_ids.is_empty() {
+            return;
+        }
+
+        let expired: HashSet<&str> = storage_ids.iter().map(String::as_str).collect();
+        let now = self.now();
+
+        self.inner.retain(|id, session| {
+            session.is_parallel()
+                || !session.expired(now)
+                || !expired.contains(self.storage_id(id).as_ref())
+        });
+    }
+
     /// Returns count of existing sessions within database.
     ///
     /// If client is None it will return Ok(0).
@@ -198,6 +484,84 @@ where
         Ok(0)
     }
 
+    /// Returns the count of sessions active within the given window, such as for showing users online.
+    ///
+    /// A session is active when it was used by a request within the window. Databases which track
+    /// activity count every unexpired session stored or used by a request within the window.
+    /// Otherwise, or if client is None, the sessions used within the window that are still loaded
+    /// in memory are counted.
+    ///
+    /// # Errors
+    /// - ['SessionError::DatabaseError'] is returned if the database failed to count the sessions.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// use chrono::Duration;
+    ///
+    /// let online = session_store.active_count(Duration::minutes(5)).await.unwrap();
+    /// ```
+    ///
+    pub async fn active_count(&self, window: Duration) -> Result<i64, SessionError> {
+        let now = self.now();
+        let since = now - window;
+
+        if let Some(client) = &self.client {
+            if let Some(count) = client
+                .count_active(
+                    since.timestamp(),
+                    now.timestamp(),
+                    &self.config.database.table_name,
+                )
+                .await?
+            {
+                return Ok(count);
+            }
+        }
+
+        Ok(self.inner.iter().filter(|s| s.last_active >= since).count() as i64)
+    }
+
+    /// Returns the total requests in flight across every session loaded in memory.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let in_flight = session_store.parallel_requests();
+    /// ```
+    ///
+    pub fn parallel_requests(&self) -> usize {
+        self.inner.iter().map(|s| s.requests).sum()
+    }
+
+    /// Returns how many sessions are pinned in memory.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let pinned = session_store.pinned_count();
+    /// ```
+    ///
+    pub fn pinned_count(&self) -> usize {
+        self.inner.iter().filter(|s| s.pinned).count()
+    }
+
+    /// Returns a snapshot of the sessions loaded in memory and the requests they hold.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// let stats = session_store.stats();
+    /// ```
+    ///
+    pub fn stats(&self) -> SessionStats {
+        self.inner
+            .iter()
+            .fold(SessionStats::default(), |mut stats, session| {
+                stats.loaded += 1;
+                stats.pinned += usize::from(session.pinned);
+                stats.parallel_requests += session.requests;
+                stats.max_parallel_requests = stats.max_parallel_requests.max(session.requests);
+                stats
+            })
+    }
+
     /// private internal function that loads a session's data from the database using an ID string.
     ///
     /// If client is None it will return Ok(None).
@@ -223,35 +587,124 @@ where
         &self,
         cookie_value: String,
     ) -> Result<Option<SessionData>, SessionError> {
-        if let Some(client) = &self.client {
-            let result: Option<String> = client
-                .load(&cookie_value, &self.config.database.table_name)
-                .await?;
+        let Some(client) = &self.client else {
+            return Ok(None);
+        };
 
-            if let Some(mut session) = result
-                .map(|session| {
-                    if let Some(key) = self.config.database.database_key.as_ref() {
-                        serde_json::from_str::<SessionData>(
-                            &match encrypt::decrypt(&cookie_value, &session, key) {
-                                Ok(v) => v,
-                                Err(err) => {
-                                    tracing::error!(err = %err, "Failed to decrypt Session data from database.");
-                                    String::new()
-                                }
-                            }
-                        )
-                    } else {
-                        serde_json::from_str::<SessionData>(&session)
-                    }
-                })
-                .transpose()?
+        let storage_id = self.storage_id(&cookie_value);
+        let Some(raw) = client
+            .load(&storage_id, &self.config.database.table_name)
+            .await?
+        else {
+            return Ok(None);
+        };
+
+        let (payload, parsed) = match self.config.database.payload_cipher.as_deref() {
+            Some(cipher) => match decrypt_payload(
+                cipher,
+                &self.config.database.decrypt_ciphers,
+                &storage_id,
+                &raw,
+            )
+            .await
             {
+                Ok(payload) => {
+                    let parsed = serde_json::from_str::<SessionData>(&payload);
+                    (payload, parsed.map_err(SessionError::from))
+                }
+                Err(err) => (raw, Err(err)),
+            },
+            None => {
+                let parsed = serde_json::from_str::<SessionData>(&raw);
+                (raw, parsed.map_err(SessionError::from))
+            }
+        };
+
+        match parsed {
+            Ok(mut session) => {
                 session.id = cookie_value;
-                return Ok(Some(session));
+
+                // A creation time this clock could not have written would let a tampered or
+                // skewed row outlive the absolute max age, so it is clamped to now.
+                session.created_at = session.created_at.min(self.now());
+                Ok(Some(session))
+            }
+            Err(error) => {
+                // Payloads written by axum_database_sessions 0.x use an older layout.
+                #[cfg(feature = "legacy-compat")]
+                if let Ok(legacy) =
+                    serde_json::from_str::<crate::legacy::LegacySessionData>(&payload)
+                {
+                    tracing::info!(
+                        session.id = %self.config.log_id(&cookie_value),
+                        "Upgrading session stored in the legacy layout."
+                    );
+                    return Ok(Some(legacy.upgrade(
+                        cookie_value,
+                        self.now(),
+                        self.config.clock.instant(),
+                    )));
+                }
+
+                self.corrupt_session(cookie_value, payload, error).await
+            }
+        }
+    }
+
+    /// Counts a new session for the IP against the distributed creation limit.
+    /// Returns false once the IP created more sessions within the window than allowed.
+    ///
+    /// Fails open, so if the database could not count the request it is allowed.
+    pub(crate) async fn creation_allowed(&self, ip: &str) -> bool {
+        let (Some(client), Some((per_ip, window))) = (
+            &self.client,
+            self.config.database.distributed_creation_limit,
+        ) else {
+            return true;
+        };
+
+        match client
+            .rate_increment(
+                &format!("creation:{}", ip),
+                window.num_seconds(),
+                &self.config.database.table_name,
+            )
+            .await
+        {
+            Ok(count) => count <= per_ip,
+            Err(err) => {
+                tracing::warn!(err = %err, "Session creation limit could not be checked.");
+                true
             }
         }
+    }
+
+    /// Applies the [`CorruptAction`] for a session which could not be read from the database.
+    async fn corrupt_session(
+        &self,
+        id: String,
+        payload: String,
+        error: SessionError,
+    ) -> Result<Option<SessionData>, SessionError> {
+        tracing::warn!(session.id = %self.config.log_id(&id), err = %error, "Session data within the database is corrupt.");
+
+        let action = match &self.config.database.corrupt_session_handler {
+            Some(handler) => handler(CorruptSession {
+                id: id.clone(),
+                payload,
+                error,
+            }),
+            None => CorruptAction::default(),
+        };
 
-        Ok(None)
+        match action {
+            CorruptAction::RecreateAndDelete => {
+                self.database_remove_session(&id).await?;
+                Ok(None)
+            }
+            CorruptAction::RecreateKeepRow => Ok(None),
+            CorruptAction::FailRequest => Err(SessionError::CorruptSession),
+        }
     }
 
     /// private internal function that stores a session's data to the database.
@@ -270,7 +723,7 @@ where
     /// let config = SessionConfig::default();
     /// let session_store = SessionStore::<SessionNullPool>::new(None, config.clone()).await.unwrap();
     /// let token = Uuid::new_v4();
-    /// let session_data = SessionData::new(token, true, &config);
+    /// let session_data = SessionData::new(token, true, &config, &SystemClock);
     ///
     /// async {
     ///     let _ = session_store.store_session(&session_data).await.unwrap();
@@ -278,30 +731,99 @@ where
     /// ```
     ///
     pub(crate) async fn store_session(&self, session: &SessionData) -> Result<(), SessionError> {
+        // The row this session stands in for may be fine, so it must not be overwritten.
+        if session.ephemeral {
+            return Ok(());
+        }
+
         if let Some(client) = &self.client {
+            let storage_id = self.storage_id(&session.id);
+            let payload = serde_json::to_string(session)?;
+            let size = payload.len();
             client
                 .store(
-                    &session.id,
-                    &if let Some(key) = self.config.database.database_key.as_ref() {
-                        encrypt::encrypt(&session.id, &serde_json::to_string(session)?, key)
-                            .map_err(|e| {
-                                SessionError::GenericNotSupportedError(format!(
-                                    "Error: {} Occurred when encrypting a Session.",
-                                    e
-                                ))
-                            })?
+                    &storage_id,
+                    &if let Some(cipher) = self.config.database.payload_cipher.as_deref() {
+                        encrypt_payload(cipher, &storage_id, &payload).await?
                     } else {
-                        serde_json::to_string(session)?
+                        payload
                     },
                     session.expires.timestamp(),
                     &self.config.database.table_name,
                 )
                 .await?;
+
+            self.record_size(session, size);
+        }
+
+        Ok(())
+    }
+
+    /// Marks the session as active within the database without storing it again.
+    pub(crate) async fn touch_active(&self, id: &str) -> Result<(), SessionError> {
+        if let Some(client) = &self.client {
+            client
+                .touch_active(
+                    &self.storage_id(id),
+                    self.now().timestamp(),
+                    &self.config.database.table_name,
+                )
+                .await?;
         }
 
         Ok(())
     }
 
+    /// Records the size of the payload stored for the session and warns if it grew too quickly.
+    fn record_size(&self, session: &SessionData, size: usize) {
+        let grown = self.inner.get_mut(&session.id).and_then(|mut instance| {
+            instance.sizes.record(
+                self.now(),
+                size,
+                &session.data,
+                self.config.memory.size_growth_warning,
+            )
+        });
+
+        if let Some(keys) = grown {
+            tracing::warn!(
+                session.id = %self.config.log_id(&session.id),
+                size,
+                keys = ?keys,
+                "Session payload grew quickly, check the keys that grew are not collecting data without bound."
+            );
+        }
+    }
+
+    /// Returns when each of the session's latest stores were made and their payload sizes in bytes, oldest first.
+    pub(crate) fn size_history(&self, id: &str) -> Vec<(DateTime<Utc>, usize)> {
+        self.inner
+            .get(id)
+            .map(|instance| instance.sizes.sizes())
+            .unwrap_or_default()
+    }
+
+    /// Returns the ids and latest stored payload sizes in bytes of the largest sessions
+    /// loaded in memory, largest first. Sessions not stored since they were loaded are left out.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// for (id, size) in session_store.largest_sessions(10) {
+    ///     println!("{id}: {size} bytes");
+    /// }
+    /// ```
+    ///
+    pub fn largest_sessions(&self, top_n: usize) -> Vec<(String, usize)> {
+        let mut sizes: Vec<(String, usize)> = self
+            .inner
+            .iter()
+            .filter_map(|instance| Some((instance.key().clone(), instance.sizes.latest()?)))
+            .collect();
+        sizes.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        sizes.truncate(top_n);
+        sizes
+    }
+
     /// Deletes all sessions in the database.
     ///
     /// If client is None it will return Ok(()).
@@ -330,6 +852,95 @@ where
         Ok(())
     }
 
+    /// Migrates rows stored using raw session ids to hashed storage ids.
+    ///
+    /// Use this once after enabling `with_hashed_storage_ids` on an existing table.
+    /// Each row keyed by a raw id is re-stored under its hashed id, re-encrypted
+    /// if a database key is set, and the raw row is deleted.
+    /// Rows whose ids already look like a hashed id (64 lowercase hex characters) are skipped.
+    /// Databases may keep deleted rows within freed pages, so the raw ids can remain within the
+    /// database files until they are reused. Use SQLite's `secure_delete` or `VACUUM` afterwards
+    /// if the files must not hold them.
+    ///
+    /// Returns the number of rows migrated. If client is None it will return Ok(0).
+    ///
+    /// # Errors
+    /// - ['SessionError::InvalidConfiguration'] is returned if hashed storage ids are not enabled.
+    /// - ['SessionError::DatabaseError'] is returned if database connection has failed or user does not have permissions.
+    ///
+    /// # Examples
+    /// ```rust ignore
+    /// use axum_session::{SessionNullPool, SessionConfig, SessionStore};
+    ///
+    /// let config = SessionConfig::default()
+    ///     .with_hashed_storage_ids(true)
+    ///     .with_storage_id_pepper("my secret pepper");
+    /// let session_store = SessionStore::<SessionNullPool>::new(None, config).await.unwrap();
+    ///
+    /// async {
+    ///     let migrated = session_store.rehash_existing_rows().await.unwrap();
+    /// };
+    /// ```
+    ///
+    pub async fn rehash_existing_rows(&self) -> Result<usize, SessionError> {
+        if !self.config.database.hashed_storage_ids {
+            return Err(SessionError::InvalidConfiguration(
+                "rehash_existing_rows requires with_hashed_storage_ids to be enabled.".to_owned(),
+            ));
+        }
+
+        let Some(client) = &self.client else {
+            return Ok(0);
+        };
+
+        let table_name = &self.config.database.table_name;
+        let mut migrated = 0;
+
+        for id in client.get_ids(table_name).await? {
+            if hash::is_hashed_id(&id) {
+                continue;
+            }
+
+            let Some(raw) = client.load(&id, table_name).await? else {
+                continue;
+            };
+
+            let cipher = self.config.database.payload_cipher.as_deref();
+            let session = match cipher {
+                Some(cipher) => {
+                    decrypt_payload(cipher, &self.config.database.decrypt_ciphers, &id, &raw)
+                        .await?
+                }
+                None => raw,
+            };
+
+            let data = serde_json::from_str::<SessionData>(&session)?;
+            let expires = data.refreshed_expires(self.now(), &self.runtime_config());
+
+            let storage_id = self.storage_id(&id);
+            let session = match cipher {
+                Some(cipher) => encrypt_payload(cipher, &storage_id, &session).await?,
+                None => session,
+            };
+
+            client
+                .store(&storage_id, &session, expires.timestamp(), table_name)
+                .await?;
+            client.delete_one_by_id(&id, table_name).await?;
+
+            #[cfg(feature = "key-store")]
+            if self.config.memory.use_bloom_filters {
+                let mut filter = self.filter.write().await;
+                filter.remove(id.as_bytes());
+                filter.add(storage_id.as_bytes());
+            }
+
+            migrated += 1;
+        }
+
+        Ok(migrated)
+    }
+
     /// Deletes all sessions in Memory.
     /// This will also Clear those keys from the filter cache if a persistent database does not exist.
     ///
@@ -352,7 +963,7 @@ where
             let mut filter = self.filter.write().await;
             self.inner
                 .iter()
-                .for_each(|value| filter.remove(value.key().as_bytes()));
+                .for_each(|value| filter.remove(self.storage_id(value.key()).as_bytes()));
         }
 
         self.inner.clear();
@@ -361,139 +972,655 @@ where
     /// Attempts to load check and clear Data.
     ///
     /// If no session is found returns false.
-    pub(crate) fn service_session_data(&self, session: &Session<T>) -> bool {
-        if let Some(mut inner) = self.inner.get_mut(&session.id) {
-            inner.service_clear(
-                self.config.memory.memory_lifespan,
-                self.config.clear_check_on_load,
-            );
-            inner.set_request();
-            return true;
+    pub(crate) fn service_session_data(&self, session: &Session<T>) -> Join {
+        // The limit is checked within the same lock as the increment so parallel
+        // requests can not all pass the check before any of them are counted.
+        let now = self.now();
+        let runtime = self.runtime_config();
+
+        accounting::join(
+            &*self.inner,
+            &session.id,
+            self.config.memory.max_parallel_requests,
+            now,
+            |inner| {
+                inner.service_clear(
+                    runtime.memory_lifespan,
+                    self.config.clear_check_on_load,
+                    self.config.clock.as_ref(),
+                    &session.changes,
+                );
+                inner.clear_past_max_age(now, &runtime, &session.changes);
+            },
+        )
+    }
+
+    /// Moves the session to a newly generated ID once its renewal was reserved using
+    /// [`accounting::take_renew`] and returns the new ID.
+    ///
+    /// The session is copied to the new ID before the database is changed, and the old ID is
+    /// only released once the database moved, so requests sending it wait instead of loading
+    /// the row while it is moved. If any step fails the renewal is undone and left for the
+    /// next request.
+    pub(crate) async fn renew_session(&self, id: &str) -> Result<String, SessionError> {
+        let result = match Session::generate_id(self).await {
+            Ok(new_id) => {
+                accounting::move_renewed(&*self.inner, id, &new_id);
+
+                match self.move_database_session(id, &new_id).await {
+                    Ok(()) => Ok(new_id),
+                    Err(err) => {
+                        accounting::cancel_renew(&*self.inner, id, Some(&new_id));
+                        Err(err)
+                    }
+                }
+            }
+            Err(err) => {
+                accounting::cancel_renew(&*self.inner, id, None);
+                Err(err)
+            }
+        };
+
+        if result.is_ok() {
+            //lets remove it from the filter. if the bottom fails just means it did not exist or was already unloaded.
+            #[cfg(feature = "key-store")]
+            if self.config.memory.use_bloom_filters {
+                let mut filter = self.filter.write().await;
+                filter.remove(self.storage_id(id).as_bytes());
+            }
+
+            accounting::finish_renew(&*self.inner, id);
         }
 
-        false
+        self.renewed.notify_waiters();
+        result
+    }
+
+    /// Moves the session's counters to the new ID and removes its row, which the renewed
+    /// session is stored under the new ID in place of.
+    async fn move_database_session(&self, id: &str, new_id: &str) -> Result<(), SessionError> {
+        if !self.is_persistent() {
+            return Ok(());
+        }
+
+        self.counter_rename(id, new_id).await?;
+
+        if let Err(err) = self.database_remove_session(id).await {
+            // Nothing could change the counters since, as requests sending the ID wait.
+            let _ = self.counter_rename(new_id, id).await;
+            return Err(err);
+        }
+
+        Ok(())
     }
 
     #[inline]
-    pub(crate) fn renew(&self, id: String) {
-        if let Some(mut instance) = self.inner.get_mut(&id) {
+    pub(crate) fn renew(&self, id: &str) {
+        if let Some(mut instance) = self.inner.get_mut(id) {
             instance.renew();
         } else {
-            tracing::warn!("Session data unexpectedly missing");
+            tracing::warn!(session.id = %self.config.log_id(id), "Session data unexpectedly missing");
         }
     }
 
     #[inline]
-    pub(crate) fn destroy(&self, id: String) {
-        if let Some(mut instance) = self.inner.get_mut(&id) {
+    pub(crate) fn destroy(&self, id: &str) {
+        if let Some(mut instance) = self.inner.get_mut(id) {
             instance.destroy();
         } else {
-            tracing::warn!("Session data unexpectedly missing");
+            tracing::warn!(session.id = %self.config.log_id(id), "Session data unexpectedly missing");
         }
     }
 
     #[inline]
-    pub(crate) fn set_longterm(&self, id: String, longterm: bool) {
-        if let Some(mut instance) = self.inner.get_mut(&id) {
+    pub(crate) fn set_longterm(&self, id: &str, longterm: bool) {
+        if let Some(mut instance) = self.inner.get_mut(id) {
             instance.set_longterm(longterm);
         } else {
-            tracing::warn!("Session data unexpectedly missing");
+            tracing::warn!(session.id = %self.config.log_id(id), "Session data unexpectedly missing");
         }
     }
 
     #[inline]
-    pub(crate) fn set_store(&self, id: String, storable: bool) {
-        if let Some(mut instance) = self.inner.get_mut(&id) {
+    pub(crate) fn set_store(&self, id: &str, storable: bool) {
+        if let Some(mut instance) = self.inner.get_mut(id) {
             instance.set_store(storable);
         } else {
-            tracing::warn!("Session data unexpectedly missing");
+            tracing::warn!(session.id = %self.config.log_id(id), "Session data unexpectedly missing");
         }
     }
 
     #[inline]
-    pub(crate) fn update(&self, id: String) {
-        if let Some(mut instance) = self.inner.get_mut(&id) {
+    pub(crate) fn update(&self, id: &str) {
+        if let Some(mut instance) = self.inner.get_mut(id) {
             instance.update();
         } else {
-            tracing::warn!("Session data unexpectedly missing");
+            tracing::warn!(session.id = %self.config.log_id(id), "Session data unexpectedly missing");
         }
     }
 
     #[inline]
-    pub(crate) fn get<N: serde::de::DeserializeOwned>(&self, id: String, key: &str) -> Option<N> {
-        if let Some(instance) = self.inner.get(&id) {
+    pub(crate) fn get<N: serde::de::DeserializeOwned>(&self, id: &str, key: &str) -> Option<N> {
+        if let Some(instance) = self.inner.get(id) {
             instance.get(key)
         } else {
-            tracing::warn!("Session data unexpectedly missing");
+            tracing::warn!(session.id = %self.config.log_id(id), "Session data unexpectedly missing");
             None
         }
     }
 
+    pub(crate) async fn get_or_load<N: serde::de::DeserializeOwned>(
+        &self,
+        id: &str,
+        key: &str,
+    ) -> Result<Option<N>, SessionError> {
+        if let Some(instance) = self.inner.get(id) {
+            return Ok(instance.get(key));
+        }
+
+        let Some(mut session) = self.load_session(id.to_owned()).await? else {
+            return Ok(None);
+        };
+
+        // Only stored sessions exist within the database. The request count covers the
+        // current request which will be removed once its response is sent.
+        session.autoremove = self.memory_autoremove();
+        session.store = true;
+        session.update = false;
+        session.requests = 1;
+        session.last_active = self.now();
+
+        // Another request may have loaded it while we waited on the database so keep theirs.
+        let instance = match self.inner.entry(id.to_owned()) {
+            dashmap::Entry::Occupied(mut entry) => {
+                entry.get_mut().set_request(self.now());
+                entry.into_ref()
+            }
+            dashmap::Entry::Vacant(entry) => entry.insert(session),
+        };
+
+        Ok(instance.get(key))
+    }
+
     #[inline]
     pub(crate) fn get_remove<N: serde::de::DeserializeOwned>(
         &self,
-        id: String,
+        id: &str,
+        changes: &ChangeTracker,
         key: &str,
     ) -> Option<N> {
-        if let Some(mut instance) = self.inner.get_mut(&id) {
+        if let Some(mut instance) = self.inner.get_mut(id) {
+            changes.touch(&instance.data, key);
             instance.get_remove(key)
         } else {
-            tracing::warn!("Session data unexpectedly missing");
+            tracing::warn!(session.id = %self.config.log_id(id), "Session data unexpectedly missing");
             None
         }
     }
 
     #[inline]
-    pub(crate) fn set(&self, id: String, key: &str, value: impl Serialize) {
-        if let Some(mut instance) = self.inner.get_mut(&id) {
+    pub(crate) fn set(&self, id: &str, changes: &ChangeTracker, key: &str, value: impl Serialize) {
+        if let Some(mut instance) = self.inner.get_mut(id) {
+            changes.touch(&instance.data, key);
             instance.set(key, value);
         } else {
-            tracing::warn!("Session data unexpectedly missing");
+            tracing::warn!(session.id = %self.config.log_id(id), "Session data unexpectedly missing");
+        }
+    }
+
+    pub(crate) fn get_all_raw(&self, id: &str) -> HashMap<String, String> {
+        if let Some(instance) = self.inner.get(id) {
+            instance.data.clone()
+        } else {
+            tracing::warn!(session.id = %self.config.log_id(id), "Session data unexpectedly missing");
+            HashMap::new()
+        }
+    }
+
+    pub(crate) fn cache_key(&self, id: &str, parts: &[&str]) -> String {
+        let instance = self.inner.get(id);
+
+        if instance.is_none() {
+            tracing::warn!(session.id = %self.config.log_id(id), "Session data unexpectedly missing");
+        }
+
+        let values = parts
+            .iter()
+            .map(|part| {
+                let value = instance
+                    .as_ref()
+                    .and_then(|instance| instance.data.get(*part).map(String::as_str));
+                (*part, value)
+            })
+            .collect::<Vec<_>>();
+
+        crate::cache_key::cache_key(&self.config, id, &values)
+    }
+
+    pub(crate) fn snapshot_flags(&self, id: &str) -> SessionFlags {
+        if let Some(instance) = self.inner.get(id) {
+            SessionFlags {
+                destroy: instance.destroy,
+                renew: instance.renew,
+                longterm: instance.longterm,
+                store: instance.store,
+            }
+        } else {
+            tracing::warn!(session.id = %self.config.log_id(id), "Session data unexpectedly missing");
+            SessionFlags::default()
+        }
+    }
+
+    pub(crate) fn restore_snapshot(
+        &self,
+        id: &str,
+        changes: &ChangeTracker,
+        snapshot: u64,
+        flags: Option<SessionFlags>,
+    ) {
+        if let Some(mut instance) = self.inner.get_mut(id) {
+            match changes.rollback_snapshot(snapshot, &mut instance.data) {
+                Rollback::Done => {}
+                Rollback::OutOfOrder => {
+                    tracing::warn!(
+                        session.id = %self.config.log_id(id),
+                        "Session snapshot was rolled back before snapshots made after it, which were rolled back with it"
+                    );
+                }
+                // Flags restored by the older snapshot are left alone.
+                Rollback::Closed => return,
+            }
+
+            instance.update = true;
+
+            if let Some(flags) = flags {
+                instance.destroy = flags.destroy;
+                instance.renew = flags.renew;
+                instance.longterm = flags.longterm;
+                instance.store = flags.store;
+            }
+        } else {
+            changes.commit_snapshot(snapshot);
+            tracing::warn!(session.id = %self.config.log_id(id), "Session data unexpectedly missing");
+        }
+    }
+
+    pub(crate) fn merge_raw(
+        &self,
+        id: &str,
+        changes: &ChangeTracker,
+        data: HashMap<String, String>,
+    ) {
+        if let Some(mut instance) = self.inner.get_mut(id) {
+            changes.touch_many(&instance.data, data.keys().map(String::as_str));
+            instance.merge(data);
+        } else {
+            tracing::warn!(session.id = %self.config.log_id(id), "Session data unexpectedly missing");
         }
     }
 
     #[inline]
-    pub(crate) fn remove(&self, id: String, key: &str) {
-        if let Some(mut instance) = self.inner.get_mut(&id) {
+    pub(crate) fn remove(&self, id: &str, changes: &ChangeTracker, key: &str) {
+        if let Some(mut instance) = self.inner.get_mut(id) {
+            changes.touch(&instance.data, key);
             instance.remove(key);
         } else {
-            tracing::warn!("Session data unexpectedly missing");
+            tracing::warn!(session.id = %self.config.log_id(id), "Session data unexpectedly missing");
+        }
+    }
+
+    #[inline]
+    pub(crate) fn remove_many(&self, id: &str, changes: &ChangeTracker, keys: &[&str]) {
+        if let Some(mut instance) = self.inner.get_mut(id) {
+            changes.touch_many(&instance.data, keys.iter().copied());
+            instance.remove_many(keys);
+        } else {
+            tracing::warn!(session.id = %self.config.log_id(id), "Session data unexpectedly missing");
+        }
+    }
+
+    pub(crate) fn set_versioned<E: SessionEntry>(
+        &self,
+        id: &str,
+        changes: &ChangeTracker,
+        value: &E,
+    ) {
+        self.set(
+            id,
+            changes,
+            E::KEY,
+            VersionedEntry {
+                v: E::VERSION,
+                d: value,
+            },
+        );
+    }
+
+    pub(crate) fn get_versioned<E: SessionEntry>(
+        &self,
+        id: &str,
+        changes: &ChangeTracker,
+    ) -> Option<E> {
+        let Some(mut instance) = self.inner.get_mut(id) else {
+            tracing::warn!(session.id = %self.config.log_id(id), "Session data unexpectedly missing");
+            return None;
+        };
+
+        let raw = instance.get::<RawVersionedEntry>(E::KEY)?;
+
+        match raw.v.cmp(&E::VERSION) {
+            std::cmp::Ordering::Equal => serde_json::from_value(raw.d).ok(),
+            std::cmp::Ordering::Less => {
+                // Write the upgraded value back so the migration only runs once.
+                let entry = E::migrate(raw.v, raw.d)?;
+                changes.touch(&instance.data, E::KEY);
+                instance.set(
+                    E::KEY,
+                    VersionedEntry {
+                        v: E::VERSION,
+                        d: &entry,
+                    },
+                );
+                Some(entry)
+            }
+            std::cmp::Ordering::Greater => {
+                tracing::warn!(
+                    session.id = %self.config.log_id(id),
+                    key = E::KEY,
+                    version = raw.v,
+                    "Session entry was stored by a newer version and was ignored."
+                );
+                None
+            }
         }
     }
 
     #[inline]
-    pub(crate) fn clear_session_data(&self, id: String) {
-        if let Some(mut instance) = self.inner.get_mut(&id) {
+    pub(crate) fn clear_session_data(&self, id: &str, changes: &ChangeTracker) {
+        if let Some(mut instance) = self.inner.get_mut(id) {
+            changes.clear(&instance.data);
             instance.clear();
         } else {
-            tracing::warn!("Session data unexpectedly missing");
+            tracing::warn!(session.id = %self.config.log_id(id), "Session data unexpectedly missing");
+        }
+    }
+
+    pub(crate) fn pin_in_memory(&self, id: &str, pin: bool) -> Result<(), SessionError> {
+        let _guard = self
+            .pin_lock
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        // Counted before the entry is locked as iterating would wait on its shard.
+        if pin && self.pinned_count() >= self.config.memory.max_pinned_sessions {
+            let already_pinned = self.inner.get(id).is_some_and(|instance| instance.pinned);
+
+            if !already_pinned {
+                return Err(SessionError::PinnedSessionLimit);
+            }
+        }
+
+        if let Some(mut instance) = self.inner.get_mut(id) {
+            instance.pinned = pin;
+
+            if !pin {
+                // Unpinned sessions unload once unused for the memory lifespan from now.
+                instance.autoremove = self.memory_autoremove();
+            }
+
+            Ok(())
+        } else {
+            Err(SessionError::NoSessionError)
+        }
+    }
+
+    pub(crate) fn is_pinned(&self, id: &str) -> bool {
+        self.inner.get(id).is_some_and(|instance| instance.pinned)
+    }
+
+    pub(crate) fn take_change_set(&self, id: &str, changes: &ChangeTracker) -> SessionChangeSet {
+        if let Some(instance) = self.inner.get(id) {
+            changes.take(&instance.data)
+        } else {
+            tracing::warn!(session.id = %self.config.log_id(id), "Session data unexpectedly missing");
+            SessionChangeSet::default()
+        }
+    }
+
+    /// Returns the database and the session's expiry if its counters may use native counters.
+    /// Sessions which will not be stored keep their counters within their data instead.
+    fn counter_client(&self, id: &str) -> Result<Option<(&T, i64)>, SessionError> {
+        let instance = self.inner.get(id).ok_or(SessionError::NoSessionError)?;
+        let storable = instance.store || !self.config.session_mode.is_opt_in();
+
+        Ok(self
+            .client
+            .as_ref()
+            .filter(|_| storable && !instance.ephemeral)
+            .map(|client| (client, instance.expires.timestamp())))
+    }
+
+    pub(crate) async fn counter_incr(
+        &self,
+        id: &str,
+        changes: &ChangeTracker,
+        name: &str,
+        delta: i64,
+    ) -> Result<i64, SessionError> {
+        if let Some((client, expires)) = self.counter_client(id)? {
+            // A value kept within the data before is moved into the native counter.
+            let carried = self
+                .inner
+                .get_mut(id)
+                .filter(|instance| instance.data.contains_key(name))
+                .and_then(|mut instance| {
+                    changes.touch(&instance.data, name);
+                    instance.get_remove::<i64>(name)
+                });
+            let storage_id = self.storage_id(id);
+            let table_name = &self.config.database.table_name;
+
+            match client
+                .counter_incr(
+                    &storage_id,
+                    name,
+                    delta.saturating_add(carried.unwrap_or_default()),
+                    expires,
+                    table_name,
+                )
+                .await
+            {
+                Ok(Some(value)) => return Ok(value),
+                result => {
+                    if let (Some(carried), Some(mut instance)) = (carried, self.inner.get_mut(id)) {
+                        changes.touch(&instance.data, name);
+                        instance.set(name, carried);
+                    }
+
+                    result?;
+                }
+            }
+        }
+
+        let mut instance = self.inner.get_mut(id).ok_or(SessionError::NoSessionError)?;
+        let value = instance
+            .get::<i64>(name)
+            .unwrap_or_default()
+            .checked_add(delta)
+            .ok_or_else(|| {
+                SessionError::GenericNotSupportedError(format!("counter {name} overflowed"))
+            })?;
+        changes.touch(&instance.data, name);
+        instance.set(name, value);
+        Ok(value)
+    }
+
+    pub(crate) async fn counter_get(&self, id: &str, name: &str) -> Result<i64, SessionError> {
+        let kept = self
+            .inner
+            .get(id)
+            .ok_or(SessionError::NoSessionError)?
+            .get::<i64>(name)
+            .unwrap_or_default();
+
+        if let Some((client, _)) = self.counter_client(id)? {
+            if let Some(value) = client
+                .counter_get(&self.storage_id(id), name, &self.config.database.table_name)
+                .await?
+            {
+                return Ok(value.saturating_add(kept));
+            }
+        }
+
+        Ok(kept)
+    }
+
+    /// Moves the session's native counters to the id it is renewed to.
+    pub(crate) async fn counter_rename(&self, id: &str, new_id: &str) -> Result<(), SessionError> {
+        if let Ok(Some((client, expires))) = self.counter_client(id) {
+            client
+                .counter_rename(
+                    &self.storage_id(id),
+                    &self.storage_id(new_id),
+                    expires,
+                    &self.config.database.table_name,
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn duplicate_from(
+        &self,
+        id: &str,
+        changes: &ChangeTracker,
+        source_id: &str,
+    ) -> Result<(), SessionError> {
+        let data = if let Some(source) = self.inner.get(source_id) {
+            source.data.clone()
+        } else if let Some(source) = self.load_session(source_id.to_owned()).await? {
+            source.data
+        } else {
+            return Err(SessionError::NoSessionError);
+        };
+
+        if let Some(mut instance) = self.inner.get_mut(id) {
+            changes.touch_many(&instance.data, data.keys().map(String::as_str));
+            instance.merge(data);
+            Ok(())
+        } else {
+            Err(SessionError::NoSessionError)
+        }
+    }
+
+    /// Serializes the Session's data into a transfer blob which expires after the
+    /// transfer lifespan. Keys the Session stores for itself are left out.
+    /// The blob is encrypted with the database key if set, otherwise signed with the
+    /// cookie key if set, otherwise left as plain JSON.
+    pub(crate) fn export_json(&self, id: &str) -> Result<String, SessionError> {
+        let data = match self.inner.get(id) {
+            Some(instance) => instance
+                .data
+                .iter()
+                .filter(|(key, _)| !SessionChangeSet::is_internal_key(key))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+            None => return Err(SessionError::NoSessionError),
+        };
+        let now = self.now();
+        let json = serde_json::to_string(&TransferPayload {
+            iat: now.timestamp(),
+            exp: (now + self.config.transfer_lifespan).timestamp(),
+            data,
+        })?;
+
+        if let Some(key) = self.config.database.database_key.as_ref() {
+            encrypt::encrypt(TRANSFER_CONTEXT, &json, key)
+                .map_err(|err| SessionError::GenericNotSupportedError(err.to_owned()))
+        } else if let Some(key) = self.config.cookie_and_header.key.as_ref() {
+            sign_header(&json, key, TRANSFER_CONTEXT)
+                .map_err(|err| SessionError::GenericNotSupportedError(err.to_owned()))
+        } else {
+            Ok(json)
+        }
+    }
+
+    /// Verifies a transfer blob made by [`SessionStore::export_json`] and merges its data
+    /// into the Session. Blobs must be made with the same keys this store is configured with,
+    /// must not have expired and must not hold keys the Session stores for itself.
+    pub(crate) fn import_json(
+        &self,
+        id: &str,
+        changes: &ChangeTracker,
+        blob: &str,
+    ) -> Result<(), SessionError> {
+        let json = if let Some(key) = self.config.database.database_key.as_ref() {
+            encrypt::decrypt(TRANSFER_CONTEXT, blob, key)
+                .map_err(|_| SessionError::InvalidTransfer)?
+        } else if let Some(key) = self.config.cookie_and_header.key.as_ref() {
+            verify_header(blob, key, TRANSFER_CONTEXT).map_err(|_| SessionError::InvalidTransfer)?
+        } else {
+            blob.to_owned()
+        };
+
+        let payload: TransferPayload =
+            serde_json::from_str(&json).map_err(|_| SessionError::InvalidTransfer)?;
+        let now = self.now().timestamp();
+
+        if payload.iat > payload.exp
+            || now >= payload.exp
+            || payload
+                .data
+                .keys()
+                .any(|key| SessionChangeSet::is_internal_key(key))
+        {
+            return Err(SessionError::InvalidTransfer);
+        }
+
+        let data = payload.data;
+
+        if let Some(mut instance) = self.inner.get_mut(id) {
+            changes.touch_many(&instance.data, data.keys().map(String::as_str));
+            instance.merge(data);
+            Ok(())
+        } else {
+            Err(SessionError::NoSessionError)
         }
     }
 
     #[inline]
-    pub(crate) fn set_session_request(&self, id: String) {
-        if let Some(mut instance) = self.inner.get_mut(&id) {
-            instance.set_request();
+    pub(crate) fn set_session_request(&self, id: &str) {
+        if let Some(mut instance) = self.inner.get_mut(id) {
+            instance.set_request(self.now());
         } else {
-            tracing::warn!("Session data unexpectedly missing");
+            tracing::warn!(session.id = %self.config.log_id(id), "Session data unexpectedly missing");
         }
     }
 
     #[inline]
-    pub(crate) fn remove_session_request(&self, id: String) {
-        if let Some(mut instance) = self.inner.get_mut(&id) {
-            instance.remove_request();
+    pub(crate) fn remove_session_request(&self, id: &str) {
+        if !accounting::end_request(&*self.inner, id) {
+            tracing::warn!(session.id = %self.config.log_id(id), "Session data unexpectedly missing");
+        }
+    }
+
+    #[inline]
+    pub(crate) fn parallel_count(&self, id: &str) -> usize {
+        if let Some(instance) = self.inner.get(id) {
+            instance.requests
         } else {
-            tracing::warn!("Session data unexpectedly missing");
+            tracing::warn!(session.id = %self.config.log_id(id), "Session data unexpectedly missing");
+            0
         }
     }
 
     #[inline]
-    pub(crate) fn is_session_parallel(&self, id: String) -> bool {
-        if let Some(instance) = self.inner.get(&id) {
+    pub(crate) fn is_session_parallel(&self, id: &str) -> bool {
+        if let Some(instance) = self.inner.get(id) {
             instance.is_parallel()
         } else {
-            tracing::warn!("Session data unexpectedly missing");
+            tracing::warn!(session.id = %self.config.log_id(id), "Session data unexpectedly missing");
             false
         }
     }
@@ -519,28 +1646,26 @@ where
     #[cfg(feature = "advanced")]
     #[cfg_attr(docsrs, doc(cfg(feature = "advanced")))]
     #[inline]
-    pub(crate) fn verify(&self, id: String) -> Result<(), SessionError> {
-        if let Some(instance) = self.inner.get(&id) {
-            if instance.expires < Utc::now() {
-                Err(SessionError::OldSessionError)
+    pub(crate) fn verify(&self, id: &str) -> SessionStatus {
+        if let Some(instance) = self.inner.get(id) {
+            if instance.expired(self.now()) {
+                SessionStatus::Expired {
+                    at: instance.expires,
+                }
             } else {
-                Ok(())
+                SessionStatus::Valid
             }
         } else {
-            Err(SessionError::NoSessionError)
+            SessionStatus::Missing
         }
     }
 
     #[cfg(feature = "advanced")]
     #[cfg_attr(docsrs, doc(cfg(feature = "advanced")))]
     #[inline]
-    pub(crate) fn update_database_expires(&self, id: String) -> Result<(), SessionError> {
-        if let Some(mut instance) = self.inner.get_mut(&id) {
-            if instance.longterm {
-                instance.expires = Utc::now() + self.config.max_lifespan;
-            } else {
-                instance.expires = Utc::now() + self.config.lifespan;
-            }
+    pub(crate) fn update_database_expires(&self, id: &str) -> Result<(), SessionError> {
+        if let Some(mut instance) = self.inner.get_mut(id) {
+            instance.expires = instance.refreshed_expires(self.now(), &self.runtime_config());
 
             Ok(())
         } else {
@@ -551,9 +1676,9 @@ where
     #[cfg(feature = "advanced")]
     #[cfg_attr(docsrs, doc(cfg(feature = "advanced")))]
     #[inline]
-    pub(crate) fn update_memory_expires(&self, id: String) -> Result<(), SessionError> {
-        if let Some(mut instance) = self.inner.get_mut(&id) {
-            instance.autoremove = Utc::now() + self.config.memory.memory_lifespan;
+    pub(crate) fn update_memory_expires(&self, id: &str) -> Result<(), SessionError> {
+        if let Some(mut instance) = self.inner.get_mut(id) {
+            instance.autoremove = self.memory_autoremove();
 
             Ok(())
         } else {
@@ -564,42 +1689,789 @@ where
     #[cfg(feature = "advanced")]
     #[cfg_attr(docsrs, doc(cfg(feature = "advanced")))]
     #[inline]
-    pub(crate) async fn force_database_update(&self, id: String) -> Result<(), SessionError> {
-        let session = if let Some(instance) = self.inner.get(&id) {
+    pub(crate) async fn persist_now(&self, id: &str) -> Result<(), SessionError> {
+        // Refresh the expiry and take the snapshot under the same guard so a purge
+        // sweep can never observe the old expiry with the new data or vice versa.
+        let session = if let Some(mut instance) = self.inner.get_mut(id) {
+            instance.expires = instance.refreshed_expires(self.now(), &self.runtime_config());
             instance.clone()
         } else {
             return Err(SessionError::NoSessionError);
         };
 
-        self.store_session(&session).await
+        // Left set when the store fails so the response phase saves the session again.
+        self.store_session(&session).await?;
+
+        // Changes made while the session was being stored still need to be saved.
+        if let Some(mut instance) = self.inner.get_mut(id) {
+            if instance.data == session.data {
+                instance.update = false;
+            }
+        }
+
+        Ok(())
     }
 
     #[cfg(feature = "advanced")]
     #[cfg_attr(docsrs, doc(cfg(feature = "advanced")))]
     #[inline]
-    pub(crate) fn memory_remove_session(&self, id: String) -> Result<(), SessionError> {
-        let is_parallel = if let Some(mut instance) = self.inner.get_mut(&id) {
-            instance.remove_request();
-            instance.is_parallel()
-        } else {
+    pub(crate) fn memory_remove_session(&self, id: &str) -> Result<(), SessionError> {
+        if !accounting::end_request(&*self.inner, id) {
             return Err(SessionError::NoSessionError);
-        };
-
-        if is_parallel {
-            let _ = self.inner.remove(&id);
         }
 
+        // A request may have joined since the count was lowered, so only unload if still idle.
+        let _ = accounting::unload_if_idle(&*self.inner, id, |_| true);
         Ok(())
     }
 
     #[inline]
-    pub(crate) async fn database_remove_session(&self, id: String) -> Result<(), SessionError> {
+    pub(crate) async fn database_remove_session(&self, id: &str) -> Result<(), SessionError> {
         if let Some(client) = &self.client {
             client
-                .delete_one_by_id(&id, &self.config.database.table_name)
+                .delete_one_by_id(&self.storage_id(id), &self.config.database.table_name)
                 .await?;
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        databases::test_pool::SessionTestPool,
+        sec::{decrypt_payload, encrypt},
+        CorruptAction, DatabasePool, Key, PayloadCipher, RuntimeConfig, SessionConfig, SessionData,
+        SessionError, SessionStore, StaticKeyCipher, STATIC_KEY_CIPHER_ID,
+    };
+    use async_trait::async_trait;
+    use base64::{engine::general_purpose, Engine as _};
+    use chrono::{Duration, Utc};
+    use futures::FutureExt;
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    fn hashed_config() -> SessionConfig {
+        SessionConfig::default()
+            .with_hashed_storage_ids(true)
+            .with_storage_id_pepper("pepper")
+            .with_database_key(Key::generate())
+    }
+
+    fn raw_id_absent(pool: &SessionTestPool, id: &str) -> bool {
+        pool.rows
+            .iter()
+            .all(|r| !r.key().1.contains(id) && !r.value().0.contains(id))
+    }
+
+    #[tokio::test]
+    async fn hashed_storage_requires_pepper() {
+        let config = SessionConfig::default().with_hashed_storage_ids(true);
+        let err = SessionStore::<SessionTestPool>::new(None, config)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SessionError::InvalidConfiguration(_)));
+    }
+
+    #[tokio::test]
+    async fn bad_configs_fail_at_construction() {
+        let bad = [
+            SessionConfig::default().with_max_age(Some(Duration::try_seconds(-1).unwrap())),
+            SessionConfig::default().with_max_age(Some(Duration::try_days(400_000_000).unwrap())),
+            SessionConfig::default().with_filter_expected_elements(0),
+            SessionConfig::default().with_filter_false_positive_probability(0.0),
+            SessionConfig::default().with_filter_false_positive_probability(1.0),
+            SessionConfig::default().with_filter_false_positive_probability(f64::NAN),
+            SessionConfig::default().with_double_submit_cookie("csrf"),
+            SessionConfig::default().with_max_parallel_requests(Some(0)),
+            SessionConfig::default().with_hashed_storage_ids(true),
+        ];
+
+        for config in bad {
+            let err = SessionStore::new(Some(SessionTestPool::default()), config)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, SessionError::InvalidConfiguration(_)));
+        }
+
+        let config = SessionConfig::default().with_max_age(None);
+        assert!(SessionStore::<SessionTestPool>::new(None, config)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn key_namespaces_are_isolated() {
+        let pool = SessionTestPool {
+            key_value: true,
+            ..Default::default()
+        };
+
+        // An empty namespace would act on the whole database.
+        let config = SessionConfig::default().with_table_name("");
+        let err = SessionStore::new(Some(pool.clone()), config.clone())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SessionError::InvalidConfiguration(_)));
+        let err = SessionStore::new(
+            Some(pool.clone()),
+            SessionConfig::default().with_key_namespace(""),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, SessionError::InvalidConfiguration(_)));
+        SessionStore::new(Some(pool.clone()), config.with_allow_global_namespace(true))
+            .await
+            .unwrap();
+        // Databases with tables never use the namespace.
+        let store = SessionStore::new(
+            Some(SessionTestPool::default()),
+            SessionConfig::default().with_key_namespace("app_a"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(store.config.database.table_name, "sessions");
+
+        let mut stores = Vec::new();
+        for namespace in ["app_a", "app_b"] {
+            let config = SessionConfig::default().with_key_namespace(namespace);
+            let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+
+            for index in 0..3 {
+                let data = SessionData::new(
+                    format!("{namespace}-{index}"),
+                    true,
+                    &store.runtime_config(),
+                    store.config.clock.as_ref(),
+                );
+                store.store_session(&data).await.unwrap();
+            }
+
+            stores.push(store);
+        }
+
+        let (a, b) = (&stores[0], &stores[1]);
+        assert_eq!(a.count().await.unwrap(), 3);
+        assert_eq!(b.count().await.unwrap(), 3);
+        let ids = a.client.as_ref().unwrap().get_ids("app_a").await.unwrap();
+        assert!(ids.len() == 3 && ids.iter().all(|id| id.starts_with("app_a-")));
+
+        a.clear_store().await.unwrap();
+        assert_eq!(a.count().await.unwrap(), 0);
+        assert_eq!(b.count().await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn cleanup_unloads_expired_sessions() {
+        let pool = SessionTestPool::default();
+        // Hashed so memory ids have to be matched against the storage ids cleanup returns.
+        let store = SessionStore::new(Some(pool.clone()), hashed_config())
+            .await
+            .unwrap();
+        let past = Utc::now() - Duration::try_minutes(5).unwrap();
+
+        for (id, requests) in [("stale", 0), ("in-flight", 1), ("refreshed", 0)] {
+            let mut data = SessionData::new(
+                id.to_owned(),
+                true,
+                &store.runtime_config(),
+                store.config.clock.as_ref(),
+            );
+            data.expires = past;
+            data.requests = requests;
+            store.store_session(&data).await.unwrap();
+
+            if id == "refreshed" {
+                data.expires = Utc::now() + Duration::try_hours(1).unwrap();
+            }
+
+            store.inner.insert(id.to_owned(), data);
+        }
+
+        let expired = store.cleanup().await.unwrap();
+        assert_eq!(expired.len(), 3);
+        assert!(store.inner.get("stale").is_none());
+        assert!(store.inner.get("in-flight").is_some());
+        assert!(store.inner.get("refreshed").is_some());
+    }
+
+    /// Config whose expired sessions handler sends every reported id list to the receiver.
+    fn recording_config() -> (SessionConfig, UnboundedReceiver<Vec<String>>) {
+        let (tx, rx) = unbounded_channel();
+        let config = SessionConfig::default().with_expired_sessions_handler(Arc::new(move |ids| {
+            let tx = tx.clone();
+            async move {
+                let _ = tx.send(ids);
+            }
+            .boxed()
+        }));
+
+        (config, rx)
+    }
+
+    #[tokio::test]
+    async fn expired_sessions_handler_reports_sweeps() {
+        let pool = SessionTestPool::default();
+        let (config, mut reported) = recording_config();
+        let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+        let now = Utc::now().timestamp();
+        pool.insert_row("sessions", "gone-a", "", now - 60);
+        pool.insert_row("sessions", "gone-b", "", now - 60);
+        pool.insert_row("sessions", "alive", "", now + 3600);
+
+        store.cleanup().await.unwrap();
+        let mut ids = reported.recv().await.unwrap();
+        ids.sort();
+        assert_eq!(ids, ["gone-a", "gone-b"]);
+
+        // Nothing expired since, so the handler is not called again.
+        store.cleanup().await.unwrap();
+        tokio::task::yield_now().await;
+        assert!(reported.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn expired_sessions_handler_skips_auto_expiry() {
+        let pool = SessionTestPool {
+            auto_expiry: true,
+            ..Default::default()
+        };
+        let (config, mut reported) = recording_config();
+        let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+        pool.insert_row("sessions", "gone", "", Utc::now().timestamp() - 60);
+
+        store.cleanup().await.unwrap();
+        tokio::task::yield_now().await;
+        assert!(reported.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn hashed_storage_round_trip() {
+        let pool = SessionTestPool::default();
+        let store = SessionStore::new(Some(pool.clone()), hashed_config())
+            .await
+            .unwrap();
+        let id = "4a4e5d3b-raw-session-id".to_owned();
+        let mut data = SessionData::new(
+            id.clone(),
+            true,
+            &store.runtime_config(),
+            store.config.clock.as_ref(),
+        );
+        data.set("user", 7);
+        store.store_session(&data).await.unwrap();
+
+        assert_eq!(pool.rows.len(), 1);
+        assert!(raw_id_absent(&pool, &id));
+
+        let storage_id = store.storage_id(&id).into_owned();
+        let (row, _) = pool.row("sessions", &storage_id).unwrap();
+        let cipher = store.config.database.payload_cipher.as_deref().unwrap();
+        assert!(decrypt_payload(cipher, &[], &storage_id, &row)
+            .await
+            .is_ok());
+        assert!(decrypt_payload(cipher, &[], &id, &row).await.is_err());
+
+        let loaded = store.load_session(id.clone()).await.unwrap().unwrap();
+        assert_eq!(loaded.id, id);
+        assert_eq!(loaded.get::<i32>("user"), Some(7));
+        assert!(store.database_exists(&id).await.unwrap());
+
+        store.database_remove_session(&id).await.unwrap();
+        assert!(pool.rows.is_empty());
+    }
+
+    /// Envelope encryption like a KMS would do it, counting the calls made to the "KMS".
+    #[derive(Default)]
+    struct MockKms {
+        master: Option<Key>,
+        calls: AtomicUsize,
+        data_key: Mutex<Option<(Vec<u8>, Key)>>,
+        unwrapped: Mutex<HashMap<Vec<u8>, Key>>,
+    }
+
+    impl MockKms {
+        fn master(&self) -> &Key {
+            self.master.as_ref().unwrap()
+        }
+    }
+
+    #[async_trait]
+    impl PayloadCipher for MockKms {
+        fn id(&self) -> u8 {
+            200
+        }
+
+        async fn encrypt(&self, aad: &str, plaintext: &[u8]) -> Result<Vec<u8>, SessionError> {
+            tokio::task::yield_now().await;
+            let (wrapped, key) = self
+                .data_key
+                .lock()
+                .unwrap()
+                .get_or_insert_with(|| {
+                    self.calls.fetch_add(1, Ordering::SeqCst);
+                    let key = Key::generate();
+                    let wrapped = encrypt::seal("data key", key.master(), self.master()).unwrap();
+                    (wrapped, key)
+                })
+                .clone();
+
+            let mut data = vec![wrapped.len() as u8];
+            data.extend(wrapped);
+            data.extend(encrypt::seal(aad, plaintext, &key).unwrap());
+            Ok(data)
+        }
+
+        async fn decrypt(&self, aad: &str, ciphertext: &[u8]) -> Result<Vec<u8>, SessionError> {
+            tokio::task::yield_now().await;
+            let (len, rest) = ciphertext.split_first().unwrap();
+            let (wrapped, sealed) = rest.split_at(*len as usize);
+            let key = self
+                .unwrapped
+                .lock()
+                .unwrap()
+                .entry(wrapped.to_vec())
+                .or_insert_with(|| {
+                    self.calls.fetch_add(1, Ordering::SeqCst);
+                    let master = encrypt::open("data key", wrapped, self.master()).unwrap();
+                    Key::from(&master)
+                })
+                .clone();
+
+            encrypt::open(aad, sealed, &key)
+        }
+    }
+
+    #[tokio::test]
+    async fn payload_cipher_round_trips() {
+        let pool = SessionTestPool::default();
+        let kms = Arc::new(MockKms {
+            master: Some(Key::generate()),
+            ..Default::default()
+        });
+        let config = SessionConfig::default().with_payload_cipher(kms.clone());
+        let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+
+        for id in ["one", "two", "three"] {
+            let mut data = SessionData::new(
+                id.to_owned(),
+                true,
+                &store.runtime_config(),
+                store.config.clock.as_ref(),
+            );
+            data.set("user", id);
+            store.store_session(&data).await.unwrap();
+        }
+
+        let (row, _) = pool.row("sessions", "one").unwrap();
+        let data = general_purpose::STANDARD
+            .decode(row.strip_prefix('$').unwrap())
+            .unwrap();
+        assert_eq!(data[0], 200);
+        assert!(!row.contains("user"));
+
+        for id in ["one", "two", "three"] {
+            let loaded = store.load_session(id.to_owned()).await.unwrap().unwrap();
+            assert_eq!(loaded.get::<String>("user").as_deref(), Some(id));
+        }
+
+        // One data key was generated and unwrapped once for all three sessions.
+        assert_eq!(kms.calls.load(Ordering::SeqCst), 2);
+
+        // The payload is bound to the id it was stored under.
+        assert!(decrypt_payload(kms.as_ref(), &[], "two", &row)
+            .await
+            .is_err());
+        pool.insert_row("sessions", "two", &row, Utc::now().timestamp() + 3600);
+        assert!(store
+            .load_session("two".to_owned())
+            .await
+            .unwrap()
+            .is_none());
+
+        // Rows stored by the static key cipher or before cipher ids are not read by other ciphers.
+        let legacy = encrypt::encrypt("one", "{}", &Key::generate()).unwrap();
+        assert!(decrypt_payload(kms.as_ref(), &[], "one", &legacy)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn legacy_static_key_rows_decrypt() {
+        let key = Key::generate();
+        let expires = (Utc::now() + Duration::try_hours(1).unwrap()).timestamp();
+
+        for config in [
+            SessionConfig::default().with_database_key(key.clone()),
+            SessionConfig::default()
+                .with_payload_cipher(Arc::new(StaticKeyCipher::new(key.clone()))),
+        ] {
+            let pool = SessionTestPool::default();
+            let mut data = SessionData::new(
+                "legacy".to_owned(),
+                true,
+                &RuntimeConfig::from(&config),
+                config.clock.as_ref(),
+            );
+            data.set("user", 5);
+            let raw =
+                encrypt::encrypt("legacy", &serde_json::to_string(&data).unwrap(), &key).unwrap();
+            pool.insert_row("sessions", "legacy", &raw, expires);
+
+            let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+            let loaded = store
+                .load_session("legacy".to_owned())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(loaded.get::<i32>("user"), Some(5));
+
+            // Stored again it is written with the static key cipher's id.
+            store.store_session(&data).await.unwrap();
+            let (row, _) = pool.row("sessions", "legacy").unwrap();
+            let stored = general_purpose::STANDARD
+                .decode(row.strip_prefix('$').unwrap())
+                .unwrap();
+            assert_eq!(stored[0], STATIC_KEY_CIPHER_ID);
+            let loaded = store
+                .load_session("legacy".to_owned())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(loaded.get::<i32>("user"), Some(5));
+        }
+    }
+
+    #[tokio::test]
+    async fn decrypt_ciphers_read_rotated_rows() {
+        let key = Key::generate();
+        let expires = (Utc::now() + Duration::try_hours(1).unwrap()).timestamp();
+        let old = SessionConfig::default().with_database_key(key.clone());
+        let pool = SessionTestPool::default();
+        let old_store = SessionStore::new(Some(pool.clone()), old).await.unwrap();
+        let mut data = SessionData::new(
+            "rotated".to_owned(),
+            true,
+            &old_store.runtime_config(),
+            old_store.config.clock.as_ref(),
+        );
+        data.set("user", 5);
+        old_store.store_session(&data).await.unwrap();
+        // Rows stored before cipher ids are read by the static key cipher too.
+        let raw = encrypt::encrypt("legacy", &serde_json::to_string(&data).unwrap(), &key).unwrap();
+        pool.insert_row("sessions", "legacy", &raw, expires);
+
+        let kms = Arc::new(MockKms {
+            master: Some(Key::generate()),
+            ..Default::default()
+        });
+        let config = SessionConfig::default()
+            .with_payload_cipher(kms.clone())
+            .with_decrypt_cipher(Arc::new(StaticKeyCipher::new(key.clone())));
+        let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+
+        for id in ["rotated", "legacy"] {
+            let loaded = store.load_session(id.to_owned()).await.unwrap().unwrap();
+            assert_eq!(loaded.get::<i32>("user"), Some(5));
+        }
+
+        // Stored again it is written by the payload cipher.
+        store.store_session(&data).await.unwrap();
+        let (row, _) = pool.row("sessions", "rotated").unwrap();
+        let stored = general_purpose::STANDARD
+            .decode(row.strip_prefix('$').unwrap())
+            .unwrap();
+        assert_eq!(stored[0], 200);
+
+        // Without the decrypt cipher the rows stored by the old key no longer load.
+        let config = SessionConfig::default().with_payload_cipher(kms.clone());
+        let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+        assert!(store
+            .load_session("legacy".to_owned())
+            .await
+            .unwrap()
+            .is_none());
+
+        for config in [
+            SessionConfig::default().with_decrypt_cipher(kms.clone()),
+            SessionConfig::default()
+                .with_payload_cipher(kms.clone())
+                .with_decrypt_cipher(kms.clone()),
+        ] {
+            assert!(matches!(
+                SessionStore::new(Some(pool.clone()), config).await,
+                Err(SessionError::InvalidConfiguration(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn rehash_existing_rows() {
+        let pool = SessionTestPool::default();
+        let config = hashed_config();
+        let key = config.database.database_key.clone().unwrap();
+        let expires = (Utc::now() + Duration::try_hours(1).unwrap()).timestamp();
+
+        for id in ["legacy-one", "legacy-two"] {
+            let mut data = SessionData::new(
+                id.to_owned(),
+                true,
+                &RuntimeConfig::from(&config),
+                config.clock.as_ref(),
+            );
+            data.set("legacy", id);
+            let raw = encrypt::encrypt(id, &serde_json::to_string(&data).unwrap(), &key).unwrap();
+            pool.insert_row("sessions", id, &raw, expires);
+        }
+
+        let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+        assert_eq!(store.rehash_existing_rows().await.unwrap(), 2);
+        // Already migrated rows are skipped.
+        assert_eq!(store.rehash_existing_rows().await.unwrap(), 0);
+
+        for id in ["legacy-one", "legacy-two"] {
+            assert!(raw_id_absent(&pool, id));
+            let loaded = store.load_session(id.to_owned()).await.unwrap().unwrap();
+            assert_eq!(loaded.get::<String>("legacy").as_deref(), Some(id));
+        }
+    }
+
+    #[tokio::test]
+    async fn load_session_separates_missing_from_failed() {
+        let pool = SessionTestPool::default();
+        let store = SessionStore::new(Some(pool.clone()), SessionConfig::default())
+            .await
+            .unwrap();
+        let expired = (Utc::now() - Duration::try_hours(1).unwrap()).timestamp();
+        let data = SessionData::new(
+            "expired-id".to_owned(),
+            true,
+            &store.runtime_config(),
+            store.config.clock.as_ref(),
+        );
+        pool.insert_row(
+            "sessions",
+            "expired-id",
+            &serde_json::to_string(&data).unwrap(),
+            expired,
+        );
+
+        assert!(store
+            .load_session("missing-id".to_owned())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(store
+            .load_session("expired-id".to_owned())
+            .await
+            .unwrap()
+            .is_none());
+
+        pool.fail_loads
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        let err = store
+            .load_session("missing-id".to_owned())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SessionError::DatabaseError(_)));
+    }
+
+    #[tokio::test]
+    async fn load_session_clamps_future_creation() {
+        let pool = SessionTestPool::default();
+        let store = SessionStore::new(Some(pool.clone()), SessionConfig::default())
+            .await
+            .unwrap();
+        let future = Utc::now() + Duration::try_days(3650).unwrap();
+        let mut data = SessionData::new(
+            "future-id".to_owned(),
+            true,
+            &store.runtime_config(),
+            store.config.clock.as_ref(),
+        );
+        data.created_at = future;
+        pool.insert_row(
+            "sessions",
+            "future-id",
+            &serde_json::to_string(&data).unwrap(),
+            future.timestamp(),
+        );
+
+        let loaded = store
+            .load_session("future-id".to_owned())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(loaded.created_at <= Utc::now());
+    }
+
+    async fn corrupt_store(
+        action: Option<CorruptAction>,
+    ) -> (
+        SessionStore<SessionTestPool>,
+        SessionTestPool,
+        Arc<Mutex<Vec<String>>>,
+    ) {
+        let pool = SessionTestPool::default();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut config = SessionConfig::default();
+
+        if let Some(action) = action {
+            let seen = seen.clone();
+            config = config.with_corrupt_session_handler(Arc::new(move |corrupt| {
+                assert!(matches!(corrupt.error, SessionError::SerdeJson(_)));
+                seen.lock()
+                    .unwrap()
+                    .push(format!("{}={}", corrupt.id, corrupt.payload));
+                action
+            }));
+        }
+
+        let store = SessionStore::new(Some(pool.clone()), config).await.unwrap();
+        let expires = (Utc::now() + Duration::try_hours(1).unwrap()).timestamp();
+        pool.insert_row("sessions", "corrupt-id", "not json", expires);
+        (store, pool, seen)
+    }
+
+    #[tokio::test]
+    async fn corrupt_session_default_deletes_row() {
+        let (store, pool, _) = corrupt_store(None).await;
+
+        assert!(store
+            .load_session("corrupt-id".to_owned())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(pool.row("sessions", "corrupt-id").is_none());
+    }
+
+    #[tokio::test]
+    async fn corrupt_session_recreate_and_delete() {
+        let (store, pool, seen) = corrupt_store(Some(CorruptAction::RecreateAndDelete)).await;
+
+        assert!(store
+            .load_session("corrupt-id".to_owned())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(pool.row("sessions", "corrupt-id").is_none());
+        assert_eq!(*seen.lock().unwrap(), ["corrupt-id=not json"]);
+    }
+
+    #[tokio::test]
+    async fn corrupt_session_recreate_keep_row() {
+        let (store, pool, seen) = corrupt_store(Some(CorruptAction::RecreateKeepRow)).await;
+
+        assert!(store
+            .load_session("corrupt-id".to_owned())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(pool.row("sessions", "corrupt-id").is_some());
+        assert_eq!(seen.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn corrupt_session_fail_request() {
+        let (store, pool, seen) = corrupt_store(Some(CorruptAction::FailRequest)).await;
+
+        let err = store
+            .load_session("corrupt-id".to_owned())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SessionError::CorruptSession));
+        assert!(pool.row("sessions", "corrupt-id").is_some());
+        assert_eq!(seen.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn update_runtime_config() {
+        let store = SessionStore::<SessionTestPool>::new(None, SessionConfig::default())
+            .await
+            .unwrap();
+        let clone = store.clone();
+        let runtime = store
+            .runtime_config()
+            .with_lifetime(Duration::try_minutes(5).unwrap())
+            .with_purge_update(Duration::try_minutes(1).unwrap());
+
+        store.update_runtime_config(runtime).await.unwrap();
+        assert_eq!(clone.runtime_config(), runtime);
+        assert!(
+            clone.timers.read().await.last_expiry_sweep
+                <= std::time::Instant::now() + std::time::Duration::from_secs(60)
+        );
+
+        let data = SessionData::new(
+            "id".to_owned(),
+            true,
+            &clone.runtime_config(),
+            clone.config.clock.as_ref(),
+        );
+        assert!(data.expires <= Utc::now() + Duration::try_minutes(5).unwrap());
+
+        let err = store
+            .update_runtime_config(runtime.with_lifetime(Duration::try_minutes(-1).unwrap()))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SessionError::InvalidConfiguration(_)));
+        assert_eq!(store.runtime_config(), runtime);
+    }
+
+    #[tokio::test]
+    async fn active_count_falls_back_to_memory() {
+        let store = SessionStore::new(Some(SessionTestPool::default()), SessionConfig::default())
+            .await
+            .unwrap();
+
+        for (id, minutes) in [("one", 1), ("four", 4), ("thirty", 30)] {
+            let mut data = SessionData::new(
+                id.to_owned(),
+                true,
+                &store.runtime_config(),
+                store.config.clock.as_ref(),
+            );
+            data.last_active = Utc::now() - Duration::try_minutes(minutes).unwrap();
+            store.inner.insert(id.to_owned(), data);
+        }
+
+        let window = Duration::try_minutes(5).unwrap();
+        assert_eq!(store.active_count(window).await.unwrap(), 2);
+        assert_eq!(
+            store
+                .active_count(Duration::try_hours(1).unwrap())
+                .await
+                .unwrap(),
+            3
+        );
+
+        // A new request marks the session active again.
+        store
+            .inner
+            .get_mut("thirty")
+            .unwrap()
+            .set_request(Utc::now());
+        assert_eq!(store.active_count(window).await.unwrap(), 3);
+    }
+
+    #[cfg(feature = "legacy-compat")]
+    #[tokio::test]
+    async fn legacy_compat_still_reports_corrupt_payloads() {
+        let (store, pool, seen) = corrupt_store(Some(CorruptAction::RecreateAndDelete)).await;
+
+        assert!(store
+            .load_session("corrupt-id".to_owned())
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(seen.lock().unwrap().len(), 1);
+        assert!(pool.row("sessions", "corrupt-id").is_none());
+    }
+}